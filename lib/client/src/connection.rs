@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+
+use storage::entry::DecodeOptions;
+use storage::server::{
+    self, peek_correlation_id, read_frame, Request, Response, FRAME_LENGTH_SIZE,
+};
+
+use crate::{ClientOptions, Error, Result};
+
+/// The `Connection` is a TCP connection to a storage node, which pipelines the requests.
+///
+/// The requests are written by a writer task in the order they are sent,
+/// and a reader task hands each response to the request with the same correlation id.
+/// Once either task fails the connection is closed, and all its pending requests fail.
+pub(crate) struct Connection {
+    next_correlation_id: AtomicU64,
+    max_frame_size: usize,
+    tx: mpsc::Sender<BytesMut>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    pending: Mutex<HashMap<u64, oneshot::Sender<Result<Response>>>>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    // Fail the pending requests, the closed flag is set first so no request is left waiting.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+impl Connection {
+    /// Connect to the node, fails with `Error::Timeout` if it takes longer than the connect timeout.
+    pub(crate) async fn connect(addr: &str, options: &ClientOptions) -> Result<Self> {
+        let stream = tokio::time::timeout(options.connect_timeout, TcpStream::connect(addr))
+            .await
+            .map_err(|_| Error::Timeout(options.connect_timeout))??;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        let (tx, rx) = mpsc::channel(options.max_in_flight);
+        tokio::spawn(write_requests(writer, rx, shared.clone()));
+        tokio::spawn(read_responses(
+            reader,
+            shared.clone(),
+            options.max_frame_size,
+            options.decode_options,
+        ));
+        Ok(Self {
+            next_correlation_id: AtomicU64::new(0),
+            max_frame_size: options.max_frame_size,
+            tx,
+            shared,
+        })
+    }
+
+    /// Returns whether the connection is closed, it can't be used anymore.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// Send the request and wait for its response, or fail after the timeout.
+    /// A request larger than the max frame size fails with `Error::BadRequest` before it's sent,
+    /// the node would close the connection shared with the other requests.
+    pub(crate) async fn call(&self, request: &Request, timeout: Duration) -> Result<Response> {
+        let correlation_id = self.next_correlation_id.fetch_add(1, Ordering::Relaxed);
+        let mut buf = BytesMut::new();
+        request.encode(correlation_id, &mut buf);
+        let size = buf.len() - FRAME_LENGTH_SIZE;
+        if size > self.max_frame_size {
+            return Err(Error::BadRequest(format!(
+                "request frame size {} exceeds the max {}",
+                size, self.max_frame_size
+            )));
+        }
+
+        let (reply, response) = oneshot::channel();
+        self.shared
+            .pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id, reply);
+        let forget = || {
+            self.shared.pending.lock().unwrap().remove(&correlation_id);
+        };
+        if self.is_closed() {
+            forget();
+            return Err(Error::ConnectionClosed);
+        }
+
+        let sent = async {
+            self.tx
+                .send(buf)
+                .await
+                .map_err(|_| Error::ConnectionClosed)?;
+            // The reply is dropped if the connection is closed.
+            response.await.map_err(|_| Error::ConnectionClosed)?
+        };
+        match tokio::time::timeout(timeout, sent).await {
+            Ok(result) => result,
+            Err(_) => {
+                forget();
+                Err(Error::Timeout(timeout))
+            }
+        }
+    }
+}
+
+async fn write_requests(
+    writer: OwnedWriteHalf,
+    mut rx: mpsc::Receiver<BytesMut>,
+    shared: Arc<Shared>,
+) {
+    let mut writer = BufWriter::new(writer);
+    while let Some(frame) = rx.recv().await {
+        if writer.write_all(&frame).await.is_err() {
+            break;
+        }
+        // Flush once no request is ready, so the pipelined requests share the writes.
+        if rx.is_empty() && writer.flush().await.is_err() {
+            break;
+        }
+    }
+    shared.close();
+}
+
+async fn read_responses(
+    reader: OwnedReadHalf,
+    shared: Arc<Shared>,
+    max_frame_size: usize,
+    options: DecodeOptions,
+) {
+    let mut reader = BufReader::new(reader);
+    while let Ok(Some(body)) = read_frame(&mut reader, max_frame_size).await {
+        let peeked = peek_correlation_id(&body);
+        let (correlation_id, result) = match (Response::decode(body, &options), peeked) {
+            (Ok((correlation_id, response)), _) => (correlation_id, Ok(response)),
+            // An entry which can't be decoded only fails its own request.
+            (Err(server::Error::Entry(e)), Some(correlation_id)) => {
+                (correlation_id, Err(Error::Entry(e)))
+            }
+            _ => break,
+        };
+        // The request is gone if it timed out.
+        if let Some(reply) = shared.pending.lock().unwrap().remove(&correlation_id) {
+            let _ = reply.send(result);
+        }
+    }
+    shared.close();
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+
+use storage::entry;
+use storage::server::{self, Op};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("entry")]
+    Entry(#[from] entry::Error),
+
+    #[error("protocol")]
+    Protocol(#[source] server::Error),
+
+    #[error("request timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("connection is closed")]
+    ConnectionClosed,
+
+    #[error("ledger {0} not found")]
+    LedgerNotFound(i64),
+
+    #[error("entry {entry_id} of ledger {log_id} not found")]
+    EntryNotFound { log_id: i64, entry_id: i64 },
+
+    #[error("ledger {0} is fenced")]
+    Fenced(i64),
+
+    #[error("ledger {0} is closed")]
+    Closed(i64),
+
+    #[error("bad request: {0}")]
+    BadRequest(String),
+
+    #[error("node is unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("node failed: {0}")]
+    Internal(String),
+
+    #[error("unexpected response to {0:?}")]
+    UnexpectedResponse(Op),
+}
+
+impl Error {
+    /// Returns whether the request may succeed if it's sent again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::Io(_) | Error::Timeout(_) | Error::ConnectionClosed | Error::Unavailable(_)
+        )
+    }
+}
+
+impl From<server::Error> for Error {
+    fn from(e: server::Error) -> Self {
+        match e {
+            server::Error::Io(e) => Error::Io(e),
+            server::Error::Entry(e) => Error::Entry(e),
+            e => Error::Protocol(e),
+        }
+    }
+}
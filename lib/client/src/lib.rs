@@ -0,0 +1,93 @@
+mod connection;
+mod error;
+mod node;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use storage::entry::DecodeOptions;
+
+pub use error::Error;
+pub use node::NodeClient;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The `ClientOptions` is the configuration of a client.
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    /// The number of connections to each node, the requests are spread over them.
+    pub connections_per_node: usize,
+    pub connect_timeout: Duration,
+    /// The time to wait for the response of a request, for each attempt.
+    pub request_timeout: Duration,
+    /// The max number of times a request is sent again after a transient failure,
+    /// e.g. a lost connection or a timeout.
+    pub max_retries: usize,
+    /// The delay before the first retry, it's doubled after each retry.
+    pub retry_backoff: Duration,
+    /// The max number of requests in flight per connection waiting to be written,
+    /// and per `read_range` call.
+    pub max_in_flight: usize,
+    /// The max size of a request or response frame, the requests should stay within the nodes' max.
+    pub max_frame_size: usize,
+    /// The limits of the entries read from the nodes.
+    pub decode_options: DecodeOptions,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            connections_per_node: 2,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            max_in_flight: 1024,
+            max_frame_size: 16 * 1024 * 1024,
+            decode_options: DecodeOptions::default(),
+        }
+    }
+}
+
+/// The `Client` talks to the storage nodes of a cluster.
+///
+/// It keeps a pool of connections to each node it talks to,
+/// the clients of the same node returned by `node` share its pool.
+/// The client is cheap to clone, the clones share the pools.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    options: ClientOptions,
+    nodes: Mutex<HashMap<String, NodeClient>>,
+}
+
+impl Client {
+    pub fn new(options: ClientOptions) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                options,
+                nodes: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Returns the options of the client.
+    pub fn options(&self) -> &ClientOptions {
+        &self.inner.options
+    }
+
+    /// Returns the client of the node at the address, the connections are opened on first use.
+    pub fn node(&self, addr: &str) -> NodeClient {
+        self.inner
+            .nodes
+            .lock()
+            .unwrap()
+            .entry(addr.to_string())
+            .or_insert_with(|| NodeClient::new(addr, self.inner.options.clone()))
+            .clone()
+    }
+}
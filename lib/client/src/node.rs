@@ -0,0 +1,527 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+
+use storage::entry::{AnyEntry, Entry, EntryRef};
+use storage::ledger;
+use storage::replication::{self, Node};
+use storage::segment::INVALID_ENTRY_ID;
+use storage::server::{Request, Response, Status};
+
+use crate::connection::Connection;
+use crate::{ClientOptions, Error, Result};
+
+/// The `NodeClient` sends requests to a storage node over a pool of connections.
+///
+/// The requests are spread over the connections round robin, and pipelined on each of them.
+/// A closed connection is replaced on its next use. The requests failing for a transient reason
+/// are retried, which is safe since all requests are idempotent.
+/// The client is cheap to clone, the clones share the pool.
+#[derive(Clone)]
+pub struct NodeClient {
+    inner: Arc<Pool>,
+}
+
+struct Pool {
+    addr: String,
+    options: ClientOptions,
+    connections: Vec<Mutex<Option<Arc<Connection>>>>,
+    next: AtomicUsize,
+}
+
+impl NodeClient {
+    /// Create the client of the node at the address, the connections are opened on first use.
+    pub fn new(addr: impl Into<String>, options: ClientOptions) -> Self {
+        let connections = (0..options.connections_per_node.max(1))
+            .map(|_| Mutex::new(None))
+            .collect();
+        Self {
+            inner: Arc::new(Pool {
+                addr: addr.into(),
+                options,
+                connections,
+                next: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Returns the address of the node.
+    pub fn addr(&self) -> &str {
+        &self.inner.addr
+    }
+
+    /// Add an entry, resolved after the entry is durable on the node.
+    pub async fn add_entry<E: Entry>(&self, entry: &E) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(entry.binary_size());
+        entry.encode(&mut buf)?;
+        self.add(entry.log_id(), entry.entry_id(), buf.freeze(), false)
+            .await
+    }
+
+    /// Read an entry stored on the node.
+    pub async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry> {
+        match self.call(Request::ReadEntry { log_id, entry_id }).await? {
+            Response::ReadEntry(entry) => Ok(entry),
+            response => Err(error_of(response, log_id, entry_id)),
+        }
+    }
+
+    /// Read the entries from `first_entry_id` to `last_entry_id` inclusive, in order.
+    /// The reads are pipelined, at most `max_in_flight` of them at once.
+    pub async fn read_range(
+        &self,
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    ) -> Result<Vec<AnyEntry>> {
+        let count = (last_entry_id - first_entry_id + 1).max(0) as usize;
+        let mut entries: Vec<Option<AnyEntry>> = (0..count).map(|_| None).collect();
+        let mut reads = JoinSet::new();
+        let mut entry_ids = first_entry_id..=last_entry_id;
+        loop {
+            while reads.len() < self.inner.options.max_in_flight.max(1) {
+                let Some(entry_id) = entry_ids.next() else {
+                    break;
+                };
+                let client = self.clone();
+                reads.spawn(async move { (entry_id, client.read_entry(log_id, entry_id).await) });
+            }
+            let Some(read) = reads.join_next().await else {
+                break;
+            };
+            let (entry_id, entry) = read.expect("read task never panics");
+            entries[(entry_id - first_entry_id) as usize] = Some(entry?);
+        }
+        Ok(entries.into_iter().flatten().collect())
+    }
+
+    /// Returns the LAC of the ledger known by the node.
+    pub async fn read_lac(&self, log_id: i64) -> Result<i64> {
+        match self.call(Request::ReadLac { log_id }).await? {
+            Response::ReadLac(lac) => Ok(lac),
+            response => Err(error_of(response, log_id, INVALID_ENTRY_ID)),
+        }
+    }
+
+    /// Fence the ledger on the node, returns the LAC known by the node.
+    pub async fn fence(&self, log_id: i64) -> Result<i64> {
+        match self.call(Request::Fence { log_id }).await? {
+            Response::Fence(lac) => Ok(lac),
+            response => Err(error_of(response, log_id, INVALID_ENTRY_ID)),
+        }
+    }
+
+    /// Close the ledger on the node with the id of its last entry.
+    pub async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()> {
+        let request = Request::Close {
+            log_id,
+            last_entry_id,
+        };
+        match self.call(request).await? {
+            Response::Close => Ok(()),
+            response => Err(error_of(response, log_id, INVALID_ENTRY_ID)),
+        }
+    }
+
+    /// Returns the ids of the entries of the ledger stored on the node,
+    /// from `first_entry_id` to `last_entry_id` inclusive, in order.
+    pub async fn entry_ids(
+        &self,
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    ) -> Result<Vec<i64>> {
+        let request = Request::EntryIds {
+            log_id,
+            first_entry_id,
+            last_entry_id,
+        };
+        match self.call(request).await? {
+            Response::EntryIds(entry_ids) => Ok(entry_ids),
+            response => Err(error_of(response, log_id, INVALID_ENTRY_ID)),
+        }
+    }
+
+    async fn add(&self, log_id: i64, entry_id: i64, entry: Bytes, recovery: bool) -> Result<()> {
+        match self.call(Request::AddEntry { recovery, entry }).await? {
+            Response::AddEntry => Ok(()),
+            response => Err(error_of(response, log_id, entry_id)),
+        }
+    }
+
+    // Send the request, and send it again after a transient failure.
+    async fn call(&self, request: Request) -> Result<Response> {
+        let options = &self.inner.options;
+        let mut backoff = options.retry_backoff;
+        let mut retries = 0;
+        loop {
+            let result = match self.connection().await {
+                Ok(connection) => connection.call(&request, options.request_timeout).await,
+                Err(e) => Err(e),
+            };
+            let retryable = match &result {
+                Ok(response) => response.status() == Status::Unavailable,
+                Err(e) => e.is_retryable(),
+            };
+            if !retryable || retries == options.max_retries {
+                return result;
+            }
+            retries += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    // Returns the next connection of the pool, it's opened if missing or closed.
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let connections = &self.inner.connections;
+        let index = self.inner.next.fetch_add(1, Ordering::Relaxed) % connections.len();
+        let mut connection = connections[index].lock().await;
+        match connection.as_ref() {
+            Some(connection) if !connection.is_closed() => Ok(connection.clone()),
+            _ => {
+                let opened =
+                    Arc::new(Connection::connect(&self.inner.addr, &self.inner.options).await?);
+                *connection = Some(opened.clone());
+                Ok(opened)
+            }
+        }
+    }
+}
+
+// The error of a failed request, the ids are those of the request.
+fn error_of(response: Response, log_id: i64, entry_id: i64) -> Error {
+    let Response::Error {
+        status, message, ..
+    } = response
+    else {
+        return Error::UnexpectedResponse(response.op());
+    };
+    match status {
+        Status::LedgerNotFound => Error::LedgerNotFound(log_id),
+        Status::EntryNotFound => Error::EntryNotFound { log_id, entry_id },
+        Status::Fenced => Error::Fenced(log_id),
+        Status::Closed => Error::Closed(log_id),
+        Status::BadRequest => Error::BadRequest(message),
+        Status::Unavailable => Error::Unavailable(message),
+        Status::Internal | Status::Ok => Error::Internal(message),
+    }
+}
+
+/// The `NodeClient` is a remote node of an ensemble,
+/// so the replicated writers and readers can run over the network.
+#[async_trait]
+impl Node for NodeClient {
+    fn id(&self) -> &str {
+        self.addr()
+    }
+
+    async fn add_entry(&self, entry: Bytes) -> replication::Result<()> {
+        let (log_id, entry_id) = {
+            let view = EntryRef::new(&entry)?;
+            (view.log_id(), view.entry_id())
+        };
+        self.add(log_id, entry_id, entry, false)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+
+    async fn recovery_add_entry(&self, entry: Bytes) -> replication::Result<()> {
+        let (log_id, entry_id) = {
+            let view = EntryRef::new(&entry)?;
+            (view.log_id(), view.entry_id())
+        };
+        self.add(log_id, entry_id, entry, true)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+
+    async fn read_entry(&self, log_id: i64, entry_id: i64) -> replication::Result<AnyEntry> {
+        NodeClient::read_entry(self, log_id, entry_id)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+
+    async fn read_lac(&self, log_id: i64) -> replication::Result<i64> {
+        NodeClient::read_lac(self, log_id)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+
+    async fn fence(&self, log_id: i64) -> replication::Result<i64> {
+        NodeClient::fence(self, log_id)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+
+    async fn close(&self, log_id: i64, last_entry_id: i64) -> replication::Result<()> {
+        NodeClient::close(self, log_id, last_entry_id)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+
+    async fn entry_ids(
+        &self,
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    ) -> replication::Result<Vec<i64>> {
+        NodeClient::entry_ids(self, log_id, first_entry_id, last_entry_id)
+            .await
+            .map_err(|e| self.replication_error(e))
+    }
+}
+
+impl NodeClient {
+    // The failures the replication acts upon keep their type, the others make the node unavailable.
+    fn replication_error(&self, e: Error) -> replication::Error {
+        match e {
+            Error::Entry(e) => replication::Error::Entry(e),
+            Error::LedgerNotFound(log_id) => ledger::Error::LedgerNotFound(log_id).into(),
+            Error::EntryNotFound { log_id, entry_id } => {
+                ledger::Error::EntryNotFound { log_id, entry_id }.into()
+            }
+            Error::Fenced(log_id) => ledger::Error::Fenced(log_id).into(),
+            Error::Closed(log_id) => ledger::Error::Closed(log_id).into(),
+            _ => replication::Error::Unavailable(self.addr().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use storage::entry::{BuilderV1, DecodeOptions};
+    use storage::ledger::LedgerStore;
+    use storage::metadata::MemoryMetadataStore;
+    use storage::replication::{
+        LocalNode, NodeSet, QuorumConfig, ReplicatedReader, ReplicatedWriter, WriterOptions,
+    };
+    use storage::segment::SegmentOptions;
+    use storage::server::{Server, ServerOptions};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::Client;
+
+    async fn start_server(dir: &std::path::Path, addr: &str) -> String {
+        let store = LedgerStore::open(dir, SegmentOptions::default())
+            .await
+            .unwrap();
+        let node = Arc::new(LocalNode::new("node", store));
+        let server = Server::bind(addr, node, ServerOptions::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        tokio::spawn(server.serve(std::future::pending()));
+        addr
+    }
+
+    fn entry(log_id: i64, entry_id: i64) -> storage::entry::EntryV1 {
+        BuilderV1::new()
+            .log_id(log_id)
+            .entry_id(entry_id)
+            .last_confirm_id(entry_id - 1)
+            .kv(
+                Bytes::from_static(b"key"),
+                Bytes::from(entry_id.to_string()),
+            )
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_add_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = start_server(dir.path(), "127.0.0.1:0").await;
+        let client = Client::new(ClientOptions::default());
+        let node = client.node(&addr);
+
+        // The adds are pipelined over the pool.
+        let mut adds = JoinSet::new();
+        for entry_id in 0..100 {
+            let node = client.node(&addr);
+            adds.spawn(async move { node.add_entry(&entry(1, entry_id)).await });
+        }
+        while let Some(added) = adds.join_next().await {
+            added.unwrap().unwrap();
+        }
+
+        let entries = node.read_range(1, 0, 99).await.unwrap();
+        assert_eq!(entries.len(), 100);
+        for (entry_id, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.entry_id(), entry_id as i64);
+            assert_eq!(entry.value(), &Bytes::from(entry_id.to_string()));
+        }
+        assert!(node.read_range(1, 5, 4).await.unwrap().is_empty());
+        assert_eq!(node.read_lac(1).await.unwrap(), 98);
+        assert_eq!(node.entry_ids(1, 97, 105).await.unwrap(), [97, 98, 99]);
+
+        // The failures are typed.
+        assert!(matches!(
+            node.read_entry(1, 100).await,
+            Err(Error::EntryNotFound {
+                log_id: 1,
+                entry_id: 100
+            })
+        ));
+        assert!(matches!(
+            node.read_range(1, 95, 105).await,
+            Err(Error::EntryNotFound { .. })
+        ));
+        assert!(matches!(
+            node.read_lac(2).await,
+            Err(Error::LedgerNotFound(2))
+        ));
+        assert_eq!(node.fence(1).await.unwrap(), 98);
+        assert!(matches!(
+            node.add_entry(&entry(1, 100)).await,
+            Err(Error::Fenced(1))
+        ));
+        node.close(1, 99).await.unwrap();
+        assert!(matches!(node.close(1, 100).await, Err(Error::Closed(1))));
+    }
+
+    #[tokio::test]
+    async fn test_decode_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = start_server(dir.path(), "127.0.0.1:0").await;
+        let options = ClientOptions {
+            decode_options: DecodeOptions {
+                max_value_len: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let node = NodeClient::new(addr, options);
+        node.add_entry(&entry(1, 0)).await.unwrap();
+        node.add_entry(&entry(1, 10)).await.unwrap();
+
+        // Only the request of the oversized entry fails, the connection is still usable.
+        node.read_entry(1, 0).await.unwrap();
+        assert!(matches!(
+            node.read_entry(1, 10).await,
+            Err(Error::Entry(storage::entry::Error::ValueTooLong { .. }))
+        ));
+        node.read_entry(1, 0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = start_server(dir.path(), "127.0.0.1:0").await;
+        let options = ClientOptions {
+            connections_per_node: 1,
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        let node = NodeClient::new(addr, options);
+        node.add_entry(&entry(1, 0)).await.unwrap();
+
+        // The oversized entry fails without being sent, the shared connection is still usable.
+        let large = BuilderV1::new()
+            .log_id(1)
+            .entry_id(1)
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![0; 2048]))
+            .build();
+        assert!(matches!(
+            node.add_entry(&large).await,
+            Err(Error::BadRequest(_))
+        ));
+        node.add_entry(&entry(1, 1)).await.unwrap();
+        assert_eq!(node.read_entry(1, 1).await.unwrap().entry_id(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let dir = tempfile::tempdir().unwrap();
+        // The first connection is dropped before the server starts.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server_addr = addr.clone();
+        let path = dir.path().to_path_buf();
+        tokio::spawn(async move {
+            drop(listener.accept().await.unwrap());
+            drop(listener);
+            start_server(&path, &server_addr).await;
+        });
+
+        let options = ClientOptions {
+            connections_per_node: 1,
+            max_retries: 10,
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let node = NodeClient::new(&addr, options.clone());
+        node.add_entry(&entry(1, 0)).await.unwrap();
+        assert_eq!(node.read_entry(1, 0).await.unwrap().entry_id(), 0);
+
+        // A node which never responds times out once the retries are used up.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.push(stream);
+            }
+        });
+        let options = ClientOptions {
+            request_timeout: Duration::from_millis(20),
+            max_retries: 2,
+            ..options
+        };
+        let node = NodeClient::new(addr, options);
+        assert!(matches!(
+            node.read_lac(1).await,
+            Err(Error::Timeout(timeout)) if timeout == Duration::from_millis(20)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replicated_over_network() {
+        let client = Client::new(ClientOptions::default());
+        let mut dirs = Vec::new();
+        let mut ensemble: Vec<Arc<dyn Node>> = Vec::new();
+        for _ in 0..3 {
+            let dir = tempfile::tempdir().unwrap();
+            let addr = start_server(dir.path(), "127.0.0.1:0").await;
+            ensemble.push(Arc::new(client.node(&addr)));
+            dirs.push(dir);
+        }
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let nodes = NodeSet::new(ensemble.clone());
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let mut writer = ReplicatedWriter::create(
+            1,
+            config,
+            nodes.clone(),
+            metadata_store.clone(),
+            WriterOptions::default(),
+        )
+        .await
+        .unwrap();
+        let ensemble = writer.ensemble().to_vec();
+        for i in 0..10 {
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            replication::recover_ledger(1, metadata_store.as_ref(), &nodes)
+                .await
+                .unwrap(),
+            9
+        );
+        let reader = ReplicatedReader::new(1, config, ensemble).unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), 9);
+        assert_eq!(
+            reader.read_entry(4).await.unwrap().value(),
+            &Bytes::from(vec![4])
+        );
+    }
+}
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use storage::entry::{self, Entry, EntryBatch, EntryRef};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(entry) = entry::decode(data) {
+        // A decoded entry must be readable and encode to the same size.
+        let _ = (entry.key(), entry.value(), entry.headers());
+        let mut buf = vec![0; entry.binary_size()];
+        assert_eq!(entry.read_at(&mut buf, 0), entry.binary_size());
+    }
+    if let Ok(entry) = EntryRef::new(data) {
+        let _ = (entry.key(), entry.value(), entry.headers().count());
+    }
+    if let Ok(batch) = EntryBatch::decode(data) {
+        // The entry ids of a decoded batch must not overflow.
+        for (_, record) in batch.iter() {
+            let _ = (record.key(), record.value(), record.headers());
+        }
+    }
+});
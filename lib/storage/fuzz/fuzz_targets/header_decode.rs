@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use storage::entry::Header;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(header) = Header::decode(data) {
+        // The delimiters may be encoded non-canonically, compare the decoded fields.
+        let mut buf = Vec::new();
+        header.encode(&mut buf).unwrap();
+        let decoded = Header::decode(&buf[..]).unwrap();
+        assert_eq!(decoded.key(), header.key());
+        assert_eq!(decoded.value(), header.value());
+    }
+});
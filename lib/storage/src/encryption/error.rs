@@ -0,0 +1,20 @@
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("key {0} not found")]
+    KeyNotFound(String),
+
+    #[error("invalid key {0}")]
+    InvalidKey(String),
+
+    #[error("can't wrap the data key with key {0}")]
+    Wrap(String),
+
+    #[error("can't unwrap the data key with key {0}")]
+    Unwrap(String),
+
+    #[error("random: {0}")]
+    Random(String),
+}
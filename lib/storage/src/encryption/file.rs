@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    generate_data_key, Error, KeyProvider, Result, WrappedKey, KEY_SIZE, NONCE_SIZE, TAG_SIZE,
+};
+
+const KEY_FILE_EXTENSION: &str = "key";
+
+/// The `FileKeyProvider` keeps its master keys in the files of a directory.
+///
+/// Each key is the 32 raw bytes of a `<key id>.key` file. The data keys are wrapped with AES-256-GCM
+/// by the current key, authenticated along with its id, the other keys only unwrap the data keys
+/// they wrapped before the rotation.
+pub struct FileKeyProvider {
+    current_key_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl FileKeyProvider {
+    /// Open the provider of the keys in the directory, the current key wraps the new data keys.
+    /// Fails with `Error::KeyNotFound` if the current key isn't in the directory.
+    pub async fn open(dir: impl AsRef<Path>, current_key_id: &str) -> Result<Self> {
+        let mut keys = HashMap::new();
+        let mut files = tokio::fs::read_dir(dir).await?;
+        while let Some(file) = files.next_entry().await? {
+            let path = file.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(KEY_FILE_EXTENSION) {
+                continue;
+            }
+            let Some(key_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let key: [u8; KEY_SIZE] = tokio::fs::read(&path)
+                .await?
+                .try_into()
+                .map_err(|_| Error::InvalidKey(key_id.to_string()))?;
+            keys.insert(key_id.to_string(), Aes256Gcm::new(&key.into()));
+        }
+        if !keys.contains_key(current_key_id) {
+            return Err(Error::KeyNotFound(current_key_id.to_string()));
+        }
+        Ok(Self {
+            current_key_id: current_key_id.to_string(),
+            keys,
+        })
+    }
+
+    /// Create a random key in the directory, readable by the owner only.
+    /// Fails if the key already exists.
+    pub async fn create_key(dir: impl AsRef<Path>, key_id: &str) -> Result<()> {
+        if key_id.is_empty()
+            || !key_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(Error::InvalidKey(key_id.to_string()));
+        }
+        let path = dir
+            .as_ref()
+            .join(format!("{}.{}", key_id, KEY_FILE_EXTENSION));
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&path).await.map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => Error::InvalidKey(key_id.to_string()),
+            _ => e.into(),
+        })?;
+        file.write_all(&generate_data_key()?).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Returns the id of the key wrapping the new data keys.
+    pub fn current_key_id(&self) -> &str {
+        &self.current_key_id
+    }
+}
+
+#[async_trait]
+impl KeyProvider for FileKeyProvider {
+    async fn wrap(&self, data_key: &[u8]) -> Result<WrappedKey> {
+        let key = &self.keys[&self.current_key_id];
+        let mut nonce = [0; NONCE_SIZE];
+        getrandom::fill(&mut nonce).map_err(|e| Error::Random(e.to_string()))?;
+        let mut wrapped = Vec::with_capacity(NONCE_SIZE + data_key.len() + TAG_SIZE);
+        wrapped.extend_from_slice(&nonce);
+        let payload = Payload {
+            msg: data_key,
+            aad: self.current_key_id.as_bytes(),
+        };
+        wrapped.extend(
+            key.encrypt(Nonce::from_slice(&nonce), payload)
+                .map_err(|_| Error::Wrap(self.current_key_id.clone()))?,
+        );
+        Ok(WrappedKey {
+            key_id: self.current_key_id.clone(),
+            wrapped,
+        })
+    }
+
+    async fn unwrap(&self, wrapped: &WrappedKey) -> Result<Vec<u8>> {
+        let key = self
+            .keys
+            .get(&wrapped.key_id)
+            .ok_or_else(|| Error::KeyNotFound(wrapped.key_id.clone()))?;
+        let unwrap = || Error::Unwrap(wrapped.key_id.clone());
+        if wrapped.wrapped.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(unwrap());
+        }
+        let (nonce, sealed) = wrapped.wrapped.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: sealed,
+            aad: wrapped.key_id.as_bytes(),
+        };
+        key.decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_key_provider() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            FileKeyProvider::open(dir.path(), "k1").await,
+            Err(Error::KeyNotFound(_))
+        ));
+        FileKeyProvider::create_key(dir.path(), "k1").await.unwrap();
+        assert!(matches!(
+            FileKeyProvider::create_key(dir.path(), "k1").await,
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            FileKeyProvider::create_key(dir.path(), "../k").await,
+            Err(Error::InvalidKey(_))
+        ));
+
+        let provider = FileKeyProvider::open(dir.path(), "k1").await.unwrap();
+        let data_key = generate_data_key().unwrap();
+        let wrapped = provider.wrap(&data_key).await.unwrap();
+        assert_eq!(wrapped.key_id, "k1");
+        assert_eq!(provider.unwrap(&wrapped).await.unwrap(), data_key);
+
+        // The rotated key still unwraps the data keys it wrapped.
+        FileKeyProvider::create_key(dir.path(), "k2").await.unwrap();
+        let rotated = FileKeyProvider::open(dir.path(), "k2").await.unwrap();
+        assert_eq!(rotated.unwrap(&wrapped).await.unwrap(), data_key);
+        assert_eq!(rotated.wrap(&data_key).await.unwrap().key_id, "k2");
+
+        // The wrapped key is authenticated along with the id of its key.
+        let mut tampered = wrapped.clone();
+        tampered.wrapped[NONCE_SIZE] ^= 1;
+        assert!(matches!(
+            rotated.unwrap(&tampered).await,
+            Err(Error::Unwrap(_))
+        ));
+        let mut moved = wrapped.clone();
+        moved.key_id = "k2".to_string();
+        assert!(matches!(
+            rotated.unwrap(&moved).await,
+            Err(Error::Unwrap(_))
+        ));
+        moved.key_id = "k3".to_string();
+        assert!(matches!(
+            rotated.unwrap(&moved).await,
+            Err(Error::KeyNotFound(_))
+        ));
+
+        std::fs::write(dir.path().join("k3.key"), b"short").unwrap();
+        assert!(matches!(
+            FileKeyProvider::open(dir.path(), "k2").await,
+            Err(Error::InvalidKey(_))
+        ));
+    }
+}
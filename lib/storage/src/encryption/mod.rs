@@ -0,0 +1,47 @@
+mod error;
+mod file;
+
+use async_trait::async_trait;
+
+pub use error::Error;
+pub use file::FileKeyProvider;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The size of an AES-256 key in bytes, the data keys and the master keys of `FileKeyProvider`.
+pub const KEY_SIZE: usize = 32;
+/// The size of an AES-GCM nonce in bytes.
+pub const NONCE_SIZE: usize = 12;
+/// The size of an AES-GCM authentication tag in bytes.
+pub const TAG_SIZE: usize = 16;
+
+/// The `WrappedKey` is a data key encrypted by a master key of a `KeyProvider`.
+///
+/// It's recorded in the metadata of the ledger whose entries the data key encrypts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    /// The id of the master key, known to the key provider.
+    pub key_id: String,
+    pub wrapped: Vec<u8>,
+}
+
+/// The `KeyProvider` wraps and unwraps the data keys of the ledgers with its master keys.
+///
+/// The master keys never leave the provider, so it may be backed by a KMS. The key which wrapped
+/// a data key must stay available to unwrap it as long as the ledger exists, even once rotated.
+#[async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Wrap the data key with the current master key.
+    async fn wrap(&self, data_key: &[u8]) -> Result<WrappedKey>;
+
+    /// Returns the data key wrapped by `wrap`.
+    /// Fails with `Error::KeyNotFound` if the master key is unknown.
+    async fn unwrap(&self, wrapped: &WrappedKey) -> Result<Vec<u8>>;
+}
+
+/// Returns a new random data key.
+pub fn generate_data_key() -> Result<[u8; KEY_SIZE]> {
+    let mut key = [0; KEY_SIZE];
+    getrandom::fill(&mut key).map_err(|e| Error::Random(e.to_string()))?;
+    Ok(key)
+}
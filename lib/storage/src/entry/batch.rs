@@ -0,0 +1,620 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::util::copy_slice;
+
+use super::util::{check_value_len, decode_headers, decode_length_delimiter};
+use super::Attr;
+use super::DecodeOptions;
+use super::Error;
+use super::Header;
+use super::Magic;
+use super::Result;
+use super::{util::copy_slice_with_multi_stage, util::customize_copy_slice_with_multi_stage};
+
+// Magic 1
+// Attr 4
+// log_id 8
+// base_entry_id 8
+// last_confirm_id 8
+// count 4
+// checksum 4 = 37
+pub(super) const COMMON_HEADER_BINARY_SIZE: usize = 37;
+const COMMON_HEADER_MAGIC_OFFSET: usize = 0;
+const COMMON_HEADER_ATTR_OFFSET: usize = 1;
+const COMMON_HEADER_LOG_ID_OFFSET: usize = 5;
+const COMMON_HEADER_BASE_ENTRY_ID_OFFSET: usize = 13;
+const COMMON_HEADER_LAC_ID_OFFSET: usize = 21;
+const COMMON_HEADER_COUNT_OFFSET: usize = 29;
+const COMMON_HEADER_CHECKSUM_OFFSET: usize = 33;
+
+/// The `BatchBuilder` struct provides a way to construct a new `EntryBatch`.
+pub struct BatchBuilder {
+    common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+    records: Vec<BatchRecord>,
+}
+
+impl Default for BatchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BatchBuilder {
+    /// Constructor for BatchBuilder
+    pub fn new() -> Self {
+        let mut b = BatchBuilder {
+            common_header: [0; COMMON_HEADER_BINARY_SIZE],
+            records: Vec::new(),
+        };
+        b.common_header[COMMON_HEADER_MAGIC_OFFSET] = Magic::Batch.into();
+        b
+    }
+
+    /// Method to set the attr of the EntryBatch
+    pub fn attr(mut self, attr: Attr) -> Self {
+        self.put_to_common_header(COMMON_HEADER_ATTR_OFFSET, &i32::from(attr).to_le_bytes());
+        self
+    }
+
+    /// Method to set the log_id shared by all entries of the EntryBatch
+    pub fn log_id(mut self, log_id: i64) -> Self {
+        self.put_to_common_header(COMMON_HEADER_LOG_ID_OFFSET, &log_id.to_le_bytes());
+        self
+    }
+
+    /// Method to set the last_confirm id shared by all entries of the EntryBatch
+    pub fn last_confirm_id(mut self, last_confirm_id: i64) -> Self {
+        self.put_to_common_header(COMMON_HEADER_LAC_ID_OFFSET, &last_confirm_id.to_le_bytes());
+        self
+    }
+
+    /// Method to append an entry to the EntryBatch.
+    /// The first entry_id becomes the base entry_id, the following ones must not be less than it.
+    ///
+    /// Panics if the entry_id is less than the base entry_id, or its delta to it overflows an i64.
+    pub fn entry(mut self, entry_id: i64, key: Bytes, value: Bytes, headers: Vec<Header>) -> Self {
+        if self.records.is_empty() {
+            self.put_to_common_header(COMMON_HEADER_BASE_ENTRY_ID_OFFSET, &entry_id.to_le_bytes());
+        }
+        let base_entry_id = get_i64(&self.common_header, COMMON_HEADER_BASE_ENTRY_ID_OFFSET);
+        assert!(
+            entry_id >= base_entry_id,
+            "entry id {} is less than the base entry id {}",
+            entry_id,
+            base_entry_id
+        );
+        let delta = entry_id.checked_sub(base_entry_id).unwrap_or_else(|| {
+            panic!(
+                "entry id {} is too far from the base entry id {}",
+                entry_id, base_entry_id
+            )
+        });
+        let mut headers = headers;
+        headers.push(Header::new(key, value));
+        self.records.push(BatchRecord {
+            delta: delta as u64,
+            headers,
+        });
+        self
+    }
+
+    /// Method to build the EntryBatch, the checksum is calculated here.
+    pub fn build(mut self) -> EntryBatch {
+        assert!(!self.records.is_empty(), "missing entries in batch");
+        let count = self.records.len() as u32;
+        self.put_to_common_header(COMMON_HEADER_COUNT_OFFSET, &count.to_le_bytes());
+        let mut batch = EntryBatch {
+            common_header: self.common_header,
+            records: self.records,
+        };
+        let checksum = batch.calculate_checksum();
+        copy_slice(
+            &checksum.to_le_bytes(),
+            &mut batch.common_header[COMMON_HEADER_CHECKSUM_OFFSET..],
+        );
+        batch
+    }
+
+    fn put_to_common_header(&mut self, offset: usize, value: &[u8]) {
+        copy_slice(value, &mut self.common_header[offset..offset + value.len()]);
+    }
+}
+
+/// The `BatchRecord` is an entry inside an `EntryBatch`.
+/// The entry_id is stored as a delta to the base entry_id of the batch,
+/// the headers are stored as in `EntryV1`, the kv is the last header.
+#[derive(Clone)]
+pub struct BatchRecord {
+    delta: u64,
+    headers: Vec<Header>,
+}
+
+impl BatchRecord {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &Bytes {
+        self.headers.last().unwrap().key()
+    }
+
+    /// Returns the value of the entry.
+    pub fn value(&self) -> &Bytes {
+        self.headers.last().unwrap().value()
+    }
+
+    /// Returns the headers of the entry.
+    pub fn headers(&self) -> &[Header] {
+        &self.headers[..self.headers.len() - 1]
+    }
+
+    fn body_binary_size(&self) -> usize {
+        let mut size = prost::encoding::encoded_len_varint(self.delta);
+        for header in &self.headers {
+            let header_size = header.binary_size();
+            size += prost::length_delimiter_len(header_size);
+            size += header_size;
+        }
+        size
+    }
+
+    fn binary_size(&self) -> usize {
+        let body_size = self.body_binary_size();
+        prost::length_delimiter_len(body_size) + body_size
+    }
+
+    fn encode<B: BufMut>(&self, mut buf: B) -> Result<()> {
+        prost::encode_length_delimiter(self.body_binary_size(), &mut buf)?;
+        prost::encoding::encode_varint(self.delta, &mut buf);
+        for header in &self.headers {
+            let size = header.binary_size();
+            prost::encode_length_delimiter(size, &mut buf)?;
+            header.encode(&mut buf)?;
+        }
+        Ok(())
+    }
+
+    // `offset` is the position of the record in the batch,
+    // the delta must keep the entry_id within the range of i64 from the base entry_id.
+    fn decode<B: Buf>(
+        mut buf: B,
+        offset: usize,
+        base_entry_id: i64,
+        options: &DecodeOptions,
+    ) -> Result<Self> {
+        let size = buf.remaining();
+        let delta = prost::encoding::decode_varint(&mut buf)
+            .ok()
+            .filter(|delta| {
+                i64::try_from(*delta)
+                    .ok()
+                    .and_then(|delta| base_entry_id.checked_add(delta))
+                    .is_some()
+            })
+            .ok_or(Error::Malformed {
+                offset,
+                reason: "invalid entry id delta",
+            })?;
+        let headers_offset = offset + size - buf.remaining();
+        let headers = decode_headers(buf, headers_offset, options)?;
+        if headers.is_empty() {
+            return Err(Error::KVNotFound);
+        }
+        check_value_len(&headers, options)?;
+        Ok(Self { delta, headers })
+    }
+
+    #[allow(unused_assignments)]
+    fn read_at(&self, buf: &mut [u8], mut offset: usize) -> usize {
+        let mut n = 0;
+        let body_size = self.body_binary_size();
+        let body_size_delimiter_size = prost::length_delimiter_len(body_size);
+        let body_size_delimiter_getter = || -> Vec<u8> {
+            let mut tmp_storage = Vec::with_capacity(body_size_delimiter_size);
+            prost::encode_length_delimiter(body_size, &mut tmp_storage).unwrap();
+            tmp_storage
+        };
+        customize_copy_slice_with_multi_stage!(
+            copy_slice(&body_size_delimiter_getter()[offset..], &mut buf[n..]),
+            body_size_delimiter_size,
+            buf,
+            offset,
+            n
+        );
+        let delta_size = prost::encoding::encoded_len_varint(self.delta);
+        let delta_getter = || -> Vec<u8> {
+            let mut tmp_storage = Vec::with_capacity(delta_size);
+            prost::encoding::encode_varint(self.delta, &mut tmp_storage);
+            tmp_storage
+        };
+        customize_copy_slice_with_multi_stage!(
+            copy_slice(&delta_getter()[offset..], &mut buf[n..]),
+            delta_size,
+            buf,
+            offset,
+            n
+        );
+        for header in &self.headers {
+            let header_size = header.binary_size();
+            let header_size_delimiter_size = prost::length_delimiter_len(header_size);
+            let header_size_delimiter_getter = || -> Vec<u8> {
+                let mut tmp_storage = Vec::with_capacity(header_size_delimiter_size);
+                prost::encode_length_delimiter(header_size, &mut tmp_storage).unwrap();
+                tmp_storage
+            };
+            customize_copy_slice_with_multi_stage!(
+                copy_slice(&header_size_delimiter_getter()[offset..], &mut buf[n..]),
+                header_size_delimiter_size,
+                buf,
+                offset,
+                n
+            );
+            customize_copy_slice_with_multi_stage!(
+                header.read_at(&mut buf[n..], offset),
+                header_size,
+                buf,
+                offset,
+                n
+            );
+        }
+        n
+    }
+}
+
+/// The `EntryBatch` struct packs many entries of the same log behind one common header,
+/// so they can be written, fsynced or replicated as a unit.
+///
+/// The common header holds the shared log_id, the base entry_id, the last confirm id, the entries count
+/// and a CRC32C checksum which covers the common header (without the checksum itself) and all records.
+/// It provides the same `encode`, `decode`, `binary_size` and `read_at` semantics as the `Entry`.
+pub struct EntryBatch {
+    pub common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+    pub records: Vec<BatchRecord>,
+}
+
+impl EntryBatch {
+    /// Returns the magic of the batch.
+    pub fn magic(&self) -> Magic {
+        Magic::try_from(self.common_header[COMMON_HEADER_MAGIC_OFFSET]).expect("invalid magic")
+    }
+
+    /// Returns the attribute of the batch.
+    pub fn attr(&self) -> Attr {
+        Attr::from(i32::from_le_bytes(
+            self.common_header[COMMON_HEADER_ATTR_OFFSET..COMMON_HEADER_ATTR_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        ))
+    }
+
+    /// Returns the log ID shared by all entries.
+    pub fn log_id(&self) -> i64 {
+        get_i64(&self.common_header, COMMON_HEADER_LOG_ID_OFFSET)
+    }
+
+    /// Returns the entry ID of the first entry.
+    pub fn base_entry_id(&self) -> i64 {
+        get_i64(&self.common_header, COMMON_HEADER_BASE_ENTRY_ID_OFFSET)
+    }
+
+    /// Returns the last confirm id shared by all entries.
+    pub fn last_confirm_id(&self) -> i64 {
+        get_i64(&self.common_header, COMMON_HEADER_LAC_ID_OFFSET)
+    }
+
+    /// Returns the number of entries in the batch.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns whether the batch has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns the entry_id and the record of each entry in the batch.
+    pub fn iter(&self) -> impl Iterator<Item = (i64, &BatchRecord)> {
+        let base_entry_id = self.base_entry_id();
+        self.records
+            .iter()
+            .map(move |r| (base_entry_id + r.delta as i64, r))
+    }
+
+    /// Returns the checksum stored in the common header.
+    pub fn checksum(&self) -> u32 {
+        u32::from_le_bytes(
+            self.common_header[COMMON_HEADER_CHECKSUM_OFFSET..]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /// Get the binary size of the batch.
+    pub fn binary_size(&self) -> usize {
+        COMMON_HEADER_BINARY_SIZE + self.records_binary_size()
+    }
+
+    /// Encodes the batch into a buffer.
+    pub fn encode<B: BufMut>(&self, mut buf: B) -> Result<()> {
+        buf.put_slice(&self.common_header);
+        self.encode_records(&mut buf)
+    }
+
+    /// Decodes the buffer into a batch.
+    pub fn decode<B: Buf>(buf: B) -> Result<Self> {
+        Self::decode_with_options(buf, &DecodeOptions::default())
+    }
+
+    /// Decodes the buffer into a batch, the limits of the options are enforced before allocating.
+    pub fn decode_with_options<B: Buf>(mut buf: B, options: &DecodeOptions) -> Result<Self> {
+        if !buf.has_remaining() {
+            return Err(Error::DecodeBufNotEnough);
+        }
+        let magic = Magic::try_from(buf.get_u8())?;
+        Self::decode_without_magic_with_options(magic, buf, options)
+    }
+
+    /// Decodes the buffer into a batch, the magic is already consumed.
+    pub fn decode_without_magic<B: Buf>(magic: Magic, buf: B) -> Result<Self> {
+        Self::decode_without_magic_with_options(magic, buf, &DecodeOptions::default())
+    }
+
+    /// Decodes the buffer into a batch with the options, the magic is already consumed.
+    pub fn decode_without_magic_with_options<B: Buf>(
+        magic: Magic,
+        mut buf: B,
+        options: &DecodeOptions,
+    ) -> Result<Self> {
+        if magic != Magic::Batch {
+            return Err(Error::InvalidMagic);
+        }
+        options.check_nesting_depth(2)?;
+        options.check_entry_size(buf.remaining() + 1)?;
+        if buf.remaining() < COMMON_HEADER_BINARY_SIZE - 1 {
+            return Err(Error::DecodeBufNotEnough);
+        }
+        let mut common_header = [0; COMMON_HEADER_BINARY_SIZE];
+        common_header[0] = magic.into();
+        buf.copy_to_slice(&mut common_header[1..]);
+
+        // Verify the checksum before parsing the records.
+        let mut body = buf.copy_to_bytes(buf.remaining());
+        let expected = u32::from_le_bytes(
+            common_header[COMMON_HEADER_CHECKSUM_OFFSET..]
+                .try_into()
+                .unwrap(),
+        );
+        let actual = crc32c::crc32c_append(
+            crc32c::crc32c(&common_header[..COMMON_HEADER_CHECKSUM_OFFSET]),
+            &body,
+        );
+        if expected != actual {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+
+        let count = u32::from_le_bytes(
+            common_header[COMMON_HEADER_COUNT_OFFSET..COMMON_HEADER_COUNT_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+        // Every record takes at least 2 bytes, don't trust the count for the allocation.
+        let mut records = Vec::with_capacity(count.min(body.remaining() / 2));
+        let size = body.remaining();
+        while body.has_remaining() {
+            let offset = COMMON_HEADER_BINARY_SIZE + size - body.remaining();
+            let length = decode_length_delimiter(&mut body, offset)?;
+            if body.remaining() < length {
+                return Err(Error::DecodeBufNotEnough);
+            }
+            let record_offset = COMMON_HEADER_BINARY_SIZE + size - body.remaining();
+            let mut record_buf = body.take(length);
+            records.push(BatchRecord::decode(
+                &mut record_buf,
+                record_offset,
+                get_i64(&common_header, COMMON_HEADER_BASE_ENTRY_ID_OFFSET),
+                options,
+            )?);
+            body = record_buf.into_inner();
+        }
+        if records.is_empty() {
+            return Err(Error::KVNotFound);
+        }
+        if records.len() != count {
+            return Err(Error::Malformed {
+                offset: COMMON_HEADER_COUNT_OFFSET,
+                reason: "record count mismatch",
+            });
+        }
+        Ok(Self {
+            common_header,
+            records,
+        })
+    }
+
+    /// Read at a specific offset of batch's binary representation.
+    pub fn read_at(&self, buf: &mut [u8], mut offset: usize) -> usize {
+        let mut n = 0;
+
+        copy_slice_with_multi_stage!(self.common_header, buf, offset, n);
+
+        for record in &self.records {
+            customize_copy_slice_with_multi_stage!(
+                record.read_at(&mut buf[n..], offset),
+                record.binary_size(),
+                buf,
+                offset,
+                n
+            );
+        }
+        n
+    }
+
+    fn calculate_checksum(&self) -> u32 {
+        let mut body = BytesMut::with_capacity(self.records_binary_size());
+        // BytesMut grows on demand, so should never fail.
+        self.encode_records(&mut body).unwrap();
+        crc32c::crc32c_append(
+            crc32c::crc32c(&self.common_header[..COMMON_HEADER_CHECKSUM_OFFSET]),
+            &body,
+        )
+    }
+
+    fn records_binary_size(&self) -> usize {
+        self.records.iter().map(|r| r.binary_size()).sum()
+    }
+
+    fn encode_records<B: BufMut>(&self, mut buf: B) -> Result<()> {
+        for record in &self.records {
+            record.encode(&mut buf)?;
+        }
+        Ok(())
+    }
+}
+
+fn get_i64(common_header: &[u8], offset: usize) -> i64 {
+    i64::from_le_bytes(common_header[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_batch() -> EntryBatch {
+        BatchBuilder::new()
+            .log_id(1)
+            .last_confirm_id(9)
+            .entry(
+                10,
+                Bytes::from_static(b"key1"),
+                Bytes::from_static(b"value1"),
+                vec![Header::new(
+                    Bytes::from_static(b"h"),
+                    Bytes::from_static(b"v"),
+                )],
+            )
+            .entry(
+                11,
+                Bytes::from_static(b"key2"),
+                Bytes::from_static(b"value2"),
+                vec![],
+            )
+            .entry(
+                300,
+                Bytes::from_static(b"key3"),
+                Bytes::from_static(b"value3"),
+                vec![],
+            )
+            .build()
+    }
+
+    fn assert_batch(batch: &EntryBatch) {
+        assert_eq!(batch.magic(), Magic::Batch);
+        assert_eq!(batch.log_id(), 1);
+        assert_eq!(batch.base_entry_id(), 10);
+        assert_eq!(batch.last_confirm_id(), 9);
+        assert_eq!(batch.len(), 3);
+        let entries: Vec<_> = batch
+            .iter()
+            .map(|(id, r)| (id, r.key().clone(), r.value().clone(), r.headers().len()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (10, Bytes::from("key1"), Bytes::from("value1"), 1),
+                (11, Bytes::from("key2"), Bytes::from("value2"), 0),
+                (300, Bytes::from("key3"), Bytes::from("value3"), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_batch_encode_decode() {
+        let batch = new_batch();
+        assert_batch(&batch);
+
+        let mut buf = BytesMut::new();
+        batch.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), batch.binary_size());
+
+        let decoded = EntryBatch::decode(buf.freeze()).unwrap();
+        assert_batch(&decoded);
+        assert_eq!(decoded.checksum(), batch.checksum());
+    }
+
+    #[test]
+    fn test_batch_checksum_mismatch() {
+        let batch = new_batch();
+        let mut buf = BytesMut::new();
+        batch.encode(&mut buf).unwrap();
+        for i in (1..COMMON_HEADER_CHECKSUM_OFFSET).chain(COMMON_HEADER_BINARY_SIZE..buf.len()) {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x01;
+            assert!(matches!(
+                EntryBatch::decode(corrupted.freeze()),
+                Err(Error::ChecksumMismatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_batch_read_at() {
+        let batch = new_batch();
+        let mut encoded = BytesMut::new();
+        batch.encode(&mut encoded).unwrap();
+
+        for step in 1..16 {
+            let mut buf = vec![0; step];
+            let mut all = Vec::new();
+            let mut n = 0;
+            loop {
+                let k = batch.read_at(&mut buf[..], n);
+                if k == 0 {
+                    break;
+                }
+                n += k;
+                all.extend_from_slice(&buf[..k]);
+            }
+            assert_eq!(all, &encoded[..]);
+        }
+    }
+
+    #[test]
+    fn test_batch_entry_id_overflow() {
+        let batch = BatchBuilder::new()
+            .entry(0, Bytes::new(), Bytes::new(), vec![])
+            .entry(i64::MAX, Bytes::new(), Bytes::new(), vec![])
+            .build();
+        assert_eq!(batch.iter().last().unwrap().0, i64::MAX);
+
+        // A forged base entry id with a valid checksum, the last entry id overflows.
+        let mut buf = BytesMut::new();
+        batch.encode(&mut buf).unwrap();
+        buf[COMMON_HEADER_BASE_ENTRY_ID_OFFSET..COMMON_HEADER_BASE_ENTRY_ID_OFFSET + 8]
+            .copy_from_slice(&1i64.to_le_bytes());
+        let checksum = crc32c::crc32c_append(
+            crc32c::crc32c(&buf[..COMMON_HEADER_CHECKSUM_OFFSET]),
+            &buf[COMMON_HEADER_BINARY_SIZE..],
+        );
+        buf[COMMON_HEADER_CHECKSUM_OFFSET..COMMON_HEADER_BINARY_SIZE]
+            .copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            EntryBatch::decode(buf.freeze()),
+            Err(Error::Malformed {
+                reason: "invalid entry id delta",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "is less than the base entry id")]
+    fn test_batch_decreasing_entry_id() {
+        BatchBuilder::new()
+            .entry(2, Bytes::new(), Bytes::new(), vec![])
+            .entry(1, Bytes::new(), Bytes::new(), vec![]);
+    }
+    #[test]
+    #[should_panic(expected = "is too far from the base entry id")]
+    fn test_batch_entry_id_too_far() {
+        BatchBuilder::new()
+            .entry(-1, Bytes::new(), Bytes::new(), vec![])
+            .entry(i64::MAX, Bytes::new(), Bytes::new(), vec![]);
+    }
+}
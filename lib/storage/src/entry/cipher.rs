@@ -0,0 +1,326 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::encryption::{KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+
+use super::{AnyEntry, Attr, Entry, Error, Header, Magic, Result, NO_TIMESTAMP};
+
+/// The key of the header carrying the id of the data key, it's the first header of an encrypted entry.
+pub const KEY_ID_HEADER_KEY: &[u8] = b"__key_id";
+
+// The id of a data key is the prefix of its SHA-256, it identifies the key without revealing it.
+const KEY_ID_SIZE: usize = 8;
+
+/// The `Cipher` encrypts the values of the entries with AES-256-GCM under a data key.
+///
+/// The values of the user headers and of the kv are encrypted once compressed, the common header
+/// and the keys of the headers stay in plaintext. The encrypted entry has the encrypted attr and
+/// starts with the key id header, each other value becomes `nonce || ciphertext || tag`.
+///
+/// Each value is authenticated along with its position and the rest of the entry: the common header,
+/// but the append time and the checksum of V2 which the storage may set once encrypted, and the keys
+/// of all the headers in order. So no header can be added, dropped, reordered or moved to another entry.
+#[derive(Clone)]
+pub struct Cipher {
+    key_id: Bytes,
+    aead: Aes256Gcm,
+}
+
+impl Cipher {
+    /// Create the cipher of the data key, its id is derived from the key.
+    pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+        Self {
+            key_id: Bytes::copy_from_slice(&Sha256::digest(key)[..KEY_ID_SIZE]),
+            aead: Aes256Gcm::new(key.into()),
+        }
+    }
+
+    /// Returns the id of the data key, recorded by the entries it encrypts.
+    pub fn key_id(&self) -> &Bytes {
+        &self.key_id
+    }
+
+    /// Encrypt the values of the entry with a random nonce each.
+    pub fn encrypt(&self, entry: &AnyEntry) -> Result<AnyEntry> {
+        let attr = entry.attr();
+        if attr.encrypted() {
+            return Err(Error::Encryption("entry is already encrypted".to_string()));
+        }
+        let attr = attr.with_encrypted(true);
+        let (_, plain) = entry.raw_parts();
+        let mut headers = Vec::with_capacity(plain.len() + 1);
+        headers.push(Header::new(
+            Bytes::from_static(KEY_ID_HEADER_KEY),
+            self.key_id.clone(),
+        ));
+        headers.extend(plain.iter().cloned());
+        let aad = aad(entry, attr, &headers);
+
+        for (i, header) in headers.iter_mut().enumerate().skip(1) {
+            let mut nonce = [0; NONCE_SIZE];
+            getrandom::fill(&mut nonce).map_err(|e| Error::Encryption(e.to_string()))?;
+            let mut value = Vec::with_capacity(NONCE_SIZE + header.value().len() + TAG_SIZE);
+            value.extend_from_slice(&nonce);
+            let payload = Payload {
+                msg: header.value(),
+                aad: &value_aad(&aad, i),
+            };
+            value.extend(
+                self.aead
+                    .encrypt(Nonce::from_slice(&nonce), payload)
+                    .map_err(|e| Error::Encryption(e.to_string()))?,
+            );
+            *header = Header::new(header.key().clone(), value.into());
+        }
+        entry.with_raw_parts(attr, headers)
+    }
+
+    /// Returns the entry as it was before the encryption, decompressed if needed.
+    /// The entry must be laid out exactly as `encrypt` returned it, the digest header must be removed first.
+    ///
+    /// Fails with `Error::Decryption` if the entry isn't encrypted by this key or was tampered with.
+    pub fn decrypt(&self, entry: &AnyEntry) -> Result<AnyEntry> {
+        let attr = entry.attr();
+        if !attr.encrypted() {
+            return Err(Error::Decryption("entry isn't encrypted"));
+        }
+        let (_, headers) = entry.raw_parts();
+        match headers.first() {
+            Some(header) if header.key() == KEY_ID_HEADER_KEY => {
+                if header.value() != &self.key_id {
+                    return Err(Error::Decryption("encrypted by another key"));
+                }
+            }
+            _ => return Err(Error::Decryption("missing key id")),
+        }
+        if headers.len() < 2 {
+            return Err(Error::KVNotFound);
+        }
+        let aad = aad(entry, attr, headers);
+
+        let mut decrypted = Vec::with_capacity(headers.len() - 1);
+        for (i, header) in headers.iter().enumerate().skip(1) {
+            let value = header.value();
+            if value.len() < NONCE_SIZE + TAG_SIZE {
+                return Err(Error::Decryption("truncated value"));
+            }
+            let (nonce, sealed) = value.split_at(NONCE_SIZE);
+            let payload = Payload {
+                msg: sealed,
+                aad: &value_aad(&aad, i),
+            };
+            let plaintext = self
+                .aead
+                .decrypt(Nonce::from_slice(nonce), payload)
+                .map_err(|_| Error::Decryption("authentication failed"))?;
+            decrypted.push(Header::new(header.key().clone(), plaintext.into()));
+        }
+        entry.with_raw_parts(attr.with_encrypted(false), decrypted)
+    }
+}
+
+// The data authenticated along with every value of the encrypted entry: its version, encrypted attr,
+// position, LAC and producer timestamp, then the key id and the length delimited keys of all the headers.
+fn aad(entry: &AnyEntry, attr: Attr, headers: &[Header]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(45 + KEY_ID_SIZE + headers.len() * 16);
+    // Setting the append time turns a V2 entry into V3, both are authenticated as the latter.
+    let magic = match entry.magic() {
+        Magic::V2 => Magic::V3,
+        magic => magic,
+    };
+    aad.push(magic.into());
+    aad.extend_from_slice(&i32::from(attr).to_le_bytes());
+    aad.extend_from_slice(&entry.log_id().to_le_bytes());
+    aad.extend_from_slice(&entry.entry_id().to_le_bytes());
+    aad.extend_from_slice(&entry.last_confirm_id().to_le_bytes());
+    aad.extend_from_slice(&entry.timestamp().unwrap_or(NO_TIMESTAMP).to_le_bytes());
+    aad.extend_from_slice(&(headers.len() as u32).to_le_bytes());
+    aad.extend_from_slice(headers[0].value());
+    for header in headers {
+        // Vec grows on demand, so should never fail.
+        prost::encode_length_delimiter(header.key().len(), &mut aad).unwrap();
+        aad.extend_from_slice(header.key());
+    }
+    aad
+}
+
+// The data authenticated along with the value of the ith header.
+fn value_aad(aad: &[u8], i: usize) -> Vec<u8> {
+    let mut value_aad = Vec::with_capacity(aad.len() + 4);
+    value_aad.extend_from_slice(aad);
+    value_aad.extend_from_slice(&(i as u32).to_le_bytes());
+    value_aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::tests::{enabled_compressions, encode, entry_v1};
+    use crate::entry::{decode, BuilderV2, Compression};
+
+    fn entry_v2(compression: Compression) -> AnyEntry {
+        AnyEntry::V2(
+            BuilderV2::new()
+                .log_id(1)
+                .entry_id(2)
+                .last_confirm_id(1)
+                .timestamp(1_700_000_000_000)
+                .header(Header::new(
+                    Bytes::from_static(b"trace"),
+                    Bytes::from_static(b"abc"),
+                ))
+                .header(Header::new(
+                    Bytes::from_static(b"span"),
+                    Bytes::from_static(b"def"),
+                ))
+                .kv(Bytes::from_static(b"key"), Bytes::from(vec![7; 100]))
+                .compression(compression)
+                .compress_headers(true)
+                .build(),
+        )
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = Cipher::new(&[1; KEY_SIZE]);
+        for compression in enabled_compressions() {
+            for plain in [AnyEntry::V1(entry_v1(compression)), entry_v2(compression)] {
+                let encrypted = cipher.encrypt(&plain).unwrap();
+                let buf = encode(&encrypted);
+                // Only the common header and the keys are in plaintext.
+                assert_eq!(buf[5..29], encode(&plain)[5..29]);
+                assert!(!buf.windows(3).any(|w| w == b"abc"));
+
+                let decoded = decode(&buf[..]).unwrap();
+                assert_eq!(decoded.magic(), plain.magic());
+                assert!(decoded.attr().encrypted());
+                assert_eq!(decoded.attr().compression().unwrap(), compression);
+                assert_eq!(decoded.headers()[0].key(), KEY_ID_HEADER_KEY);
+                assert_eq!(decoded.headers()[0].value(), cipher.key_id());
+                assert_eq!(decoded.key(), &Bytes::from_static(b"key"));
+                assert_eq!(decoded.entry_id(), 2);
+
+                let decrypted = cipher.decrypt(&decoded).unwrap();
+                assert_eq!(encode(&decrypted), encode(&plain));
+                assert_eq!(decrypted.headers().len(), plain.headers().len());
+                assert_eq!(decrypted.headers()[0].value(), &Bytes::from_static(b"abc"));
+                assert_eq!(decrypted.value(), &Bytes::from(vec![7; 100]));
+
+                // The nonces are random, the same entry is never encrypted the same way.
+                assert_ne!(encode(&cipher.encrypt(&plain).unwrap()), buf);
+            }
+        }
+
+        // The append time may be set once encrypted.
+        let AnyEntry::V2(mut encrypted) = cipher.encrypt(&entry_v2(Compression::None)).unwrap()
+        else {
+            panic!("not an EntryV2");
+        };
+        encrypted.set_append_time(1_700_000_000_005);
+        let decoded = decode(&encode(&AnyEntry::V2(encrypted))[..]).unwrap();
+        let decrypted = cipher.decrypt(&decoded).unwrap();
+        assert_eq!(decrypted.append_time(), Some(1_700_000_000_005));
+        assert_eq!(decrypted.headers()[1].value(), &Bytes::from_static(b"def"));
+
+        // Even if the entry had no time and turns into V3.
+        let plain = BuilderV2::new()
+            .log_id(1)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build();
+        assert_eq!(plain.magic(), Magic::V2);
+        let AnyEntry::V2(mut encrypted) = cipher.encrypt(&AnyEntry::V2(plain)).unwrap() else {
+            panic!("not an EntryV2");
+        };
+        encrypted.set_append_time(1_700_000_000_005);
+        assert_eq!(encrypted.magic(), Magic::V3);
+        let decrypted = cipher.decrypt(&AnyEntry::V2(encrypted)).unwrap();
+        assert_eq!(decrypted.value(), &Bytes::from_static(b"value"));
+    }
+
+    #[test]
+    fn test_decrypt_invalid() {
+        let cipher = Cipher::new(&[1; KEY_SIZE]);
+        let plain = AnyEntry::V1(entry_v1(Compression::None));
+        assert!(matches!(
+            cipher.decrypt(&plain),
+            Err(Error::Decryption("entry isn't encrypted"))
+        ));
+        let encrypted = cipher.encrypt(&plain).unwrap();
+        assert!(matches!(
+            cipher.encrypt(&encrypted),
+            Err(Error::Encryption(_))
+        ));
+        assert!(matches!(
+            Cipher::new(&[2; KEY_SIZE]).decrypt(&encrypted),
+            Err(Error::Decryption("encrypted by another key"))
+        ));
+
+        // A flipped bit of a value, or a value moved to another entry, fails the authentication.
+        let buf = encode(&encrypted);
+        let mut tampered = buf.to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&decode(&tampered[..]).unwrap()),
+            Err(Error::Decryption("authentication failed"))
+        ));
+        let mut moved = buf.to_vec();
+        moved[13] ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&decode(&moved[..]).unwrap()),
+            Err(Error::Decryption("authentication failed"))
+        ));
+        // So does a rewritten LAC.
+        let mut rewritten = buf.to_vec();
+        rewritten[21] ^= 1;
+        assert!(matches!(
+            cipher.decrypt(&decode(&rewritten[..]).unwrap()),
+            Err(Error::Decryption("authentication failed"))
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_tampered_headers() {
+        let cipher = Cipher::new(&[1; KEY_SIZE]);
+        let encrypted = cipher.encrypt(&entry_v2(Compression::None)).unwrap();
+        let (_, headers) = encrypted.raw_parts();
+        let attr = encrypted.attr();
+        let tampered = |headers: Vec<Header>| {
+            cipher.decrypt(&encrypted.with_raw_parts(attr, headers).unwrap())
+        };
+
+        // A header added before the key id.
+        let mut added = vec![Header::new(
+            Bytes::from_static(b"extra"),
+            Bytes::from_static(b"x"),
+        )];
+        added.extend(headers.iter().cloned());
+        assert!(matches!(
+            tampered(added),
+            Err(Error::Decryption("missing key id"))
+        ));
+        // A header added, dropped or reordered after it.
+        let mut added = headers.to_vec();
+        added.insert(1, headers[1].clone());
+        let mut dropped = headers.to_vec();
+        dropped.remove(1);
+        let mut reordered = headers.to_vec();
+        reordered.swap(1, 2);
+        for headers in [added, dropped, reordered] {
+            assert!(matches!(
+                tampered(headers),
+                Err(Error::Decryption("authentication failed"))
+            ));
+        }
+        // A header renamed.
+        let mut renamed = headers.to_vec();
+        renamed[1] = Header::new(Bytes::from_static(b"other"), headers[1].value().clone());
+        assert!(matches!(
+            tampered(renamed),
+            Err(Error::Decryption("authentication failed"))
+        ));
+        tampered(headers.to_vec()).unwrap();
+    }
+}
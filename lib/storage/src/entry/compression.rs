@@ -0,0 +1,227 @@
+use bytes::Bytes;
+
+use super::Attr;
+use super::Compression;
+use super::Error;
+use super::Header;
+use super::Result;
+
+/// The key of the header carrying the digest of a replicated entry.
+/// Its value is never compressed, whatever the attr says, so the digest is verified without the codec.
+pub const DIGEST_HEADER_KEY: &[u8] = b"__digest";
+
+/// Compress the data with the given codec.
+pub fn compress(compression: Compression, data: &[u8]) -> Result<Bytes> {
+    match compression {
+        Compression::None => Ok(Bytes::copy_from_slice(data)),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data).into()),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+            .map(Bytes::from)
+            .map_err(|e| Error::Compression(e.to_string())),
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => snap::raw::Encoder::new()
+            .compress_vec(data)
+            .map(Bytes::from)
+            .map_err(|e| Error::Compression(e.to_string())),
+        #[allow(unreachable_patterns)]
+        _ => Err(Error::UnsupportedCompression(compression)),
+    }
+}
+
+/// Decompress the data with the given codec.
+pub fn decompress(compression: Compression, data: &[u8]) -> Result<Bytes> {
+    decompress_with_limit(compression, data, usize::MAX)
+}
+
+/// Decompress the data with the given codec,
+/// fails with `Error::ValueTooLong` if the data decompresses to more than `max_len` bytes.
+#[cfg_attr(
+    not(any(feature = "lz4", feature = "zstd", feature = "snappy")),
+    allow(unused_variables)
+)]
+pub(super) fn decompress_with_limit(
+    compression: Compression,
+    data: &[u8],
+    max_len: usize,
+) -> Result<Bytes> {
+    match compression {
+        Compression::None => Ok(Bytes::copy_from_slice(data)),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            if let Some(prefix) = data.get(..4) {
+                let len = u32::from_le_bytes(prefix.try_into().unwrap()) as usize;
+                check_decompressed_len(len, data, max_len)?;
+            }
+            lz4_flex::decompress_size_prepended(data)
+                .map(Bytes::from)
+                .map_err(|e| Error::Compression(e.to_string()))
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            use std::io::Read;
+
+            // The frame content size is optional, read one byte past the limit to detect it.
+            let mut decoder =
+                zstd::Decoder::new(data).map_err(|e| Error::Compression(e.to_string()))?;
+            let mut decompressed = Vec::new();
+            (&mut decoder)
+                .take((max_len as u64).saturating_add(1))
+                .read_to_end(&mut decompressed)
+                .map_err(|e| Error::Compression(e.to_string()))?;
+            if decompressed.len() > max_len {
+                return Err(Error::ValueTooLong {
+                    len: decompressed.len(),
+                    max: max_len,
+                });
+            }
+            Ok(decompressed.into())
+        }
+        #[cfg(feature = "snappy")]
+        Compression::Snappy => {
+            let len =
+                snap::raw::decompress_len(data).map_err(|e| Error::Compression(e.to_string()))?;
+            check_decompressed_len(len, data, max_len)?;
+            snap::raw::Decoder::new()
+                .decompress_vec(data)
+                .map(Bytes::from)
+                .map_err(|e| Error::Compression(e.to_string()))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(Error::UnsupportedCompression(compression)),
+    }
+}
+
+// Neither lz4 nor snappy can compress better than this ratio.
+#[cfg(any(feature = "lz4", feature = "snappy"))]
+const MAX_COMPRESSION_RATIO: u64 = 255;
+
+/// The decompressed length is read from the untrusted input and allocated up front,
+/// reject the lengths over the limit or that the input can't decompress to.
+#[cfg(any(feature = "lz4", feature = "snappy"))]
+fn check_decompressed_len(len: usize, data: &[u8], max_len: usize) -> Result<()> {
+    if len > max_len {
+        return Err(Error::ValueTooLong { len, max: max_len });
+    }
+    if len as u64 > data.len() as u64 * MAX_COMPRESSION_RATIO {
+        return Err(Error::Compression(format!(
+            "decompressed length {} exceeds the input of {} bytes",
+            len,
+            data.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Compress the headers in place according to the attr.
+/// The last header is the kv, its value is always compressed when a codec is set,
+/// the other headers' values are compressed only if the attr says so, except the digest.
+pub(super) fn compress_headers(attr: Attr, headers: &mut [Header]) -> Result<()> {
+    let compression = attr.compression()?;
+    if compression == Compression::None {
+        return Ok(());
+    }
+    let kv_index = headers.len() - 1;
+    for (i, header) in headers.iter_mut().enumerate() {
+        if value_compressed(attr, header, i == kv_index) {
+            *header = Header::new(header.key().clone(), compress(compression, header.value())?);
+        }
+    }
+    Ok(())
+}
+
+/// Decompress the headers according to the attr.
+/// Returns `None` if the headers are not compressed, or encrypted so they can only be
+/// decompressed once decrypted.
+pub(super) fn decompress_headers(
+    attr: Attr,
+    headers: &[Header],
+    max_value_len: usize,
+) -> Result<Option<Vec<Header>>> {
+    let compression = attr.compression()?;
+    if compression == Compression::None || attr.encrypted() {
+        return Ok(None);
+    }
+    let kv_index = headers.len() - 1;
+    let mut decompressed = Vec::with_capacity(headers.len());
+    for (i, header) in headers.iter().enumerate() {
+        if value_compressed(attr, header, i == kv_index) {
+            decompressed.push(Header::new(
+                header.key().clone(),
+                decompress_with_limit(compression, header.value(), max_value_len)?,
+            ));
+        } else {
+            decompressed.push(header.clone());
+        }
+    }
+    Ok(Some(decompressed))
+}
+
+// Returns whether the value of the header is compressed once a codec is set.
+fn value_compressed(attr: Attr, header: &Header, is_kv: bool) -> bool {
+    is_kv || (attr.headers_compressed() && header.key() != DIGEST_HEADER_KEY)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    pub(crate) fn enabled_compressions() -> Vec<Compression> {
+        #[allow(unused_mut)]
+        let mut compressions = vec![Compression::None];
+        #[cfg(feature = "lz4")]
+        compressions.push(Compression::Lz4);
+        #[cfg(feature = "zstd")]
+        compressions.push(Compression::Zstd);
+        #[cfg(feature = "snappy")]
+        compressions.push(Compression::Snappy);
+        compressions
+    }
+
+    #[test]
+    fn test_compress_decompress() {
+        let data = b"{\"key\":\"value\",\"key\":\"value\",\"key\":\"value\",\"key\":\"value\"}";
+        for compression in enabled_compressions() {
+            let compressed = compress(compression, data).unwrap();
+            let decompressed = decompress(compression, &compressed).unwrap();
+            assert_eq!(&decompressed[..], &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_decompress_hostile_length() {
+        // The length prefix claims 4 GiB out of a few bytes.
+        #[cfg(feature = "lz4")]
+        assert!(matches!(
+            decompress(Compression::Lz4, b"\xff\xff\xff\xff\x00"),
+            Err(Error::Compression(_))
+        ));
+        #[cfg(feature = "snappy")]
+        assert!(matches!(
+            decompress(Compression::Snappy, b"\xff\xff\xff\xff\x0f\x00"),
+            Err(Error::Compression(_))
+        ));
+    }
+
+    #[test]
+    fn test_decompress_corrupted() {
+        for compression in enabled_compressions() {
+            if compression != Compression::None {
+                assert!(decompress(compression, b"\x05\x00\x00\x00\xff").is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported_compression() {
+        for compression in [Compression::Lz4, Compression::Zstd, Compression::Snappy] {
+            if !enabled_compressions().contains(&compression) {
+                assert!(matches!(
+                    compress(compression, b"data"),
+                    Err(Error::UnsupportedCompression(_))
+                ));
+            }
+        }
+    }
+}
@@ -6,6 +6,9 @@ pub enum Error {
     #[error("decode buffer not enough")]
     DecodeBufNotEnough,
 
+    #[error("malformed entry at offset {offset}: {reason}")]
+    Malformed { offset: usize, reason: &'static str },
+
     #[error("prost encode")]
     ProstEncode(#[from] prost::EncodeError),
 
@@ -14,4 +17,49 @@ pub enum Error {
 
     #[error("kv not found")]
     KVNotFound,
+
+    #[error("invalid compression codec {0}")]
+    InvalidCompression(u8),
+
+    #[error("compression codec {0:?} is not enabled")]
+    UnsupportedCompression(super::Compression),
+
+    #[error("compression: {0}")]
+    Compression(String),
+
+    #[error("entry size {size} exceeds the limit {max}")]
+    EntryTooLarge { size: usize, max: usize },
+
+    #[error("header count {count} exceeds the limit {max}")]
+    TooManyHeaders { count: usize, max: usize },
+
+    #[error("key length {len} exceeds the limit {max}")]
+    KeyTooLong { len: usize, max: usize },
+
+    #[error("value length {len} exceeds the limit {max}")]
+    ValueTooLong { len: usize, max: usize },
+
+    #[error("nesting depth {depth} exceeds the limit {max}")]
+    NestingTooDeep { depth: usize, max: usize },
+
+    #[error("checksum mismatch, expected {expected:#010x}, actual {actual:#010x}")]
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    #[error("encryption: {0}")]
+    Encryption(String),
+
+    #[error("decryption: {0}")]
+    Decryption(&'static str),
+
+    #[error("header key {} is reserved", String::from_utf8_lossy(.0))]
+    ReservedHeaderKey(bytes::Bytes),
+
+    #[error("invalid value of system header {0}")]
+    InvalidSystemHeader(&'static str),
+
+    #[error("system header {0} is duplicated")]
+    DuplicatedSystemHeader(&'static str),
+
+    #[error("system header {0} is encrypted, the entry must be decrypted first")]
+    EncryptedSystemHeader(&'static str),
 }
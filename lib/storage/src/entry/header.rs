@@ -1,9 +1,13 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
 use bytes::{Buf, BufMut, Bytes};
 
 use crate::util::copy_slice;
 
-use super::Result;
+use super::util::decode_length_delimiter;
 use super::{util::copy_slice_with_multi_stage, util::customize_copy_slice_with_multi_stage};
+use super::{DecodeOptions, Error, Result};
 
 // Defining a struct Header with key and value as Bytes
 // It use length delimited encoding
@@ -31,6 +35,7 @@ impl Header {
     }
 
     /// read at a specific offset of Header's binary representation.
+    #[allow(unused_assignments)]
     pub fn read_at(&self, buf: &mut [u8], mut offset: usize) -> usize {
         let key_len = self.key.len();
         let key_len_size = prost::length_delimiter_len(key_len);
@@ -43,7 +48,7 @@ impl Header {
         };
 
         customize_copy_slice_with_multi_stage!(
-            copy_slice(&key_len_delimiter_getter(), &mut buf[n..]),
+            copy_slice(&key_len_delimiter_getter()[offset..], &mut buf[n..]),
             key_len_size,
             buf,
             offset,
@@ -73,10 +78,32 @@ impl Header {
         Ok(())
     }
 
-    /// Method to decode the Header from a buffer
-    pub fn decode<B: Buf>(mut buf: B) -> Result<Self> {
+    /// Method to decode the Header from a buffer, the whole buffer is consumed.
+    pub fn decode<B: Buf>(buf: B) -> Result<Self> {
+        Self::decode_at(buf, 0, &DecodeOptions::default())
+    }
+
+    /// Method to decode the Header from a buffer at `offset` of the entry,
+    /// the offset is only used to report malformed input.
+    pub(super) fn decode_at<B: Buf>(
+        mut buf: B,
+        offset: usize,
+        options: &DecodeOptions,
+    ) -> Result<Self> {
         // Decode the length of the key from the buffer
-        let key_len = prost::decode_length_delimiter(&mut buf)?;
+        let key_len = decode_length_delimiter(&mut buf, offset)?;
+        if key_len > options.max_key_len {
+            return Err(Error::KeyTooLong {
+                len: key_len,
+                max: options.max_key_len,
+            });
+        }
+        if buf.remaining() < key_len {
+            return Err(Error::Malformed {
+                offset,
+                reason: "key length exceeds the header",
+            });
+        }
         // Read the key from the buffer
         let key = buf.copy_to_bytes(key_len);
         // Read the value from the buffer
@@ -86,6 +113,89 @@ impl Header {
     }
 }
 
+// The entries with at least this many headers index them by key.
+const HEADER_INDEX_THRESHOLD: usize = 8;
+
+// The `HeaderIndex` maps the keys of the headers to their positions in order,
+// so looking up a key doesn't scan all the headers.
+pub(super) struct HeaderIndex {
+    positions: HashMap<Bytes, Vec<usize>>,
+}
+
+impl HeaderIndex {
+    // Build the index of the headers, `None` if there're too few of them to need one.
+    pub(super) fn build(headers: &[Header]) -> Option<Self> {
+        if headers.len() < HEADER_INDEX_THRESHOLD {
+            return None;
+        }
+        let mut positions = HashMap::<Bytes, Vec<usize>>::with_capacity(headers.len());
+        for (i, header) in headers.iter().enumerate() {
+            positions.entry(header.key.clone()).or_default().push(i);
+        }
+        Some(Self { positions })
+    }
+
+    // Returns the index of the headers, built on the first call.
+    pub(super) fn get_or_build<'a>(
+        index: &'a OnceLock<Option<Self>>,
+        headers: &[Header],
+    ) -> Option<&'a Self> {
+        index.get_or_init(|| Self::build(headers)).as_ref()
+    }
+
+    // Returns the headers with the key, the index is used if there's one.
+    pub(super) fn get_all<'a, 'k>(
+        index: Option<&'a Self>,
+        headers: &'a [Header],
+        key: &'k [u8],
+    ) -> HeadersByKey<'a, 'k> {
+        let inner = match index {
+            Some(index) => HeadersByKeyInner::Indexed {
+                headers,
+                positions: index
+                    .positions
+                    .get(key)
+                    .map_or(&[][..], Vec::as_slice)
+                    .iter(),
+            },
+            None => HeadersByKeyInner::Scan {
+                headers: headers.iter(),
+                key,
+            },
+        };
+        HeadersByKey { inner }
+    }
+}
+
+/// The `HeadersByKey` iterates over the headers of an entry with the same key, in order.
+pub struct HeadersByKey<'a, 'k> {
+    inner: HeadersByKeyInner<'a, 'k>,
+}
+
+enum HeadersByKeyInner<'a, 'k> {
+    Indexed {
+        headers: &'a [Header],
+        positions: std::slice::Iter<'a, usize>,
+    },
+    Scan {
+        headers: std::slice::Iter<'a, Header>,
+        key: &'k [u8],
+    },
+}
+
+impl<'a> Iterator for HeadersByKey<'a, '_> {
+    type Item = &'a Header;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            HeadersByKeyInner::Indexed { headers, positions } => {
+                positions.next().map(|i| &headers[*i])
+            }
+            HeadersByKeyInner::Scan { headers, key } => headers.find(|header| header.key() == *key),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,4 +304,58 @@ mod tests {
         assert_eq!(all, b"\x03keyvalue");
         assert_eq!(offset, header.binary_size());
     }
+
+    #[test]
+    fn test_header_index() {
+        let headers: Vec<_> = (0..HEADER_INDEX_THRESHOLD * 2)
+            .map(|i| {
+                let key = if i % 2 == 0 {
+                    "even".to_string()
+                } else {
+                    format!("key{}", i)
+                };
+                Header::new(Bytes::from(key), Bytes::from(i.to_string()))
+            })
+            .collect();
+        assert!(HeaderIndex::build(&headers[..HEADER_INDEX_THRESHOLD - 1]).is_none());
+        let index = HeaderIndex::build(&headers).unwrap();
+
+        // The indexed lookups match the scans.
+        for key in [&b"even"[..], b"key3", b"key15", b"missing"] {
+            let scanned: Vec<_> = HeaderIndex::get_all(None, &headers, key)
+                .map(|header| header.value().clone())
+                .collect();
+            let indexed: Vec<_> = HeaderIndex::get_all(Some(&index), &headers, key)
+                .map(|header| header.value().clone())
+                .collect();
+            assert_eq!(scanned, indexed);
+        }
+        let values: Vec<_> = HeaderIndex::get_all(Some(&index), &headers, b"even")
+            .map(|header| header.value().clone())
+            .collect();
+        assert_eq!(values, ["0", "2", "4", "6", "8", "10", "12", "14"]);
+        assert!(HeaderIndex::get_all(Some(&index), &headers, b"missing")
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_read_at_multi_byte_delimiter() {
+        let header = Header::new(Bytes::from(vec![b'k'; 200]), Bytes::from_static(b"value"));
+        let mut encoded = BytesMut::new();
+        header.encode(&mut encoded).unwrap();
+
+        let mut buf = vec![0; 1];
+        let mut all = vec![];
+        let mut offset = 0;
+        loop {
+            let n = header.read_at(&mut buf, offset);
+            if n == 0 {
+                break;
+            }
+            offset += n;
+            all.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(all, &encoded[..]);
+    }
 }
@@ -1,31 +1,47 @@
+use std::sync::OnceLock;
+
 use bytes::{Buf, BufMut, Bytes};
 
 use crate::util::copy_slice;
 
+use super::compression::{compress_headers, decompress_headers};
+use super::header::HeaderIndex;
+use super::system_header::validate_headers;
+use super::util::{check_value_len, decode_headers};
 use super::Attr;
+use super::Compression;
+use super::DecodeOptions;
 use super::Entry;
-use super::Header;
 use super::Magic;
 use super::Result;
 use super::{util::copy_slice_with_multi_stage, util::customize_copy_slice_with_multi_stage};
+use super::{Header, HeadersByKey};
 
 // Magic 1
 // Attr 4
 // log_id 8
 // entry_id 8
 // last_confirm_id 8 = 29
-const COMMON_HEADER_BINARY_SIZE: usize = 29;
-const COMMON_HEADER_MAGIC_OFFSET: usize = 0;
-const COMMON_HEADER_ATTR_OFFSET: usize = 1;
-const COMMON_HEADER_LOG_ID_OFFSET: usize = 5;
-const COMMON_HEADER_ENTRY_ID_OFFSET: usize = 13;
-const COMMON_HEADER_LAC_ID_OFFSET: usize = 21;
+pub(super) const COMMON_HEADER_BINARY_SIZE: usize = 29;
+pub(super) const COMMON_HEADER_MAGIC_OFFSET: usize = 0;
+pub(super) const COMMON_HEADER_ATTR_OFFSET: usize = 1;
+pub(super) const COMMON_HEADER_LOG_ID_OFFSET: usize = 5;
+pub(super) const COMMON_HEADER_ENTRY_ID_OFFSET: usize = 13;
+pub(super) const COMMON_HEADER_LAC_ID_OFFSET: usize = 21;
 
 /// The `EntryBuilder` struct provides a way to construct a new `Entry`.
 pub struct BuilderV1 {
     common_header: [u8; COMMON_HEADER_BINARY_SIZE],
     kv: Option<Header>,
     headers: Vec<Header>,
+    compression: Compression,
+    compress_headers: bool,
+}
+
+impl Default for BuilderV1 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BuilderV1 {
@@ -35,6 +51,8 @@ impl BuilderV1 {
             common_header: [0; COMMON_HEADER_BINARY_SIZE],
             kv: None,
             headers: Vec::new(),
+            compression: Compression::None,
+            compress_headers: false,
         };
         b.common_header[COMMON_HEADER_MAGIC_OFFSET] = Magic::V1.into();
         b
@@ -76,14 +94,62 @@ impl BuilderV1 {
         self
     }
 
+    /// Method to set the compression codec of the kv value
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Method to also compress the values of the user headers
+    pub fn compress_headers(mut self, compress_headers: bool) -> Self {
+        self.compress_headers = compress_headers;
+        self
+    }
+
     /// Method to build the Entry
-    pub fn build(mut self) -> impl Entry {
+    ///
+    /// Panics if the kv is missing or the payload can not be compressed,
+    /// use `try_build` to handle these errors. Unlike `try_build` the user headers aren't validated,
+    /// so the keys with the reserved prefix still build as they did before the system headers.
+    pub fn build(self) -> EntryV1 {
+        assert!(self.kv.is_some(), "missing kv field in entry");
+        self.build_unvalidated().expect("failed to build entry")
+    }
+
+    /// Method to build the Entry, the payload is compressed here.
+    ///
+    /// Fails with `Error::ReservedHeaderKey` or `Error::InvalidSystemHeader` if a user header
+    /// uses a reserved key but isn't a well-formed system header, see `validate_headers`.
+    /// Note the keys starting with `__` built before the system headers are rejected here.
+    pub fn try_build(self) -> Result<EntryV1> {
+        validate_headers(&self.headers)?;
+        self.build_unvalidated()
+    }
+
+    // Build the entry without validating the user headers.
+    fn build_unvalidated(mut self) -> Result<EntryV1> {
         self.headers
-            .push(self.kv.expect("missing kv field in entry"));
-        EntryV1 {
+            .push(self.kv.take().ok_or(super::Error::KVNotFound)?);
+        let attr = Attr::from(self.get_i32_from_common_header(COMMON_HEADER_ATTR_OFFSET))
+            .with_compression(self.compression)
+            .with_headers_compressed(self.compress_headers);
+        self.put_i32_to_common_header(COMMON_HEADER_ATTR_OFFSET, attr.into());
+
+        // Keep the uncompressed headers so the built entry doesn't need to decompress them again.
+        let uncompressed = (self.compression != Compression::None).then(|| self.headers.clone());
+        compress_headers(attr, &mut self.headers)?;
+        Ok(EntryV1 {
             common_header: self.common_header,
             headers: self.headers,
-        }
+            uncompressed,
+            index: OnceLock::new(),
+        })
+    }
+
+    fn get_i32_from_common_header(&self, offset: usize) -> i32 {
+        let mut buf = [0; 4];
+        copy_slice(&self.common_header[offset..offset + 4], &mut buf);
+        i32::from_le_bytes(buf)
     }
 
     fn put_i64_to_common_header(&mut self, offset: usize, value: i64) {
@@ -111,9 +177,16 @@ impl BuilderV1 {
 /// * `headers` - A vector of `Header` instances that represents the headers of the entry.
 /// * `key` - A `Bytes` instance that represents the keys of the entry.
 /// * `value` - A `Bytes` instance that represents the values of the entry.
+///
+/// The binary representation of the headers is kept, compressed if the attr says so, see `raw_headers`,
+/// the accessors return the uncompressed headers. The fields are private so the cached uncompressed
+/// headers and header index can't go stale.
 pub struct EntryV1 {
-    pub common_header: [u8; COMMON_HEADER_BINARY_SIZE],
-    pub headers: Vec<Header>,
+    common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+    headers: Vec<Header>,
+    uncompressed: Option<Vec<Header>>,
+    // Built on the first lookup of a header.
+    index: OnceLock<Option<HeaderIndex>>,
 }
 
 impl Entry for EntryV1 {
@@ -137,16 +210,35 @@ impl Entry for EntryV1 {
         self.get_i64_from_common_header(COMMON_HEADER_LAC_ID_OFFSET)
     }
 
+    // The V1 common header has no time fields.
+    fn timestamp(&self) -> Option<i64> {
+        None
+    }
+
+    fn append_time(&self) -> Option<i64> {
+        None
+    }
+
     fn key(&self) -> &Bytes {
-        self.headers.last().unwrap().key()
+        self.uncompressed_headers().last().unwrap().key()
     }
 
     fn value(&self) -> &Bytes {
-        self.headers.last().unwrap().value()
+        self.uncompressed_headers().last().unwrap().value()
     }
 
     fn headers(&self) -> &[Header] {
-        &self.headers[..self.headers.len() - 1]
+        let headers = self.uncompressed_headers();
+        &headers[..headers.len() - 1]
+    }
+
+    fn get_all_headers<'a, 'k>(&'a self, key: &'k [u8]) -> HeadersByKey<'a, 'k> {
+        let headers = self.headers();
+        HeaderIndex::get_all(
+            HeaderIndex::get_or_build(&self.index, headers),
+            headers,
+            key,
+        )
     }
 
     fn binary_size(&self) -> usize {
@@ -169,27 +261,35 @@ impl Entry for EntryV1 {
         Ok(())
     }
 
-    fn decode_without_magic<B: Buf>(magic: Magic, mut buf: B) -> Result<Self> {
+    fn decode_without_magic_with_options<B: Buf>(
+        magic: Magic,
+        mut buf: B,
+        options: &DecodeOptions,
+    ) -> Result<Self> {
+        options.check_nesting_depth(1)?;
+        options.check_entry_size(buf.remaining() + 1)?;
+        if buf.remaining() < COMMON_HEADER_BINARY_SIZE - 1 {
+            return Err(super::Error::DecodeBufNotEnough);
+        }
         let mut common_header = [0; COMMON_HEADER_BINARY_SIZE];
         common_header[0] = magic.into();
         buf.copy_to_slice(&mut common_header[1..]);
 
-        // Read the value from the buffer
-        let mut headers = Vec::new();
-        while buf.has_remaining() {
-            // Decode the length of the value from the buffer
-            let length = prost::decode_length_delimiter(&mut buf)?;
-            let mut header_buf = buf.take(length);
-            headers.push(Header::decode(&mut header_buf)?);
-            buf = header_buf.into_inner();
-        }
+        // Read the headers from the buffer, the last one is the kv
+        let headers = decode_headers(buf, COMMON_HEADER_BINARY_SIZE, options)?;
         if headers.is_empty() {
             return Err(super::Error::KVNotFound);
         }
-        Ok(Self {
+        let mut entry = Self {
             common_header,
             headers,
-        })
+            uncompressed: None,
+            index: OnceLock::new(),
+        };
+        entry.uncompressed =
+            decompress_headers(entry.attr(), &entry.headers, options.max_value_len)?;
+        check_value_len(entry.uncompressed_headers(), options)?;
+        Ok(entry)
     }
 
     fn read_at(&self, buf: &mut [u8], mut offset: usize) -> usize {
@@ -206,7 +306,7 @@ impl Entry for EntryV1 {
                 tmp_storage
             };
             customize_copy_slice_with_multi_stage!(
-                copy_slice(&header_size_delimiter_getter(), &mut buf[n..]),
+                copy_slice(&header_size_delimiter_getter()[offset..], &mut buf[n..]),
                 header_size_delimiter_size,
                 buf,
                 offset,
@@ -225,6 +325,35 @@ impl Entry for EntryV1 {
 }
 
 impl EntryV1 {
+    /// Returns the binary representation of the common header.
+    pub fn common_header(&self) -> &[u8; COMMON_HEADER_BINARY_SIZE] {
+        &self.common_header
+    }
+
+    /// Returns the binary representation of the headers, the kv last, compressed if the attr says so.
+    pub fn raw_headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    // Create the entry from the binary representation of its headers, decompressed here if needed.
+    pub(super) fn from_raw_headers(
+        common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+        headers: Vec<Header>,
+    ) -> Result<Self> {
+        let mut entry = Self {
+            common_header,
+            headers,
+            uncompressed: None,
+            index: OnceLock::new(),
+        };
+        entry.uncompressed = decompress_headers(entry.attr(), &entry.headers, usize::MAX)?;
+        Ok(entry)
+    }
+
+    fn uncompressed_headers(&self) -> &[Header] {
+        self.uncompressed.as_deref().unwrap_or(&self.headers)
+    }
+
     fn get_i64_from_common_header(&self, offset: usize) -> i64 {
         let mut buf = [0; 8];
         copy_slice(&self.common_header[offset..offset + 8], &mut buf);
@@ -0,0 +1,497 @@
+use std::sync::OnceLock;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::util::copy_slice;
+
+use super::compression::{compress_headers, decompress_headers};
+use super::header::HeaderIndex;
+use super::system_header::validate_headers;
+use super::util::{check_value_len, decode_headers};
+use super::Attr;
+use super::Compression;
+use super::DecodeOptions;
+use super::Entry;
+use super::Error;
+use super::Magic;
+use super::Result;
+use super::NO_TIMESTAMP;
+use super::{util::copy_slice_with_multi_stage, util::customize_copy_slice_with_multi_stage};
+use super::{Header, HeadersByKey};
+
+// Magic 1
+// Attr 4
+// log_id 8
+// entry_id 8
+// last_confirm_id 8
+// timestamp 8
+// append_time 8
+// checksum 4 = 49
+//
+// It's the `Magic::V3` layout, the `Magic::V2` one has no time fields and the checksum
+// follows the last confirm id. The common header is kept in the former layout in both cases.
+pub(super) const COMMON_HEADER_BINARY_SIZE: usize = 49;
+pub(super) const COMMON_HEADER_MAGIC_OFFSET: usize = 0;
+pub(super) const COMMON_HEADER_ATTR_OFFSET: usize = 1;
+pub(super) const COMMON_HEADER_LOG_ID_OFFSET: usize = 5;
+pub(super) const COMMON_HEADER_ENTRY_ID_OFFSET: usize = 13;
+pub(super) const COMMON_HEADER_LAC_ID_OFFSET: usize = 21;
+pub(super) const COMMON_HEADER_TIMESTAMP_OFFSET: usize = 29;
+pub(super) const COMMON_HEADER_APPEND_TIME_OFFSET: usize = 37;
+pub(super) const COMMON_HEADER_CHECKSUM_OFFSET: usize = 45;
+pub(super) const V2_COMMON_HEADER_BINARY_SIZE: usize = 33;
+pub(super) const V2_COMMON_HEADER_CHECKSUM_OFFSET: usize = 29;
+
+// Returns the size of the encoded common header of the magic and the offset of its checksum.
+pub(super) fn common_header_layout(magic: Magic) -> (usize, usize) {
+    match magic {
+        Magic::V2 => (
+            V2_COMMON_HEADER_BINARY_SIZE,
+            V2_COMMON_HEADER_CHECKSUM_OFFSET,
+        ),
+        _ => (COMMON_HEADER_BINARY_SIZE, COMMON_HEADER_CHECKSUM_OFFSET),
+    }
+}
+
+/// The `BuilderV2` struct provides a way to construct a new checksummed `Entry`.
+pub struct BuilderV2 {
+    common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+    kv: Option<Header>,
+    headers: Vec<Header>,
+    compression: Compression,
+    compress_headers: bool,
+}
+
+impl Default for BuilderV2 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BuilderV2 {
+    /// Constructor for BuilderV2
+    pub fn new() -> Self {
+        let mut b = BuilderV2 {
+            common_header: [0; COMMON_HEADER_BINARY_SIZE],
+            kv: None,
+            headers: Vec::new(),
+            compression: Compression::None,
+            compress_headers: false,
+        };
+        b.common_header[COMMON_HEADER_MAGIC_OFFSET] = Magic::V2.into();
+        b.put_i64_to_common_header(COMMON_HEADER_TIMESTAMP_OFFSET, NO_TIMESTAMP);
+        b.put_i64_to_common_header(COMMON_HEADER_APPEND_TIME_OFFSET, NO_TIMESTAMP);
+        b
+    }
+
+    /// Method to set the attr of the Entry
+    pub fn attr(mut self, attr: Attr) -> Self {
+        self.put_i32_to_common_header(COMMON_HEADER_ATTR_OFFSET, attr.into());
+        self
+    }
+
+    /// Method to set the log_id of the Entry
+    pub fn log_id(mut self, log_id: i64) -> Self {
+        self.put_i64_to_common_header(COMMON_HEADER_LOG_ID_OFFSET, log_id);
+        self
+    }
+
+    /// Method to set the entry_id of the Entry
+    pub fn entry_id(mut self, entry_id: i64) -> Self {
+        self.put_i64_to_common_header(COMMON_HEADER_ENTRY_ID_OFFSET, entry_id);
+        self
+    }
+
+    /// Method to set the last_confirm id of the Entry
+    pub fn last_confirm_id(mut self, last_confirm_id: i64) -> Self {
+        self.put_i64_to_common_header(COMMON_HEADER_LAC_ID_OFFSET, last_confirm_id);
+        self
+    }
+
+    /// Method to set the producer timestamp of the Entry, in milliseconds since the Unix epoch
+    pub fn timestamp(mut self, timestamp: i64) -> Self {
+        self.put_i64_to_common_header(COMMON_HEADER_TIMESTAMP_OFFSET, timestamp);
+        self
+    }
+
+    /// Method to set the append time of the Entry, in milliseconds since the Unix epoch
+    pub fn append_time(mut self, append_time: i64) -> Self {
+        self.put_i64_to_common_header(COMMON_HEADER_APPEND_TIME_OFFSET, append_time);
+        self
+    }
+
+    /// Method to set the kv of the EntryBuilder
+    pub fn kv(mut self, key: Bytes, value: Bytes) -> Self {
+        self.kv = Some(Header::new(key, value));
+        self
+    }
+
+    /// Method to set the header of the EntryBuilder
+    pub fn header(mut self, header: Header) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    /// Method to set the compression codec of the kv value
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Method to also compress the values of the user headers
+    pub fn compress_headers(mut self, compress_headers: bool) -> Self {
+        self.compress_headers = compress_headers;
+        self
+    }
+
+    /// Method to build the Entry
+    ///
+    /// Panics if the kv is missing or the payload can not be compressed,
+    /// use `try_build` to handle these errors. Unlike `try_build` the user headers aren't validated,
+    /// so the keys with the reserved prefix still build as they did before the system headers.
+    pub fn build(self) -> EntryV2 {
+        assert!(self.kv.is_some(), "missing kv field in entry");
+        self.build_unvalidated().expect("failed to build entry")
+    }
+
+    /// Method to build the Entry, the payload is compressed and the checksum is calculated here.
+    ///
+    /// Fails with `Error::ReservedHeaderKey` or `Error::InvalidSystemHeader` if a user header
+    /// uses a reserved key but isn't a well-formed system header, see `validate_headers`.
+    /// Note the keys starting with `__` built before the system headers are rejected here.
+    pub fn try_build(self) -> Result<EntryV2> {
+        validate_headers(&self.headers)?;
+        self.build_unvalidated()
+    }
+
+    // Build the entry without validating the user headers.
+    fn build_unvalidated(mut self) -> Result<EntryV2> {
+        self.headers.push(self.kv.take().ok_or(Error::KVNotFound)?);
+        let attr = Attr::from(self.get_i32_from_common_header(COMMON_HEADER_ATTR_OFFSET))
+            .with_compression(self.compression)
+            .with_headers_compressed(self.compress_headers);
+        self.put_i32_to_common_header(COMMON_HEADER_ATTR_OFFSET, attr.into());
+
+        // Keep the uncompressed headers so the built entry doesn't need to decompress them again.
+        let uncompressed = (self.compression != Compression::None).then(|| self.headers.clone());
+        compress_headers(attr, &mut self.headers)?;
+        // The entries without any time keep the `Magic::V2` layout.
+        let timed = [
+            COMMON_HEADER_TIMESTAMP_OFFSET,
+            COMMON_HEADER_APPEND_TIME_OFFSET,
+        ]
+        .into_iter()
+        .any(|offset| self.common_header[offset..offset + 8] != NO_TIMESTAMP.to_le_bytes());
+        if timed {
+            self.common_header[COMMON_HEADER_MAGIC_OFFSET] = Magic::V3.into();
+        }
+        let mut entry = EntryV2 {
+            common_header: self.common_header,
+            headers: self.headers,
+            uncompressed,
+            index: OnceLock::new(),
+        };
+        let checksum = entry.calculate_checksum();
+        copy_slice(
+            &checksum.to_le_bytes(),
+            &mut entry.common_header[COMMON_HEADER_CHECKSUM_OFFSET..],
+        );
+        Ok(entry)
+    }
+
+    fn get_i32_from_common_header(&self, offset: usize) -> i32 {
+        let mut buf = [0; 4];
+        copy_slice(&self.common_header[offset..offset + 4], &mut buf);
+        i32::from_le_bytes(buf)
+    }
+
+    fn put_i64_to_common_header(&mut self, offset: usize, value: i64) {
+        copy_slice(
+            &value.to_le_bytes(),
+            &mut self.common_header[offset..offset + 8],
+        );
+    }
+
+    fn put_i32_to_common_header(&mut self, offset: usize, value: i32) {
+        copy_slice(
+            &value.to_le_bytes(),
+            &mut self.common_header[offset..offset + 4],
+        );
+    }
+}
+
+/// The `EntryV2` struct is a log entry protected by a CRC32C checksum.
+///
+/// The layout is the same as `EntryV1` except that the common header is extended by the producer timestamp,
+/// the append time and a 4 bytes checksum. A missing timestamp is stored as `NO_TIMESTAMP`.
+/// The checksum covers the common header (without the checksum itself) and all length delimited headers,
+/// so it's calculated over the compressed form if the payload is compressed.
+///
+/// An entry without any time is encoded with `Magic::V2`, whose common header has no time fields,
+/// the other ones with `Magic::V3`. Setting the append time turns the former into the latter.
+pub struct EntryV2 {
+    common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+    headers: Vec<Header>,
+    uncompressed: Option<Vec<Header>>,
+    // Built on the first lookup of a header.
+    index: OnceLock<Option<HeaderIndex>>,
+}
+
+impl Entry for EntryV2 {
+    fn magic(&self) -> Magic {
+        Magic::try_from(self.common_header[COMMON_HEADER_MAGIC_OFFSET]).expect("invalid magic")
+    }
+
+    fn attr(&self) -> Attr {
+        Attr::from(self.get_i32_from_common_header(COMMON_HEADER_ATTR_OFFSET))
+    }
+
+    fn log_id(&self) -> i64 {
+        self.get_i64_from_common_header(COMMON_HEADER_LOG_ID_OFFSET)
+    }
+
+    fn entry_id(&self) -> i64 {
+        self.get_i64_from_common_header(COMMON_HEADER_ENTRY_ID_OFFSET)
+    }
+
+    fn last_confirm_id(&self) -> i64 {
+        self.get_i64_from_common_header(COMMON_HEADER_LAC_ID_OFFSET)
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        Some(self.get_i64_from_common_header(COMMON_HEADER_TIMESTAMP_OFFSET))
+            .filter(|timestamp| *timestamp != NO_TIMESTAMP)
+    }
+
+    fn append_time(&self) -> Option<i64> {
+        Some(self.get_i64_from_common_header(COMMON_HEADER_APPEND_TIME_OFFSET))
+            .filter(|append_time| *append_time != NO_TIMESTAMP)
+    }
+
+    fn key(&self) -> &Bytes {
+        self.uncompressed_headers().last().unwrap().key()
+    }
+
+    fn value(&self) -> &Bytes {
+        self.uncompressed_headers().last().unwrap().value()
+    }
+
+    fn headers(&self) -> &[Header] {
+        let headers = self.uncompressed_headers();
+        &headers[..headers.len() - 1]
+    }
+
+    fn get_all_headers<'a, 'k>(&'a self, key: &'k [u8]) -> HeadersByKey<'a, 'k> {
+        let headers = self.headers();
+        HeaderIndex::get_all(
+            HeaderIndex::get_or_build(&self.index, headers),
+            headers,
+            key,
+        )
+    }
+
+    fn binary_size(&self) -> usize {
+        common_header_layout(self.magic()).0 + self.headers_binary_size()
+    }
+
+    fn encode<B: BufMut>(&self, mut buf: B) -> Result<()> {
+        let (common_header, size) = self.encoded_common_header();
+        buf.put_slice(&common_header[..size]);
+        self.encode_headers(&mut buf)
+    }
+
+    fn decode_without_magic_with_options<B: Buf>(
+        magic: Magic,
+        mut buf: B,
+        options: &DecodeOptions,
+    ) -> Result<Self> {
+        options.check_nesting_depth(1)?;
+        options.check_entry_size(buf.remaining() + 1)?;
+        let (size, checksum_offset) = common_header_layout(magic);
+        if buf.remaining() < size - 1 {
+            return Err(Error::DecodeBufNotEnough);
+        }
+        let mut common_header = [0; COMMON_HEADER_BINARY_SIZE];
+        common_header[0] = magic.into();
+        buf.copy_to_slice(&mut common_header[1..size]);
+
+        // Verify the checksum before parsing the headers.
+        let body = buf.copy_to_bytes(buf.remaining());
+        let expected = u32::from_le_bytes(common_header[checksum_offset..size].try_into().unwrap());
+        let actual =
+            crc32c::crc32c_append(crc32c::crc32c(&common_header[..checksum_offset]), &body);
+        if expected != actual {
+            return Err(Error::ChecksumMismatch { expected, actual });
+        }
+        if magic == Magic::V2 {
+            common_header.copy_within(checksum_offset..size, COMMON_HEADER_CHECKSUM_OFFSET);
+            for offset in [
+                COMMON_HEADER_TIMESTAMP_OFFSET,
+                COMMON_HEADER_APPEND_TIME_OFFSET,
+            ] {
+                common_header[offset..offset + 8].copy_from_slice(&NO_TIMESTAMP.to_le_bytes());
+            }
+        }
+
+        // Read the headers from the body, the last one is the kv
+        let headers = decode_headers(body, size, options)?;
+        if headers.is_empty() {
+            return Err(Error::KVNotFound);
+        }
+        let mut entry = Self {
+            common_header,
+            headers,
+            uncompressed: None,
+            index: OnceLock::new(),
+        };
+        entry.uncompressed =
+            decompress_headers(entry.attr(), &entry.headers, options.max_value_len)?;
+        check_value_len(entry.uncompressed_headers(), options)?;
+        Ok(entry)
+    }
+
+    fn read_at(&self, buf: &mut [u8], mut offset: usize) -> usize {
+        let mut n = 0;
+
+        let (common_header, size) = self.encoded_common_header();
+        let common_header = &common_header[..size];
+        copy_slice_with_multi_stage!(common_header, buf, offset, n);
+
+        for header in &self.headers {
+            let header_size = header.binary_size();
+            let header_size_delimiter_size = prost::length_delimiter_len(header_size);
+            let header_size_delimiter_getter = || -> Vec<u8> {
+                let mut tmp_storage = Vec::with_capacity(header_size_delimiter_size);
+                prost::encode_length_delimiter(header_size, &mut tmp_storage).unwrap();
+                tmp_storage
+            };
+            customize_copy_slice_with_multi_stage!(
+                copy_slice(&header_size_delimiter_getter()[offset..], &mut buf[n..]),
+                header_size_delimiter_size,
+                buf,
+                offset,
+                n
+            );
+            customize_copy_slice_with_multi_stage!(
+                header.read_at(&mut buf[n..], offset),
+                header_size,
+                buf,
+                offset,
+                n
+            );
+        }
+        n
+    }
+}
+
+impl EntryV2 {
+    /// Returns the binary representation of the common header.
+    pub fn common_header(&self) -> &[u8; COMMON_HEADER_BINARY_SIZE] {
+        &self.common_header
+    }
+
+    /// Returns the binary representation of the headers, the kv last, compressed if the attr says so.
+    pub fn raw_headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    // Create the entry from the binary representation of its headers, decompressed here if needed,
+    // the checksum is calculated again.
+    pub(super) fn from_raw_headers(
+        common_header: [u8; COMMON_HEADER_BINARY_SIZE],
+        headers: Vec<Header>,
+    ) -> Result<Self> {
+        let mut entry = Self {
+            common_header,
+            headers,
+            uncompressed: None,
+            index: OnceLock::new(),
+        };
+        entry.uncompressed = decompress_headers(entry.attr(), &entry.headers, usize::MAX)?;
+        let checksum = entry.calculate_checksum();
+        copy_slice(
+            &checksum.to_le_bytes(),
+            &mut entry.common_header[COMMON_HEADER_CHECKSUM_OFFSET..],
+        );
+        Ok(entry)
+    }
+
+    /// Returns the checksum stored in the common header.
+    pub fn checksum(&self) -> u32 {
+        u32::from_le_bytes(
+            self.common_header[COMMON_HEADER_CHECKSUM_OFFSET..]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    /// Set the time the entry is appended, in milliseconds since the Unix epoch,
+    /// the entry turns into `Magic::V3` if needed and the checksum is calculated again.
+    pub fn set_append_time(&mut self, append_time: i64) {
+        self.common_header[COMMON_HEADER_MAGIC_OFFSET] = Magic::V3.into();
+        copy_slice(
+            &append_time.to_le_bytes(),
+            &mut self.common_header[COMMON_HEADER_APPEND_TIME_OFFSET..],
+        );
+        let checksum = self.calculate_checksum();
+        copy_slice(
+            &checksum.to_le_bytes(),
+            &mut self.common_header[COMMON_HEADER_CHECKSUM_OFFSET..],
+        );
+    }
+
+    fn uncompressed_headers(&self) -> &[Header] {
+        self.uncompressed.as_deref().unwrap_or(&self.headers)
+    }
+
+    fn calculate_checksum(&self) -> u32 {
+        let mut body = BytesMut::with_capacity(self.headers_binary_size());
+        // BytesMut grows on demand, so should never fail.
+        self.encode_headers(&mut body).unwrap();
+        // Both layouts start the same up to their checksum.
+        let (_, checksum_offset) = common_header_layout(self.magic());
+        crc32c::crc32c_append(
+            crc32c::crc32c(&self.common_header[..checksum_offset]),
+            &body,
+        )
+    }
+
+    // Returns the common header in the layout of the magic, with its size.
+    fn encoded_common_header(&self) -> ([u8; COMMON_HEADER_BINARY_SIZE], usize) {
+        let (size, checksum_offset) = common_header_layout(self.magic());
+        let mut common_header = self.common_header;
+        common_header.copy_within(COMMON_HEADER_CHECKSUM_OFFSET.., checksum_offset);
+        (common_header, size)
+    }
+
+    fn headers_binary_size(&self) -> usize {
+        let mut size = 0;
+        for header in &self.headers {
+            let header_size = header.binary_size();
+            size += prost::length_delimiter_len(header_size);
+            size += header_size;
+        }
+        size
+    }
+
+    fn encode_headers<B: BufMut>(&self, mut buf: B) -> Result<()> {
+        for header in &self.headers {
+            let size = header.binary_size();
+            prost::encode_length_delimiter(size, &mut buf)?;
+            header.encode(&mut buf)?;
+        }
+        Ok(())
+    }
+
+    fn get_i64_from_common_header(&self, offset: usize) -> i64 {
+        let mut buf = [0; 8];
+        copy_slice(&self.common_header[offset..offset + 8], &mut buf);
+        i64::from_le_bytes(buf)
+    }
+
+    fn get_i32_from_common_header(&self, offset: usize) -> i32 {
+        let mut buf = [0; 4];
+        copy_slice(&self.common_header[offset..offset + 4], &mut buf);
+        i32::from_le_bytes(buf)
+    }
+}
@@ -1,22 +1,49 @@
+mod batch;
+mod cipher;
+mod compression;
 mod error;
 mod header;
 mod impls_v1;
+mod impls_v2;
+mod options;
+mod system_header;
 mod util;
+mod view;
 
+pub use batch::{BatchBuilder, BatchRecord, EntryBatch};
 use bytes::{Buf, BufMut};
+pub use cipher::{Cipher, KEY_ID_HEADER_KEY};
+pub use compression::{compress, decompress, DIGEST_HEADER_KEY};
 pub use error::Error;
-pub use header::Header;
-pub use util::{Attr, Magic};
+use header::HeaderIndex;
+pub use header::{Header, HeadersByKey};
+pub use impls_v1::{BuilderV1, EntryV1};
+pub use impls_v2::{BuilderV2, EntryV2};
+pub use options::DecodeOptions;
+pub use system_header::{
+    is_reserved_key, validate_headers, ContentType, PayloadCompression, ProducerId, SchemaId,
+    Sequence, SystemHeader, TraceId, RESERVED_KEY_PREFIX, SYSTEM_HEADER_KEYS,
+};
+pub use util::{Attr, Compression, Magic};
+pub use view::{EntryRef, HeaderRef, HeaderRefIter};
 
-use self::impls_v1::{BuilderV1, EntryV1};
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// The value of a time field of the common header which isn't set.
+pub const NO_TIMESTAMP: i64 = -1;
+
 /// decode an entry from a buffer.
-pub fn decode<B: Buf>(mut buf: B) -> Result<impl Entry> {
-    let magic = Magic::try_from(buf.get_u8())?;
-    match magic {
-        Magic::V1 => EntryV1::decode_without_magic(magic, buf),
+pub fn decode<B: Buf>(buf: B) -> Result<AnyEntry> {
+    decode_with_options(buf, &DecodeOptions::default())
+}
+
+/// decode an entry from a buffer, the limits of the options are enforced before allocating.
+pub fn decode_with_options<B: Buf>(mut buf: B, options: &DecodeOptions) -> Result<AnyEntry> {
+    if !buf.has_remaining() {
+        return Err(Error::DecodeBufNotEnough);
     }
+    let magic = Magic::try_from(buf.get_u8())?;
+    AnyEntry::decode_without_magic_with_options(magic, buf, options)
 }
 
 pub trait Entry {
@@ -35,6 +62,12 @@ pub trait Entry {
     /// Returns the last confirm id of the entry.
     fn last_confirm_id(&self) -> i64;
 
+    /// Returns the timestamp set by the producer, in milliseconds since the Unix epoch.
+    fn timestamp(&self) -> Option<i64>;
+
+    /// Returns the time the entry was appended, in milliseconds since the Unix epoch, if it was recorded.
+    fn append_time(&self) -> Option<i64>;
+
     /// Returns the key of the entry.
     fn key(&self) -> &bytes::Bytes;
 
@@ -44,6 +77,31 @@ pub trait Entry {
     /// Returns the headers of the entry.
     fn headers(&self) -> &[Header];
 
+    /// Returns the first header with the key, `None` if the entry doesn't have it.
+    fn get_header(&self, key: &[u8]) -> Option<&Header> {
+        self.get_all_headers(key).next()
+    }
+
+    /// Returns the headers with the key in order, for the multi-valued keys.
+    /// The headers are scanned by default, the entries with many headers index them
+    /// on the first lookup so the next ones don't scan all of them.
+    fn get_all_headers<'a, 'k>(&'a self, key: &'k [u8]) -> HeadersByKey<'a, 'k> {
+        HeaderIndex::get_all(None, self.headers(), key)
+    }
+
+    /// Returns the value of the system header, `None` if the entry doesn't have it.
+    /// Fails with `Error::InvalidSystemHeader` if its value is malformed,
+    /// or with `Error::EncryptedSystemHeader` if the entry is encrypted.
+    fn header<H: SystemHeader>(&self) -> Result<Option<H>> {
+        let Some(header) = self.get_header(H::KEY.as_bytes()) else {
+            return Ok(None);
+        };
+        if self.attr().encrypted() {
+            return Err(Error::EncryptedSystemHeader(H::KEY));
+        }
+        H::decode_value(header.value()).map(Some)
+    }
+
     /// Get the binary size of the entry.
     fn binary_size(&self) -> usize;
 
@@ -52,6 +110,18 @@ pub trait Entry {
 
     /// Decodes the buffer into an entry.
     fn decode_without_magic<B: Buf>(magic: Magic, buf: B) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::decode_without_magic_with_options(magic, buf, &DecodeOptions::default())
+    }
+
+    /// Decodes the buffer into an entry, the limits of the options are enforced before allocating.
+    fn decode_without_magic_with_options<B: Buf>(
+        magic: Magic,
+        buf: B,
+        options: &DecodeOptions,
+    ) -> Result<Self>
     where
         Self: Sized;
 
@@ -59,11 +129,154 @@ pub trait Entry {
     fn read_at(&self, buf: &mut [u8], offset: usize) -> usize;
 }
 
+/// The `AnyEntry` is an entry of any supported version, dispatched by its magic.
+pub enum AnyEntry {
+    V1(EntryV1),
+    V2(EntryV2),
+}
+
+impl AnyEntry {
+    // Returns the common header and the binary representation of the headers, the kv last.
+    pub(crate) fn raw_parts(&self) -> (&[u8], &[Header]) {
+        match self {
+            AnyEntry::V1(entry) => (entry.common_header(), entry.raw_headers()),
+            AnyEntry::V2(entry) => (entry.common_header(), entry.raw_headers()),
+        }
+    }
+
+    // Returns the entry of the same version with the attr and the binary representation of the headers,
+    // the checksum of a V2 entry is calculated again.
+    pub(crate) fn with_raw_parts(&self, attr: Attr, headers: Vec<Header>) -> Result<Self> {
+        let attr = i32::from(attr).to_le_bytes();
+        match self {
+            AnyEntry::V1(entry) => {
+                let mut common_header = *entry.common_header();
+                common_header[impls_v1::COMMON_HEADER_ATTR_OFFSET..][..4].copy_from_slice(&attr);
+                EntryV1::from_raw_headers(common_header, headers).map(Self::V1)
+            }
+            AnyEntry::V2(entry) => {
+                let mut common_header = *entry.common_header();
+                common_header[impls_v2::COMMON_HEADER_ATTR_OFFSET..][..4].copy_from_slice(&attr);
+                EntryV2::from_raw_headers(common_header, headers).map(Self::V2)
+            }
+        }
+    }
+}
+
+macro_rules! dispatch_any_entry {
+    ($self:expr, $entry:ident => $call:expr) => {
+        match $self {
+            AnyEntry::V1($entry) => $call,
+            AnyEntry::V2($entry) => $call,
+        }
+    };
+}
+
+impl Entry for AnyEntry {
+    fn magic(&self) -> Magic {
+        dispatch_any_entry!(self, e => e.magic())
+    }
+
+    fn attr(&self) -> Attr {
+        dispatch_any_entry!(self, e => e.attr())
+    }
+
+    fn log_id(&self) -> i64 {
+        dispatch_any_entry!(self, e => e.log_id())
+    }
+
+    fn entry_id(&self) -> i64 {
+        dispatch_any_entry!(self, e => e.entry_id())
+    }
+
+    fn last_confirm_id(&self) -> i64 {
+        dispatch_any_entry!(self, e => e.last_confirm_id())
+    }
+
+    fn timestamp(&self) -> Option<i64> {
+        dispatch_any_entry!(self, e => e.timestamp())
+    }
+
+    fn append_time(&self) -> Option<i64> {
+        dispatch_any_entry!(self, e => e.append_time())
+    }
+
+    fn key(&self) -> &bytes::Bytes {
+        dispatch_any_entry!(self, e => e.key())
+    }
+
+    fn value(&self) -> &bytes::Bytes {
+        dispatch_any_entry!(self, e => e.value())
+    }
+
+    fn headers(&self) -> &[Header] {
+        dispatch_any_entry!(self, e => e.headers())
+    }
+
+    fn get_all_headers<'a, 'k>(&'a self, key: &'k [u8]) -> HeadersByKey<'a, 'k> {
+        dispatch_any_entry!(self, e => e.get_all_headers(key))
+    }
+
+    fn binary_size(&self) -> usize {
+        dispatch_any_entry!(self, e => e.binary_size())
+    }
+
+    fn encode<B: BufMut>(&self, buf: B) -> Result<()> {
+        dispatch_any_entry!(self, e => e.encode(buf))
+    }
+
+    fn decode_without_magic_with_options<B: Buf>(
+        magic: Magic,
+        buf: B,
+        options: &DecodeOptions,
+    ) -> Result<Self> {
+        match magic {
+            Magic::V1 => {
+                EntryV1::decode_without_magic_with_options(magic, buf, options).map(Self::V1)
+            }
+            Magic::V2 | Magic::V3 => {
+                EntryV2::decode_without_magic_with_options(magic, buf, options).map(Self::V2)
+            }
+            // A batch is not an entry, use `EntryBatch::decode` instead.
+            Magic::Batch => Err(Error::InvalidMagic),
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> usize {
+        dispatch_any_entry!(self, e => e.read_at(buf, offset))
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use bytes::{Bytes, BytesMut};
 
+    pub(crate) use super::compression::tests::enabled_compressions;
+
+    /// Returns the binary representation of the entry.
+    pub(crate) fn encode<E: Entry>(entry: &E) -> BytesMut {
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        buf
+    }
+
+    /// Returns a V1 entry with a header and a compressible value, its headers are compressed too.
+    pub(crate) fn entry_v1(compression: Compression) -> EntryV1 {
+        BuilderV1::new()
+            .log_id(1)
+            .entry_id(2)
+            .last_confirm_id(1)
+            .header(Header::new(
+                Bytes::from_static(b"trace"),
+                Bytes::from_static(b"abc"),
+            ))
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![7; 100]))
+            .compression(compression)
+            .compress_headers(true)
+            .build()
+    }
+
     #[test]
     fn test_entry_builder_build() {
         let key = Bytes::from_static(b"key");
@@ -91,6 +304,29 @@ mod tests {
         assert_eq!(entry.headers()[0].value(), header.value());
     }
 
+    #[test]
+    fn test_decode_v1_attr() {
+        // The bits of the attr outside the compression and encryption ones are kept as they are.
+        for attr in [0x20, 0x100, -0x100] {
+            let entry = BuilderV1::new()
+                .log_id(1)
+                .entry_id(2)
+                .attr(Attr::from(attr))
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .build();
+            let buf = encode(&entry);
+            assert_eq!(&buf[1..5], &attr.to_le_bytes());
+
+            let decoded = decode(buf.clone().freeze()).unwrap();
+            assert_eq!(i32::from(decoded.attr()), attr);
+            assert_eq!(decoded.attr().compression().unwrap(), Compression::None);
+            assert!(!decoded.attr().headers_compressed());
+            assert!(!decoded.attr().encrypted());
+            assert_eq!(decoded.value(), &Bytes::from_static(b"value"));
+            assert_eq!(encode(&decoded), buf);
+        }
+    }
+
     #[test]
     #[should_panic(expected = "missing kv field in entry")]
     fn test_entry_builder_build_unwrap_none() {
@@ -232,4 +468,348 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_entry_v2_encode_decode() {
+        let key = Bytes::from_static(b"key");
+        let value = Bytes::from_static(b"value");
+        let header = Header::new(key.clone(), value.clone());
+
+        let entry = BuilderV2::new()
+            .log_id(1)
+            .entry_id(2)
+            .attr(Attr::default())
+            .last_confirm_id(3)
+            .kv(key.clone(), value.clone())
+            .header(header.clone())
+            .build();
+        assert_eq!(entry.magic(), Magic::V2);
+        assert_eq!(
+            entry.binary_size(),
+            1 + 4 + 8 + 8 + 8 + 4 + (key.len() + value.len() + 2) * 2
+        );
+
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), entry.binary_size());
+
+        let decoded_entry = decode(buf.freeze()).unwrap();
+        assert_eq!(decoded_entry.magic(), Magic::V2);
+        assert_eq!(decoded_entry.log_id(), 1);
+        assert_eq!(decoded_entry.entry_id(), 2);
+        assert_eq!(decoded_entry.last_confirm_id(), 3);
+        assert_eq!(decoded_entry.key(), &key);
+        assert_eq!(decoded_entry.value(), &value);
+        assert_eq!(decoded_entry.headers().len(), 1);
+        assert_eq!(decoded_entry.headers()[0].key(), header.key());
+        assert_eq!(decoded_entry.headers()[0].value(), header.value());
+    }
+
+    #[test]
+    fn test_get_header() {
+        // Few headers are scanned, many are indexed, the lookups behave the same.
+        for (compression, count) in compression::tests::enabled_compressions()
+            .into_iter()
+            .flat_map(|compression| [(compression, 3), (compression, 20)])
+        {
+            let mut builder = BuilderV2::new()
+                .compression(compression)
+                .compress_headers(true)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"));
+            for i in 0..count {
+                let key = if i % 3 == 0 {
+                    "route".to_string()
+                } else {
+                    format!("h{}", i)
+                };
+                builder = builder.header(Header::new(Bytes::from(key), Bytes::from(i.to_string())));
+            }
+            let entry = builder.build();
+            let mut buf = BytesMut::new();
+            entry.encode(&mut buf).unwrap();
+            let decoded_entry = decode(buf.freeze()).unwrap();
+
+            for entry in [&AnyEntry::V2(entry), &decoded_entry] {
+                assert_eq!(entry.get_header(b"h1").unwrap().value(), "1");
+                assert_eq!(entry.get_header(b"route").unwrap().value(), "0");
+                assert!(entry.get_header(b"missing").is_none());
+                // The kv is not a header.
+                assert!(entry.get_header(b"key").is_none());
+                let routes: Vec<_> = entry
+                    .get_all_headers(b"route")
+                    .map(|header| header.value().clone())
+                    .collect();
+                let expected: Vec<_> = (0..count).step_by(3).map(|i| i.to_string()).collect();
+                assert_eq!(routes, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_entry_v2_timestamps() {
+        let builder = || {
+            BuilderV2::new()
+                .log_id(1)
+                .entry_id(2)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+        };
+        // The entries without any time keep the V2 layout.
+        let entry = builder().build();
+        assert_eq!(entry.magic(), Magic::V2);
+        assert_eq!(entry.timestamp(), None);
+        assert_eq!(entry.append_time(), None);
+
+        let mut entry = builder().timestamp(1_700_000_000_000).build();
+        assert_eq!(entry.magic(), Magic::V3);
+        entry.set_append_time(1_700_000_000_005);
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        let decoded_entry = decode(buf.freeze()).unwrap();
+        assert_eq!(decoded_entry.magic(), Magic::V3);
+        assert_eq!(decoded_entry.timestamp(), Some(1_700_000_000_000));
+        assert_eq!(decoded_entry.append_time(), Some(1_700_000_000_005));
+
+        // Setting the append time of a V2 entry extends its common header.
+        let mut entry = builder().build();
+        let size = entry.binary_size();
+        entry.set_append_time(1_700_000_000_005);
+        assert_eq!(entry.magic(), Magic::V3);
+        assert_eq!(entry.binary_size(), size + 16);
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        let decoded_entry = decode(buf.freeze()).unwrap();
+        assert_eq!(decoded_entry.timestamp(), None);
+        assert_eq!(decoded_entry.append_time(), Some(1_700_000_000_005));
+        assert_eq!(decoded_entry.value(), &Bytes::from_static(b"value"));
+
+        // V1 has no time fields.
+        let entry = BuilderV1::new()
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build();
+        assert_eq!(entry.timestamp(), None);
+        assert_eq!(entry.append_time(), None);
+    }
+
+    #[test]
+    fn test_entry_v2_checksum_mismatch() {
+        let entry = BuilderV2::new()
+            .log_id(1)
+            .entry_id(2)
+            .last_confirm_id(3)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .header(Header::new(
+                Bytes::from_static(b"key"),
+                Bytes::from_static(b"value"),
+            ))
+            .build();
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+
+        // Flip one bit at every position except the magic and the checksum itself.
+        for i in (1..29).chain(33..buf.len()) {
+            let mut corrupted = buf.clone();
+            corrupted[i] ^= 0x01;
+            match decode(corrupted.freeze()) {
+                Err(Error::ChecksumMismatch { expected, actual }) => {
+                    assert_eq!(expected, entry.checksum());
+                    assert_ne!(expected, actual);
+                }
+                _ => panic!("corruption at {} should be detected", i),
+            }
+        }
+    }
+
+    #[test]
+    fn test_entry_v2_read_at() {
+        let entry = BuilderV2::new()
+            .log_id(1)
+            .entry_id(2)
+            .last_confirm_id(3)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .header(Header::new(
+                Bytes::from_static(b"key"),
+                Bytes::from_static(b"value"),
+            ))
+            .build();
+        let mut encoded = BytesMut::new();
+        entry.encode(&mut encoded).unwrap();
+
+        for step in 1..16 {
+            let mut buf = vec![0; step];
+            let mut all = Vec::new();
+            let mut n = 0;
+            loop {
+                let k = entry.read_at(&mut buf[..], n);
+                if k == 0 {
+                    break;
+                }
+                n += k;
+                all.extend_from_slice(&buf[..k]);
+            }
+            assert_eq!(all, &encoded[..]);
+        }
+    }
+
+    #[test]
+    fn test_entry_compression() {
+        let key = Bytes::from_static(b"key");
+        let value = Bytes::from("{\"key\":\"value\"}".repeat(64));
+        let header = Header::new(key.clone(), value.clone());
+
+        for compression in compression::tests::enabled_compressions() {
+            for compress_headers in [false, true] {
+                let v1 = BuilderV1::new()
+                    .kv(key.clone(), value.clone())
+                    .header(header.clone())
+                    .compression(compression)
+                    .compress_headers(compress_headers)
+                    .build();
+                let v2 = BuilderV2::new()
+                    .kv(key.clone(), value.clone())
+                    .header(header.clone())
+                    .compression(compression)
+                    .compress_headers(compress_headers)
+                    .build();
+                assert_compressed_entry(&v1, compression, compress_headers);
+                assert_compressed_entry(&v2, compression, compress_headers);
+            }
+        }
+    }
+
+    fn assert_compressed_entry<E: Entry>(
+        entry: &E,
+        compression: Compression,
+        compress_headers: bool,
+    ) {
+        let key = Bytes::from_static(b"key");
+        let value = Bytes::from("{\"key\":\"value\"}".repeat(64));
+        let plain_size = entry.headers()[0].binary_size() + entry.key().len() + value.len();
+
+        assert_eq!(entry.attr().compression().unwrap(), compression);
+        assert_eq!(entry.attr().headers_compressed(), compress_headers);
+        assert_eq!(entry.key(), &key);
+        assert_eq!(entry.value(), &value);
+        assert_eq!(entry.headers()[0].value(), &value);
+        if compression != Compression::None {
+            // The binary size is the compressed on-disk size.
+            assert!(entry.binary_size() < plain_size);
+        }
+
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        assert_eq!(buf.len(), entry.binary_size());
+        let mut read = vec![0; entry.binary_size()];
+        assert_eq!(entry.read_at(&mut read, 0), entry.binary_size());
+        assert_eq!(&read[..], &buf[..]);
+
+        let decoded_entry = decode(buf.freeze()).unwrap();
+        assert_eq!(decoded_entry.attr(), entry.attr());
+        assert_eq!(decoded_entry.key(), &key);
+        assert_eq!(decoded_entry.value(), &value);
+        assert_eq!(decoded_entry.headers().len(), 1);
+        assert_eq!(decoded_entry.headers()[0].value(), &value);
+        assert_eq!(decoded_entry.binary_size(), entry.binary_size());
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let key = Bytes::from_static(b"key");
+        let value = Bytes::from_static(b"value");
+        let entry = BuilderV1::new()
+            .log_id(1)
+            .kv(key.clone(), value.clone())
+            .header(Header::new(key.clone(), value.clone()))
+            .build();
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+
+        // The prefix ending at a header boundary is still a valid V1 entry,
+        // the framing can't tell it's truncated, `Magic::V2` detects it by the checksum.
+        let boundary = buf.len() - (key.len() + value.len() + 2);
+        for i in 0..buf.len() {
+            assert_eq!(
+                decode(&buf[..i]).is_ok(),
+                i == boundary,
+                "prefix of {} bytes",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let mut buf = BytesMut::new();
+        BuilderV1::new()
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build()
+            .encode(&mut buf)
+            .unwrap();
+        let common_header = buf.split_to(impls_v1::COMMON_HEADER_BINARY_SIZE);
+
+        // The length delimiter of the first header never ends.
+        let mut malformed = common_header.clone();
+        malformed.extend_from_slice(&[0xff; 11]);
+        assert!(matches!(
+            decode(&malformed[..]),
+            Err(Error::Malformed { offset: 29, .. })
+        ));
+
+        // The key length exceeds the header.
+        let mut malformed = common_header.clone();
+        malformed.extend_from_slice(&[2, 5, b'k']);
+        assert!(matches!(
+            decode(&malformed[..]),
+            Err(Error::Malformed { offset: 30, .. })
+        ));
+        assert!(matches!(
+            Header::decode(&[5, b'k'][..]),
+            Err(Error::Malformed { offset: 0, .. })
+        ));
+    }
+
+    /// Mutate every byte of encoded entries, decoding must fail or succeed but never panic.
+    #[test]
+    fn test_decode_mutated() {
+        let mut entries = Vec::new();
+        for compression in compression::tests::enabled_compressions() {
+            let mut buf = BytesMut::new();
+            BuilderV1::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .header(Header::new(
+                    Bytes::from_static(b"k"),
+                    Bytes::from_static(b"v"),
+                ))
+                .compression(compression)
+                .compress_headers(true)
+                .build()
+                .encode(&mut buf)
+                .unwrap();
+            entries.push(buf);
+        }
+        let mut batch = BytesMut::new();
+        BatchBuilder::new()
+            .entry(
+                0,
+                Bytes::from_static(b"key"),
+                Bytes::from_static(b"value"),
+                vec![],
+            )
+            .build()
+            .encode(&mut batch)
+            .unwrap();
+        entries.push(batch);
+
+        for buf in entries {
+            for i in 0..buf.len() {
+                for value in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+                    let mut mutated = buf.clone();
+                    mutated[i] = value;
+                    let _ = decode(&mutated[..]);
+                    let _ = EntryBatch::decode(&mutated[..]);
+                    let _ = EntryRef::new(&mutated[..]);
+                    let _ = Header::decode(&mutated[i..]);
+                }
+            }
+        }
+    }
 }
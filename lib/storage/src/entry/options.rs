@@ -0,0 +1,208 @@
+/// The `DecodeOptions` limits what a decoded entry may hold.
+///
+/// The limits are checked against the lengths read from the buffer before anything is allocated,
+/// so a server can reject an oversized write from an untrusted peer early.
+/// The default options only enforce the limits of the format itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// The maximum binary size of an entry or a batch.
+    pub max_entry_size: usize,
+    /// The maximum number of user headers of an entry or a batch record, the kv is excluded.
+    pub max_headers: usize,
+    /// The maximum length of a header key, including the key of the kv.
+    pub max_key_len: usize,
+    /// The maximum length of a header value after decompression, including the value of the kv.
+    pub max_value_len: usize,
+    /// The maximum nesting depth, an entry holds headers (depth 1),
+    /// a batch holds records which hold headers (depth 2).
+    pub max_nesting_depth: usize,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            // The entries are framed by a u32 length in segments and journals.
+            max_entry_size: u32::MAX as usize,
+            max_headers: usize::MAX,
+            max_key_len: usize::MAX,
+            max_value_len: usize::MAX,
+            max_nesting_depth: 2,
+        }
+    }
+}
+
+impl DecodeOptions {
+    /// Returns an error if the entry size exceeds the limit.
+    pub(super) fn check_entry_size(&self, size: usize) -> super::Result<()> {
+        if size > self.max_entry_size {
+            return Err(super::Error::EntryTooLarge {
+                size,
+                max: self.max_entry_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns an error if the nesting depth exceeds the limit.
+    pub(super) fn check_nesting_depth(&self, depth: usize) -> super::Result<()> {
+        if depth > self.max_nesting_depth {
+            return Err(super::Error::NestingTooDeep {
+                depth,
+                max: self.max_nesting_depth,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::impls_v1::COMMON_HEADER_BINARY_SIZE;
+    use crate::entry::tests::{enabled_compressions, encode};
+    use crate::entry::{decode_with_options, BatchBuilder, BuilderV1, BuilderV2, Compression};
+    use crate::entry::{Entry, EntryBatch, EntryV1, Error, Header, Magic};
+    use bytes::{Bytes, BytesMut};
+
+    fn new_entry(headers: usize, compression: Compression) -> BytesMut {
+        let mut builder = BuilderV2::new()
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![b'v'; 1024]))
+            .compression(compression);
+        for _ in 0..headers {
+            builder = builder.header(Header::new(
+                Bytes::from_static(b"k"),
+                Bytes::from_static(b"v"),
+            ));
+        }
+        encode(&builder.build())
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let buf = new_entry(2, Compression::None);
+        let size = buf.len();
+        let options = DecodeOptions {
+            max_entry_size: size,
+            max_headers: 2,
+            max_key_len: 3,
+            max_value_len: 1024,
+            max_nesting_depth: 1,
+        };
+        assert!(decode_with_options(&buf[..], &options).is_ok());
+
+        let check = |options: DecodeOptions, expected: Error| {
+            let err = decode_with_options(&buf[..], &options).err().unwrap();
+            assert_eq!(err.to_string(), expected.to_string());
+        };
+        check(
+            DecodeOptions {
+                max_entry_size: size - 1,
+                ..options
+            },
+            Error::EntryTooLarge {
+                size,
+                max: size - 1,
+            },
+        );
+        check(
+            DecodeOptions {
+                max_headers: 1,
+                ..options
+            },
+            Error::TooManyHeaders { count: 2, max: 1 },
+        );
+        check(
+            DecodeOptions {
+                max_key_len: 2,
+                ..options
+            },
+            Error::KeyTooLong { len: 3, max: 2 },
+        );
+        check(
+            DecodeOptions {
+                max_value_len: 1023,
+                ..options
+            },
+            Error::ValueTooLong {
+                len: 1024,
+                max: 1023,
+            },
+        );
+        check(
+            DecodeOptions {
+                max_nesting_depth: 0,
+                ..options
+            },
+            Error::NestingTooDeep { depth: 1, max: 0 },
+        );
+    }
+
+    #[test]
+    fn test_decode_limits_decompressed() {
+        // The value compresses well, the limit applies to the decompressed value.
+        for compression in enabled_compressions() {
+            let buf = new_entry(0, compression);
+            let options = DecodeOptions {
+                max_value_len: 1024,
+                ..Default::default()
+            };
+            assert!(decode_with_options(&buf[..], &options).is_ok());
+            let options = DecodeOptions {
+                max_value_len: 1000,
+                ..Default::default()
+            };
+            assert!(matches!(
+                decode_with_options(&buf[..], &options),
+                Err(Error::ValueTooLong { max: 1000, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decode_limits_rejected_before_allocating() {
+        // The header claims a key of 4 GiB.
+        let mut buf = encode(
+            &BuilderV1::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .build(),
+        );
+        buf.truncate(COMMON_HEADER_BINARY_SIZE);
+        let mut header = Vec::new();
+        prost::encode_length_delimiter(u32::MAX as usize, &mut header).unwrap();
+        let mut delimited = Vec::new();
+        prost::encode_length_delimiter(header.len(), &mut delimited).unwrap();
+        buf.extend_from_slice(&delimited);
+        buf.extend_from_slice(&header);
+        let options = DecodeOptions {
+            max_key_len: 1024,
+            ..Default::default()
+        };
+        assert!(matches!(
+            EntryV1::decode_without_magic_with_options(Magic::V1, &buf[1..], &options),
+            Err(Error::KeyTooLong { max: 1024, .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_batch_limits() {
+        let batch = BatchBuilder::new()
+            .entry(
+                0,
+                Bytes::from_static(b"key"),
+                Bytes::from_static(b"value"),
+                vec![],
+            )
+            .build();
+        let mut buf = BytesMut::new();
+        batch.encode(&mut buf).unwrap();
+        assert!(EntryBatch::decode_with_options(&buf[..], &DecodeOptions::default()).is_ok());
+        let options = DecodeOptions {
+            max_nesting_depth: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            EntryBatch::decode_with_options(&buf[..], &options),
+            Err(Error::NestingTooDeep { depth: 2, max: 1 })
+        ));
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use super::{Compression, Error, Header, Result};
+
+/// The prefix of the reserved header keys, the headers of the system features use it
+/// and the user headers can't, unless they're well-formed system headers.
+/// It's enforced by `try_build` of the builders, `build` accepts the keys used before.
+pub const RESERVED_KEY_PREFIX: &[u8] = b"__";
+
+/// The keys of the registered system headers, see `SystemHeader`.
+pub const SYSTEM_HEADER_KEYS: [&str; 6] = [
+    ContentType::KEY,
+    TraceId::KEY,
+    ProducerId::KEY,
+    Sequence::KEY,
+    SchemaId::KEY,
+    PayloadCompression::KEY,
+];
+
+/// The `SystemHeader` is a well-known header with a reserved key and a typed value.
+///
+/// A system header is attached like any other header with `to_header`,
+/// and read from an entry with `Entry::header`. It's single-valued.
+pub trait SystemHeader: Sized {
+    /// The reserved key of the header.
+    const KEY: &'static str;
+
+    /// Encode the value of the header.
+    fn encode_value(&self) -> Bytes;
+
+    /// Decode the value of the header.
+    /// Fails with `Error::InvalidSystemHeader` if the value is malformed.
+    fn decode_value(value: &[u8]) -> Result<Self>;
+
+    /// Returns the header carrying the value.
+    fn to_header(&self) -> Header {
+        Header::new(
+            Bytes::from_static(Self::KEY.as_bytes()),
+            self.encode_value(),
+        )
+    }
+}
+
+/// Returns whether the header key is reserved.
+pub fn is_reserved_key(key: &[u8]) -> bool {
+    key.starts_with(RESERVED_KEY_PREFIX)
+}
+
+/// Validate the headers given by the user: a reserved key is only allowed for a registered
+/// system header with a well-formed value, and at most once.
+pub fn validate_headers(headers: &[Header]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for header in headers {
+        if !is_reserved_key(header.key()) {
+            continue;
+        }
+        let Some(key) = SYSTEM_HEADER_KEYS
+            .into_iter()
+            .find(|key| key.as_bytes() == &header.key()[..])
+        else {
+            return Err(Error::ReservedHeaderKey(header.key().clone()));
+        };
+        if !seen.insert(key) {
+            return Err(Error::DuplicatedSystemHeader(key));
+        }
+        check_value(key, header.value())?;
+    }
+    Ok(())
+}
+
+// Decode the value of the registered system header to check it.
+fn check_value(key: &str, value: &[u8]) -> Result<()> {
+    match key {
+        ContentType::KEY => ContentType::decode_value(value).map(drop),
+        TraceId::KEY => TraceId::decode_value(value).map(drop),
+        ProducerId::KEY => ProducerId::decode_value(value).map(drop),
+        Sequence::KEY => Sequence::decode_value(value).map(drop),
+        SchemaId::KEY => SchemaId::decode_value(value).map(drop),
+        PayloadCompression::KEY => PayloadCompression::decode_value(value).map(drop),
+        _ => unreachable!("unregistered system header {}", key),
+    }
+}
+
+/// The `ContentType` is the media type of the value, such as `application/json`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType(pub String);
+
+impl SystemHeader for ContentType {
+    const KEY: &'static str = "__content_type";
+
+    fn encode_value(&self) -> Bytes {
+        Bytes::copy_from_slice(self.0.as_bytes())
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self> {
+        std::str::from_utf8(value)
+            .map(|content_type| Self(content_type.to_string()))
+            .map_err(|_| Error::InvalidSystemHeader(Self::KEY))
+    }
+}
+
+/// The `TraceId` is the 16 bytes id of the distributed trace which produced the entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceId(pub [u8; 16]);
+
+impl SystemHeader for TraceId {
+    const KEY: &'static str = "__trace_id";
+
+    fn encode_value(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.0)
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self> {
+        value
+            .try_into()
+            .map(Self)
+            .map_err(|_| Error::InvalidSystemHeader(Self::KEY))
+    }
+}
+
+/// The `ProducerId` identifies the producer of the entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProducerId(pub u64);
+
+impl SystemHeader for ProducerId {
+    const KEY: &'static str = "__producer_id";
+
+    fn encode_value(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.0.to_le_bytes())
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self> {
+        decode_u64(value, Self::KEY).map(Self)
+    }
+}
+
+/// The `Sequence` is the sequence number of the entry among the entries of its producer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequence(pub u64);
+
+impl SystemHeader for Sequence {
+    const KEY: &'static str = "__sequence";
+
+    fn encode_value(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.0.to_le_bytes())
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self> {
+        decode_u64(value, Self::KEY).map(Self)
+    }
+}
+
+/// The `SchemaId` is the id of the schema of the value in a schema registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaId(pub u32);
+
+impl SystemHeader for SchemaId {
+    const KEY: &'static str = "__schema_id";
+
+    fn encode_value(&self) -> Bytes {
+        Bytes::copy_from_slice(&self.0.to_le_bytes())
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self> {
+        value
+            .try_into()
+            .map(|value| Self(u32::from_le_bytes(value)))
+            .map_err(|_| Error::InvalidSystemHeader(Self::KEY))
+    }
+}
+
+/// The `PayloadCompression` is the codec the producer compressed the value with.
+///
+/// Unlike the compression of the attr, the storage never decompresses the value,
+/// it's left to the consumer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadCompression(pub Compression);
+
+impl SystemHeader for PayloadCompression {
+    const KEY: &'static str = "__compression";
+
+    fn encode_value(&self) -> Bytes {
+        Bytes::copy_from_slice(&[self.0.into()])
+    }
+
+    fn decode_value(value: &[u8]) -> Result<Self> {
+        match value {
+            [codec] => Compression::try_from(*codec)
+                .map(Self)
+                .map_err(|_| Error::InvalidSystemHeader(Self::KEY)),
+            _ => Err(Error::InvalidSystemHeader(Self::KEY)),
+        }
+    }
+}
+
+fn decode_u64(value: &[u8], key: &'static str) -> Result<u64> {
+    value
+        .try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| Error::InvalidSystemHeader(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KEY_SIZE;
+    use crate::entry::{decode, AnyEntry, BuilderV1, BuilderV2, Cipher, Entry, KEY_ID_HEADER_KEY};
+    use crate::replication::DIGEST_HEADER_KEY;
+    use bytes::BytesMut;
+
+    fn user_header(key: &'static [u8], value: &'static [u8]) -> Header {
+        Header::new(Bytes::from_static(key), Bytes::from_static(value))
+    }
+
+    #[test]
+    fn test_system_headers() {
+        let trace_id = TraceId([7; 16]);
+        let entry = BuilderV2::new()
+            .kv(Bytes::from_static(b"__key"), Bytes::from_static(b"value"))
+            .header(ContentType("application/json".to_string()).to_header())
+            .header(trace_id.to_header())
+            .header(ProducerId(3).to_header())
+            .header(Sequence(u64::MAX).to_header())
+            .header(SchemaId(42).to_header())
+            .header(PayloadCompression(Compression::Zstd).to_header())
+            .header(user_header(b"tenant", b"a"))
+            .build();
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        let entry = decode(buf.freeze()).unwrap();
+
+        assert_eq!(
+            entry.header::<ContentType>().unwrap(),
+            Some(ContentType("application/json".to_string()))
+        );
+        assert_eq!(entry.header::<TraceId>().unwrap(), Some(trace_id));
+        assert_eq!(entry.header::<ProducerId>().unwrap(), Some(ProducerId(3)));
+        assert_eq!(
+            entry.header::<Sequence>().unwrap(),
+            Some(Sequence(u64::MAX))
+        );
+        assert_eq!(entry.header::<SchemaId>().unwrap(), Some(SchemaId(42)));
+        assert_eq!(
+            entry.header::<PayloadCompression>().unwrap(),
+            Some(PayloadCompression(Compression::Zstd))
+        );
+
+        let entry = BuilderV1::new()
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build();
+        assert_eq!(entry.header::<TraceId>().unwrap(), None);
+
+        // The values of an encrypted entry can't be decoded before it's decrypted.
+        let cipher = Cipher::new(&[1; KEY_SIZE]);
+        let plain = AnyEntry::V2(
+            BuilderV2::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .header(SchemaId(42).to_header())
+                .build(),
+        );
+        let encrypted = cipher.encrypt(&plain).unwrap();
+        assert!(matches!(
+            encrypted.header::<SchemaId>(),
+            Err(Error::EncryptedSystemHeader("__schema_id"))
+        ));
+        assert_eq!(encrypted.header::<TraceId>().unwrap(), None);
+        assert_eq!(
+            cipher
+                .decrypt(&encrypted)
+                .unwrap()
+                .header::<SchemaId>()
+                .unwrap(),
+            Some(SchemaId(42))
+        );
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        // The keys of the system features are reserved too.
+        for key in [DIGEST_HEADER_KEY, KEY_ID_HEADER_KEY, b"__other"] {
+            assert!(is_reserved_key(key));
+            assert!(matches!(
+                validate_headers(&[user_header(key, b"value")]),
+                Err(Error::ReservedHeaderKey(k)) if k == key
+            ));
+        }
+        for key in SYSTEM_HEADER_KEYS {
+            assert!(is_reserved_key(key.as_bytes()));
+        }
+        assert!(!is_reserved_key(b"_trace_id"));
+
+        assert!(matches!(
+            validate_headers(&[user_header(b"__trace_id", b"short")]),
+            Err(Error::InvalidSystemHeader("__trace_id"))
+        ));
+        assert!(matches!(
+            validate_headers(&[user_header(b"__compression", b"\x09")]),
+            Err(Error::InvalidSystemHeader("__compression"))
+        ));
+        assert!(matches!(
+            validate_headers(&[SchemaId(1).to_header(), SchemaId(2).to_header()]),
+            Err(Error::DuplicatedSystemHeader("__schema_id"))
+        ));
+        validate_headers(&[
+            SchemaId(1).to_header(),
+            user_header(b"tenant", b"a"),
+            user_header(b"tenant", b"b"),
+        ])
+        .unwrap();
+
+        // The builders reject the invalid headers.
+        assert!(matches!(
+            BuilderV1::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .header(user_header(b"__digest", b"forged"))
+                .try_build(),
+            Err(Error::ReservedHeaderKey(_))
+        ));
+        assert!(matches!(
+            BuilderV2::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .header(user_header(b"__sequence", b""))
+                .try_build(),
+            Err(Error::InvalidSystemHeader("__sequence"))
+        ));
+
+        // Only `try_build` validates, `build` keeps accepting the keys used before the system headers.
+        let entry = BuilderV2::new()
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .header(user_header(b"__legacy", b"value"))
+            .build();
+        assert_eq!(entry.headers()[0].key(), &Bytes::from_static(b"__legacy"));
+    }
+}
@@ -1,9 +1,62 @@
-use super::Error;
+use bytes::Buf;
+
+use super::{DecodeOptions, Error, Header, Result};
 
 /// The `Attr` is used to identify the type of the entry.
+///
+/// The lowest 5 bits hold the compression and the encryption of the entry, for all versions.
+/// They were reserved in the V1 attr and always written as zero, so the V1 entries written
+/// before keep decoding unchanged. The other bits are left to the producers.
 #[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
 pub struct Attr(i32);
 
+// The lowest 3 bits of the attr hold the compression codec of the payload.
+const ATTR_COMPRESSION_MASK: i32 = 0x07;
+// Whether the user headers' values are compressed too.
+const ATTR_HEADERS_COMPRESSED: i32 = 0x08;
+// Whether the values are encrypted, see `Cipher`.
+const ATTR_ENCRYPTED: i32 = 0x10;
+
+impl Attr {
+    /// Returns the compression codec of the entry payload.
+    pub fn compression(&self) -> super::Result<Compression> {
+        Compression::try_from((self.0 & ATTR_COMPRESSION_MASK) as u8)
+    }
+
+    /// Returns a new attr with the compression codec set.
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self((self.0 & !ATTR_COMPRESSION_MASK) | u8::from(compression) as i32)
+    }
+
+    /// Returns whether the user headers' values are compressed.
+    pub fn headers_compressed(&self) -> bool {
+        self.0 & ATTR_HEADERS_COMPRESSED != 0
+    }
+
+    /// Returns a new attr with the headers compressed flag set.
+    pub fn with_headers_compressed(self, compressed: bool) -> Self {
+        if compressed {
+            Self(self.0 | ATTR_HEADERS_COMPRESSED)
+        } else {
+            Self(self.0 & !ATTR_HEADERS_COMPRESSED)
+        }
+    }
+
+    /// Returns whether the values of the user headers and the kv are encrypted.
+    pub fn encrypted(&self) -> bool {
+        self.0 & ATTR_ENCRYPTED != 0
+    }
+
+    /// Returns a new attr with the encrypted flag set.
+    pub fn with_encrypted(self, encrypted: bool) -> Self {
+        if encrypted {
+            Self(self.0 | ATTR_ENCRYPTED)
+        } else {
+            Self(self.0 & !ATTR_ENCRYPTED)
+        }
+    }
+}
+
 impl From<i32> for Attr {
     fn from(attr: i32) -> Self {
         Self(attr)
@@ -22,6 +75,11 @@ impl From<Attr> for i32 {
 #[repr(u8)]
 pub enum Magic {
     V1 = 0x01,
+    V2 = 0x02,
+    // The V2 entry with the producer timestamp and the append time, see `EntryV2`.
+    V3 = 0x03,
+    // The batch of entries, see `EntryBatch`.
+    Batch = 0x81,
 }
 
 impl TryFrom<u8> for Magic {
@@ -30,6 +88,9 @@ impl TryFrom<u8> for Magic {
     fn try_from(value: u8) -> super::Result<Self> {
         match value {
             0x01 => Ok(Self::V1),
+            0x02 => Ok(Self::V2),
+            0x03 => Ok(Self::V3),
+            0x81 => Ok(Self::Batch),
             _ => Err(Error::InvalidMagic),
         }
     }
@@ -39,8 +100,95 @@ impl From<Magic> for u8 {
     fn from(magic: Magic) -> Self {
         match magic {
             Magic::V1 => 0x01,
+            Magic::V2 => 0x02,
+            Magic::V3 => 0x03,
+            Magic::Batch => 0x81,
+        }
+    }
+}
+
+/// The `Compression` is the codec used to compress the payload of the entry.
+/// Each codec is only available when the corresponding cargo feature is enabled.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    None = 0x00,
+    Lz4 = 0x01,
+    Zstd = 0x02,
+    Snappy = 0x03,
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = super::Error;
+
+    fn try_from(value: u8) -> super::Result<Self> {
+        match value {
+            0x00 => Ok(Self::None),
+            0x01 => Ok(Self::Lz4),
+            0x02 => Ok(Self::Zstd),
+            0x03 => Ok(Self::Snappy),
+            _ => Err(Error::InvalidCompression(value)),
+        }
+    }
+}
+
+impl From<Compression> for u8 {
+    fn from(compression: Compression) -> Self {
+        compression as u8
+    }
+}
+
+/// Decode a length delimiter, `offset` is the position of the delimiter in the entry.
+pub(super) fn decode_length_delimiter<B: Buf>(buf: &mut B, offset: usize) -> Result<usize> {
+    prost::decode_length_delimiter(buf).map_err(|_| Error::Malformed {
+        offset,
+        reason: "invalid length delimiter",
+    })
+}
+
+/// Decode the length delimited headers until the buffer is exhausted,
+/// `offset` is the position of the buffer in the entry.
+pub(super) fn decode_headers<B: Buf>(
+    mut buf: B,
+    offset: usize,
+    options: &DecodeOptions,
+) -> Result<Vec<Header>> {
+    let size = buf.remaining();
+    let mut headers = Vec::new();
+    while buf.has_remaining() {
+        // The kv is not counted as a user header.
+        if headers.len() > options.max_headers {
+            return Err(Error::TooManyHeaders {
+                count: headers.len(),
+                max: options.max_headers,
+            });
+        }
+        // Decode the length of the header from the buffer
+        let delimiter_offset = offset + size - buf.remaining();
+        let length = decode_length_delimiter(&mut buf, delimiter_offset)?;
+        if buf.remaining() < length {
+            return Err(Error::DecodeBufNotEnough);
+        }
+        let header_offset = offset + size - buf.remaining();
+        let mut header_buf = buf.take(length);
+        headers.push(Header::decode_at(&mut header_buf, header_offset, options)?);
+        buf = header_buf.into_inner();
+    }
+    Ok(headers)
+}
+
+/// Returns an error if any value of the decoded (and decompressed) headers exceeds the limit.
+pub(super) fn check_value_len(headers: &[Header], options: &DecodeOptions) -> Result<()> {
+    for header in headers {
+        if header.value().len() > options.max_value_len {
+            return Err(Error::ValueTooLong {
+                len: header.value().len(),
+                max: options.max_value_len,
+            });
         }
     }
+    Ok(())
 }
 
 macro_rules! copy_slice_with_multi_stage {
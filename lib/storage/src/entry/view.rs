@@ -0,0 +1,332 @@
+use bytes::Buf;
+
+use super::impls_v1;
+use super::impls_v2;
+use super::util::decode_length_delimiter;
+use super::Attr;
+use super::Error;
+use super::Magic;
+use super::Result;
+use super::NO_TIMESTAMP;
+
+/// The `EntryRef` is a zero-copy view over an encoded entry.
+///
+/// The framing is validated once when the view is created (and the checksum is verified for `Magic::V2` and `Magic::V3`),
+/// the fixed fields are read straight from the common header,
+/// the key, value and headers are borrowed from the underlying buffer and parsed lazily.
+/// The value and headers are returned in their on-disk form, so they are compressed if the attr says so.
+#[derive(Clone, Copy)]
+pub struct EntryRef<'a> {
+    buf: &'a [u8],
+    magic: Magic,
+    // Offset of the first length delimited header.
+    body_offset: usize,
+    // Offset of the kv header (without its length delimiter) and its size.
+    kv_offset: usize,
+    kv_size: usize,
+}
+
+impl<'a> EntryRef<'a> {
+    /// Create a view over the whole buffer, which must hold exactly one encoded entry.
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        if buf.is_empty() {
+            return Err(Error::DecodeBufNotEnough);
+        }
+        let magic = Magic::try_from(buf[impls_v1::COMMON_HEADER_MAGIC_OFFSET])?;
+        let (body_offset, checksum_offset) = match magic {
+            Magic::V1 => (impls_v1::COMMON_HEADER_BINARY_SIZE, None),
+            Magic::V2 | Magic::V3 => {
+                let (size, checksum_offset) = impls_v2::common_header_layout(magic);
+                (size, Some(checksum_offset))
+            }
+            Magic::Batch => return Err(Error::InvalidMagic),
+        };
+        if buf.len() < body_offset {
+            return Err(Error::DecodeBufNotEnough);
+        }
+        if let Some(checksum_offset) = checksum_offset {
+            let expected =
+                u32::from_le_bytes(buf[checksum_offset..body_offset].try_into().unwrap());
+            let actual =
+                crc32c::crc32c_append(crc32c::crc32c(&buf[..checksum_offset]), &buf[body_offset..]);
+            if expected != actual {
+                return Err(Error::ChecksumMismatch { expected, actual });
+            }
+        }
+
+        // Walk through the length delimited headers, the last one is the kv.
+        let mut kv = None;
+        let mut offset = body_offset;
+        while offset < buf.len() {
+            let (header_offset, header_size) = next_header(buf, offset)?;
+            kv = Some((header_offset, header_size));
+            offset = header_offset + header_size;
+        }
+        let (kv_offset, kv_size) = kv.ok_or(Error::KVNotFound)?;
+        Ok(Self {
+            buf,
+            magic,
+            body_offset,
+            kv_offset,
+            kv_size,
+        })
+    }
+
+    /// Returns the magic of the entry.
+    pub fn magic(&self) -> Magic {
+        self.magic
+    }
+
+    /// Returns the attribute of the entry.
+    pub fn attr(&self) -> Attr {
+        Attr::from(i32::from_le_bytes(
+            self.buf[impls_v1::COMMON_HEADER_ATTR_OFFSET..impls_v1::COMMON_HEADER_ATTR_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        ))
+    }
+
+    /// Returns the log ID of the entry.
+    pub fn log_id(&self) -> i64 {
+        self.get_i64(impls_v1::COMMON_HEADER_LOG_ID_OFFSET)
+    }
+
+    /// Returns the entry ID of the entry.
+    pub fn entry_id(&self) -> i64 {
+        self.get_i64(impls_v1::COMMON_HEADER_ENTRY_ID_OFFSET)
+    }
+
+    /// Returns the last confirm id of the entry.
+    pub fn last_confirm_id(&self) -> i64 {
+        self.get_i64(impls_v1::COMMON_HEADER_LAC_ID_OFFSET)
+    }
+
+    /// Returns the timestamp set by the producer, only `Magic::V3` has one.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.get_time(impls_v2::COMMON_HEADER_TIMESTAMP_OFFSET)
+    }
+
+    /// Returns the time the entry was appended if it was recorded, only `Magic::V3` has one.
+    pub fn append_time(&self) -> Option<i64> {
+        self.get_time(impls_v2::COMMON_HEADER_APPEND_TIME_OFFSET)
+    }
+
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &'a [u8] {
+        HeaderRef::parse(&self.buf[self.kv_offset..self.kv_offset + self.kv_size]).key()
+    }
+
+    /// Returns the value of the entry in its on-disk form.
+    pub fn value(&self) -> &'a [u8] {
+        HeaderRef::parse(&self.buf[self.kv_offset..self.kv_offset + self.kv_size]).value()
+    }
+
+    /// Returns an iterator over the user headers of the entry.
+    pub fn headers(&self) -> HeaderRefIter<'a> {
+        HeaderRefIter {
+            buf: self.buf,
+            offset: self.body_offset,
+            end: self.kv_offset - prost::length_delimiter_len(self.kv_size),
+        }
+    }
+
+    /// Get the binary size of the entry.
+    pub fn binary_size(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns the binary representation of the entry.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    fn get_i64(&self, offset: usize) -> i64 {
+        i64::from_le_bytes(self.buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn get_time(&self, offset: usize) -> Option<i64> {
+        if self.magic != Magic::V3 {
+            return None;
+        }
+        Some(self.get_i64(offset)).filter(|time| *time != NO_TIMESTAMP)
+    }
+}
+
+/// Decode the length delimiter at offset, returns the offset and the size of the header after it.
+fn next_header(buf: &[u8], offset: usize) -> Result<(usize, usize)> {
+    let mut delimited = &buf[offset..];
+    let header_size = decode_length_delimiter(&mut delimited, offset)?;
+    let header_offset = buf.len() - delimited.remaining();
+    if delimited.remaining() < header_size {
+        return Err(Error::DecodeBufNotEnough);
+    }
+    // Validate the key length of the header, so the accessors never fail.
+    let mut header = &delimited[..header_size];
+    let key_len = decode_length_delimiter(&mut header, header_offset)?;
+    if header.remaining() < key_len {
+        return Err(Error::Malformed {
+            offset: header_offset,
+            reason: "key length exceeds the header",
+        });
+    }
+    Ok((header_offset, header_size))
+}
+
+/// The `HeaderRef` is a zero-copy view over an encoded `Header`.
+#[derive(Clone, Copy)]
+pub struct HeaderRef<'a> {
+    key: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> HeaderRef<'a> {
+    // The header must be validated by `next_header` before.
+    fn parse(mut buf: &'a [u8]) -> Self {
+        let key_len = prost::decode_length_delimiter(&mut buf).expect("validated header");
+        let (key, value) = buf.split_at(key_len);
+        Self { key, value }
+    }
+
+    /// Getter for key
+    pub fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    /// Getter for value
+    pub fn value(&self) -> &'a [u8] {
+        self.value
+    }
+}
+
+/// The `HeaderRefIter` iterates the user headers of an `EntryRef`.
+pub struct HeaderRefIter<'a> {
+    buf: &'a [u8],
+    offset: usize,
+    end: usize,
+}
+
+impl<'a> Iterator for HeaderRefIter<'a> {
+    type Item = HeaderRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+        let mut delimited = &self.buf[self.offset..self.end];
+        let header_size = prost::decode_length_delimiter(&mut delimited).expect("validated header");
+        let header_offset = self.end - delimited.remaining();
+        self.offset = header_offset + header_size;
+        Some(HeaderRef::parse(
+            &self.buf[header_offset..header_offset + header_size],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::tests::encode;
+    use crate::entry::{BuilderV1, BuilderV2, Header};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_entry_ref() {
+        let v1 = encode(
+            &BuilderV1::new()
+                .log_id(1)
+                .entry_id(2)
+                .last_confirm_id(3)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .header(Header::new(
+                    Bytes::from_static(b"h1"),
+                    Bytes::from_static(b"v1"),
+                ))
+                .header(Header::new(
+                    Bytes::from_static(b"h2"),
+                    Bytes::from_static(b"v2"),
+                ))
+                .build(),
+        );
+        let v2 = encode(
+            &BuilderV2::new()
+                .log_id(1)
+                .entry_id(2)
+                .last_confirm_id(3)
+                .timestamp(1_700_000_000_000)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .header(Header::new(
+                    Bytes::from_static(b"h1"),
+                    Bytes::from_static(b"v1"),
+                ))
+                .header(Header::new(
+                    Bytes::from_static(b"h2"),
+                    Bytes::from_static(b"v2"),
+                ))
+                .build(),
+        );
+
+        for (buf, magic) in [(&v1, Magic::V1), (&v2, Magic::V3)] {
+            let entry = EntryRef::new(buf).unwrap();
+            assert_eq!(entry.magic(), magic);
+            assert_eq!(entry.attr(), Attr::default());
+            assert_eq!(entry.log_id(), 1);
+            assert_eq!(entry.entry_id(), 2);
+            assert_eq!(entry.last_confirm_id(), 3);
+            let timestamp = (magic == Magic::V3).then_some(1_700_000_000_000);
+            assert_eq!(entry.timestamp(), timestamp);
+            assert_eq!(entry.append_time(), None);
+            assert_eq!(entry.key(), b"key");
+            assert_eq!(entry.value(), b"value");
+            assert_eq!(entry.binary_size(), buf.len());
+            let headers: Vec<_> = entry.headers().map(|h| (h.key(), h.value())).collect();
+            assert_eq!(
+                headers,
+                vec![(&b"h1"[..], &b"v1"[..]), (&b"h2"[..], &b"v2"[..])]
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "snappy")]
+    fn test_entry_ref_compressed() {
+        let value = Bytes::from("value".repeat(64));
+        let buf = encode(
+            &BuilderV2::new()
+                .kv(Bytes::from_static(b"key"), value.clone())
+                .compression(crate::entry::Compression::Snappy)
+                .build(),
+        );
+        let entry = EntryRef::new(&buf).unwrap();
+        assert_eq!(entry.key(), b"key");
+        assert_ne!(entry.value(), &value[..]);
+        assert_eq!(
+            crate::entry::decompress(entry.attr().compression().unwrap(), entry.value()).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn test_entry_ref_invalid() {
+        let buf = encode(
+            &BuilderV1::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .build(),
+        );
+        for i in 0..buf.len() {
+            assert!(EntryRef::new(&buf[..i]).is_err());
+        }
+        assert!(matches!(EntryRef::new(&buf[..29]), Err(Error::KVNotFound)));
+
+        let mut buf = encode(
+            &BuilderV2::new()
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .build(),
+        );
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
+        assert!(matches!(
+            EntryRef::new(&buf),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,23 @@
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("entry")]
+    Entry(#[from] crate::entry::Error),
+
+    #[error("invalid journal magic")]
+    InvalidMagic,
+
+    #[error("unsupported journal version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("entry of {0} bytes is too large for a journal")]
+    EntryTooLarge(usize),
+
+    #[error("journal failed: {0}")]
+    Failed(String),
+
+    #[error("journal is closed")]
+    Closed,
+}
@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+
+use bytes::BytesMut;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::entry::{self, AnyEntry};
+
+use super::{Error, Result};
+use super::{JOURNAL_HEADER_SIZE, JOURNAL_MAGIC, JOURNAL_VERSION, RECORD_LENGTH_SIZE};
+
+/// The `JournalFile` appends framed records to a journal file.
+pub(super) struct JournalFile {
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl JournalFile {
+    /// Create a new journal file and write the file header, the file must not exist.
+    /// The directory is synced so the file isn't lost with the entries acknowledged in it.
+    pub(super) async fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .await?;
+        if let Some(dir) = path.parent() {
+            crate::util::sync_dir(dir).await?;
+        }
+        let mut file = BufWriter::new(file);
+        file.write_all(&JOURNAL_MAGIC).await?;
+        file.write_all(&JOURNAL_VERSION.to_le_bytes()).await?;
+        Ok(Self {
+            file,
+            size: JOURNAL_HEADER_SIZE,
+        })
+    }
+
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Write a framed record, it's buffered until `sync` is called.
+    pub(super) async fn write(&mut self, record: &[u8]) -> Result<()> {
+        self.file.write_all(record).await?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the buffered records and sync them to the disk.
+    pub(super) async fn sync(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file.get_ref().sync_data().await?;
+        Ok(())
+    }
+}
+
+/// The `JournalReader` reads the entries of a journal file in order.
+pub struct JournalReader {
+    path: PathBuf,
+    file: BufReader<File>,
+    position: u64,
+    size: u64,
+}
+
+impl JournalReader {
+    /// Open a journal file and validate its file header.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path).await?;
+        let size = file.metadata().await?.len();
+        if size < JOURNAL_HEADER_SIZE {
+            return Err(Error::InvalidMagic);
+        }
+        let mut header = [0; JOURNAL_HEADER_SIZE as usize];
+        file.read_exact(&mut header).await?;
+        if header[..4] != JOURNAL_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != JOURNAL_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        Ok(Self {
+            path,
+            file: BufReader::new(file),
+            position: JOURNAL_HEADER_SIZE,
+            size,
+        })
+    }
+
+    /// Returns the path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the position of the next entry.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read the next entry, returns its position and the entry,
+    /// or `None` if all entries are read.
+    pub async fn next_entry(&mut self) -> Result<Option<(u64, AnyEntry)>> {
+        if self.position + RECORD_LENGTH_SIZE > self.size {
+            return Ok(None);
+        }
+        let length = self.file.read_u32_le().await? as usize;
+        // A corrupted length must not allocate past the end of the file.
+        if length as u64 > self.size - self.position - RECORD_LENGTH_SIZE {
+            return Err(entry::Error::DecodeBufNotEnough.into());
+        }
+        let mut buf = BytesMut::zeroed(length);
+        self.file.read_exact(&mut buf).await?;
+        let entry = entry::decode(buf.freeze())?;
+
+        let position = self.position;
+        self.position += RECORD_LENGTH_SIZE + length as u64;
+        Ok(Some((position, entry)))
+    }
+}
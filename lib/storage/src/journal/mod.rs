@@ -0,0 +1,523 @@
+mod error;
+mod file;
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::entry::Entry;
+
+pub use error::Error;
+use file::JournalFile;
+pub use file::JournalReader;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The journal file layout:
+//
+// File header: magic 4 + version 4 = 8
+// Records: length 4 + encoded entry, repeated
+const JOURNAL_MAGIC: [u8; 4] = *b"LGJN";
+const JOURNAL_VERSION: u32 = 1;
+pub const JOURNAL_HEADER_SIZE: u64 = 8;
+const RECORD_LENGTH_SIZE: u64 = 4;
+const JOURNAL_FILE_EXTENSION: &str = "jnl";
+
+/// The `SyncPolicy` decides when the journal is synced to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every group commit.
+    Always,
+    /// Sync at most once per interval, the callers wait until the next sync.
+    Interval(Duration),
+    /// Sync once the unsynced bytes reach the threshold,
+    /// the callers wait at most `max_delay` if the threshold isn't reached.
+    Bytes { threshold: u64, max_delay: Duration },
+}
+
+/// The `JournalOptions` is the configuration of a journal.
+#[derive(Debug, Clone)]
+pub struct JournalOptions {
+    pub sync_policy: SyncPolicy,
+    /// A new journal file is started once appending would make the current one exceed this size.
+    pub max_file_size: u64,
+    /// The max number of appends waiting to be written.
+    pub queue_capacity: usize,
+}
+
+impl Default for JournalOptions {
+    fn default() -> Self {
+        Self {
+            sync_policy: SyncPolicy::Always,
+            max_file_size: 256 * 1024 * 1024,
+            queue_capacity: 4096,
+        }
+    }
+}
+
+/// Returns the path of the journal file with the sequence number in the directory.
+pub fn journal_path(dir: impl AsRef<Path>, sequence: u64) -> PathBuf {
+    dir.as_ref()
+        .join(format!("{:020}.{}", sequence, JOURNAL_FILE_EXTENSION))
+}
+
+/// List the journal files in the directory, ordered by their sequence numbers.
+pub async fn list_journal_files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(dir_entry) = read_dir.next_entry().await? {
+        let path = dir_entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(JOURNAL_FILE_EXTENSION) {
+            continue;
+        }
+        let sequence = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok());
+        if let Some(sequence) = sequence {
+            files.push((sequence, path));
+        }
+    }
+    files.sort_by_key(|(sequence, _)| *sequence);
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+enum Command {
+    Append {
+        record: Bytes,
+        done: oneshot::Sender<Result<()>>,
+    },
+    Close {
+        done: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// The `Journal` is a write-ahead log shared by many concurrent writers.
+///
+/// Appends are queued to a background task, which writes all queued appends as a group
+/// and syncs them according to the `SyncPolicy`.
+/// An append is resolved only after its entry is durable.
+pub struct Journal {
+    sender: mpsc::Sender<Command>,
+    handle: JoinHandle<()>,
+    syncs: Arc<AtomicU64>,
+}
+
+impl Journal {
+    /// Open the journal in the directory, the directory is created if missing.
+    /// A new journal file is always started after the existing ones.
+    pub async fn open(dir: impl AsRef<Path>, options: JournalOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        let sequence = match list_journal_files(&dir).await?.last() {
+            Some(path) => {
+                path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_default()
+                    + 1
+            }
+            None => 0,
+        };
+        let file = JournalFile::create(journal_path(&dir, sequence)).await?;
+
+        let (sender, receiver) = mpsc::channel(options.queue_capacity);
+        let syncs = Arc::new(AtomicU64::new(0));
+        let committer = GroupCommitter {
+            dir,
+            options,
+            sequence,
+            file,
+            unsynced_bytes: 0,
+            pending: Vec::new(),
+            failure: None,
+            syncs: syncs.clone(),
+        };
+        let handle = tokio::spawn(committer.run(receiver));
+        Ok(Self {
+            sender,
+            handle,
+            syncs,
+        })
+    }
+
+    /// Returns the number of times the journal files were synced so far.
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
+
+    /// Append an entry, resolved after the entry is durable.
+    pub async fn append<E: Entry>(&self, entry: &E) -> Result<()> {
+        let binary_size = entry.binary_size();
+        if binary_size == 0 || binary_size > u32::MAX as usize {
+            return Err(Error::EntryTooLarge(binary_size));
+        }
+        let mut record = BytesMut::with_capacity(RECORD_LENGTH_SIZE as usize + binary_size);
+        record.put_u32_le(binary_size as u32);
+        entry.encode(&mut record)?;
+
+        let (done, wait) = oneshot::channel();
+        self.sender
+            .send(Command::Append {
+                record: record.freeze(),
+                done,
+            })
+            .await
+            .map_err(|_| Error::Closed)?;
+        wait.await.map_err(|_| Error::Closed)?
+    }
+
+    /// Sync all appended entries and stop the journal.
+    pub async fn close(self) -> Result<()> {
+        let (done, wait) = oneshot::channel();
+        self.sender
+            .send(Command::Close { done })
+            .await
+            .map_err(|_| Error::Closed)?;
+        let result = wait.await.map_err(|_| Error::Closed)?;
+        let _ = self.handle.await;
+        result
+    }
+}
+
+struct GroupCommitter {
+    dir: PathBuf,
+    options: JournalOptions,
+    sequence: u64,
+    file: JournalFile,
+    unsynced_bytes: u64,
+    // The appends which are written but not synced yet.
+    pending: Vec<oneshot::Sender<Result<()>>>,
+    // Once a write or sync fails, the journal is unusable.
+    failure: Option<String>,
+    syncs: Arc<AtomicU64>,
+}
+
+impl GroupCommitter {
+    async fn run(mut self, mut receiver: mpsc::Receiver<Command>) {
+        // The ticker bounds how long the appends wait when the policy doesn't sync every group.
+        let (period, sync_on_tick) = match self.options.sync_policy {
+            SyncPolicy::Always => (Duration::from_secs(3600), false),
+            SyncPolicy::Interval(period) => (period, true),
+            SyncPolicy::Bytes { max_delay, .. } => (max_delay, true),
+        };
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                command = receiver.recv() => {
+                    let Some(mut command) = command else {
+                        self.sync().await;
+                        return;
+                    };
+                    // Coalesce all queued appends into a group.
+                    loop {
+                        match command {
+                            Command::Append { record, done } => self.write(record, done).await,
+                            Command::Close { done } => {
+                                self.sync().await;
+                                let _ = done.send(self.check());
+                                return;
+                            }
+                        }
+                        match receiver.try_recv() {
+                            Ok(next) => command = next,
+                            Err(_) => break,
+                        }
+                    }
+                    let should_sync = match self.options.sync_policy {
+                        SyncPolicy::Always => true,
+                        SyncPolicy::Interval(_) => false,
+                        SyncPolicy::Bytes { threshold, .. } => self.unsynced_bytes >= threshold,
+                    };
+                    if should_sync {
+                        self.sync().await;
+                    }
+                }
+                _ = ticker.tick(), if sync_on_tick && !self.pending.is_empty() => {
+                    self.sync().await;
+                }
+            }
+        }
+    }
+
+    async fn write(&mut self, record: Bytes, done: oneshot::Sender<Result<()>>) {
+        if let Err(e) = self.check() {
+            let _ = done.send(Err(e));
+            return;
+        }
+        if self.file.size() > JOURNAL_HEADER_SIZE
+            && self.file.size() + record.len() as u64 > self.options.max_file_size
+        {
+            self.roll().await;
+        }
+        let result = self.file.write(&record).await;
+        self.unsynced_bytes += record.len() as u64;
+        self.pending.push(done);
+        if let Err(e) = result {
+            self.fail(e);
+        }
+    }
+
+    async fn roll(&mut self) {
+        self.sync().await;
+        if self.failure.is_some() {
+            return;
+        }
+        match JournalFile::create(journal_path(&self.dir, self.sequence + 1)).await {
+            Ok(file) => {
+                self.sequence += 1;
+                self.file = file;
+            }
+            Err(e) => self.fail(e),
+        }
+    }
+
+    /// Sync the written records and resolve the pending appends.
+    async fn sync(&mut self) {
+        if self.failure.is_none() && self.unsynced_bytes > 0 {
+            if let Err(e) = self.file.sync().await {
+                self.fail(e);
+            }
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+        }
+        self.unsynced_bytes = 0;
+        for done in self.pending.drain(..) {
+            let _ = done.send(match &self.failure {
+                Some(failure) => Err(Error::Failed(failure.clone())),
+                None => Ok(()),
+            });
+        }
+    }
+
+    fn fail(&mut self, e: Error) {
+        if self.failure.is_none() {
+            self.failure = Some(e.to_string());
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match &self.failure {
+            Some(failure) => Err(Error::Failed(failure.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::BuilderV2;
+    use std::sync::Arc;
+
+    fn new_entry(log_id: i64, entry_id: i64) -> impl Entry {
+        BuilderV2::new()
+            .log_id(log_id)
+            .entry_id(entry_id)
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![0; 64]))
+            .build()
+    }
+
+    async fn read_all(dir: &Path) -> Vec<(i64, i64)> {
+        let mut entries = Vec::new();
+        for path in list_journal_files(dir).await.unwrap() {
+            let mut reader = JournalReader::open(&path).await.unwrap();
+            while let Some((_, entry)) = reader.next_entry().await.unwrap() {
+                entries.push((entry.log_id(), entry.entry_id()));
+            }
+        }
+        entries
+    }
+
+    async fn concurrent_append(options: JournalOptions) {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Arc::new(Journal::open(dir.path(), options).await.unwrap());
+
+        let mut handles = Vec::new();
+        for log_id in 0..8 {
+            let journal = journal.clone();
+            handles.push(tokio::spawn(async move {
+                for entry_id in 0..50 {
+                    journal.append(&new_entry(log_id, entry_id)).await.unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        Arc::into_inner(journal).unwrap().close().await.unwrap();
+
+        let entries = read_all(dir.path()).await;
+        assert_eq!(entries.len(), 8 * 50);
+        for log_id in 0..8 {
+            let entry_ids: Vec<_> = entries
+                .iter()
+                .filter(|(id, _)| *id == log_id)
+                .map(|(_, entry_id)| *entry_id)
+                .collect();
+            assert_eq!(entry_ids, (0..50).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_journal_sync_always() {
+        concurrent_append(JournalOptions::default()).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_journal_sync_interval() {
+        concurrent_append(JournalOptions {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(2)),
+            ..Default::default()
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_journal_sync_bytes() {
+        concurrent_append(JournalOptions {
+            sync_policy: SyncPolicy::Bytes {
+                threshold: 4096,
+                max_delay: Duration::from_millis(2),
+            },
+            ..Default::default()
+        })
+        .await;
+
+        // The appends are only synced once the threshold is reached, or after the max delay.
+        let dir = tempfile::tempdir().unwrap();
+        let record_size = RECORD_LENGTH_SIZE + new_entry(0, 0).binary_size() as u64;
+        let journal = Arc::new(
+            Journal::open(
+                dir.path(),
+                JournalOptions {
+                    sync_policy: SyncPolicy::Bytes {
+                        threshold: 10 * record_size,
+                        max_delay: Duration::from_secs(3600),
+                    },
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let append = |entry_ids: std::ops::Range<i64>| {
+            entry_ids
+                .map(|entry_id| {
+                    let journal = journal.clone();
+                    tokio::spawn(async move { journal.append(&new_entry(0, entry_id)).await })
+                })
+                .collect::<Vec<_>>()
+        };
+        for handle in append(0..10) {
+            handle.await.unwrap().unwrap();
+        }
+        assert_eq!(journal.syncs(), 1);
+        let pending = append(10..11).remove(0);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), async {
+                while !pending.is_finished() {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await
+            .is_err(),
+            "the append is below the threshold"
+        );
+        for handle in append(11..20) {
+            handle.await.unwrap().unwrap();
+        }
+        pending.await.unwrap().unwrap();
+        assert_eq!(journal.syncs(), 2);
+        Arc::into_inner(journal).unwrap().close().await.unwrap();
+
+        let journal = Journal::open(
+            dir.path(),
+            JournalOptions {
+                sync_policy: SyncPolicy::Bytes {
+                    threshold: u64::MAX,
+                    max_delay: Duration::from_millis(10),
+                },
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        journal.append(&new_entry(0, 11)).await.unwrap();
+        assert_eq!(journal.syncs(), 1);
+        journal.close().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_journal_roll() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::open(
+            dir.path(),
+            JournalOptions {
+                max_file_size: 1024,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        for entry_id in 0..40 {
+            journal.append(&new_entry(1, entry_id)).await.unwrap();
+        }
+        journal.close().await.unwrap();
+        let files = list_journal_files(dir.path()).await.unwrap();
+        assert!(files.len() > 1);
+        for path in &files {
+            assert!(tokio::fs::metadata(path).await.unwrap().len() <= 1024);
+        }
+        assert_eq!(read_all(dir.path()).await.len(), 40);
+
+        // Reopening starts a new journal file.
+        let journal = Journal::open(dir.path(), JournalOptions::default())
+            .await
+            .unwrap();
+        journal.close().await.unwrap();
+        assert_eq!(
+            list_journal_files(dir.path()).await.unwrap().len(),
+            files.len() + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_journal_corrupted_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::open(dir.path(), JournalOptions::default())
+            .await
+            .unwrap();
+        journal.append(&new_entry(1, 0)).await.unwrap();
+        journal.close().await.unwrap();
+        let path = list_journal_files(dir.path()).await.unwrap().remove(0);
+        let mut data = tokio::fs::read(&path).await.unwrap();
+        let position = JOURNAL_HEADER_SIZE as usize;
+        data[position..position + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        tokio::fs::write(&path, data).await.unwrap();
+
+        let mut reader = JournalReader::open(&path).await.unwrap();
+        assert!(matches!(
+            reader.next_entry().await,
+            Err(Error::Entry(crate::entry::Error::DecodeBufNotEnough))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_journal_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::open(dir.path(), JournalOptions::default())
+            .await
+            .unwrap();
+        let sender = journal.sender.clone();
+        journal.close().await.unwrap();
+        let (done, _) = oneshot::channel();
+        assert!(sender.send(Command::Close { done }).await.is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use crate::{entry, recovery, segment};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("entry")]
+    Entry(#[from] entry::Error),
+
+    #[error("segment")]
+    Segment(#[from] segment::Error),
+
+    #[error("recovery")]
+    Recovery(#[from] recovery::Error),
+
+    #[error("ledger {0} already exists")]
+    LedgerExists(i64),
+
+    #[error("ledger {0} not found")]
+    LedgerNotFound(i64),
+
+    #[error("ledger {0} is fenced")]
+    Fenced(i64),
+
+    #[error("ledger {0} is closed")]
+    Closed(i64),
+
+    #[error("entry {entry_id} of ledger {log_id} not found")]
+    EntryNotFound { log_id: i64, entry_id: i64 },
+
+    #[error("entry {entry_id} of ledger {log_id} is not confirmed yet")]
+    EntryNotConfirmed { log_id: i64, entry_id: i64 },
+
+    #[error("corrupted state of ledger {0}")]
+    CorruptedState(i64),
+}
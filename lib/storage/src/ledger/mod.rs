@@ -0,0 +1,130 @@
+mod error;
+mod reader;
+mod store;
+mod writer;
+
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+pub use error::Error;
+pub use reader::LedgerReader;
+pub use store::LedgerStore;
+pub use writer::LedgerWriter;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The ledger state file layout:
+//
+// magic 4 + version 4 + state 1 + last_entry_id 8 + crc32c 4 = 21
+const STATE_MAGIC: [u8; 4] = *b"LGST";
+const STATE_VERSION: u32 = 1;
+const STATE_SIZE: usize = 21;
+const STATE_FILE_NAME: &str = "ledger.state";
+
+/// The `LedgerState` is the persisted state of a ledger.
+///
+/// A ledger is created `Open`, a stale writer is stopped by fencing it,
+/// and it's sealed by closing it with the id of its last entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerState {
+    Open,
+    Fenced,
+    Closed { last_entry_id: i64 },
+}
+
+impl LedgerState {
+    fn encode(&self) -> BytesMut {
+        let (tag, last_entry_id) = match self {
+            Self::Open => (0, crate::segment::INVALID_ENTRY_ID),
+            Self::Fenced => (1, crate::segment::INVALID_ENTRY_ID),
+            Self::Closed { last_entry_id } => (2, *last_entry_id),
+        };
+        let mut buf = BytesMut::with_capacity(STATE_SIZE);
+        buf.put_slice(&STATE_MAGIC);
+        buf.put_u32_le(STATE_VERSION);
+        buf.put_u8(tag);
+        buf.put_i64_le(last_entry_id);
+        let checksum = crc32c::crc32c(&buf);
+        buf.put_u32_le(checksum);
+        buf
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() != STATE_SIZE {
+            return None;
+        }
+        let (mut body, checksum) = data.split_at(STATE_SIZE - 4);
+        if crc32c::crc32c(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return None;
+        }
+        if body[..4] != STATE_MAGIC {
+            return None;
+        }
+        body.advance(4);
+        if body.get_u32_le() != STATE_VERSION {
+            return None;
+        }
+        let tag = body.get_u8();
+        let last_entry_id = body.get_i64_le();
+        match tag {
+            0 => Some(Self::Open),
+            1 => Some(Self::Fenced),
+            2 => Some(Self::Closed { last_entry_id }),
+            _ => None,
+        }
+    }
+
+    /// Load the state of the ledger in the directory.
+    async fn load(log_id: i64, dir: &Path) -> Result<Self> {
+        match tokio::fs::read(dir.join(STATE_FILE_NAME)).await {
+            Ok(data) => Self::decode(&data).ok_or(Error::CorruptedState(log_id)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(Error::LedgerNotFound(log_id))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Persist the state of the ledger in the directory, the file is replaced atomically
+    /// and the directory is synced so the rename is durable.
+    async fn persist(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(STATE_FILE_NAME);
+        let tmp_path = path.with_extension("state.tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &self.encode()).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        crate::util::sync_dir(dir).await?;
+        Ok(())
+    }
+}
+
+/// Returns the directory of the ledger, which holds its state file and segments.
+pub fn ledger_dir(dir: impl AsRef<Path>, log_id: i64) -> PathBuf {
+    dir.as_ref().join(format!("{:020}", log_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_encode_decode() {
+        for state in [
+            LedgerState::Open,
+            LedgerState::Fenced,
+            LedgerState::Closed { last_entry_id: 42 },
+        ] {
+            let buf = state.encode();
+            assert_eq!(buf.len(), STATE_SIZE);
+            assert_eq!(LedgerState::decode(&buf), Some(state));
+            for i in 0..buf.len() {
+                let mut corrupted = buf.clone();
+                corrupted[i] ^= 0x01;
+                assert_eq!(LedgerState::decode(&corrupted), None);
+            }
+            assert_eq!(LedgerState::decode(&buf[..STATE_SIZE - 1]), None);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::time::Duration;
+
+use crate::entry::{AnyEntry, Entry};
+
+use super::{Error, LedgerStore, Result};
+
+/// The `LedgerReader` reads the confirmed entries of a ledger.
+///
+/// Only the entries up to the last add confirmed (LAC) are readable,
+/// a tailing reader waits for the LAC to advance with `wait_for_lac` instead of polling.
+pub struct LedgerReader {
+    store: LedgerStore,
+    log_id: i64,
+}
+
+impl LedgerReader {
+    /// Open a reader of an existing ledger in the store.
+    pub async fn open(store: &LedgerStore, log_id: i64) -> Result<Self> {
+        store.state(log_id).await?;
+        Ok(Self {
+            store: store.clone(),
+            log_id,
+        })
+    }
+
+    /// Returns the log id of the ledger.
+    pub fn log_id(&self) -> i64 {
+        self.log_id
+    }
+
+    /// Returns the LAC of the ledger.
+    pub async fn read_lac(&self) -> Result<i64> {
+        self.store.read_lac(self.log_id).await
+    }
+
+    /// Wait until the LAC advances past `after`, returns the new LAC.
+    /// The current LAC is returned if it doesn't advance within the timeout,
+    /// which is always the case once the ledger is closed and all its entries are read.
+    pub async fn wait_for_lac(&self, after: i64, timeout: Duration) -> Result<i64> {
+        let mut receiver = self.store.watch_lac(self.log_id).await?;
+        let advanced = tokio::time::timeout(timeout, receiver.wait_for(|lac| *lac > after))
+            .await
+            .map(|lac| lac.map(|lac| *lac));
+        match advanced {
+            Ok(Ok(lac)) => Ok(lac),
+            // Timed out, or the ledger is dropped by the store.
+            _ => Ok(*receiver.borrow()),
+        }
+    }
+
+    /// Find the first confirmed entry whose time is at or after the timestamp, to replay the ledger from there.
+    /// Returns `None` if there's no such entry yet.
+    pub async fn find_entry_by_time(&self, timestamp: i64) -> Result<Option<AnyEntry>> {
+        let lac = self.read_lac().await?;
+        let entry = self
+            .store
+            .find_entry_by_time(self.log_id, timestamp)
+            .await?;
+        Ok(entry.filter(|entry| entry.entry_id() <= lac))
+    }
+
+    /// Read a confirmed entry, fails with `Error::EntryNotConfirmed` if it's beyond the LAC.
+    pub async fn read_entry(&self, entry_id: i64) -> Result<AnyEntry> {
+        if entry_id > self.read_lac().await? {
+            return Err(Error::EntryNotConfirmed {
+                log_id: self.log_id,
+                entry_id,
+            });
+        }
+        self.store.read_entry(self.log_id, entry_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{BuilderV1, BuilderV2};
+    use crate::ledger::LedgerWriter;
+    use crate::segment::{SegmentOptions, INVALID_ENTRY_ID};
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_read_lac() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        let mut writer = LedgerWriter::create(&store, 1).await.unwrap();
+        let reader = LedgerReader::open(&store, 1).await.unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), INVALID_ENTRY_ID);
+        assert!(matches!(
+            reader.read_entry(0).await,
+            Err(Error::EntryNotConfirmed { .. })
+        ));
+
+        for i in 0..3 {
+            writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from(vec![i as u8]),
+                    vec![],
+                )
+                .await
+                .unwrap();
+            assert_eq!(reader.read_lac().await.unwrap(), i);
+            let entry = reader.read_entry(i).await.unwrap();
+            assert_eq!(entry.entry_id(), i);
+            assert_eq!(entry.value(), &Bytes::from(vec![i as u8]));
+        }
+
+        // An entry added by another writer without acknowledgement isn't confirmed,
+        // but the LAC carried by it never moves the LAC backward.
+        store
+            .add_entry(
+                &BuilderV1::new()
+                    .log_id(1)
+                    .entry_id(3)
+                    .last_confirm_id(1)
+                    .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                    .build(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), 2);
+        assert!(matches!(
+            reader.read_entry(3).await,
+            Err(Error::EntryNotConfirmed { .. })
+        ));
+
+        // The LAC is rebuilt from the entries after a restart.
+        drop((writer, store, reader));
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        let reader = LedgerReader::open(&store, 1).await.unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), 1);
+        store.close(1, 3).await.unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), 3);
+        assert_eq!(reader.read_entry(3).await.unwrap().entry_id(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_lac() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        let mut writer = LedgerWriter::create(&store, 1).await.unwrap();
+        let reader = LedgerReader::open(&store, 1).await.unwrap();
+
+        // Nothing is confirmed within the timeout.
+        assert_eq!(
+            reader
+                .wait_for_lac(INVALID_ENTRY_ID, Duration::from_millis(10))
+                .await
+                .unwrap(),
+            INVALID_ENTRY_ID
+        );
+
+        let tailing = tokio::spawn(async move {
+            let mut lac = INVALID_ENTRY_ID;
+            let mut values = Vec::new();
+            while lac < 4 {
+                let new_lac = reader
+                    .wait_for_lac(lac, Duration::from_secs(10))
+                    .await
+                    .unwrap();
+                assert!(new_lac > lac);
+                for entry_id in lac + 1..=new_lac {
+                    values.push(reader.read_entry(entry_id).await.unwrap().value().clone());
+                }
+                lac = new_lac;
+            }
+            values
+        });
+        for i in 0..5 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+        let values = tailing.await.unwrap();
+        assert_eq!(
+            values,
+            (0..5).map(|i| Bytes::from(vec![i])).collect::<Vec<_>>()
+        );
+
+        // The LAC of a closed ledger doesn't advance anymore.
+        writer.close().await.unwrap();
+        let reader = LedgerReader::open(&store, 1).await.unwrap();
+        assert_eq!(
+            reader
+                .wait_for_lac(4, Duration::from_millis(10))
+                .await
+                .unwrap(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_find_entry_by_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SegmentOptions {
+            max_segment_size: 256,
+            index_interval: 2,
+        };
+        let store = LedgerStore::open(dir.path(), options.clone())
+            .await
+            .unwrap();
+        store.create_ledger(1).await.unwrap();
+        let reader = LedgerReader::open(&store, 1).await.unwrap();
+        // Entry i is produced at 1000 + 10 * i, the entries span several segments.
+        for i in 0..20 {
+            store
+                .add_entry(
+                    &BuilderV2::new()
+                        .log_id(1)
+                        .entry_id(i)
+                        .last_confirm_id(i - 1)
+                        .timestamp(1000 + 10 * i)
+                        .kv(Bytes::from_static(b"key"), Bytes::from(vec![0; 32]))
+                        .build(),
+                )
+                .await
+                .unwrap();
+        }
+        assert!(
+            crate::segment::list_segments(crate::ledger::ledger_dir(dir.path(), 1))
+                .await
+                .unwrap()
+                .len()
+                > 2
+        );
+
+        let find = |timestamp| {
+            let reader = &reader;
+            async move {
+                reader
+                    .find_entry_by_time(timestamp)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.entry_id())
+            }
+        };
+        assert_eq!(find(0).await, Some(0));
+        assert_eq!(find(1001).await, Some(1));
+        assert_eq!(find(1100).await, Some(10));
+        assert_eq!(find(1185).await, None, "entry 19 isn't confirmed");
+        assert_eq!(find(1191).await, None);
+
+        // After a restart the sealed segments are searched by their time index files,
+        // a missing one is rebuilt.
+        store.close(1, 19).await.unwrap();
+        drop((store, reader));
+        let segments = crate::segment::list_segments(crate::ledger::ledger_dir(dir.path(), 1))
+            .await
+            .unwrap();
+        tokio::fs::remove_file(crate::segment::time_index_path(&segments[1]))
+            .await
+            .unwrap();
+        let store = LedgerStore::open(dir.path(), options).await.unwrap();
+        let reader = LedgerReader::open(&store, 1).await.unwrap();
+        for i in 0..20 {
+            assert_eq!(
+                reader
+                    .find_entry_by_time(995 + 10 * i)
+                    .await
+                    .unwrap()
+                    .map(|entry| entry.entry_id()),
+                Some(i)
+            );
+        }
+
+        // The indexes of the sealed segments are kept once loaded.
+        let time_index_path = crate::segment::time_index_path(&segments[1]);
+        tokio::fs::remove_file(&time_index_path).await.unwrap();
+        assert_eq!(
+            reader
+                .find_entry_by_time(1185)
+                .await
+                .unwrap()
+                .map(|entry| entry.entry_id()),
+            Some(19)
+        );
+        assert!(!tokio::fs::try_exists(&time_index_path).await.unwrap());
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use tokio::sync::{watch, Mutex, OnceCell};
+
+use crate::entry::{AnyEntry, Entry};
+use crate::recovery;
+use crate::segment::{
+    self, RollingSegmentWriter, SegmentIndex, SegmentOptions, SegmentReader, TimeIndex,
+};
+
+use super::{ledger_dir, Error, LedgerState, Result};
+
+/// The `LedgerStore` stores the entries and the states of the ledgers in a directory.
+///
+/// Each ledger has its own directory holding its state file and segments.
+/// A ledger is loaded on first use, its torn segment tails are recovered and
+/// the location of every entry is kept in memory.
+/// The store is cheap to clone, the clones share the loaded ledgers.
+#[derive(Clone)]
+pub struct LedgerStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    dir: PathBuf,
+    options: SegmentOptions,
+    // The lock only guards the slots, a ledger is loaded or created in its own slot
+    // so the other ledgers aren't blocked meanwhile.
+    ledgers: Mutex<HashMap<i64, Arc<LedgerSlot>>>,
+}
+
+type LedgerSlot = OnceCell<Arc<Mutex<Ledger>>>;
+
+struct Ledger {
+    log_id: i64,
+    dir: PathBuf,
+    state: LedgerState,
+    // `None` once the ledger is closed.
+    writer: Option<RollingSegmentWriter>,
+    // The segment and the record position of every stored entry.
+    entries: BTreeMap<i64, (Arc<Path>, u64)>,
+    // The last add confirmed, it only moves forward.
+    lac: watch::Sender<i64>,
+    // The indexes of the segments no longer appended, loaded by the first lookup by time.
+    sealed_indexes: HashMap<Arc<Path>, Arc<(SegmentIndex, TimeIndex)>>,
+}
+
+impl LedgerStore {
+    /// Open the store in the directory, it's created if missing.
+    pub async fn open(dir: impl AsRef<Path>, options: SegmentOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                dir,
+                options,
+                ledgers: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Returns the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    /// Create an open ledger, fails if the ledger already exists.
+    pub async fn create_ledger(&self, log_id: i64) -> Result<()> {
+        let slot = self.slot(log_id).await;
+        let mut created = false;
+        let result = slot
+            .get_or_try_init(|| async {
+                let dir = ledger_dir(&self.inner.dir, log_id);
+                if tokio::fs::try_exists(&dir).await? {
+                    return Err(Error::LedgerExists(log_id));
+                }
+                tokio::fs::create_dir_all(&dir).await?;
+                crate::util::sync_dir(&self.inner.dir).await?;
+                LedgerState::Open.persist(&dir).await?;
+                let writer = RollingSegmentWriter::open(&dir, self.inner.options.clone()).await?;
+                created = true;
+                Ok(Arc::new(Mutex::new(Ledger {
+                    log_id,
+                    dir,
+                    state: LedgerState::Open,
+                    writer: Some(writer),
+                    entries: BTreeMap::new(),
+                    lac: watch::Sender::new(segment::INVALID_ENTRY_ID),
+                    sealed_indexes: HashMap::new(),
+                })))
+            })
+            .await;
+        match result {
+            Ok(_) if created => Ok(()),
+            Ok(_) => Err(Error::LedgerExists(log_id)),
+            Err(e) => {
+                self.release_slot(log_id, &slot).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Add an entry to its ledger, resolved after the entry is durable.
+    /// The last confirmed id carried by the entry advances the LAC of the ledger.
+    /// Adding an entry which is already stored is a no-op.
+    pub async fn add_entry<E: Entry>(&self, entry: &E) -> Result<()> {
+        self.add(entry, false).await
+    }
+
+    /// Add an entry to its ledger during the recovery of the ledger,
+    /// unlike `add_entry` the entry is accepted even if the ledger is fenced.
+    pub async fn recovery_add_entry<E: Entry>(&self, entry: &E) -> Result<()> {
+        self.add(entry, true).await
+    }
+
+    async fn add<E: Entry>(&self, entry: &E, recovery: bool) -> Result<()> {
+        let ledger = self.ledger(entry.log_id()).await?;
+        let mut ledger = ledger.lock().await;
+        match ledger.state {
+            LedgerState::Fenced if recovery => {}
+            _ => ledger.check_open()?,
+        }
+        if ledger.entries.contains_key(&entry.entry_id()) {
+            return Ok(());
+        }
+        let writer = ledger
+            .writer
+            .as_mut()
+            .expect("unclosed ledger has a writer");
+        let (path, position) = writer.append(entry).await?;
+        writer.sync().await?;
+        ledger
+            .entries
+            .insert(entry.entry_id(), (Arc::from(path), position));
+        ledger.advance_lac(entry.last_confirm_id());
+        Ok(())
+    }
+
+    /// Read a stored entry of the ledger, confirmed or not.
+    pub async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry> {
+        let ledger = self.ledger(log_id).await?;
+        let (path, position) = ledger
+            .lock()
+            .await
+            .entries
+            .get(&entry_id)
+            .cloned()
+            .ok_or(Error::EntryNotFound { log_id, entry_id })?;
+        let mut reader = SegmentReader::open(&path).await?;
+        reader.seek(position).await?;
+        match reader.next_entry().await? {
+            Some((_, entry)) if entry.entry_id() == entry_id => Ok(entry),
+            _ => Err(Error::EntryNotFound { log_id, entry_id }),
+        }
+    }
+
+    /// Find the first stored entry of the ledger whose time is at or after the timestamp,
+    /// see `TimeIndex` for the time of an entry. The segments older than the timestamp are skipped
+    /// by their time index, it's rebuilt if missing. The indexes of the segments no longer appended
+    /// are kept in memory once loaded.
+    pub async fn find_entry_by_time(
+        &self,
+        log_id: i64,
+        timestamp: i64,
+    ) -> Result<Option<AnyEntry>> {
+        // The indexes of the segment being appended are only in memory.
+        let ledger = self.ledger(log_id).await?;
+        let segments = {
+            let ledger = ledger.lock().await;
+            let current = ledger.writer.as_ref().and_then(|writer| writer.current());
+            let mut paths: Vec<Arc<Path>> = Vec::new();
+            for (path, _) in ledger.entries.values() {
+                if paths.last() != Some(path) {
+                    paths.push(path.clone());
+                }
+            }
+            paths
+                .into_iter()
+                .map(|path| {
+                    let indexes = match current.filter(|current| current.path() == &*path) {
+                        Some(current) => Some(Arc::new((
+                            current.index().clone(),
+                            current.time_index().clone(),
+                        ))),
+                        None => ledger.sealed_indexes.get(&path).cloned(),
+                    };
+                    (path, indexes)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let interval = self.inner.options.index_interval;
+        for (path, indexes) in segments {
+            let indexes = match indexes {
+                Some(indexes) => indexes,
+                None => {
+                    let indexes = Arc::new((
+                        SegmentIndex::load_or_rebuild(&path, interval).await?,
+                        TimeIndex::load_or_rebuild(&path, interval).await?,
+                    ));
+                    ledger
+                        .lock()
+                        .await
+                        .sealed_indexes
+                        .insert(path.clone(), indexes.clone());
+                    indexes
+                }
+            };
+            let (index, time_index) = &*indexes;
+            if time_index
+                .max_timestamp()
+                .is_none_or(|max_timestamp| max_timestamp < timestamp)
+            {
+                continue;
+            }
+            let mut reader = SegmentReader::open(&path).await?;
+            if let Some(entry) = reader
+                .find_entry_by_time(timestamp, time_index, index)
+                .await?
+            {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Advance the LAC of the ledger, the writer calls it once an entry is acknowledged,
+    /// so the readers don't have to wait for the next entry to carry it.
+    /// The LAC never moves backward.
+    pub async fn update_lac(&self, log_id: i64, lac: i64) -> Result<()> {
+        let ledger = self.ledger(log_id).await?;
+        ledger.lock().await.advance_lac(lac);
+        Ok(())
+    }
+
+    /// Returns the LAC of the ledger.
+    pub async fn read_lac(&self, log_id: i64) -> Result<i64> {
+        Ok(*self.watch_lac(log_id).await?.borrow())
+    }
+
+    /// Returns a receiver notified whenever the LAC of the ledger advances.
+    pub async fn watch_lac(&self, log_id: i64) -> Result<watch::Receiver<i64>> {
+        let ledger = self.ledger(log_id).await?;
+        let receiver = ledger.lock().await.lac.subscribe();
+        Ok(receiver)
+    }
+
+    /// Fence the ledger, no more entries can be added to it,
+    /// so a stale writer can't append after another one took over.
+    /// Fencing a closed ledger is a no-op.
+    pub async fn fence(&self, log_id: i64) -> Result<()> {
+        let ledger = self.ledger(log_id).await?;
+        let mut ledger = ledger.lock().await;
+        if ledger.state == LedgerState::Open {
+            ledger.set_state(LedgerState::Fenced).await?;
+        }
+        Ok(())
+    }
+
+    /// Close the ledger with the id of its last entry, the current segment is sealed.
+    /// Closing a closed ledger again is a no-op if the last entry id is the same.
+    pub async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()> {
+        let ledger = self.ledger(log_id).await?;
+        let mut ledger = ledger.lock().await;
+        match ledger.state {
+            LedgerState::Closed {
+                last_entry_id: closed,
+            } if closed == last_entry_id => return Ok(()),
+            LedgerState::Closed { .. } => return Err(Error::Closed(log_id)),
+            LedgerState::Open | LedgerState::Fenced => {}
+        }
+        if let Some(writer) = ledger.writer.take() {
+            writer.close().await?;
+        }
+        ledger
+            .set_state(LedgerState::Closed { last_entry_id })
+            .await?;
+        // All entries of a closed ledger are confirmed.
+        ledger.advance_lac(last_entry_id);
+        Ok(())
+    }
+
+    /// Returns the state of the ledger.
+    pub async fn state(&self, log_id: i64) -> Result<LedgerState> {
+        let ledger = self.ledger(log_id).await?;
+        let state = ledger.lock().await.state;
+        Ok(state)
+    }
+
+    /// Returns the ids of the stored entries of the ledger from `first_entry_id` to `last_entry_id` inclusive,
+    /// in order. Only the in-memory locations are looked up, no entry is read.
+    pub async fn entry_ids(
+        &self,
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    ) -> Result<Vec<i64>> {
+        if first_entry_id > last_entry_id {
+            return Ok(Vec::new());
+        }
+        let ledger = self.ledger(log_id).await?;
+        let ledger = ledger.lock().await;
+        Ok(ledger
+            .entries
+            .range(first_entry_id..=last_entry_id)
+            .map(|(entry_id, _)| *entry_id)
+            .collect())
+    }
+
+    /// Returns the id of the last stored entry of the ledger,
+    /// `segment::INVALID_ENTRY_ID` if no entry is stored.
+    pub async fn last_entry_id(&self, log_id: i64) -> Result<i64> {
+        let ledger = self.ledger(log_id).await?;
+        let ledger = ledger.lock().await;
+        Ok(ledger
+            .entries
+            .last_key_value()
+            .map(|(entry_id, _)| *entry_id)
+            .unwrap_or(segment::INVALID_ENTRY_ID))
+    }
+
+    /// Returns the loaded ledger, it's loaded from the disk on first use.
+    async fn ledger(&self, log_id: i64) -> Result<Arc<Mutex<Ledger>>> {
+        let slot = self.slot(log_id).await;
+        let result = slot
+            .get_or_try_init(|| async {
+                let dir = ledger_dir(&self.inner.dir, log_id);
+                let ledger = Ledger::load(log_id, dir, &self.inner.options).await?;
+                Ok(Arc::new(Mutex::new(ledger)))
+            })
+            .await;
+        match result {
+            Ok(ledger) => Ok(ledger.clone()),
+            Err(e) => {
+                self.release_slot(log_id, &slot).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Returns the slot of the ledger, the store-wide lock is only held to find it.
+    async fn slot(&self, log_id: i64) -> Arc<LedgerSlot> {
+        let mut ledgers = self.inner.ledgers.lock().await;
+        ledgers.entry(log_id).or_default().clone()
+    }
+
+    // Drop the slot left empty by a failed load, unless it was replaced or filled meanwhile.
+    async fn release_slot(&self, log_id: i64, slot: &Arc<LedgerSlot>) {
+        let mut ledgers = self.inner.ledgers.lock().await;
+        if let Some(current) = ledgers.get(&log_id) {
+            if Arc::ptr_eq(current, slot) && !current.initialized() {
+                ledgers.remove(&log_id);
+            }
+        }
+    }
+}
+
+impl Ledger {
+    async fn load(log_id: i64, dir: PathBuf, options: &SegmentOptions) -> Result<Self> {
+        let state = LedgerState::load(log_id, &dir).await?;
+        recovery::recover_segments(&dir).await?;
+
+        let mut entries = BTreeMap::new();
+        let mut lac = match state {
+            LedgerState::Closed { last_entry_id } => last_entry_id,
+            LedgerState::Open | LedgerState::Fenced => segment::INVALID_ENTRY_ID,
+        };
+        for path in segment::list_segments(&dir).await? {
+            let mut reader = SegmentReader::open(&path).await?;
+            let path: Arc<Path> = Arc::from(path);
+            while let Some((position, entry)) = reader.next_entry().await? {
+                entries.insert(entry.entry_id(), (path.clone(), position));
+                lac = lac.max(entry.last_confirm_id());
+            }
+        }
+        let writer = match state {
+            LedgerState::Closed { .. } => None,
+            LedgerState::Open | LedgerState::Fenced => {
+                Some(RollingSegmentWriter::open(&dir, options.clone()).await?)
+            }
+        };
+        Ok(Self {
+            log_id,
+            dir,
+            state,
+            writer,
+            entries,
+            lac: watch::Sender::new(lac),
+            sealed_indexes: HashMap::new(),
+        })
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.state {
+            LedgerState::Open => Ok(()),
+            LedgerState::Fenced => Err(Error::Fenced(self.log_id)),
+            LedgerState::Closed { .. } => Err(Error::Closed(self.log_id)),
+        }
+    }
+
+    fn advance_lac(&self, lac: i64) {
+        self.lac.send_if_modified(|current| {
+            if lac > *current {
+                *current = lac;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    async fn set_state(&mut self, state: LedgerState) -> Result<()> {
+        state.persist(&self.dir).await?;
+        self.state = state;
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use bytes::Bytes;
+
+use crate::entry::{BuilderV1, Header};
+use crate::segment::INVALID_ENTRY_ID;
+
+use super::{LedgerStore, Result};
+
+/// The `LedgerWriter` is the single writer of a ledger.
+///
+/// It assigns monotonically increasing entry ids starting from 0, and stamps every entry
+/// with the last confirmed id, the id of the last entry acknowledged before it.
+pub struct LedgerWriter {
+    store: LedgerStore,
+    log_id: i64,
+    last_entry_id: i64,
+    last_confirm_id: i64,
+}
+
+impl LedgerWriter {
+    /// Create the ledger in the store and return its writer.
+    pub async fn create(store: &LedgerStore, log_id: i64) -> Result<Self> {
+        store.create_ledger(log_id).await?;
+        Ok(Self {
+            store: store.clone(),
+            log_id,
+            last_entry_id: INVALID_ENTRY_ID,
+            last_confirm_id: INVALID_ENTRY_ID,
+        })
+    }
+
+    /// Returns the log id of the ledger.
+    pub fn log_id(&self) -> i64 {
+        self.log_id
+    }
+
+    /// Returns the id of the last appended entry.
+    pub fn last_entry_id(&self) -> i64 {
+        self.last_entry_id
+    }
+
+    /// Returns the id of the last acknowledged entry.
+    pub fn last_confirm_id(&self) -> i64 {
+        self.last_confirm_id
+    }
+
+    /// Append an entry, returns its entry id once it's durable.
+    /// Fails with `Error::Fenced` if another writer fenced the ledger.
+    pub async fn append(&mut self, key: Bytes, value: Bytes, headers: Vec<Header>) -> Result<i64> {
+        let entry_id = self.last_entry_id + 1;
+        let mut builder = BuilderV1::new()
+            .log_id(self.log_id)
+            .entry_id(entry_id)
+            .last_confirm_id(self.last_confirm_id)
+            .kv(key, value);
+        for header in headers {
+            builder = builder.header(header);
+        }
+        let entry = builder.try_build()?;
+        self.store.add_entry(&entry).await?;
+
+        // The entry is acknowledged, advance the LAC for the tailing readers.
+        self.last_entry_id = entry_id;
+        self.last_confirm_id = entry_id;
+        self.store.update_lac(self.log_id, entry_id).await?;
+        Ok(entry_id)
+    }
+
+    /// Close the ledger, returns the id of its last entry.
+    pub async fn close(self) -> Result<i64> {
+        self.store.close(self.log_id, self.last_entry_id).await?;
+        Ok(self.last_entry_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Entry;
+    use crate::ledger::{ledger_dir, Error, LedgerState};
+    use crate::segment::{self, SegmentOptions, SegmentReader};
+
+    async fn read_entries(store: &LedgerStore, log_id: i64) -> Vec<(i64, i64, Bytes)> {
+        let mut entries = Vec::new();
+        for path in segment::list_segments(ledger_dir(store.dir(), log_id))
+            .await
+            .unwrap()
+        {
+            let mut reader = SegmentReader::open(&path).await.unwrap();
+            while let Some((_, entry)) = reader.next_entry().await.unwrap() {
+                entries.push((
+                    entry.entry_id(),
+                    entry.last_confirm_id(),
+                    entry.value().clone(),
+                ));
+            }
+        }
+        entries
+    }
+
+    #[tokio::test]
+    async fn test_append_and_close() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        let mut writer = LedgerWriter::create(&store, 1).await.unwrap();
+        for i in 0..3 {
+            let entry_id = writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from(format!("value-{}", i)),
+                    vec![Header::new(
+                        Bytes::from_static(b"h"),
+                        Bytes::from_static(b"v"),
+                    )],
+                )
+                .await
+                .unwrap();
+            assert_eq!(entry_id, i);
+            assert_eq!(writer.last_confirm_id(), i);
+        }
+        assert_eq!(
+            read_entries(&store, 1).await,
+            vec![
+                (0, -1, Bytes::from("value-0")),
+                (1, 0, Bytes::from("value-1")),
+                (2, 1, Bytes::from("value-2")),
+            ]
+        );
+        assert_eq!(store.last_entry_id(1).await.unwrap(), 2);
+        assert!(matches!(
+            LedgerWriter::create(&store, 1).await,
+            Err(Error::LedgerExists(1))
+        ));
+
+        assert_eq!(writer.close().await.unwrap(), 2);
+        assert_eq!(
+            store.state(1).await.unwrap(),
+            LedgerState::Closed { last_entry_id: 2 }
+        );
+        let entry = BuilderV1::new()
+            .log_id(1)
+            .entry_id(3)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build();
+        assert!(matches!(
+            store.add_entry(&entry).await,
+            Err(Error::Closed(1))
+        ));
+
+        // The state and the entries survive a restart.
+        drop(store);
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.state(1).await.unwrap(),
+            LedgerState::Closed { last_entry_id: 2 }
+        );
+        assert_eq!(store.last_entry_id(1).await.unwrap(), 2);
+        assert!(matches!(
+            store.state(2).await,
+            Err(Error::LedgerNotFound(2))
+        ));
+
+        // A failed load doesn't keep the ledger from being created,
+        // and only one of the concurrent creations succeeds.
+        let (first, second) = tokio::join!(store.create_ledger(2), store.create_ledger(2));
+        assert!(matches!(
+            (first, second),
+            (Ok(()), Err(Error::LedgerExists(2))) | (Err(Error::LedgerExists(2)), Ok(()))
+        ));
+        assert_eq!(store.state(2).await.unwrap(), LedgerState::Open);
+    }
+
+    #[tokio::test]
+    async fn test_fence() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        let mut writer = LedgerWriter::create(&store, 1).await.unwrap();
+        writer
+            .append(
+                Bytes::from_static(b"key"),
+                Bytes::from_static(b"v0"),
+                vec![],
+            )
+            .await
+            .unwrap();
+
+        store.fence(1).await.unwrap();
+        assert!(matches!(
+            writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from_static(b"v1"),
+                    vec![]
+                )
+                .await,
+            Err(Error::Fenced(1))
+        ));
+        assert_eq!(writer.last_entry_id(), 0);
+
+        // The fence survives a restart, a stale writer still can't append.
+        drop(store);
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(store.state(1).await.unwrap(), LedgerState::Fenced);
+        assert_eq!(store.last_entry_id(1).await.unwrap(), 0);
+        let entry = BuilderV1::new()
+            .log_id(1)
+            .entry_id(1)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"v1"))
+            .build();
+        assert!(matches!(
+            store.add_entry(&entry).await,
+            Err(Error::Fenced(1))
+        ));
+
+        // A fenced ledger can still be closed.
+        store.close(1, 0).await.unwrap();
+        store.fence(1).await.unwrap();
+        assert_eq!(
+            store.state(1).await.unwrap(),
+            LedgerState::Closed { last_entry_id: 0 }
+        );
+        assert!(matches!(store.close(1, 1).await, Err(Error::Closed(1))));
+    }
+
+    #[tokio::test]
+    async fn test_torn_first_entry_of_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SegmentOptions {
+            max_segment_size: 128,
+            ..Default::default()
+        };
+        let entry = |entry_id| {
+            BuilderV1::new()
+                .log_id(1)
+                .entry_id(entry_id)
+                .kv(Bytes::from_static(b"key"), Bytes::from(vec![0; 64]))
+                .build()
+        };
+        let store = LedgerStore::open(dir.path(), options.clone())
+            .await
+            .unwrap();
+        store.create_ledger(1).await.unwrap();
+        for entry_id in 0..2 {
+            store.add_entry(&entry(entry_id)).await.unwrap();
+        }
+        drop(store);
+
+        // The only entry of the last segment is torn by a crash.
+        let segments = segment::list_segments(ledger_dir(dir.path(), 1))
+            .await
+            .unwrap();
+        assert_eq!(segments.len(), 2);
+        let size = tokio::fs::metadata(&segments[1]).await.unwrap().len();
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&segments[1])
+            .await
+            .unwrap();
+        file.set_len(size - 1).await.unwrap();
+        drop(file);
+
+        // The entry can be added again after a restart.
+        let store = LedgerStore::open(dir.path(), options).await.unwrap();
+        assert_eq!(store.last_entry_id(1).await.unwrap(), 0);
+        store.add_entry(&entry(1)).await.unwrap();
+        store.add_entry(&entry(2)).await.unwrap();
+        assert_eq!(store.read_entry(1, 1).await.unwrap().entry_id(), 1);
+        assert_eq!(
+            read_entries(&store, 1)
+                .await
+                .into_iter()
+                .map(|(entry_id, _, _)| entry_id)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+}
@@ -1,2 +1,10 @@
+pub mod encryption;
 pub mod entry;
+pub mod journal;
+pub mod ledger;
+pub mod metadata;
+pub mod recovery;
+pub mod replication;
+pub mod segment;
+pub mod server;
 pub mod util;
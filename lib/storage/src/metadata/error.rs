@@ -0,0 +1,30 @@
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("metadata of ledger {0} already exists")]
+    LedgerExists(i64),
+
+    #[error("metadata of ledger {0} not found")]
+    LedgerNotFound(i64),
+
+    #[error("metadata of ledger {log_id} is at version {actual}, not {expected}")]
+    BadVersion {
+        log_id: i64,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("prost decode")]
+    ProstDecode(#[from] prost::DecodeError),
+
+    #[error("unsupported metadata format version {0}")]
+    UnsupportedFormat(u32),
+
+    #[error("malformed metadata: {0}")]
+    Malformed(&'static str),
+
+    #[error("corrupted metadata of ledger {0}")]
+    Corrupted(i64),
+}
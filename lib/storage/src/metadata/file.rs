@@ -0,0 +1,423 @@
+use std::collections::{hash_map, BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{watch, Mutex};
+
+use crate::replication::QuorumConfig;
+
+use super::{
+    check_version, DigestType, Error, LedgerMetadata, LedgerStatus, MetadataStore, Result, Version,
+    Versioned, Watched,
+};
+
+// The metadata file layout:
+//
+// magic 4 + file version 4 + metadata version 8 + body + crc32c 4
+//
+// The body of the file version 2 is the protobuf message of `LedgerMetadata::encode_to_vec`.
+// The body of the file version 1, written by older releases, is
+// status 1 + last_entry_id 8 + ensemble size 4 + write quorum 4 + ack quorum 4
+// + node count 4 + (node id length 2 + node id) * node count
+//
+// The tombstone file of a deleted ledger layout:
+//
+// metadata version 8 + crc32c 4
+const METADATA_MAGIC: [u8; 4] = *b"LGMD";
+const FILE_VERSION: u32 = 2;
+const LEGACY_FILE_VERSION: u32 = 1;
+const FILE_HEADER_SIZE: usize = 16;
+const LEGACY_BODY_HEADER_SIZE: usize = 25;
+const METADATA_FILE_EXTENSION: &str = "meta";
+const TOMBSTONE_FILE_EXTENSION: &str = "deleted";
+const TOMBSTONE_SIZE: usize = 12;
+
+/// The `FileMetadataStore` keeps the metadata of each ledger in a file of a directory.
+///
+/// A file is replaced atomically on every update. The store must be the only one using
+/// the directory, its watchers are only notified of the updates made through it.
+///
+/// A deleted ledger leaves a tombstone file with its last version, so the ledger created
+/// again continues from it. The tombstones are kept.
+pub struct FileMetadataStore {
+    dir: PathBuf,
+    // The ledgers loaded from the directory.
+    ledgers: Mutex<HashMap<i64, Watched>>,
+}
+
+impl FileMetadataStore {
+    /// Open the store in the directory, it's created if missing.
+    pub async fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            dir,
+            ledgers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, log_id: i64) -> PathBuf {
+        self.dir
+            .join(format!("{:020}.{}", log_id, METADATA_FILE_EXTENSION))
+    }
+
+    fn tombstone_path(&self, log_id: i64) -> PathBuf {
+        self.dir
+            .join(format!("{:020}.{}", log_id, TOMBSTONE_FILE_EXTENSION))
+    }
+
+    // Returns the last version of the deleted ledger, 0 if it was never deleted.
+    async fn deleted_version(&self, log_id: i64) -> Result<Version> {
+        let data = match tokio::fs::read(self.tombstone_path(log_id)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() != TOMBSTONE_SIZE
+            || crc32c::crc32c(&data[..8]) != u32::from_le_bytes(data[8..].try_into().unwrap())
+        {
+            return Err(Error::Corrupted(log_id));
+        }
+        Ok(u64::from_le_bytes(data[..8].try_into().unwrap()))
+    }
+
+    // Returns the loaded ledger, it's loaded from its file on first use.
+    async fn ledger<'a>(
+        &self,
+        ledgers: &'a mut HashMap<i64, Watched>,
+        log_id: i64,
+    ) -> Result<&'a Watched> {
+        match ledgers.entry(log_id) {
+            hash_map::Entry::Occupied(entry) => Ok(entry.into_mut()),
+            hash_map::Entry::Vacant(entry) => {
+                let metadata = match tokio::fs::read(self.path(log_id)).await {
+                    Ok(data) => Some(decode(log_id, &data)?),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => return Err(e.into()),
+                };
+                Ok(entry.insert(watch::Sender::new(metadata)))
+            }
+        }
+    }
+
+    async fn persist(&self, log_id: i64, metadata: &Versioned<LedgerMetadata>) -> Result<()> {
+        self.replace(self.path(log_id), &encode(metadata)).await
+    }
+
+    // Replace the file atomically, the rename is synced so it survives a crash once acknowledged.
+    async fn replace(&self, path: PathBuf, data: &[u8]) -> Result<()> {
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(data).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        crate::util::sync_dir(&self.dir).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetadataStore for FileMetadataStore {
+    async fn create(&self, log_id: i64, metadata: LedgerMetadata) -> Result<Version> {
+        let mut ledgers = self.ledgers.lock().await;
+        let watched = self.ledger(&mut ledgers, log_id).await?;
+        if watched.borrow().is_some() {
+            return Err(Error::LedgerExists(log_id));
+        }
+        let version = self.deleted_version(log_id).await? + 1;
+        let metadata = Versioned {
+            value: metadata,
+            version,
+        };
+        self.persist(log_id, &metadata).await?;
+        watched.send_replace(Some(metadata));
+        Ok(version)
+    }
+
+    async fn read(&self, log_id: i64) -> Result<Versioned<LedgerMetadata>> {
+        let mut ledgers = self.ledgers.lock().await;
+        let watched = self.ledger(&mut ledgers, log_id).await?;
+        let metadata = watched.borrow().clone();
+        metadata.ok_or(Error::LedgerNotFound(log_id))
+    }
+
+    async fn compare_and_set(
+        &self,
+        log_id: i64,
+        expected: Version,
+        metadata: LedgerMetadata,
+    ) -> Result<Version> {
+        let mut ledgers = self.ledgers.lock().await;
+        let watched = self.ledger(&mut ledgers, log_id).await?;
+        check_version(log_id, &watched.borrow(), expected)?;
+        let metadata = Versioned {
+            value: metadata,
+            version: expected + 1,
+        };
+        self.persist(log_id, &metadata).await?;
+        watched.send_replace(Some(metadata));
+        Ok(expected + 1)
+    }
+
+    async fn delete(&self, log_id: i64, expected: Version) -> Result<()> {
+        let mut ledgers = self.ledgers.lock().await;
+        let watched = self.ledger(&mut ledgers, log_id).await?;
+        check_version(log_id, &watched.borrow(), expected)?;
+        // The tombstone is written first, the metadata file wins if both are left by a crash.
+        let mut tombstone = BytesMut::with_capacity(TOMBSTONE_SIZE);
+        tombstone.put_u64_le(expected);
+        let checksum = crc32c::crc32c(&tombstone);
+        tombstone.put_u32_le(checksum);
+        self.replace(self.tombstone_path(log_id), &tombstone)
+            .await?;
+        tokio::fs::remove_file(self.path(log_id)).await?;
+        crate::util::sync_dir(&self.dir).await?;
+        watched.send_replace(None);
+        Ok(())
+    }
+
+    async fn ledgers(&self) -> Result<Vec<i64>> {
+        let _ledgers = self.ledgers.lock().await;
+        let mut log_ids = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(METADATA_FILE_EXTENSION) {
+                continue;
+            }
+            if let Some(log_id) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok())
+            {
+                log_ids.push(log_id);
+            }
+        }
+        log_ids.sort_unstable();
+        Ok(log_ids)
+    }
+
+    async fn watch(
+        &self,
+        log_id: i64,
+    ) -> Result<watch::Receiver<Option<Versioned<LedgerMetadata>>>> {
+        let mut ledgers = self.ledgers.lock().await;
+        Ok(self.ledger(&mut ledgers, log_id).await?.subscribe())
+    }
+}
+
+fn encode(metadata: &Versioned<LedgerMetadata>) -> BytesMut {
+    let body = metadata.value.encode_to_vec();
+    let mut buf = BytesMut::with_capacity(FILE_HEADER_SIZE + body.len() + 4);
+    buf.put_slice(&METADATA_MAGIC);
+    buf.put_u32_le(FILE_VERSION);
+    buf.put_u64_le(metadata.version);
+    buf.put_slice(&body);
+    let checksum = crc32c::crc32c(&buf);
+    buf.put_u32_le(checksum);
+    buf
+}
+
+fn decode(log_id: i64, data: &[u8]) -> Result<Versioned<LedgerMetadata>> {
+    let corrupted = Error::Corrupted(log_id);
+    let Some((mut body, checksum)) = data
+        .len()
+        .checked_sub(4)
+        .filter(|size| *size >= FILE_HEADER_SIZE)
+        .map(|size| data.split_at(size))
+    else {
+        return Err(corrupted);
+    };
+    if crc32c::crc32c(body) != u32::from_le_bytes(checksum.try_into().unwrap())
+        || body[..4] != METADATA_MAGIC
+    {
+        return Err(corrupted);
+    }
+    body.advance(4);
+    let file_version = body.get_u32_le();
+    let version = body.get_u64_le();
+    let value = match file_version {
+        FILE_VERSION => LedgerMetadata::decode(body)?,
+        LEGACY_FILE_VERSION => decode_legacy(body).ok_or(corrupted)?,
+        _ => return Err(Error::UnsupportedFormat(file_version)),
+    };
+    Ok(Versioned { value, version })
+}
+
+// Decode the body of the file version 1, it predates the ensemble history,
+// the digest type, the encryption, the creation time and the properties which are defaulted.
+fn decode_legacy(mut body: &[u8]) -> Option<LedgerMetadata> {
+    if body.remaining() < LEGACY_BODY_HEADER_SIZE {
+        return None;
+    }
+    let status = match body.get_u8() {
+        0 => LedgerStatus::Open,
+        1 => LedgerStatus::InRecovery,
+        2 => LedgerStatus::Closed,
+        _ => return None,
+    };
+    let last_entry_id = body.get_i64_le();
+    let quorum = QuorumConfig::new(
+        body.get_u32_le() as usize,
+        body.get_u32_le() as usize,
+        body.get_u32_le() as usize,
+    )
+    .ok()?;
+    let count = body.get_u32_le() as usize;
+    let mut ensemble = Vec::with_capacity(count.min(body.remaining() / 2));
+    for _ in 0..count {
+        if body.remaining() < 2 {
+            return None;
+        }
+        let len = body.get_u16_le() as usize;
+        if body.remaining() < len {
+            return None;
+        }
+        ensemble.push(String::from_utf8(body[..len].to_vec()).ok()?);
+        body.advance(len);
+    }
+    if body.has_remaining() || ensemble.len() != quorum.ensemble_size {
+        return None;
+    }
+    Some(LedgerMetadata {
+        ensembles: BTreeMap::from([(0, ensemble)]),
+        quorum,
+        status,
+        last_entry_id,
+        digest_type: DigestType::None,
+        encryption: None,
+        ctime: 0,
+        properties: BTreeMap::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::{check_metadata_store, metadata};
+
+    #[tokio::test]
+    async fn test_file_metadata_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileMetadataStore::open(dir.path()).await.unwrap();
+        check_metadata_store(&store).await;
+
+        // The metadata is reloaded from the files.
+        let versioned = store.read(3).await.unwrap();
+        drop(store);
+        let store = FileMetadataStore::open(dir.path()).await.unwrap();
+        assert_eq!(store.ledgers().await.unwrap(), vec![1, 2, 3]);
+        assert_eq!(store.read(3).await.unwrap(), versioned);
+        assert_eq!(store.compare_and_set(3, 1, metadata(1)).await.unwrap(), 2);
+
+        // The version of a deleted ledger is reloaded from its tombstone.
+        store.delete(3, 2).await.unwrap();
+        drop(store);
+        let store = FileMetadataStore::open(dir.path()).await.unwrap();
+        assert_eq!(store.ledgers().await.unwrap(), vec![1, 2]);
+        assert!(matches!(store.read(3).await, Err(Error::LedgerNotFound(3))));
+        assert_eq!(store.create(3, metadata(1)).await.unwrap(), 3);
+
+        // A corrupted tombstone fails the creation rather than restarting the versions.
+        std::fs::write(store.tombstone_path(4), [1; TOMBSTONE_SIZE]).unwrap();
+        assert!(matches!(
+            store.create(4, metadata(1)).await,
+            Err(Error::Corrupted(4))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileMetadataStore::open(dir.path()).await.unwrap();
+        store.create(1, metadata(3)).await.unwrap();
+        let path = store.path(1);
+        drop(store);
+
+        let data = std::fs::read(&path).unwrap();
+        let versioned = decode(1, &data).unwrap();
+        assert_eq!(versioned.value, metadata(3));
+        assert_eq!(encode(&versioned), data);
+        for i in 0..data.len() {
+            let mut mutated = data.clone();
+            mutated[i] ^= 0x40;
+            assert!(decode(1, &mutated).is_err());
+            assert!(matches!(decode(1, &data[..i]), Err(Error::Corrupted(1))));
+        }
+
+        std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+        let store = FileMetadataStore::open(dir.path()).await.unwrap();
+        assert!(matches!(store.read(1).await, Err(Error::Corrupted(1))));
+    }
+
+    // Encode the metadata in the file version 1 of older releases.
+    fn encode_legacy(metadata: &Versioned<LedgerMetadata>) -> BytesMut {
+        let Versioned { value, version } = metadata;
+        let mut buf = BytesMut::new();
+        buf.put_slice(&METADATA_MAGIC);
+        buf.put_u32_le(LEGACY_FILE_VERSION);
+        buf.put_u64_le(*version);
+        buf.put_u8(value.status as u8);
+        buf.put_i64_le(value.last_entry_id);
+        buf.put_u32_le(value.quorum.ensemble_size as u32);
+        buf.put_u32_le(value.quorum.write_quorum as u32);
+        buf.put_u32_le(value.quorum.ack_quorum as u32);
+        let ensemble = value.ensemble_at(0);
+        buf.put_u32_le(ensemble.len() as u32);
+        for node in ensemble {
+            buf.put_u16_le(node.len() as u16);
+            buf.put_slice(node.as_bytes());
+        }
+        let checksum = crc32c::crc32c(&buf);
+        buf.put_u32_le(checksum);
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileMetadataStore::open(dir.path()).await.unwrap();
+        let mut legacy = metadata(3);
+        legacy.status = LedgerStatus::Closed;
+        legacy.last_entry_id = 7;
+        legacy.ctime = 0;
+        let versioned = Versioned {
+            value: legacy,
+            version: 4,
+        };
+        std::fs::write(store.path(1), encode_legacy(&versioned)).unwrap();
+
+        // The legacy metadata is loaded, and written in the current format on its next update.
+        assert_eq!(store.read(1).await.unwrap(), versioned);
+        assert_eq!(
+            store
+                .compare_and_set(1, 4, versioned.value.clone())
+                .await
+                .unwrap(),
+            5
+        );
+        let data = std::fs::read(store.path(1)).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            FILE_VERSION
+        );
+
+        let mut unsupported = data.clone();
+        unsupported[4] = 3;
+        let checksum = crc32c::crc32c(&unsupported[..data.len() - 4]);
+        let len = unsupported.len();
+        unsupported[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            decode(1, &unsupported),
+            Err(Error::UnsupportedFormat(3))
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use prost::Message;
+
+use crate::encryption::WrappedKey;
+use crate::replication::QuorumConfig;
+
+use super::{DigestType, Error, LedgerMetadata, LedgerStatus, Result};
+
+/// The format version of the metadata written by this release.
+///
+/// It's bumped whenever the meaning of the fields changes, the fields added since
+/// an older version are defaulted by `migrate` when its metadata is loaded.
+pub const METADATA_FORMAT_VERSION: u32 = 1;
+
+// The protobuf messages of the metadata, declared with the prost derives
+// so building the crate doesn't need protoc. Tags must never be reused.
+#[derive(Clone, PartialEq, Message)]
+struct LedgerMetadataFormat {
+    #[prost(uint32, tag = "1")]
+    format_version: u32,
+    #[prost(message, repeated, tag = "2")]
+    ensembles: Vec<EnsembleFormat>,
+    #[prost(uint32, tag = "3")]
+    ensemble_size: u32,
+    #[prost(uint32, tag = "4")]
+    write_quorum: u32,
+    #[prost(uint32, tag = "5")]
+    ack_quorum: u32,
+    #[prost(enumeration = "StatusFormat", tag = "6")]
+    status: i32,
+    #[prost(int64, tag = "7")]
+    last_entry_id: i64,
+    #[prost(enumeration = "DigestTypeFormat", tag = "8")]
+    digest_type: i32,
+    #[prost(int64, tag = "9")]
+    ctime: i64,
+    #[prost(btree_map = "string, bytes", tag = "10")]
+    properties: BTreeMap<String, Vec<u8>>,
+    #[prost(message, optional, tag = "11")]
+    encryption: Option<EncryptionFormat>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EnsembleFormat {
+    #[prost(int64, tag = "1")]
+    first_entry_id: i64,
+    #[prost(string, repeated, tag = "2")]
+    nodes: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct EncryptionFormat {
+    #[prost(string, tag = "1")]
+    key_id: String,
+    #[prost(bytes = "vec", tag = "2")]
+    wrapped_key: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum StatusFormat {
+    Open = 0,
+    InRecovery = 1,
+    Closed = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+enum DigestTypeFormat {
+    None = 0,
+    Crc32c = 1,
+    HmacSha256 = 2,
+}
+
+impl LedgerMetadata {
+    /// Encode the metadata as a protobuf message of the current format version.
+    pub fn encode_to_vec(&self) -> Vec<u8> {
+        let format = LedgerMetadataFormat {
+            format_version: METADATA_FORMAT_VERSION,
+            ensembles: self
+                .ensembles
+                .iter()
+                .map(|(first_entry_id, nodes)| EnsembleFormat {
+                    first_entry_id: *first_entry_id,
+                    nodes: nodes.clone(),
+                })
+                .collect(),
+            ensemble_size: self.quorum.ensemble_size as u32,
+            write_quorum: self.quorum.write_quorum as u32,
+            ack_quorum: self.quorum.ack_quorum as u32,
+            status: match self.status {
+                LedgerStatus::Open => StatusFormat::Open,
+                LedgerStatus::InRecovery => StatusFormat::InRecovery,
+                LedgerStatus::Closed => StatusFormat::Closed,
+            } as i32,
+            last_entry_id: self.last_entry_id,
+            digest_type: match self.digest_type {
+                DigestType::None => DigestTypeFormat::None,
+                DigestType::Crc32c => DigestTypeFormat::Crc32c,
+                DigestType::HmacSha256 => DigestTypeFormat::HmacSha256,
+            } as i32,
+            ctime: self.ctime,
+            properties: self.properties.clone(),
+            encryption: self.encryption.as_ref().map(|key| EncryptionFormat {
+                key_id: key.key_id.clone(),
+                wrapped_key: key.wrapped.clone(),
+            }),
+        };
+        format.encode_to_vec()
+    }
+
+    /// Decode the metadata from a protobuf message of any supported format version.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let format = migrate(LedgerMetadataFormat::decode(buf)?)?;
+        let quorum = QuorumConfig::new(
+            format.ensemble_size as usize,
+            format.write_quorum as usize,
+            format.ack_quorum as usize,
+        )
+        .map_err(|_| Error::Malformed("invalid quorum"))?;
+        let mut ensembles = BTreeMap::new();
+        for ensemble in format.ensembles {
+            if ensemble.nodes.len() != quorum.ensemble_size {
+                return Err(Error::Malformed("ensemble size doesn't match the quorum"));
+            }
+            if ensembles
+                .insert(ensemble.first_entry_id, ensemble.nodes)
+                .is_some()
+            {
+                return Err(Error::Malformed("duplicated ensemble"));
+            }
+        }
+        if ensembles.first_key_value().map(|(first, _)| *first) != Some(0) {
+            return Err(Error::Malformed(
+                "ensemble history doesn't start at entry 0",
+            ));
+        }
+        let status = match StatusFormat::try_from(format.status) {
+            Ok(StatusFormat::Open) => LedgerStatus::Open,
+            Ok(StatusFormat::InRecovery) => LedgerStatus::InRecovery,
+            Ok(StatusFormat::Closed) => LedgerStatus::Closed,
+            Err(_) => return Err(Error::Malformed("invalid status")),
+        };
+        let digest_type = match DigestTypeFormat::try_from(format.digest_type) {
+            Ok(DigestTypeFormat::None) => DigestType::None,
+            Ok(DigestTypeFormat::Crc32c) => DigestType::Crc32c,
+            Ok(DigestTypeFormat::HmacSha256) => DigestType::HmacSha256,
+            Err(_) => return Err(Error::Malformed("invalid digest type")),
+        };
+        Ok(Self {
+            ensembles,
+            quorum,
+            status,
+            last_entry_id: format.last_entry_id,
+            digest_type,
+            encryption: format.encryption.map(|key| WrappedKey {
+                key_id: key.key_id,
+                wrapped: key.wrapped_key,
+            }),
+            ctime: format.ctime,
+            properties: format.properties,
+        })
+    }
+}
+
+// Bring the metadata of an older format version up to the current one.
+fn migrate(format: LedgerMetadataFormat) -> Result<LedgerMetadataFormat> {
+    match format.format_version {
+        METADATA_FORMAT_VERSION => Ok(format),
+        // The version is never 0 once written, the message is likely not metadata.
+        0 => Err(Error::Malformed("missing format version")),
+        version => Err(Error::UnsupportedFormat(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::metadata;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut metadata = metadata(3);
+        metadata
+            .ensembles
+            .insert(10, vec!["node-0".into(), "node-3".into(), "node-2".into()]);
+        metadata.status = LedgerStatus::Closed;
+        metadata.last_entry_id = 20;
+        metadata.digest_type = DigestType::Crc32c;
+        metadata
+            .properties
+            .insert("owner".to_string(), b"test".to_vec());
+        metadata.encryption = Some(WrappedKey {
+            key_id: "k1".to_string(),
+            wrapped: vec![1; 60],
+        });
+        let buf = metadata.encode_to_vec();
+        assert_eq!(LedgerMetadata::decode(&buf).unwrap(), metadata);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let valid = LedgerMetadataFormat::decode(&metadata(3).encode_to_vec()[..]).unwrap();
+        let mut format = valid.clone();
+        format.format_version = METADATA_FORMAT_VERSION + 1;
+        assert!(matches!(
+            LedgerMetadata::decode(&format.encode_to_vec()),
+            Err(Error::UnsupportedFormat(version)) if version == METADATA_FORMAT_VERSION + 1
+        ));
+
+        let mutations: [fn(&mut LedgerMetadataFormat); 5] = [
+            |f| f.format_version = 0,
+            |f| f.ack_quorum = 4,
+            |f| f.ensembles.clear(),
+            |f| f.ensembles[0].first_entry_id = 1,
+            |f| f.status = 9,
+        ];
+        for mutate in mutations {
+            let mut format = valid.clone();
+            mutate(&mut format);
+            assert!(matches!(
+                LedgerMetadata::decode(&format.encode_to_vec()),
+                Err(Error::Malformed(_))
+            ));
+        }
+        assert!(matches!(
+            LedgerMetadata::decode(b"\xff\xff"),
+            Err(Error::ProstDecode(_))
+        ));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use super::{
+    check_version, Error, LedgerMetadata, MetadataStore, Result, Version, Versioned, Watched,
+};
+
+/// The `MemoryMetadataStore` keeps the metadata in memory, it's lost once dropped.
+#[derive(Default)]
+pub struct MemoryMetadataStore {
+    ledgers: Mutex<Ledgers>,
+}
+
+#[derive(Default)]
+struct Ledgers {
+    watched: HashMap<i64, Watched>,
+    // The last version of the deleted ledgers, the ledgers created again continue from it.
+    deleted: HashMap<i64, Version>,
+}
+
+impl MemoryMetadataStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl MetadataStore for MemoryMetadataStore {
+    async fn create(&self, log_id: i64, metadata: LedgerMetadata) -> Result<Version> {
+        let mut ledgers = self.ledgers.lock().unwrap();
+        let version = ledgers.deleted.get(&log_id).copied().unwrap_or(0) + 1;
+        let watched = ledgers
+            .watched
+            .entry(log_id)
+            .or_insert_with(|| watch::Sender::new(None));
+        if watched.borrow().is_some() {
+            return Err(Error::LedgerExists(log_id));
+        }
+        watched.send_replace(Some(Versioned {
+            value: metadata,
+            version,
+        }));
+        Ok(version)
+    }
+
+    async fn read(&self, log_id: i64) -> Result<Versioned<LedgerMetadata>> {
+        let ledgers = self.ledgers.lock().unwrap();
+        ledgers
+            .watched
+            .get(&log_id)
+            .and_then(|watched| watched.borrow().clone())
+            .ok_or(Error::LedgerNotFound(log_id))
+    }
+
+    async fn compare_and_set(
+        &self,
+        log_id: i64,
+        expected: Version,
+        metadata: LedgerMetadata,
+    ) -> Result<Version> {
+        let ledgers = self.ledgers.lock().unwrap();
+        let watched = ledgers
+            .watched
+            .get(&log_id)
+            .ok_or(Error::LedgerNotFound(log_id))?;
+        check_version(log_id, &watched.borrow(), expected)?;
+        watched.send_replace(Some(Versioned {
+            value: metadata,
+            version: expected + 1,
+        }));
+        Ok(expected + 1)
+    }
+
+    async fn delete(&self, log_id: i64, expected: Version) -> Result<()> {
+        let mut ledgers = self.ledgers.lock().unwrap();
+        let watched = ledgers
+            .watched
+            .get(&log_id)
+            .ok_or(Error::LedgerNotFound(log_id))?;
+        check_version(log_id, &watched.borrow(), expected)?;
+        watched.send_replace(None);
+        ledgers.deleted.insert(log_id, expected);
+        Ok(())
+    }
+
+    async fn ledgers(&self) -> Result<Vec<i64>> {
+        let ledgers = self.ledgers.lock().unwrap();
+        let mut log_ids: Vec<i64> = ledgers
+            .watched
+            .iter()
+            .filter(|(_, watched)| watched.borrow().is_some())
+            .map(|(log_id, _)| *log_id)
+            .collect();
+        log_ids.sort_unstable();
+        Ok(log_ids)
+    }
+
+    async fn watch(
+        &self,
+        log_id: i64,
+    ) -> Result<watch::Receiver<Option<Versioned<LedgerMetadata>>>> {
+        let mut ledgers = self.ledgers.lock().unwrap();
+        Ok(ledgers
+            .watched
+            .entry(log_id)
+            .or_insert_with(|| watch::Sender::new(None))
+            .subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::check_metadata_store;
+
+    #[tokio::test]
+    async fn test_memory_metadata_store() {
+        check_metadata_store(&MemoryMetadataStore::new()).await;
+    }
+}
@@ -0,0 +1,290 @@
+mod error;
+mod file;
+mod format;
+mod memory;
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+use crate::encryption::WrappedKey;
+use crate::replication::QuorumConfig;
+use crate::segment::INVALID_ENTRY_ID;
+
+pub use error::Error;
+pub use file::FileMetadataStore;
+pub use format::METADATA_FORMAT_VERSION;
+pub use memory::MemoryMetadataStore;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The version of the metadata of a ledger, it's 1 once created and incremented by every update.
+/// A ledger created again after its deletion continues from its last version,
+/// so a writer holding a version of the deleted ledger can't update the new one.
+pub type Version = u64;
+
+/// The `LedgerStatus` is the status of a ledger in its metadata.
+///
+/// Unlike the `LedgerState` of a node, it's the status of the ledger in the whole cluster,
+/// a ledger is `InRecovery` while a new writer fences and recovers it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerStatus {
+    Open,
+    InRecovery,
+    Closed,
+}
+
+/// The `DigestType` is the digest protecting the entries of a ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestType {
+    None,
+    Crc32c,
+    HmacSha256,
+}
+
+/// The `LedgerMetadata` describes where and how the entries of a ledger are stored.
+///
+/// It's serialized as a protobuf message, see `encode_to_vec` and `decode`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerMetadata {
+    /// The history of the ensembles keyed by the id of their first entry, the first one starts at 0.
+    /// Each ensemble lists the ids of its nodes in the order of the write sets.
+    pub ensembles: BTreeMap<i64, Vec<String>>,
+    pub quorum: QuorumConfig,
+    pub status: LedgerStatus,
+    /// The id of the last entry once the ledger is closed, `INVALID_ENTRY_ID` before.
+    pub last_entry_id: i64,
+    pub digest_type: DigestType,
+    /// The data key encrypting the entries, `None` if they're stored in plaintext.
+    pub encryption: Option<WrappedKey>,
+    /// The creation time in milliseconds since the Unix epoch.
+    pub ctime: i64,
+    /// The properties set by the application, opaque to the storage.
+    pub properties: BTreeMap<String, Vec<u8>>,
+}
+
+impl LedgerMetadata {
+    /// Create the metadata of an open ledger, created now.
+    pub fn new(ensemble: Vec<String>, quorum: QuorumConfig) -> Self {
+        let ctime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        Self {
+            ensembles: BTreeMap::from([(0, ensemble)]),
+            quorum,
+            status: LedgerStatus::Open,
+            last_entry_id: INVALID_ENTRY_ID,
+            digest_type: DigestType::None,
+            encryption: None,
+            ctime,
+            properties: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the ensemble which stores the entry.
+    pub fn ensemble_at(&self, entry_id: i64) -> &[String] {
+        self.ensembles
+            .range(..=entry_id)
+            .next_back()
+            .or_else(|| self.ensembles.first_key_value())
+            .map(|(_, ensemble)| ensemble.as_slice())
+            .expect("ensemble history is never empty")
+    }
+
+    /// Returns the current ensemble with the id of its first entry.
+    pub fn current_ensemble(&self) -> (i64, &[String]) {
+        self.ensembles
+            .last_key_value()
+            .map(|(first_entry_id, ensemble)| (*first_entry_id, ensemble.as_slice()))
+            .expect("ensemble history is never empty")
+    }
+}
+
+/// The `Versioned` is a value with its version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: Version,
+}
+
+/// The `MetadataStore` stores the metadata of the ledgers.
+///
+/// The metadata is only updated by compare-and-swap against its version,
+/// so concurrent writers and recoveries of a ledger can't overwrite each other's updates.
+#[async_trait]
+pub trait MetadataStore: Send + Sync {
+    /// Create the metadata of a new ledger, returns its version, see `Version`.
+    /// Fails with `Error::LedgerExists` if the ledger already has metadata.
+    async fn create(&self, log_id: i64, metadata: LedgerMetadata) -> Result<Version>;
+
+    /// Read the metadata of the ledger with its version.
+    async fn read(&self, log_id: i64) -> Result<Versioned<LedgerMetadata>>;
+
+    /// Replace the metadata of the ledger if it's still at the expected version, returns the new version.
+    /// Fails with `Error::BadVersion` if it was updated since.
+    async fn compare_and_set(
+        &self,
+        log_id: i64,
+        expected: Version,
+        metadata: LedgerMetadata,
+    ) -> Result<Version>;
+
+    /// Delete the metadata of the ledger if it's still at the expected version.
+    async fn delete(&self, log_id: i64, expected: Version) -> Result<()>;
+
+    /// Returns the log ids of the ledgers which have metadata, in ascending order.
+    async fn ledgers(&self) -> Result<Vec<i64>>;
+
+    /// Returns a receiver of the metadata of the ledger, notified whenever it's created, updated or deleted.
+    /// The ledger doesn't need to exist yet, its metadata is `None` until it's created.
+    async fn watch(
+        &self,
+        log_id: i64,
+    ) -> Result<watch::Receiver<Option<Versioned<LedgerMetadata>>>>;
+}
+
+// The current metadata of a ledger, held by the sender notifying its watchers.
+type Watched = watch::Sender<Option<Versioned<LedgerMetadata>>>;
+
+// Returns an error unless the current metadata is at the expected version.
+fn check_version(
+    log_id: i64,
+    current: &Option<Versioned<LedgerMetadata>>,
+    expected: Version,
+) -> Result<()> {
+    match current {
+        None => Err(Error::LedgerNotFound(log_id)),
+        Some(current) if current.version != expected => Err(Error::BadVersion {
+            log_id,
+            expected,
+            actual: current.version,
+        }),
+        Some(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ensemble_at() {
+        let mut metadata = metadata(2);
+        metadata
+            .ensembles
+            .insert(5, vec!["node-0".to_string(), "node-2".to_string()]);
+        assert_eq!(metadata.ensemble_at(0), ["node-0", "node-1"]);
+        assert_eq!(metadata.ensemble_at(4), ["node-0", "node-1"]);
+        assert_eq!(metadata.ensemble_at(5), ["node-0", "node-2"]);
+        assert_eq!(metadata.ensemble_at(100), ["node-0", "node-2"]);
+        assert_eq!(
+            metadata.current_ensemble(),
+            (5, &["node-0".to_string(), "node-2".to_string()][..])
+        );
+    }
+
+    pub(crate) fn metadata(nodes: usize) -> LedgerMetadata {
+        let mut metadata = LedgerMetadata::new(
+            (0..nodes).map(|i| format!("node-{}", i)).collect(),
+            QuorumConfig::new(nodes, nodes, nodes / 2 + 1).unwrap(),
+        );
+        metadata.ctime = 1_700_000_000_000;
+        metadata
+    }
+
+    /// Check the behavior shared by all implementations of the `MetadataStore`.
+    pub(crate) async fn check_metadata_store(store: &dyn MetadataStore) {
+        assert!(matches!(store.read(1).await, Err(Error::LedgerNotFound(1))));
+        let mut watcher = store.watch(1).await.unwrap();
+        assert!(watcher.borrow_and_update().is_none());
+
+        assert_eq!(store.create(1, metadata(3)).await.unwrap(), 1);
+        assert!(matches!(
+            store.create(1, metadata(5)).await,
+            Err(Error::LedgerExists(1))
+        ));
+        assert_eq!(store.create(3, metadata(5)).await.unwrap(), 1);
+        assert_eq!(store.create(2, metadata(1)).await.unwrap(), 1);
+        assert_eq!(store.ledgers().await.unwrap(), vec![1, 2, 3]);
+        assert!(watcher.has_changed().unwrap());
+        assert_eq!(
+            watcher.borrow_and_update().clone(),
+            Some(Versioned {
+                value: metadata(3),
+                version: 1
+            })
+        );
+
+        // Only the update of the current version succeeds.
+        let mut recovering = metadata(3);
+        recovering.status = LedgerStatus::InRecovery;
+        assert_eq!(
+            store
+                .compare_and_set(1, 1, recovering.clone())
+                .await
+                .unwrap(),
+            2
+        );
+        let mut closed = recovering.clone();
+        closed.status = LedgerStatus::Closed;
+        closed.last_entry_id = 9;
+        assert!(matches!(
+            store.compare_and_set(1, 1, closed.clone()).await,
+            Err(Error::BadVersion {
+                log_id: 1,
+                expected: 1,
+                actual: 2
+            })
+        ));
+        assert_eq!(
+            store.read(1).await.unwrap(),
+            Versioned {
+                value: recovering,
+                version: 2
+            }
+        );
+        assert_eq!(
+            store.compare_and_set(1, 2, closed.clone()).await.unwrap(),
+            3
+        );
+        assert!(matches!(
+            store.compare_and_set(4, 1, closed.clone()).await,
+            Err(Error::LedgerNotFound(4))
+        ));
+
+        // The watcher sees the latest metadata.
+        watcher.changed().await.unwrap();
+        assert_eq!(
+            watcher.borrow_and_update().clone(),
+            Some(Versioned {
+                value: closed,
+                version: 3
+            })
+        );
+
+        assert!(matches!(
+            store.delete(1, 2).await,
+            Err(Error::BadVersion { .. })
+        ));
+        store.delete(1, 3).await.unwrap();
+        assert!(matches!(store.read(1).await, Err(Error::LedgerNotFound(1))));
+        assert_eq!(store.ledgers().await.unwrap(), vec![2, 3]);
+        watcher.changed().await.unwrap();
+        assert!(watcher.borrow_and_update().is_none());
+
+        // A deleted ledger can be created again, its versions keep increasing
+        // so the stale versions of the deleted ledger don't match.
+        assert_eq!(store.create(1, metadata(3)).await.unwrap(), 4);
+        assert!(matches!(
+            store.compare_and_set(1, 1, metadata(3)).await,
+            Err(Error::BadVersion {
+                log_id: 1,
+                expected: 1,
+                actual: 4
+            })
+        ));
+        assert_eq!(store.read(1).await.unwrap().version, 4);
+    }
+}
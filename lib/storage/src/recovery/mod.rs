@@ -0,0 +1,373 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::entry;
+use crate::journal::{self, JournalReader};
+use crate::segment::{self, SegmentReader};
+use crate::util::error_chain;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("segment")]
+    Segment(#[from] segment::Error),
+
+    #[error("journal")]
+    Journal(#[from] journal::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The `RecoveryReport` describes what the recovery of a file kept and discarded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    pub path: PathBuf,
+    /// The number of valid entries kept in the file.
+    pub entries: usize,
+    /// The size of the file after recovery, 0 if the file is removed.
+    pub valid_size: u64,
+    /// The number of bytes truncated from the tail of the file.
+    pub discarded_bytes: u64,
+    /// The reason why the tail is discarded, `None` if the file is intact.
+    pub reason: Option<String>,
+}
+
+impl RecoveryReport {
+    /// Returns whether the file is intact.
+    pub fn is_intact(&self) -> bool {
+        self.discarded_bytes == 0
+    }
+}
+
+/// Recover a segment file, the file is truncated at the first torn or corrupted record.
+/// The other errors, such as the I/O errors, fail the recovery and leave the file untouched.
+/// A sealed segment keeps its footer only if all its records are valid,
+/// the index of a truncated segment is removed so it will be rebuilt.
+/// The file is removed if even its file header is torn, or if no entry is left in it,
+/// so the entry it was created for can be appended again to a new segment of the same name.
+pub async fn recover_segment(path: impl AsRef<Path>) -> Result<RecoveryReport> {
+    let path = path.as_ref();
+    let size = tokio::fs::metadata(path).await?.len();
+    let mut reader = match SegmentReader::open(path).await {
+        Ok(reader) => reader,
+        Err(segment::Error::InvalidMagic) if size < segment::SEGMENT_HEADER_SIZE => {
+            return remove_torn_file(path, size).await;
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = 0;
+    let reason = loop {
+        match reader.next_entry().await {
+            Ok(Some(_)) => entries += 1,
+            Ok(None) => break None,
+            Err(segment::Error::Entry(e)) if is_corrupted(&e) => break Some(error_chain(&e)),
+            Err(segment::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                break Some(error_chain(&e))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    // A sealed segment must end with the footer right after the last record.
+    let valid_size = match reader.footer() {
+        Some(_) if reason.is_none() && reader.position() == reader.end() => size,
+        _ => reader.position(),
+    };
+    let reason = reason.or_else(|| (valid_size < size).then(|| "torn record".to_string()));
+    if entries == 0 {
+        tokio::fs::remove_file(path).await?;
+        remove_indexes(path).await?;
+        return Ok(RecoveryReport {
+            path: path.to_path_buf(),
+            entries,
+            valid_size: 0,
+            discarded_bytes: size,
+            reason: Some(reason.unwrap_or_else(|| "no entries".to_string())),
+        });
+    }
+    if valid_size < size {
+        truncate(path, valid_size).await?;
+        remove_indexes(path).await?;
+    }
+    Ok(RecoveryReport {
+        path: path.to_path_buf(),
+        entries,
+        valid_size,
+        discarded_bytes: size - valid_size,
+        reason,
+    })
+}
+
+/// Recover a journal file, the file is truncated at the first torn or corrupted record.
+/// The other errors, such as the I/O errors, fail the recovery and leave the file untouched.
+/// The file is removed if even its file header is torn.
+pub async fn recover_journal_file(path: impl AsRef<Path>) -> Result<RecoveryReport> {
+    let path = path.as_ref();
+    let size = tokio::fs::metadata(path).await?.len();
+    let mut reader = match JournalReader::open(path).await {
+        Ok(reader) => reader,
+        Err(journal::Error::InvalidMagic) if size < journal::JOURNAL_HEADER_SIZE => {
+            return remove_torn_file(path, size).await;
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = 0;
+    let reason = loop {
+        match reader.next_entry().await {
+            Ok(Some(_)) => entries += 1,
+            Ok(None) => break None,
+            Err(journal::Error::Entry(e)) if is_corrupted(&e) => break Some(error_chain(&e)),
+            Err(journal::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                break Some(error_chain(&e))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let valid_size = reader.position();
+    let reason = reason.or_else(|| (valid_size < size).then(|| "torn record".to_string()));
+    if valid_size < size {
+        truncate(path, valid_size).await?;
+    }
+    Ok(RecoveryReport {
+        path: path.to_path_buf(),
+        entries,
+        valid_size,
+        discarded_bytes: size - valid_size,
+        reason,
+    })
+}
+
+/// Recover all segments in the directory.
+pub async fn recover_segments(dir: impl AsRef<Path>) -> Result<Vec<RecoveryReport>> {
+    let mut reports = Vec::new();
+    for path in segment::list_segments(dir).await? {
+        reports.push(recover_segment(path).await?);
+    }
+    Ok(reports)
+}
+
+/// Recover all journal files in the directory.
+pub async fn recover_journal(dir: impl AsRef<Path>) -> Result<Vec<RecoveryReport>> {
+    let mut reports = Vec::new();
+    for path in journal::list_journal_files(dir).await? {
+        reports.push(recover_journal_file(path).await?);
+    }
+    Ok(reports)
+}
+
+async fn truncate(path: &Path, size: u64) -> Result<()> {
+    let file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.set_len(size).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+// Remove the indexes of the segment, they're rebuilt when needed.
+async fn remove_indexes(path: &Path) -> Result<()> {
+    for index_path in [segment::index_path(path), segment::time_index_path(path)] {
+        match tokio::fs::remove_file(index_path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Whether the record failed to decode because it's torn or corrupted, a record compressed
+// by a codec which isn't enabled is valid and must be kept.
+fn is_corrupted(error: &entry::Error) -> bool {
+    !matches!(error, entry::Error::UnsupportedCompression(_))
+}
+
+async fn remove_torn_file(path: &Path, size: u64) -> Result<RecoveryReport> {
+    tokio::fs::remove_file(path).await?;
+    Ok(RecoveryReport {
+        path: path.to_path_buf(),
+        entries: 0,
+        valid_size: 0,
+        discarded_bytes: size,
+        reason: Some("torn file header".to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{BuilderV1, BuilderV2, Entry};
+    use crate::journal::{Journal, JournalOptions};
+    use crate::segment::{SegmentOptions, SegmentWriter};
+    use bytes::Bytes;
+
+    const ENTRIES: i64 = 4;
+
+    fn new_entry(entry_id: i64) -> impl Entry {
+        BuilderV2::new()
+            .log_id(1)
+            .entry_id(entry_id)
+            .kv(
+                Bytes::from_static(b"key"),
+                Bytes::from(vec![entry_id as u8; 8]),
+            )
+            .build()
+    }
+
+    /// Returns the file content and the end position of each record.
+    async fn write_segment(dir: &Path, seal: bool) -> (Vec<u8>, Vec<u64>) {
+        let path = dir.join("source.seg");
+        let mut writer = SegmentWriter::create(&path, &SegmentOptions::default())
+            .await
+            .unwrap();
+        let mut ends = Vec::new();
+        for i in 0..ENTRIES {
+            let entry = new_entry(i);
+            let position = writer.append(&entry).await.unwrap();
+            ends.push(position + SegmentWriter::record_size(&entry));
+        }
+        if seal {
+            writer.seal().await.unwrap();
+        } else {
+            writer.sync().await.unwrap();
+        }
+        (tokio::fs::read(&path).await.unwrap(), ends)
+    }
+
+    /// Simulate a crash at every byte offset by keeping only the prefix of the file.
+    #[tokio::test]
+    async fn test_recover_segment_crash_at_every_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, ends) = write_segment(dir.path(), false).await;
+        assert_eq!(*ends.last().unwrap(), data.len() as u64);
+
+        for offset in 0..=data.len() {
+            let path = dir.path().join(format!("{:020}.seg", offset));
+            tokio::fs::write(&path, &data[..offset]).await.unwrap();
+            let report = recover_segment(&path).await.unwrap();
+
+            // The segments without any entry left are removed.
+            let kept = ends.iter().filter(|end| **end <= offset as u64).count();
+            if kept == 0 {
+                assert_eq!(report.entries, 0);
+                assert_eq!(report.valid_size, 0);
+                assert_eq!(report.discarded_bytes, offset as u64);
+                assert!(tokio::fs::metadata(&path).await.is_err());
+                continue;
+            }
+            let valid_size = ends[kept - 1];
+            assert_eq!(report.entries, kept);
+            assert_eq!(report.valid_size, valid_size);
+            assert_eq!(report.discarded_bytes, offset as u64 - valid_size);
+            assert_eq!(report.is_intact(), offset as u64 == valid_size);
+            assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), valid_size);
+
+            // The recovered segment is readable and recovering it again changes nothing.
+            let mut reader = SegmentReader::open(&path).await.unwrap();
+            for i in 0..kept {
+                let (_, entry) = reader.next_entry().await.unwrap().unwrap();
+                assert_eq!(entry.entry_id(), i as i64);
+            }
+            assert!(reader.next_entry().await.unwrap().is_none());
+            assert!(recover_segment(&path).await.unwrap().is_intact());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_sealed_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, ends) = write_segment(dir.path(), true).await;
+
+        let path = dir.path().join("sealed.seg");
+        tokio::fs::write(&path, &data).await.unwrap();
+        let report = recover_segment(&path).await.unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.entries, ENTRIES as usize);
+
+        // A torn footer is discarded.
+        tokio::fs::write(&path, &data[..data.len() - 1])
+            .await
+            .unwrap();
+        let report = recover_segment(&path).await.unwrap();
+        assert_eq!(report.entries, ENTRIES as usize);
+        assert_eq!(report.valid_size, *ends.last().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_recover_corrupted_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut data, ends) = write_segment(dir.path(), true).await;
+
+        // Flip a bit in the value of the third entry.
+        data[ends[2] as usize - 1] ^= 0x01;
+        let path = dir.path().join("corrupted.seg");
+        tokio::fs::write(&path, &data).await.unwrap();
+        let report = recover_segment(&path).await.unwrap();
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.valid_size, ends[1]);
+        assert!(report.reason.unwrap().contains("checksum mismatch"));
+        let reader = SegmentReader::open(&path).await.unwrap();
+        assert!(reader.footer().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recover_journal_crash_at_every_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal_dir = dir.path().join("journal");
+        let journal = Journal::open(&journal_dir, JournalOptions::default())
+            .await
+            .unwrap();
+        let mut sizes = Vec::new();
+        for i in 0..ENTRIES {
+            // Mix the entry versions, the torn V1 records are detected by the framing.
+            if i % 2 == 0 {
+                let entry = new_entry(i);
+                sizes.push(4 + entry.binary_size() as u64);
+                journal.append(&entry).await.unwrap();
+            } else {
+                let entry = BuilderV1::new()
+                    .entry_id(i)
+                    .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                    .build();
+                sizes.push(4 + entry.binary_size() as u64);
+                journal.append(&entry).await.unwrap();
+            }
+        }
+        journal.close().await.unwrap();
+        let source = journal::list_journal_files(&journal_dir).await.unwrap();
+        let data = tokio::fs::read(&source[0]).await.unwrap();
+        let ends: Vec<u64> = sizes
+            .iter()
+            .scan(journal::JOURNAL_HEADER_SIZE, |end, size| {
+                *end += size;
+                Some(*end)
+            })
+            .collect();
+        assert_eq!(*ends.last().unwrap(), data.len() as u64);
+
+        for offset in 0..=data.len() {
+            let path = dir.path().join(format!("{:020}.jnl", offset));
+            tokio::fs::write(&path, &data[..offset]).await.unwrap();
+            let report = recover_journal_file(&path).await.unwrap();
+
+            if (offset as u64) < journal::JOURNAL_HEADER_SIZE {
+                assert_eq!(report.valid_size, 0);
+                assert!(tokio::fs::metadata(&path).await.is_err());
+                continue;
+            }
+            let kept = ends.iter().filter(|end| **end <= offset as u64).count();
+            let valid_size = if kept == 0 {
+                journal::JOURNAL_HEADER_SIZE
+            } else {
+                ends[kept - 1]
+            };
+            assert_eq!(report.entries, kept);
+            assert_eq!(report.valid_size, valid_size);
+            assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), valid_size);
+        }
+        let reports = recover_journal(dir.path()).await.unwrap();
+        assert!(reports.iter().all(|r| r.is_intact()));
+    }
+}
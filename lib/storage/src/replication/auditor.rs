@@ -0,0 +1,649 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use tokio::sync::watch;
+
+use crate::entry::{AnyEntry, Entry};
+use crate::ledger;
+use crate::metadata::{self, LedgerMetadata, LedgerStatus, MetadataStore};
+
+use super::{Error, Node, NodeSet, QuorumConfig, Result};
+
+// The max number of entry ids listed by a request of the audit.
+const ENTRY_IDS_WINDOW: i64 = 64 * 1024;
+
+/// The `AuditorOptions` is the configuration of an `Auditor`.
+#[derive(Debug, Clone)]
+pub struct AuditorOptions {
+    /// The time between the end of an audit and the start of the next one.
+    pub interval: Duration,
+    /// The max number of bytes copied per second, unlimited if `None`.
+    pub max_bytes_per_sec: Option<u64>,
+    /// The size of the chunks a copied entry is read in with `read_at`, the rate limit applies to each chunk.
+    /// A node only takes whole entries, so the entry is sent once all its chunks are read.
+    pub chunk_size: usize,
+}
+
+impl Default for AuditorOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            max_bytes_per_sec: Some(64 * 1024 * 1024),
+            chunk_size: 64 * 1024,
+        }
+    }
+}
+
+/// The `Fragment` is the share of a ledger stored by a node of one of its ensembles:
+/// the entries of the ensemble's range which are striped to the node's index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    pub log_id: i64,
+    /// The id of the first entry of the ensemble, its key in the ensemble history.
+    pub first_entry_id: i64,
+    /// The id of the last entry of the ensemble.
+    pub last_entry_id: i64,
+    /// The index of the node in the ensemble.
+    pub index: usize,
+    /// The id of the node.
+    pub node: String,
+}
+
+impl Fragment {
+    /// Returns the ids of the entries of the fragment.
+    pub fn entry_ids(&self, config: QuorumConfig) -> impl Iterator<Item = i64> + '_ {
+        (self.first_entry_id..=self.last_entry_id)
+            .filter(move |entry_id| config.write_set(*entry_id).contains(&self.index))
+    }
+}
+
+/// The `AuditProgress` is the progress of the current audit, or of the last one once it's over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuditProgress {
+    /// The number of audits over.
+    pub audits: u64,
+    pub ledgers_audited: usize,
+    pub fragments_under_replicated: usize,
+    pub fragments_replicated: usize,
+    /// The number of under-replicated fragments which couldn't be replicated, they are retried by the next audit.
+    pub fragments_failed: usize,
+    pub entries_copied: u64,
+    pub bytes_copied: u64,
+}
+
+/// The `Auditor` finds the under-replicated fragments of the ledgers and replicates them again.
+///
+/// A fragment is under-replicated if its node isn't among the available nodes any more,
+/// or if the node is missing some of its entries. Only the ensembles which can't get
+/// new entries are audited: all of them once the ledger is closed, all but the current one before.
+///
+/// An under-replicated fragment is copied from the surviving replicas to a node outside of its ensemble,
+/// which then replaces the node in the ensemble history of the metadata.
+pub struct Auditor {
+    nodes: RwLock<NodeSet>,
+    metadata_store: Arc<dyn MetadataStore>,
+    options: AuditorOptions,
+    progress: watch::Sender<AuditProgress>,
+}
+
+impl Auditor {
+    pub fn new(
+        nodes: NodeSet,
+        metadata_store: Arc<dyn MetadataStore>,
+        options: AuditorOptions,
+    ) -> Self {
+        Self {
+            nodes: RwLock::new(nodes),
+            metadata_store,
+            options,
+            progress: watch::Sender::new(AuditProgress::default()),
+        }
+    }
+
+    /// Replace the available nodes, the fragments of the nodes missing from them are handled as lost.
+    pub fn set_nodes(&self, nodes: NodeSet) {
+        *self.nodes.write().unwrap() = nodes;
+    }
+
+    /// Returns a receiver of the progress, notified as the audits go.
+    pub fn progress(&self) -> watch::Receiver<AuditProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Audit the ledgers periodically until the shutdown future resolves.
+    /// A failed audit is retried after the interval.
+    pub async fn run(&self, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = self.audit() => {}
+            }
+            tokio::select! {
+                _ = &mut shutdown => return,
+                _ = tokio::time::sleep(self.options.interval) => {}
+            }
+        }
+    }
+
+    /// Audit all ledgers and replicate their under-replicated fragments.
+    /// The failure of a fragment doesn't stop the audit, it's reported by the progress.
+    pub async fn audit(&self) -> Result<()> {
+        self.progress.send_modify(|progress| {
+            *progress = AuditProgress {
+                audits: progress.audits,
+                ..Default::default()
+            }
+        });
+        let mut limiter = RateLimiter::new(self.options.max_bytes_per_sec);
+        for log_id in self.metadata_store.ledgers().await? {
+            let fragments = match self.audit_ledger(log_id).await {
+                Ok(fragments) => fragments,
+                // The ledger was deleted meanwhile.
+                Err(Error::Metadata(metadata::Error::LedgerNotFound(_))) => continue,
+                Err(e) => return Err(e),
+            };
+            self.progress.send_modify(|progress| {
+                progress.ledgers_audited += 1;
+                progress.fragments_under_replicated += fragments.len();
+            });
+            for fragment in &fragments {
+                let replicated = self.replicate_with(fragment, &mut limiter).await.is_ok();
+                self.progress.send_modify(|progress| {
+                    if replicated {
+                        progress.fragments_replicated += 1;
+                    } else {
+                        progress.fragments_failed += 1;
+                    }
+                });
+            }
+        }
+        self.progress.send_modify(|progress| progress.audits += 1);
+        Ok(())
+    }
+
+    /// Returns the under-replicated fragments of the ledger.
+    ///
+    /// The ids of the entries stored by the node of a fragment are listed to find the missing ones,
+    /// no entry is read. A node which fails otherwise may be down for a while, its fragment isn't reported.
+    pub async fn audit_ledger(&self, log_id: i64) -> Result<Vec<Fragment>> {
+        let metadata = self.metadata_store.read(log_id).await?.value;
+        let nodes = self.nodes.read().unwrap().clone();
+        let mut fragments = Vec::new();
+        for (first_entry_id, last_entry_id, ensemble) in sealed_ensembles(&metadata) {
+            for (index, id) in ensemble.iter().enumerate() {
+                let fragment = Fragment {
+                    log_id,
+                    first_entry_id,
+                    last_entry_id,
+                    index,
+                    node: id.clone(),
+                };
+                let under_replicated = match nodes.get(id) {
+                    Some(node) => is_missing_entries(node, &fragment, metadata.quorum).await,
+                    None => fragment.entry_ids(metadata.quorum).next().is_some(),
+                };
+                if under_replicated {
+                    fragments.push(fragment);
+                }
+            }
+        }
+        Ok(fragments)
+    }
+
+    /// Copy the fragment from its surviving replicas to a new node, and replace its node in the metadata.
+    /// Fails with `Error::FragmentChanged` if the node was replaced since the fragment was audited.
+    pub async fn replicate(&self, fragment: &Fragment) -> Result<()> {
+        let mut limiter = RateLimiter::new(self.options.max_bytes_per_sec);
+        self.replicate_with(fragment, &mut limiter).await
+    }
+
+    async fn replicate_with(&self, fragment: &Fragment, limiter: &mut RateLimiter) -> Result<()> {
+        let log_id = fragment.log_id;
+        let versioned = self.metadata_store.read(log_id).await?;
+        let mut metadata = versioned.value;
+        let ensemble = metadata
+            .ensembles
+            .get(&fragment.first_entry_id)
+            .filter(|ensemble| ensemble.get(fragment.index) == Some(&fragment.node))
+            .ok_or(Error::FragmentChanged {
+                log_id,
+                first_entry_id: fragment.first_entry_id,
+            })?;
+        let nodes = self.nodes.read().unwrap().clone();
+        // A node never stores two replicas of an entry.
+        let exclude: HashSet<String> = ensemble.iter().cloned().collect();
+        let target = nodes.pick(1, &exclude)?.remove(0);
+
+        for entry_id in fragment.entry_ids(metadata.quorum) {
+            let entry =
+                read_from_replicas(&nodes, ensemble, metadata.quorum, fragment, entry_id).await?;
+            self.copy_entry(&entry, &target, limiter).await?;
+        }
+        // The writer is gone, nothing but the recovery adds of the other fragments may reach the node.
+        if metadata.status == LedgerStatus::Closed {
+            target.fence(log_id).await?;
+        }
+
+        metadata
+            .ensembles
+            .get_mut(&fragment.first_entry_id)
+            .expect("ensemble is checked above")[fragment.index] = target.id().to_string();
+        self.metadata_store
+            .compare_and_set(log_id, versioned.version, metadata)
+            .await?;
+        Ok(())
+    }
+
+    // Read the entry chunk by chunk, each chunk waits for the rate limiter, then send it whole to the node.
+    async fn copy_entry(
+        &self,
+        entry: &AnyEntry,
+        target: &Arc<dyn Node>,
+        limiter: &mut RateLimiter,
+    ) -> Result<()> {
+        let size = entry.binary_size();
+        let mut buf = BytesMut::zeroed(size);
+        let mut offset = 0;
+        while offset < size {
+            let end = size.min(offset + self.options.chunk_size.max(1));
+            let n = entry.read_at(&mut buf[offset..end], offset);
+            if n == 0 {
+                break;
+            }
+            limiter.acquire(n).await;
+            offset += n;
+        }
+        target.recovery_add_entry(buf.freeze()).await?;
+        self.progress.send_modify(|progress| {
+            progress.entries_copied += 1;
+            progress.bytes_copied += size as u64;
+        });
+        Ok(())
+    }
+}
+
+// Returns the ensembles which can't get new entries with the ids of their first and last entries,
+// the empty ones are skipped.
+fn sealed_ensembles(metadata: &LedgerMetadata) -> Vec<(i64, i64, &[String])> {
+    let mut sealed = Vec::new();
+    let mut ensembles = metadata.ensembles.iter().peekable();
+    while let Some((first_entry_id, ensemble)) = ensembles.next() {
+        let last_entry_id = match ensembles.peek() {
+            Some((next_entry_id, _)) => **next_entry_id - 1,
+            None if metadata.status == LedgerStatus::Closed => metadata.last_entry_id,
+            None => break,
+        };
+        if last_entry_id >= *first_entry_id {
+            sealed.push((*first_entry_id, last_entry_id, ensemble.as_slice()));
+        }
+    }
+    sealed
+}
+
+// Returns true if the node doesn't store an entry of the fragment.
+// The stored ids are listed a window at a time, so a long fragment doesn't make a huge response.
+async fn is_missing_entries(
+    node: &Arc<dyn Node>,
+    fragment: &Fragment,
+    config: QuorumConfig,
+) -> bool {
+    let mut first_entry_id = fragment.first_entry_id;
+    while first_entry_id <= fragment.last_entry_id {
+        let last_entry_id = fragment
+            .last_entry_id
+            .min(first_entry_id.saturating_add(ENTRY_IDS_WINDOW - 1));
+        let stored = match node
+            .entry_ids(fragment.log_id, first_entry_id, last_entry_id)
+            .await
+        {
+            Ok(stored) => stored.into_iter().collect::<HashSet<_>>(),
+            Err(Error::Ledger(ledger::Error::LedgerNotFound(_))) => return true,
+            Err(_) => return false,
+        };
+        let missing = (first_entry_id..=last_entry_id).any(|entry_id| {
+            config.write_set(entry_id).contains(&fragment.index) && !stored.contains(&entry_id)
+        });
+        if missing {
+            return true;
+        }
+        first_entry_id = last_entry_id + 1;
+    }
+    false
+}
+
+// Read the entry from the other nodes of its write set.
+async fn read_from_replicas(
+    nodes: &NodeSet,
+    ensemble: &[String],
+    config: QuorumConfig,
+    fragment: &Fragment,
+    entry_id: i64,
+) -> Result<AnyEntry> {
+    for index in config.write_set(entry_id) {
+        if index == fragment.index {
+            continue;
+        }
+        let Some(node) = nodes.get(&ensemble[index]) else {
+            continue;
+        };
+        if let Ok(entry) = node.read_entry(fragment.log_id, entry_id).await {
+            return Ok(entry);
+        }
+    }
+    Err(Error::EntryUnreadable {
+        log_id: fragment.log_id,
+        entry_id,
+    })
+}
+
+// Paces the copies to the max number of bytes per second since its creation.
+struct RateLimiter {
+    max_bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(max_bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            max_bytes_per_sec,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    async fn acquire(&mut self, bytes: usize) {
+        let Some(max_bytes_per_sec) = self.max_bytes_per_sec else {
+            return;
+        };
+        self.bytes += bytes as u64;
+        let due = Duration::from_secs_f64(self.bytes as f64 / max_bytes_per_sec.max(1) as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            tokio::time::sleep(due - elapsed).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::metadata::MemoryMetadataStore;
+    use crate::replication::tests::{flaky_nodes, FlakyNode};
+    use crate::replication::{ReaderOptions, ReplicatedReader, ReplicatedWriter, WriterOptions};
+
+    fn node_set(nodes: &[Arc<FlakyNode>]) -> NodeSet {
+        NodeSet::new(nodes.iter().map(|n| n.clone() as Arc<dyn Node>))
+    }
+
+    async fn create_writer(
+        log_id: i64,
+        config: QuorumConfig,
+        nodes: &[Arc<FlakyNode>],
+        metadata_store: &Arc<MemoryMetadataStore>,
+    ) -> ReplicatedWriter {
+        ReplicatedWriter::create(
+            log_id,
+            config,
+            node_set(nodes),
+            metadata_store.clone(),
+            WriterOptions::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn append(writer: &mut ReplicatedWriter, i: u8, value_size: usize) {
+        writer
+            .append(
+                Bytes::from_static(b"key"),
+                Bytes::from(vec![i; value_size]),
+                vec![],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_audit_lost_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 4).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let mut writer = create_writer(1, config, &nodes[..3], &metadata_store).await;
+        for i in 0..10 {
+            append(&mut writer, i, 1).await;
+        }
+        writer.close().await.unwrap();
+        // Every entry is stored by a single node, it's lost with it.
+        let mut writer = create_writer(
+            2,
+            QuorumConfig::new(3, 1, 1).unwrap(),
+            &nodes[..3],
+            &metadata_store,
+        )
+        .await;
+        for i in 0..3 {
+            append(&mut writer, i, 1).await;
+        }
+        writer.close().await.unwrap();
+
+        // Node 1 is lost, node 3 joins.
+        let mut available = node_set(&nodes);
+        available.remove("node-1");
+        let auditor = Auditor::new(
+            available.clone(),
+            metadata_store.clone(),
+            AuditorOptions::default(),
+        );
+        assert_eq!(
+            auditor.audit_ledger(1).await.unwrap(),
+            vec![Fragment {
+                log_id: 1,
+                first_entry_id: 0,
+                last_entry_id: 9,
+                index: 1,
+                node: "node-1".to_string(),
+            }]
+        );
+
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let mut progress = auditor.progress();
+        let (_, progress) = tokio::join!(
+            auditor.run(async {
+                let _ = shutdown_rx.await;
+            }),
+            async {
+                let progress = progress
+                    .wait_for(|progress| progress.audits == 1)
+                    .await
+                    .map(|progress| *progress);
+                shutdown_tx.send(()).unwrap();
+                progress.unwrap()
+            }
+        );
+        // Entries 0, 1, 3, 4, 6, 7 and 9 of ledger 1 are copied, entry 1 of ledger 2 is lost.
+        assert_eq!(
+            progress,
+            AuditProgress {
+                audits: 1,
+                ledgers_audited: 2,
+                fragments_under_replicated: 2,
+                fragments_replicated: 1,
+                fragments_failed: 1,
+                entries_copied: 7,
+                bytes_copied: progress.bytes_copied,
+            }
+        );
+
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.ensemble_at(0), ["node-0", "node-3", "node-2"]);
+        assert!(auditor.audit_ledger(1).await.unwrap().is_empty());
+        let reader =
+            ReplicatedReader::from_metadata(1, &metadata, &available, &ReaderOptions::default())
+                .await
+                .unwrap();
+        for i in 0..10 {
+            assert_eq!(
+                reader.read_entry(i).await.unwrap().value(),
+                &Bytes::from(vec![i as u8])
+            );
+        }
+        assert_eq!(
+            nodes[3].node.store().state(1).await.unwrap(),
+            ledger::LedgerState::Fenced
+        );
+        assert_eq!(
+            metadata_store.read(2).await.unwrap().value.ensemble_at(0),
+            ["node-0", "node-1", "node-2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_missing_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 4).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let mut writer = create_writer(1, config, &nodes[..3], &metadata_store).await;
+        for i in 0..3 {
+            append(&mut writer, i, 1).await;
+        }
+        // Node 2 misses entry 3, the writer has no node to replace it, it's back afterwards.
+        nodes[2].wait_for_adds(3).await;
+        nodes[2].set_down(true);
+        append(&mut writer, 3, 1).await;
+        nodes[2].wait_for_adds(4).await;
+        nodes[2].set_down(false);
+        append(&mut writer, 4, 1).await;
+        assert_eq!(
+            metadata_store.read(1).await.unwrap().value.ensembles.len(),
+            1
+        );
+
+        // The current ensemble of the open ledger isn't audited.
+        let auditor = Auditor::new(
+            node_set(&nodes),
+            metadata_store.clone(),
+            AuditorOptions::default(),
+        );
+        assert!(auditor.audit_ledger(1).await.unwrap().is_empty());
+        writer.close().await.unwrap();
+        // The stored entries are listed, not read.
+        let fragments = auditor.audit_ledger(1).await.unwrap();
+        assert!(nodes.iter().all(|node| node.reads() == 0));
+        assert_eq!(
+            fragments,
+            vec![Fragment {
+                log_id: 1,
+                first_entry_id: 0,
+                last_entry_id: 4,
+                index: 2,
+                node: "node-2".to_string(),
+            }]
+        );
+        auditor.replicate(&fragments[0]).await.unwrap();
+        assert!(matches!(
+            auditor.replicate(&fragments[0]).await,
+            Err(Error::FragmentChanged {
+                log_id: 1,
+                first_entry_id: 0
+            })
+        ));
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.ensemble_at(0), ["node-0", "node-1", "node-3"]);
+        for entry_id in 0..5 {
+            nodes[3].node.store().read_entry(1, entry_id).await.unwrap();
+        }
+        assert!(auditor.audit_ledger(1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_audit_open_ledger() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 4).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let mut writer = create_writer(1, config, &nodes, &metadata_store).await;
+        append(&mut writer, 0, 1).await;
+        // Node 2 misses entry 1 and is replaced from there, it's lost afterwards.
+        nodes[2].wait_for_adds(1).await;
+        nodes[2].set_down(true);
+        append(&mut writer, 1, 1).await;
+        append(&mut writer, 2, 1).await;
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(
+            metadata.current_ensemble(),
+            (
+                1,
+                &[
+                    "node-0".to_string(),
+                    "node-1".to_string(),
+                    "node-3".to_string()
+                ][..]
+            )
+        );
+
+        let mut available = node_set(&nodes);
+        available.remove("node-2");
+        let auditor = Auditor::new(available, metadata_store.clone(), AuditorOptions::default());
+        let fragments = auditor.audit_ledger(1).await.unwrap();
+        assert_eq!(
+            fragments,
+            vec![Fragment {
+                log_id: 1,
+                first_entry_id: 0,
+                last_entry_id: 0,
+                index: 2,
+                node: "node-2".to_string(),
+            }]
+        );
+        auditor.replicate(&fragments[0]).await.unwrap();
+
+        // The writer carries on over the updated metadata.
+        append(&mut writer, 3, 1).await;
+        writer.close().await.unwrap();
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.status, LedgerStatus::Closed);
+        assert_eq!(metadata.last_entry_id, 3);
+        assert_eq!(metadata.ensemble_at(0), ["node-0", "node-1", "node-3"]);
+        assert!(auditor.audit_ledger(1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_audit_rate_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 4).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let mut writer = create_writer(1, config, &nodes[..3], &metadata_store).await;
+        for i in 0..4 {
+            append(&mut writer, i, 1000).await;
+        }
+        writer.close().await.unwrap();
+
+        let mut available = node_set(&nodes);
+        available.remove("node-0");
+        let options = AuditorOptions {
+            max_bytes_per_sec: Some(10_000),
+            chunk_size: 100,
+            ..Default::default()
+        };
+        let auditor = Auditor::new(available, metadata_store, options);
+        let start = Instant::now();
+        auditor.audit().await.unwrap();
+        let progress = *auditor.progress().borrow();
+        assert_eq!(progress.fragments_replicated, 1);
+        assert_eq!(progress.entries_copied, 3);
+        assert!(progress.bytes_copied > 3000);
+        assert!(
+            start.elapsed() >= Duration::from_secs_f64(progress.bytes_copied as f64 / 10_000.0)
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::entry::{self, AnyEntry, Entry, EntryV1, Header, DIGEST_HEADER_KEY};
+use crate::metadata::DigestType;
+
+use super::{Error, Result};
+
+/// The `Digest` computes and verifies the digests of the entries of a ledger.
+///
+/// The digest covers the encoded entry without the digest, it's carried by a header inserted right after
+/// the common header so the nodes store it like any other header. Its value is never compressed,
+/// so it's verified against the stored bytes whatever the codec of the entry.
+#[derive(Clone)]
+pub struct Digest {
+    digest_type: DigestType,
+    // The HMAC keyed by the password, cloned for each entry.
+    hmac: Hmac<Sha256>,
+}
+
+impl Digest {
+    /// Create the digest of the type, the password keys the HMAC and is ignored by the other types.
+    pub fn new(digest_type: DigestType, password: &[u8]) -> Self {
+        Self {
+            digest_type,
+            // HMAC takes keys of any length.
+            hmac: Hmac::new_from_slice(password).expect("invalid HMAC key"),
+        }
+    }
+
+    /// Returns the type of the digest.
+    pub fn digest_type(&self) -> DigestType {
+        self.digest_type
+    }
+
+    /// Encode the entry with its digest header, as sent to the nodes.
+    pub fn encode(&self, entry: &EntryV1) -> Result<Bytes> {
+        let mut buf = BytesMut::with_capacity(entry.binary_size());
+        entry.encode(&mut buf)?;
+        if self.digest_type == DigestType::None {
+            return Ok(buf.freeze());
+        }
+
+        let digest = Bytes::from(self.compute(&buf));
+        let header = Header::new(Bytes::from_static(DIGEST_HEADER_KEY), digest);
+        let header_size = header.binary_size();
+        let common_header_size = entry.common_header().len();
+        let mut sealed = BytesMut::with_capacity(
+            buf.len() + prost::length_delimiter_len(header_size) + header_size,
+        );
+        sealed.put_slice(&buf[..common_header_size]);
+        prost::encode_length_delimiter(header_size, &mut sealed).map_err(entry::Error::from)?;
+        header.encode(&mut sealed)?;
+        sealed.put_slice(&buf[common_header_size..]);
+        Ok(sealed.freeze())
+    }
+
+    /// Verify the digest of the entry read from a node, returns the entry without the digest header.
+    /// Fails with `Error::DigestMismatch` if it doesn't match or the entry has no digest.
+    pub fn verify(&self, entry: AnyEntry) -> Result<AnyEntry> {
+        if self.digest_type == DigestType::None {
+            return Ok(entry);
+        }
+        let (common_header, headers) = entry.raw_parts();
+        let mismatch = || Error::DigestMismatch {
+            log_id: entry.log_id(),
+            entry_id: entry.entry_id(),
+        };
+        // The last header is the kv, it's never the digest.
+        if headers.len() < 2 || headers[0].key() != DIGEST_HEADER_KEY {
+            return Err(mismatch());
+        }
+
+        // The encoded entry without the digest header, as the writer encoded it.
+        let mut buf = BytesMut::with_capacity(entry.binary_size());
+        buf.put_slice(common_header);
+        for header in &headers[1..] {
+            prost::encode_length_delimiter(header.binary_size(), &mut buf)
+                .map_err(entry::Error::from)?;
+            header.encode(&mut buf)?;
+        }
+        let digest = headers[0].value();
+        let matched = match self.digest_type {
+            DigestType::None => true,
+            DigestType::Crc32c => self.compute(&buf) == digest[..],
+            // The HMAC is compared in constant time.
+            DigestType::HmacSha256 => {
+                let mut hmac = self.hmac.clone();
+                hmac.update(&buf);
+                hmac.verify_slice(digest).is_ok()
+            }
+        };
+        if !matched {
+            return Err(mismatch());
+        }
+        Ok(entry.with_raw_parts(entry.attr(), headers[1..].to_vec())?)
+    }
+
+    fn compute(&self, data: &[u8]) -> Vec<u8> {
+        match self.digest_type {
+            DigestType::None => Vec::new(),
+            DigestType::Crc32c => crc32c::crc32c(data).to_le_bytes().to_vec(),
+            DigestType::HmacSha256 => {
+                let mut hmac = self.hmac.clone();
+                hmac.update(data);
+                hmac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::tests::{enabled_compressions, entry_v1};
+    use crate::entry::Compression;
+
+    #[test]
+    fn test_digest_encode_verify() {
+        for digest_type in [DigestType::Crc32c, DigestType::HmacSha256] {
+            for compression in enabled_compressions() {
+                let digest = Digest::new(digest_type, b"secret");
+                let buf = digest.encode(&entry_v1(compression)).unwrap();
+                let decoded = entry::decode(buf.clone()).unwrap();
+                assert_eq!(decoded.headers()[0].key(), DIGEST_HEADER_KEY);
+                // The digest is stored as is, even if the other headers are compressed.
+                let AnyEntry::V1(stored) = &decoded else {
+                    panic!("not an EntryV1");
+                };
+                assert_eq!(
+                    stored.raw_headers()[0].value(),
+                    decoded.headers()[0].value()
+                );
+                assert_eq!(
+                    stored.raw_headers()[0].value().len(),
+                    if digest_type == DigestType::Crc32c {
+                        4
+                    } else {
+                        32
+                    }
+                );
+                let verified = digest.verify(decoded).unwrap();
+                assert_eq!(verified.headers().len(), 1);
+                assert_eq!(verified.headers()[0].value(), &Bytes::from_static(b"abc"));
+                assert_eq!(verified.value(), &Bytes::from(vec![7; 100]));
+
+                // The digest covers the common header.
+                let mut tampered = buf.to_vec();
+                tampered[13] ^= 1;
+                let decoded = entry::decode(&tampered[..]).unwrap();
+                assert!(matches!(
+                    digest.verify(decoded),
+                    Err(Error::DigestMismatch {
+                        log_id: 1,
+                        entry_id: 3
+                    })
+                ));
+
+                // Only the HMAC depends on the password.
+                let other = Digest::new(digest_type, b"other");
+                let decoded = entry::decode(buf).unwrap();
+                assert_eq!(
+                    other.verify(decoded).is_ok(),
+                    digest_type == DigestType::Crc32c
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_digest_missing() {
+        let none = Digest::new(DigestType::None, &[]);
+        let buf = none.encode(&entry_v1(Compression::None)).unwrap();
+        let mut expected = BytesMut::new();
+        entry_v1(Compression::None).encode(&mut expected).unwrap();
+        assert_eq!(buf, expected.freeze());
+
+        none.verify(entry::decode(buf.clone()).unwrap()).unwrap();
+        assert!(matches!(
+            Digest::new(DigestType::Crc32c, &[]).verify(entry::decode(buf).unwrap()),
+            Err(Error::DigestMismatch {
+                log_id: 1,
+                entry_id: 2
+            })
+        ));
+    }
+}
@@ -0,0 +1,63 @@
+use crate::{encryption, entry, ledger, metadata};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("entry")]
+    Entry(#[from] entry::Error),
+
+    #[error("ledger")]
+    Ledger(#[from] ledger::Error),
+
+    #[error("metadata")]
+    Metadata(#[from] metadata::Error),
+
+    #[error("encryption")]
+    Encryption(#[from] encryption::Error),
+
+    #[error("node {0} is unavailable")]
+    Unavailable(String),
+
+    #[error("invalid quorum, ensemble {ensemble_size}, write quorum {write_quorum}, ack quorum {ack_quorum}")]
+    InvalidQuorum {
+        ensemble_size: usize,
+        write_quorum: usize,
+        ack_quorum: usize,
+    },
+
+    #[error(
+        "entry {entry_id} is acknowledged by {acks} nodes, less than the ack quorum {ack_quorum}"
+    )]
+    NotEnoughAcks {
+        entry_id: i64,
+        acks: usize,
+        ack_quorum: usize,
+    },
+
+    #[error("ledger {log_id} is fenced on {fenced} nodes, less than the required {required}")]
+    NotEnoughFenced {
+        log_id: i64,
+        fenced: usize,
+        required: usize,
+    },
+
+    #[error("can't tell whether entry {entry_id} of ledger {log_id} was acknowledged, too many nodes failed")]
+    RecoveryIncomplete { log_id: i64, entry_id: i64 },
+
+    #[error("entry {entry_id} of ledger {log_id} can't be read from any node")]
+    EntryUnreadable { log_id: i64, entry_id: i64 },
+
+    #[error("{available} nodes are available, less than the required {required}")]
+    NotEnoughNodes { available: usize, required: usize },
+
+    #[error("ensemble of entry {first_entry_id} of ledger {log_id} changed since the audit")]
+    FragmentChanged { log_id: i64, first_entry_id: i64 },
+
+    #[error("digest mismatch of entry {entry_id} of ledger {log_id}")]
+    DigestMismatch { log_id: i64, entry_id: i64 },
+
+    #[error("an append to ledger {0} failed, the ledger must be recovered")]
+    WriterFailed(i64),
+
+    #[error("entries of ledger {0} are encrypted, a key provider is required")]
+    KeyProviderRequired(i64),
+}
@@ -0,0 +1,325 @@
+mod auditor;
+mod digest;
+mod error;
+mod node;
+mod reader;
+mod recovery;
+mod writer;
+
+pub use crate::entry::DIGEST_HEADER_KEY;
+pub use auditor::{AuditProgress, Auditor, AuditorOptions, Fragment};
+pub use digest::Digest;
+pub use error::Error;
+pub use node::{LocalNode, Node, NodeSet};
+pub use reader::{ReaderOptions, ReplicatedReader};
+pub use recovery::recover_ledger;
+pub use writer::{ReplicatedWriter, WriterOptions};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The `QuorumConfig` describes how the entries of a ledger are replicated.
+///
+/// Each entry is written to `write_quorum` nodes out of the `ensemble_size` nodes of the ensemble,
+/// and acknowledged once `ack_quorum` of them have stored it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuorumConfig {
+    pub ensemble_size: usize,
+    pub write_quorum: usize,
+    pub ack_quorum: usize,
+}
+
+impl QuorumConfig {
+    /// Create a config, fails unless `1 <= ack_quorum <= write_quorum <= ensemble_size`.
+    pub fn new(ensemble_size: usize, write_quorum: usize, ack_quorum: usize) -> Result<Self> {
+        if ack_quorum == 0 || ack_quorum > write_quorum || write_quorum > ensemble_size {
+            return Err(Error::InvalidQuorum {
+                ensemble_size,
+                write_quorum,
+                ack_quorum,
+            });
+        }
+        Ok(Self {
+            ensemble_size,
+            write_quorum,
+            ack_quorum,
+        })
+    }
+
+    /// Returns the indexes in the ensemble of the nodes storing the entry,
+    /// the entries are striped round-robin, starting from the node at `entry_id % ensemble_size`.
+    pub fn write_set(&self, entry_id: i64) -> Vec<usize> {
+        let first = entry_id.rem_euclid(self.ensemble_size as i64) as usize;
+        (0..self.write_quorum)
+            .map(|i| (first + i) % self.ensemble_size)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use async_trait::async_trait;
+    use bytes::{Bytes, BytesMut};
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::entry::{AnyEntry, BuilderV1, BuilderV2, Entry};
+    use crate::ledger::LedgerStore;
+    use crate::segment::SegmentOptions;
+
+    /// Returns the in-process nodes, each of them stores its ledgers in a sub-directory.
+    pub(crate) async fn local_nodes(dir: &Path, count: usize) -> Vec<Arc<LocalNode>> {
+        let mut nodes = Vec::new();
+        for i in 0..count {
+            let store =
+                LedgerStore::open(dir.join(format!("node-{}", i)), SegmentOptions::default())
+                    .await
+                    .unwrap();
+            nodes.push(Arc::new(LocalNode::new(format!("node-{}", i), store)));
+        }
+        nodes
+    }
+
+    /// The `DownNode` fails every request.
+    pub(crate) struct DownNode(pub(crate) String);
+
+    #[async_trait]
+    impl Node for DownNode {
+        fn id(&self) -> &str {
+            &self.0
+        }
+
+        async fn add_entry(&self, _entry: Bytes) -> Result<()> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+
+        async fn recovery_add_entry(&self, _entry: Bytes) -> Result<()> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+
+        async fn read_entry(&self, _log_id: i64, _entry_id: i64) -> Result<AnyEntry> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+
+        async fn read_lac(&self, _log_id: i64) -> Result<i64> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+
+        async fn fence(&self, _log_id: i64) -> Result<i64> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+
+        async fn close(&self, _log_id: i64, _last_entry_id: i64) -> Result<()> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+
+        async fn entry_ids(
+            &self,
+            _log_id: i64,
+            _first_entry_id: i64,
+            _last_entry_id: i64,
+        ) -> Result<Vec<i64>> {
+            Err(Error::Unavailable(self.0.clone()))
+        }
+    }
+
+    /// The `FlakyNode` forwards the requests to a node unless it's down,
+    /// the adds can be delayed to make it a slow node. The entry reads are counted,
+    /// and so are the adds once over: stored, failed or given up by the writer.
+    pub(crate) struct FlakyNode {
+        pub(crate) node: Arc<LocalNode>,
+        down: AtomicBool,
+        delay_ms: AtomicU64,
+        reads: AtomicU64,
+        adds: watch::Sender<u64>,
+    }
+
+    impl FlakyNode {
+        pub(crate) fn new(node: Arc<LocalNode>) -> Self {
+            Self {
+                node,
+                down: AtomicBool::new(false),
+                delay_ms: AtomicU64::new(0),
+                reads: AtomicU64::new(0),
+                adds: watch::Sender::new(0),
+            }
+        }
+
+        /// Wait until the node is over with the number of adds, the adds to the nodes
+        /// beyond the ack quorum may still be in flight once an append returns.
+        pub(crate) async fn wait_for_adds(&self, adds: u64) {
+            let mut rx = self.adds.subscribe();
+            rx.wait_for(|over| *over >= adds).await.unwrap();
+        }
+
+        pub(crate) fn reads(&self) -> u64 {
+            self.reads.load(Ordering::SeqCst)
+        }
+
+        pub(crate) fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+
+        pub(crate) fn set_delay(&self, delay: Duration) {
+            self.delay_ms
+                .store(delay.as_millis() as u64, Ordering::SeqCst);
+        }
+
+        fn check(&self) -> Result<()> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(Error::Unavailable(self.node.id().to_string()));
+            }
+            Ok(())
+        }
+
+        async fn delay(&self) {
+            let delay_ms = self.delay_ms.load(Ordering::SeqCst);
+            if delay_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Node for FlakyNode {
+        fn id(&self) -> &str {
+            self.node.id()
+        }
+
+        async fn add_entry(&self, entry: Bytes) -> Result<()> {
+            let _over = AddOver(&self.adds);
+            self.check()?;
+            self.delay().await;
+            self.node.add_entry(entry).await
+        }
+
+        async fn recovery_add_entry(&self, entry: Bytes) -> Result<()> {
+            self.check()?;
+            self.delay().await;
+            self.node.recovery_add_entry(entry).await
+        }
+
+        async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry> {
+            self.check()?;
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.node.read_entry(log_id, entry_id).await
+        }
+
+        async fn read_lac(&self, log_id: i64) -> Result<i64> {
+            self.check()?;
+            self.node.read_lac(log_id).await
+        }
+
+        async fn fence(&self, log_id: i64) -> Result<i64> {
+            self.check()?;
+            self.node.fence(log_id).await
+        }
+
+        async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()> {
+            self.check()?;
+            self.node.close(log_id, last_entry_id).await
+        }
+
+        async fn entry_ids(
+            &self,
+            log_id: i64,
+            first_entry_id: i64,
+            last_entry_id: i64,
+        ) -> Result<Vec<i64>> {
+            self.check()?;
+            self.node
+                .entry_ids(log_id, first_entry_id, last_entry_id)
+                .await
+        }
+    }
+
+    // Counts an add once it's over when dropped, so an add timed out by the writer is counted too.
+    struct AddOver<'a>(&'a watch::Sender<u64>);
+
+    impl Drop for AddOver<'_> {
+        fn drop(&mut self) {
+            self.0.send_modify(|over| *over += 1);
+        }
+    }
+
+    /// Returns the flaky in-process nodes, see `local_nodes`.
+    pub(crate) async fn flaky_nodes(dir: &Path, count: usize) -> Vec<Arc<FlakyNode>> {
+        local_nodes(dir, count)
+            .await
+            .into_iter()
+            .map(|node| Arc::new(FlakyNode::new(node)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_local_node_append_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LedgerStore::open(dir.path(), SegmentOptions::default())
+            .await
+            .unwrap();
+        let node = LocalNode::new("node-0", store).with_append_time(true);
+        let encode = |entry: AnyEntry| {
+            let mut buf = BytesMut::new();
+            entry.encode(&mut buf).unwrap();
+            buf.freeze()
+        };
+        let entry = |entry_id| {
+            BuilderV2::new()
+                .log_id(1)
+                .entry_id(entry_id)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+        };
+
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        node.add_entry(encode(AnyEntry::V2(entry(0).build())))
+            .await
+            .unwrap();
+        let append_time = node.read_entry(1, 0).await.unwrap().append_time().unwrap();
+        assert!(append_time >= start);
+
+        // An append time set by the writer, a recovery add or a V1 entry are kept as they are.
+        node.add_entry(encode(AnyEntry::V2(entry(1).append_time(5).build())))
+            .await
+            .unwrap();
+        assert_eq!(node.read_entry(1, 1).await.unwrap().append_time(), Some(5));
+        node.recovery_add_entry(encode(AnyEntry::V2(entry(2).build())))
+            .await
+            .unwrap();
+        assert_eq!(node.read_entry(1, 2).await.unwrap().append_time(), None);
+        let v1 = BuilderV1::new()
+            .log_id(1)
+            .entry_id(3)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build();
+        node.add_entry(encode(AnyEntry::V1(v1))).await.unwrap();
+        assert_eq!(node.read_entry(1, 3).await.unwrap().append_time(), None);
+    }
+
+    #[test]
+    fn test_quorum_config() {
+        assert!(QuorumConfig::new(3, 3, 2).is_ok());
+        assert!(QuorumConfig::new(3, 4, 2).is_err());
+        assert!(QuorumConfig::new(3, 2, 3).is_err());
+        assert!(QuorumConfig::new(3, 2, 0).is_err());
+
+        let config = QuorumConfig::new(5, 3, 2).unwrap();
+        assert_eq!(config.write_set(0), vec![0, 1, 2]);
+        assert_eq!(config.write_set(3), vec![3, 4, 0]);
+        assert_eq!(config.write_set(9), vec![4, 0, 1]);
+        // Every node stores the same share of the entries.
+        let mut counts = [0; 5];
+        for entry_id in 0..100 {
+            for index in config.write_set(entry_id) {
+                counts[index] += 1;
+            }
+        }
+        assert_eq!(counts, [60; 5]);
+    }
+}
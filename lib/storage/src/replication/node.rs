@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::entry::{self, AnyEntry, DecodeOptions, Entry};
+use crate::ledger::{self, LedgerStore};
+
+use super::{Error, Result};
+
+/// The `Node` is a storage node of an ensemble, which stores the entries of many ledgers.
+///
+/// A ledger is created on a node by the first request about it,
+/// so a node which never received an entry of a ledger can still be fenced and closed.
+#[async_trait]
+pub trait Node: Send + Sync {
+    /// Returns the id of the node, unique in the cluster.
+    fn id(&self) -> &str;
+
+    /// Add an encoded entry, resolved after the entry is durable on the node.
+    async fn add_entry(&self, entry: Bytes) -> Result<()>;
+
+    /// Add an encoded entry while recovering the ledger, it's accepted even if the ledger is fenced.
+    async fn recovery_add_entry(&self, entry: Bytes) -> Result<()>;
+
+    /// Read an entry stored on the node.
+    async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry>;
+
+    /// Returns the LAC of the ledger known by the node.
+    async fn read_lac(&self, log_id: i64) -> Result<i64>;
+
+    /// Fence the ledger on the node, returns the LAC known by the node.
+    async fn fence(&self, log_id: i64) -> Result<i64>;
+
+    /// Close the ledger on the node with the id of its last entry.
+    async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()>;
+
+    /// Returns the ids of the entries of the ledger stored on the node,
+    /// from `first_entry_id` to `last_entry_id` inclusive, in order.
+    async fn entry_ids(
+        &self,
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    ) -> Result<Vec<i64>>;
+}
+
+/// The `LocalNode` is an in-process node backed by a `LedgerStore`.
+///
+/// The added entries are decoded with its decode options, which only enforce the limits
+/// of the format by default, see `with_decode_options`.
+/// The node may record the time it stores the entries, see `with_append_time`.
+/// That time is local to the node, the replicas of an entry stamped by several nodes differ.
+pub struct LocalNode {
+    id: String,
+    store: LedgerStore,
+    decode_options: DecodeOptions,
+    append_time: bool,
+}
+
+impl LocalNode {
+    pub fn new(id: impl Into<String>, store: LedgerStore) -> Self {
+        Self {
+            id: id.into(),
+            store,
+            decode_options: DecodeOptions::default(),
+            append_time: false,
+        }
+    }
+
+    /// Set the limits of the added entries, an entry exceeding them is rejected before it's stored.
+    pub fn with_decode_options(mut self, decode_options: DecodeOptions) -> Self {
+        self.decode_options = decode_options;
+        self
+    }
+
+    /// Set the append time of the added V2 entries which don't have one to the time they're added.
+    /// The recovery adds keep the entries as they are, they were added before.
+    ///
+    /// The time is the clock of this node, so it's meant for the ledgers stored by a single node:
+    /// each replica of a V2 entry added to several nodes would get its own time and a reader
+    /// could see any of them. The `ReplicatedWriter` writes V1 entries, which are never stamped.
+    pub fn with_append_time(mut self, append_time: bool) -> Self {
+        self.append_time = append_time;
+        self
+    }
+
+    /// Returns the store of the node.
+    pub fn store(&self) -> &LedgerStore {
+        &self.store
+    }
+
+    async fn ensure_ledger(&self, log_id: i64) -> Result<()> {
+        match self.store.create_ledger(log_id).await {
+            Ok(()) | Err(ledger::Error::LedgerExists(_)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Node for LocalNode {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    async fn add_entry(&self, entry: Bytes) -> Result<()> {
+        let mut entry = entry::decode_with_options(entry, &self.decode_options)?;
+        if let AnyEntry::V2(entry) = &mut entry {
+            if self.append_time && entry.append_time().is_none() {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as i64);
+                entry.set_append_time(now);
+            }
+        }
+        self.ensure_ledger(entry.log_id()).await?;
+        Ok(self.store.add_entry(&entry).await?)
+    }
+
+    async fn recovery_add_entry(&self, entry: Bytes) -> Result<()> {
+        let entry = entry::decode_with_options(entry, &self.decode_options)?;
+        self.ensure_ledger(entry.log_id()).await?;
+        Ok(self.store.recovery_add_entry(&entry).await?)
+    }
+
+    async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry> {
+        Ok(self.store.read_entry(log_id, entry_id).await?)
+    }
+
+    async fn read_lac(&self, log_id: i64) -> Result<i64> {
+        Ok(self.store.read_lac(log_id).await?)
+    }
+
+    async fn fence(&self, log_id: i64) -> Result<i64> {
+        self.ensure_ledger(log_id).await?;
+        self.store.fence(log_id).await?;
+        Ok(self.store.read_lac(log_id).await?)
+    }
+
+    async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()> {
+        self.ensure_ledger(log_id).await?;
+        Ok(self.store.close(log_id, last_entry_id).await?)
+    }
+
+    async fn entry_ids(
+        &self,
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    ) -> Result<Vec<i64>> {
+        Ok(self
+            .store
+            .entry_ids(log_id, first_entry_id, last_entry_id)
+            .await?)
+    }
+}
+
+/// The `NodeSet` is the set of nodes available to the ensembles, keyed by their ids.
+#[derive(Clone, Default)]
+pub struct NodeSet {
+    nodes: BTreeMap<String, Arc<dyn Node>>,
+}
+
+impl NodeSet {
+    pub fn new(nodes: impl IntoIterator<Item = Arc<dyn Node>>) -> Self {
+        Self {
+            nodes: nodes
+                .into_iter()
+                .map(|node| (node.id().to_string(), node))
+                .collect(),
+        }
+    }
+
+    /// Add a node, it replaces the node with the same id.
+    pub fn insert(&mut self, node: Arc<dyn Node>) {
+        self.nodes.insert(node.id().to_string(), node);
+    }
+
+    /// Remove the node, returns it if it was in the set.
+    pub fn remove(&mut self, id: &str) -> Option<Arc<dyn Node>> {
+        self.nodes.remove(id)
+    }
+
+    /// Returns the node with the id.
+    pub fn get(&self, id: &str) -> Option<&Arc<dyn Node>> {
+        self.nodes.get(id)
+    }
+
+    /// Returns the number of nodes.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns true if there is no node.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the nodes of the ensemble listed by their ids.
+    /// Fails with `Error::Unavailable` if a node isn't in the set.
+    pub fn ensemble(&self, ids: &[String]) -> Result<Vec<Arc<dyn Node>>> {
+        ids.iter()
+            .map(|id| {
+                self.nodes
+                    .get(id)
+                    .cloned()
+                    .ok_or_else(|| Error::Unavailable(id.clone()))
+            })
+            .collect()
+    }
+
+    /// Pick `count` nodes which aren't excluded, in the order of their ids.
+    /// Fails with `Error::NotEnoughNodes` if there are fewer of them.
+    pub fn pick(&self, count: usize, exclude: &HashSet<String>) -> Result<Vec<Arc<dyn Node>>> {
+        let picked: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(id, _)| !exclude.contains(*id))
+            .map(|(_, node)| node.clone())
+            .take(count)
+            .collect();
+        if picked.len() < count {
+            return Err(Error::NotEnoughNodes {
+                available: picked.len(),
+                required: count,
+            });
+        }
+        Ok(picked)
+    }
+}
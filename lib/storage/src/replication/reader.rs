@@ -0,0 +1,528 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::encryption::{self, KeyProvider};
+use crate::entry::{AnyEntry, Cipher, Entry};
+use crate::metadata::{DigestType, LedgerMetadata};
+
+use super::{Digest, Error, Node, NodeSet, QuorumConfig, Result};
+
+/// The `ReaderOptions` is the configuration of a `ReplicatedReader` created from metadata.
+#[derive(Clone, Default)]
+pub struct ReaderOptions {
+    /// The password keying the HMAC digest, it's ignored by the other digest types.
+    pub password: Vec<u8>,
+    /// The provider unwrapping the data key of an encrypted ledger.
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
+}
+
+// The password and the key provider are left out.
+impl fmt::Debug for ReaderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReaderOptions").finish_non_exhaustive()
+    }
+}
+
+/// The `ReplicatedReader` reads the entries of a ledger replicated across an ensemble.
+///
+/// An entry is read from the first node of its write set which has it,
+/// so the reads survive the failure of up to `write_quorum - 1` nodes.
+/// The write set is taken from the ensemble which stored the entry, in the ensemble history of the ledger.
+///
+/// The digest of every entry read is verified, a replica failing it is skipped.
+/// The entries of an encrypted ledger are decrypted once verified.
+pub struct ReplicatedReader {
+    log_id: i64,
+    config: QuorumConfig,
+    digest: Digest,
+    cipher: Option<Cipher>,
+    // The ensembles keyed by the id of their first entry, the nodes which
+    // are no longer in the cluster are `None` and handled as failed.
+    ensembles: BTreeMap<i64, Vec<Option<Arc<dyn Node>>>>,
+}
+
+impl ReplicatedReader {
+    /// Create a reader of the ledger stored by a single ensemble, it must match the config.
+    pub fn new(log_id: i64, config: QuorumConfig, ensemble: Vec<Arc<dyn Node>>) -> Result<Self> {
+        if ensemble.len() != config.ensemble_size {
+            return Err(Error::InvalidQuorum {
+                ensemble_size: ensemble.len(),
+                write_quorum: config.write_quorum,
+                ack_quorum: config.ack_quorum,
+            });
+        }
+        Ok(Self {
+            log_id,
+            config,
+            digest: Digest::new(DigestType::None, &[]),
+            cipher: None,
+            ensembles: BTreeMap::from([(0, ensemble.into_iter().map(Some).collect())]),
+        })
+    }
+
+    /// Create a reader of the ledger following the ensemble history of its metadata,
+    /// the ids of the ensembles are resolved against the nodes.
+    /// Fails with `Error::KeyProviderRequired` if the ledger is encrypted and the options have no key provider.
+    pub async fn from_metadata(
+        log_id: i64,
+        metadata: &LedgerMetadata,
+        nodes: &NodeSet,
+        options: &ReaderOptions,
+    ) -> Result<Self> {
+        let cipher = match (&metadata.encryption, &options.key_provider) {
+            (None, _) => None,
+            (Some(_), None) => return Err(Error::KeyProviderRequired(log_id)),
+            (Some(wrapped), Some(key_provider)) => {
+                let data_key = key_provider
+                    .unwrap(wrapped)
+                    .await?
+                    .try_into()
+                    .map_err(|_| encryption::Error::InvalidKey(wrapped.key_id.clone()))?;
+                Some(Cipher::new(&data_key))
+            }
+        };
+        let ensembles = metadata
+            .ensembles
+            .iter()
+            .map(|(first_entry_id, ids)| {
+                let ensemble = ids.iter().map(|id| nodes.get(id).cloned()).collect();
+                (*first_entry_id, ensemble)
+            })
+            .collect();
+        Ok(Self {
+            log_id,
+            config: metadata.quorum,
+            digest: Digest::new(metadata.digest_type, &options.password),
+            cipher,
+            ensembles,
+        })
+    }
+
+    /// Returns the log id of the ledger.
+    pub fn log_id(&self) -> i64 {
+        self.log_id
+    }
+
+    /// Read the entry from the nodes of its write set.
+    /// Fails with `Error::DigestMismatch` if no replica is readable and one of them failed the digest,
+    /// or with the decryption error if one of them couldn't be decrypted.
+    pub async fn read_entry(&self, entry_id: i64) -> Result<AnyEntry> {
+        let ensemble = self.ensemble_at(entry_id);
+        let mut mismatch = None;
+        for index in self.config.write_set(entry_id) {
+            let Some(node) = &ensemble[index] else {
+                continue;
+            };
+            if let Ok(entry) = node.read_entry(self.log_id, entry_id).await {
+                match self
+                    .digest
+                    .verify(entry)
+                    .and_then(|entry| self.decrypt(entry))
+                {
+                    Ok(entry) => return Ok(entry),
+                    Err(e) => mismatch = Some(e),
+                }
+            }
+        }
+        Err(mismatch.unwrap_or(Error::EntryUnreadable {
+            log_id: self.log_id,
+            entry_id,
+        }))
+    }
+
+    /// Returns the highest LAC known by the nodes of the current ensemble.
+    /// The LAC is carried by the entries, so it lags one entry behind the writer until the ledger is closed.
+    pub async fn read_lac(&self) -> Result<i64> {
+        let (_, ensemble) = self
+            .ensembles
+            .last_key_value()
+            .expect("ensemble history is never empty");
+        let mut lac = None;
+        let mut error = None;
+        for node in ensemble {
+            let Some(node) = node else {
+                continue;
+            };
+            match node.read_lac(self.log_id).await {
+                Ok(node_lac) => lac = lac.max(Some(node_lac)),
+                Err(e) => error = Some(e),
+            }
+        }
+        match (lac, error) {
+            (Some(lac), _) => Ok(lac),
+            (None, Some(e)) => Err(e),
+            (None, None) => Err(Error::NotEnoughNodes {
+                available: 0,
+                required: 1,
+            }),
+        }
+    }
+
+    // Returns the entry decrypted, as it was before the writer encrypted it.
+    // Every entry of an encrypted ledger must be encrypted, a node could clear the attr
+    // to return a forged plaintext.
+    fn decrypt(&self, entry: AnyEntry) -> Result<AnyEntry> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.decrypt(&entry)?),
+            None if entry.attr().encrypted() => Err(Error::KeyProviderRequired(self.log_id)),
+            None => Ok(entry),
+        }
+    }
+
+    // Returns the ensemble which stored the entry.
+    fn ensemble_at(&self, entry_id: i64) -> &[Option<Arc<dyn Node>>] {
+        self.ensembles
+            .range(..=entry_id)
+            .next_back()
+            .or_else(|| self.ensembles.first_key_value())
+            .map(|(_, ensemble)| ensemble.as_slice())
+            .expect("ensemble history is never empty")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::FileKeyProvider;
+    use crate::entry::{self, Entry, Header};
+    use crate::metadata::{DigestType, MemoryMetadataStore, MetadataStore};
+    use crate::replication::tests::{flaky_nodes, local_nodes, DownNode};
+    use crate::replication::{LocalNode, ReplicatedWriter, WriterOptions};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+
+    // The `StrippingNode` clears the encrypted attr of the entries it reads,
+    // their CRC32C digest is computed again so it stays valid.
+    struct StrippingNode(Arc<LocalNode>);
+
+    #[async_trait]
+    impl Node for StrippingNode {
+        fn id(&self) -> &str {
+            self.0.id()
+        }
+
+        async fn add_entry(&self, entry: Bytes) -> Result<()> {
+            self.0.add_entry(entry).await
+        }
+
+        async fn recovery_add_entry(&self, entry: Bytes) -> Result<()> {
+            self.0.recovery_add_entry(entry).await
+        }
+
+        async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry> {
+            let entry = self.0.read_entry(log_id, entry_id).await?;
+            let (_, headers) = entry.raw_parts();
+            let stripped =
+                entry.with_raw_parts(entry.attr().with_encrypted(false), headers[1..].to_vec())?;
+            let AnyEntry::V1(stripped) = stripped else {
+                panic!("not an EntryV1");
+            };
+            let buf = Digest::new(DigestType::Crc32c, &[]).encode(&stripped)?;
+            Ok(entry::decode(buf)?)
+        }
+
+        async fn read_lac(&self, log_id: i64) -> Result<i64> {
+            self.0.read_lac(log_id).await
+        }
+
+        async fn fence(&self, log_id: i64) -> Result<i64> {
+            self.0.fence(log_id).await
+        }
+
+        async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()> {
+            self.0.close(log_id, last_entry_id).await
+        }
+
+        async fn entry_ids(
+            &self,
+            log_id: i64,
+            first_entry_id: i64,
+            last_entry_id: i64,
+        ) -> Result<Vec<i64>> {
+            self.0
+                .entry_ids(log_id, first_entry_id, last_entry_id)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 4).await;
+        let ensemble: Vec<Arc<dyn Node>> = nodes.iter().map(|n| n.clone() as _).collect();
+        let config = QuorumConfig::new(4, 2, 2).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, ensemble.clone()).unwrap();
+        for i in 0..8 {
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+        let reader = ReplicatedReader::new(1, config, ensemble.clone()).unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), 6);
+
+        // Any single node can fail.
+        for down in 0..4 {
+            let mut ensemble = ensemble.clone();
+            ensemble[down] = Arc::new(DownNode(format!("node-{}", down)));
+            let reader = ReplicatedReader::new(1, config, ensemble).unwrap();
+            for i in 0..8 {
+                let entry = reader.read_entry(i).await.unwrap();
+                assert_eq!(entry.value(), &Bytes::from(vec![i as u8]));
+            }
+        }
+
+        // The whole write set of the entry fails.
+        let mut ensemble = ensemble.clone();
+        ensemble[0] = Arc::new(DownNode("node-0".to_string()));
+        ensemble[1] = Arc::new(DownNode("node-1".to_string()));
+        let reader = ReplicatedReader::new(1, config, ensemble).unwrap();
+        assert!(matches!(
+            reader.read_entry(0).await,
+            Err(Error::EntryUnreadable {
+                log_id: 1,
+                entry_id: 0
+            })
+        ));
+
+        writer.close().await.unwrap();
+        let reader =
+            ReplicatedReader::new(1, config, nodes.iter().map(|n| n.clone() as _).collect())
+                .unwrap();
+        assert_eq!(reader.read_lac().await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_replicated_read_ensemble_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 5).await;
+        let mut node_set = NodeSet::new(nodes.iter().map(|n| n.clone() as Arc<dyn Node>));
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let mut writer = ReplicatedWriter::create(
+            1,
+            config,
+            node_set.clone(),
+            metadata_store.clone(),
+            WriterOptions::default(),
+        )
+        .await
+        .unwrap();
+        for i in 0..12 {
+            // Two ensemble changes, node 1 is replaced by node 3, then node 3 by node 4.
+            if i == 4 || i == 8 {
+                nodes[if i == 4 { 1 } else { 3 }].set_down(true);
+            }
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.ensembles.len(), 3);
+        assert_eq!(
+            metadata.current_ensemble().1,
+            ["node-0", "node-4", "node-2"]
+        );
+
+        // The failed nodes left the cluster, the entries are read from the other nodes of their write sets.
+        node_set.remove("node-1");
+        node_set.remove("node-3");
+        let reader =
+            ReplicatedReader::from_metadata(1, &metadata, &node_set, &ReaderOptions::default())
+                .await
+                .unwrap();
+        for i in 0..12 {
+            let entry = reader.read_entry(i).await.unwrap();
+            assert_eq!(entry.value(), &Bytes::from(vec![i as u8]));
+        }
+        assert_eq!(reader.read_lac().await.unwrap(), 10);
+
+        // Only the last replacement is left, it holds the entries striped to it since the last change.
+        let node_set = NodeSet::new([nodes[4].clone() as Arc<dyn Node>]);
+        let reader =
+            ReplicatedReader::from_metadata(1, &metadata, &node_set, &ReaderOptions::default())
+                .await
+                .unwrap();
+        for i in 0..12 {
+            assert_eq!(
+                reader.read_entry(i).await.is_ok(),
+                i >= 8 && config.write_set(i).contains(&1)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_read_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 3).await;
+        let node_set = NodeSet::new(nodes.iter().map(|n| n.clone() as Arc<dyn Node>));
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let options = WriterOptions {
+            digest_type: DigestType::HmacSha256,
+            password: b"secret".to_vec(),
+            ..Default::default()
+        };
+        let mut writer = ReplicatedWriter::create(
+            1,
+            QuorumConfig::new(3, 2, 2).unwrap(),
+            node_set.clone(),
+            metadata_store.clone(),
+            options,
+        )
+        .await
+        .unwrap();
+        for i in 0..3 {
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.digest_type, DigestType::HmacSha256);
+
+        let reader = ReplicatedReader::from_metadata(
+            1,
+            &metadata,
+            &node_set,
+            &ReaderOptions {
+                password: b"secret".to_vec(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        for i in 0..3 {
+            let entry = reader.read_entry(i).await.unwrap();
+            assert_eq!(entry.value(), &Bytes::from(vec![i as u8]));
+        }
+        let reader = ReplicatedReader::from_metadata(
+            1,
+            &metadata,
+            &node_set,
+            &ReaderOptions {
+                password: b"wrong".to_vec(),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            reader.read_entry(1).await,
+            Err(Error::DigestMismatch {
+                log_id: 1,
+                entry_id: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replicated_read_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 3).await;
+        let node_set = NodeSet::new(nodes.iter().map(|n| n.clone() as Arc<dyn Node>));
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let key_dir = tempfile::tempdir().unwrap();
+        FileKeyProvider::create_key(key_dir.path(), "k1")
+            .await
+            .unwrap();
+        let key_provider: Arc<dyn KeyProvider> =
+            Arc::new(FileKeyProvider::open(key_dir.path(), "k1").await.unwrap());
+        let options = WriterOptions {
+            digest_type: DigestType::Crc32c,
+            key_provider: Some(key_provider.clone()),
+            ..Default::default()
+        };
+        let mut writer = ReplicatedWriter::create(
+            1,
+            QuorumConfig::new(3, 3, 2).unwrap(),
+            node_set.clone(),
+            metadata_store.clone(),
+            options,
+        )
+        .await
+        .unwrap();
+        let header = Header::new(Bytes::from_static(b"trace"), Bytes::from_static(b"t-1"));
+        for i in 0..3 {
+            writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from(format!("secret-{}", i)),
+                    vec![header.clone()],
+                )
+                .await
+                .unwrap();
+        }
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.encryption.as_ref().unwrap().key_id, "k1");
+
+        // The nodes only store the ciphertext, the common header stays in plaintext.
+        let stored = nodes[0].node.store().read_entry(1, 2).await.unwrap();
+        assert!(stored.attr().encrypted());
+        assert_eq!((stored.log_id(), stored.entry_id()), (1, 2));
+        let mut buf = vec![0; stored.binary_size()];
+        stored.read_at(&mut buf, 0);
+        assert!(!buf.windows(6).any(|w| w == b"secret" || w[..3] == *b"t-1"));
+
+        let reader = ReplicatedReader::from_metadata(
+            1,
+            &metadata,
+            &node_set,
+            &ReaderOptions {
+                key_provider: Some(key_provider.clone()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        for i in 0..3 {
+            let entry = reader.read_entry(i).await.unwrap();
+            assert!(!entry.attr().encrypted());
+            assert_eq!(entry.key(), &Bytes::from_static(b"key"));
+            assert_eq!(entry.value(), &Bytes::from(format!("secret-{}", i)));
+            assert_eq!(entry.headers().len(), 1);
+            assert_eq!(entry.headers()[0].value(), header.value());
+        }
+
+        assert!(matches!(
+            ReplicatedReader::from_metadata(1, &metadata, &node_set, &ReaderOptions::default())
+                .await,
+            Err(Error::KeyProviderRequired(1))
+        ));
+        let unencrypted = ReplicatedReader::new(
+            1,
+            metadata.quorum,
+            nodes.iter().map(|n| n.clone() as _).collect(),
+        )
+        .unwrap();
+        assert!(matches!(
+            unencrypted.read_entry(0).await,
+            Err(Error::KeyProviderRequired(1))
+        ));
+
+        // An entry of the encrypted ledger returned without the encrypted attr is rejected.
+        let stripping = NodeSet::new(
+            nodes
+                .iter()
+                .map(|n| Arc::new(StrippingNode(n.node.clone())) as Arc<dyn Node>),
+        );
+        let reader = ReplicatedReader::from_metadata(
+            1,
+            &metadata,
+            &stripping,
+            &ReaderOptions {
+                key_provider: Some(key_provider),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            reader.read_entry(0).await,
+            Err(Error::Entry(entry::Error::Decryption(
+                "entry isn't encrypted"
+            )))
+        ));
+    }
+}
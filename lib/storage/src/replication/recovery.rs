@@ -0,0 +1,532 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+
+use crate::entry::{AnyEntry, Entry};
+use crate::ledger;
+use crate::metadata::{LedgerMetadata, LedgerStatus, MetadataStore, Versioned};
+
+use super::writer::{close_on_ensemble, replicate_entry};
+use super::{Error, Node, NodeSet, QuorumConfig, Result};
+
+/// Recover the ledger of a crashed writer and close it, returns the id of its last entry.
+///
+/// The metadata is moved to `InRecovery` first, so the stale writer can't change the ensemble
+/// any more, then the ledger is fenced on every node of the current ensemble, so the stale writer
+/// can't get any more entry acknowledged. Reading forward from the highest LAC returned by the fences,
+/// every entry found on a node of its write set, in the ensemble which stored it, is re-replicated
+/// to its write set, and the first entry missing from enough nodes to never have been acknowledged
+/// ends the ledger. The metadata is closed with the id of the last entry.
+///
+/// The ids of the ensembles are resolved against the nodes, the missing nodes are handled as down.
+/// The nodes are visited in the ensemble order, so the recovery of the same ledger
+/// always ends at the same entry, and recovering a closed ledger changes nothing.
+pub async fn recover_ledger(
+    log_id: i64,
+    metadata_store: &dyn MetadataStore,
+    nodes: &NodeSet,
+) -> Result<i64> {
+    let mut metadata = metadata_store.read(log_id).await?;
+    match metadata.value.status {
+        LedgerStatus::Closed => return Ok(metadata.value.last_entry_id),
+        LedgerStatus::InRecovery => {}
+        LedgerStatus::Open => {
+            metadata = update_status(log_id, metadata_store, metadata, |metadata| {
+                metadata.status = LedgerStatus::InRecovery;
+            })
+            .await?;
+        }
+    }
+    let config = metadata.value.quorum;
+    let ensembles: BTreeMap<i64, Vec<Arc<dyn Node>>> = metadata
+        .value
+        .ensembles
+        .iter()
+        .map(|(first_entry_id, ids)| {
+            let ensemble = ids
+                .iter()
+                .map(|id| match nodes.get(id) {
+                    Some(node) => node.clone(),
+                    None => Arc::new(LostNode(id.clone())) as _,
+                })
+                .collect();
+            (*first_entry_id, ensemble)
+        })
+        .collect();
+    let ensemble_at = |entry_id: i64| {
+        ensembles
+            .range(..=entry_id)
+            .next_back()
+            .or_else(|| ensembles.first_key_value())
+            .map(|(_, ensemble)| ensemble.as_slice())
+            .expect("ensemble history is never empty")
+    };
+    let (_, current) = ensembles
+        .last_key_value()
+        .expect("ensemble history is never empty");
+    if current.len() != config.ensemble_size {
+        return Err(Error::InvalidQuorum {
+            ensemble_size: current.len(),
+            write_quorum: config.write_quorum,
+            ack_quorum: config.ack_quorum,
+        });
+    }
+
+    // Once at most `ack_quorum - 1` nodes are left unfenced, no ack quorum can be formed.
+    // Only the current ensemble gets new entries.
+    let required = config.ensemble_size - config.ack_quorum + 1;
+    let mut fenced = 0;
+    let mut lac = None;
+    for node in current {
+        if let Ok(node_lac) = node.fence(log_id).await {
+            fenced += 1;
+            lac = lac.max(Some(node_lac));
+        }
+    }
+    let Some(lac) = lac.filter(|_| fenced >= required) else {
+        return Err(Error::NotEnoughFenced {
+            log_id,
+            fenced,
+            required,
+        });
+    };
+
+    // The entries up to the LAC are acknowledged, the ones after it may be anywhere.
+    let mut last_entry_id = lac;
+    loop {
+        let entry_id = last_entry_id + 1;
+        let ensemble = ensemble_at(entry_id);
+        let Some(entry) = read_for_recovery(log_id, entry_id, config, ensemble).await? else {
+            break;
+        };
+        let mut buf = BytesMut::with_capacity(entry.binary_size());
+        entry.encode(&mut buf)?;
+        replicate_entry(config, ensemble, entry_id, buf.freeze(), true, None, None).await?;
+        last_entry_id = entry_id;
+    }
+
+    close_on_ensemble(log_id, last_entry_id, current, config.ack_quorum).await?;
+    update_status(log_id, metadata_store, metadata, |metadata| {
+        metadata.status = LedgerStatus::Closed;
+        metadata.last_entry_id = last_entry_id;
+    })
+    .await?;
+    Ok(last_entry_id)
+}
+
+// Update the metadata by compare-and-swap, any concurrent update fails the recovery.
+async fn update_status(
+    log_id: i64,
+    metadata_store: &dyn MetadataStore,
+    metadata: Versioned<LedgerMetadata>,
+    update: impl FnOnce(&mut LedgerMetadata),
+) -> Result<Versioned<LedgerMetadata>> {
+    let mut value = metadata.value;
+    update(&mut value);
+    let version = metadata_store
+        .compare_and_set(log_id, metadata.version, value.clone())
+        .await?;
+    Ok(Versioned { value, version })
+}
+
+/// Read the entry from its write set, returns `None` if it's missing from so many nodes
+/// that it can't have been acknowledged.
+async fn read_for_recovery(
+    log_id: i64,
+    entry_id: i64,
+    config: QuorumConfig,
+    ensemble: &[Arc<dyn Node>],
+) -> Result<Option<AnyEntry>> {
+    let mut missing = 0;
+    for index in config.write_set(entry_id) {
+        match ensemble[index].read_entry(log_id, entry_id).await {
+            Ok(entry) => return Ok(Some(entry)),
+            Err(Error::Ledger(
+                ledger::Error::EntryNotFound { .. } | ledger::Error::LedgerNotFound(_),
+            )) => missing += 1,
+            Err(_) => {}
+        }
+    }
+    // An acknowledged entry is stored by at least `ack_quorum` nodes of its write set.
+    if missing > config.write_quorum - config.ack_quorum {
+        Ok(None)
+    } else {
+        Err(Error::RecoveryIncomplete { log_id, entry_id })
+    }
+}
+
+// A node of the ensemble which is no longer in the cluster, it fails every request.
+struct LostNode(String);
+
+#[async_trait]
+impl Node for LostNode {
+    fn id(&self) -> &str {
+        &self.0
+    }
+
+    async fn add_entry(&self, _entry: Bytes) -> Result<()> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+
+    async fn recovery_add_entry(&self, _entry: Bytes) -> Result<()> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+
+    async fn read_entry(&self, _log_id: i64, _entry_id: i64) -> Result<AnyEntry> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+
+    async fn read_lac(&self, _log_id: i64) -> Result<i64> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+
+    async fn fence(&self, _log_id: i64) -> Result<i64> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+
+    async fn close(&self, _log_id: i64, _last_entry_id: i64) -> Result<()> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+
+    async fn entry_ids(
+        &self,
+        _log_id: i64,
+        _first_entry_id: i64,
+        _last_entry_id: i64,
+    ) -> Result<Vec<i64>> {
+        Err(Error::Unavailable(self.0.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::ledger::LedgerState;
+    use crate::metadata::MemoryMetadataStore;
+    use crate::replication::tests::{local_nodes, DownNode};
+    use crate::replication::{LocalNode, ReaderOptions, ReplicatedReader, ReplicatedWriter};
+
+    // Returns a metadata store with the open ledger stored by a single ensemble.
+    async fn metadata_store(
+        config: QuorumConfig,
+        ensemble: &[Arc<dyn Node>],
+    ) -> MemoryMetadataStore {
+        let ids = ensemble.iter().map(|node| node.id().to_string()).collect();
+        let store = MemoryMetadataStore::new();
+        store
+            .create(1, LedgerMetadata::new(ids, config))
+            .await
+            .unwrap();
+        store
+    }
+
+    /// The `FaultyNode` stops receiving the entries once the writer used up its budget of adds,
+    /// as if the writer died in the middle of sending an entry to its write set.
+    /// An unreadable node fails all reads, as if it went down after being fenced.
+    struct FaultyNode {
+        node: Arc<LocalNode>,
+        budget: Arc<AtomicUsize>,
+        readable: bool,
+    }
+
+    #[async_trait]
+    impl Node for FaultyNode {
+        fn id(&self) -> &str {
+            self.node.id()
+        }
+
+        async fn add_entry(&self, entry: Bytes) -> Result<()> {
+            let taken = self
+                .budget
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |b| b.checked_sub(1));
+            match taken {
+                Ok(_) => self.node.add_entry(entry).await,
+                Err(_) => Err(Error::Unavailable(self.id().to_string())),
+            }
+        }
+
+        async fn recovery_add_entry(&self, entry: Bytes) -> Result<()> {
+            self.node.recovery_add_entry(entry).await
+        }
+
+        async fn read_entry(&self, log_id: i64, entry_id: i64) -> Result<AnyEntry> {
+            if !self.readable {
+                return Err(Error::Unavailable(self.id().to_string()));
+            }
+            self.node.read_entry(log_id, entry_id).await
+        }
+
+        async fn read_lac(&self, log_id: i64) -> Result<i64> {
+            self.node.read_lac(log_id).await
+        }
+
+        async fn fence(&self, log_id: i64) -> Result<i64> {
+            self.node.fence(log_id).await
+        }
+
+        async fn close(&self, log_id: i64, last_entry_id: i64) -> Result<()> {
+            self.node.close(log_id, last_entry_id).await
+        }
+
+        async fn entry_ids(
+            &self,
+            log_id: i64,
+            first_entry_id: i64,
+            last_entry_id: i64,
+        ) -> Result<Vec<i64>> {
+            self.node
+                .entry_ids(log_id, first_entry_id, last_entry_id)
+                .await
+        }
+    }
+
+    // A xorshift generator, so every run crashes the writer at the same points.
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_crashed_writer() {
+        let configs = [(3, 3, 2), (3, 2, 2), (4, 3, 2), (5, 3, 3), (3, 3, 1)];
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for round in 0..40 {
+            let (ensemble_size, write_quorum, ack_quorum) = configs[round % configs.len()];
+            let config = QuorumConfig::new(ensemble_size, write_quorum, ack_quorum).unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            let nodes = local_nodes(dir.path(), ensemble_size).await;
+            let ensemble: Vec<Arc<dyn Node>> = nodes.iter().map(|n| n.clone() as _).collect();
+            let metadata_store = metadata_store(config, &ensemble).await;
+            let node_set = NodeSet::new(ensemble.clone());
+
+            // The writer dies after a random number of adds.
+            let budget = Arc::new(AtomicUsize::new(random.next(12 * write_quorum)));
+            let crashing: Vec<Arc<dyn Node>> = nodes
+                .iter()
+                .map(|node| {
+                    Arc::new(FaultyNode {
+                        node: node.clone(),
+                        budget: budget.clone(),
+                        readable: true,
+                    }) as _
+                })
+                .collect();
+            let mut writer = ReplicatedWriter::new(1, config, crashing).unwrap();
+            let mut attempted = -1;
+            for i in 0..12 {
+                attempted = i;
+                let appended = writer
+                    .append(
+                        Bytes::from_static(b"key"),
+                        Bytes::from(vec![i as u8]),
+                        vec![],
+                    )
+                    .await;
+                if appended.is_err() {
+                    break;
+                }
+            }
+            let acknowledged = writer.last_entry_id();
+
+            let last_entry_id = recover_ledger(1, &metadata_store, &node_set).await.unwrap();
+            assert!(
+                acknowledged <= last_entry_id && last_entry_id <= attempted,
+                "round {}: acknowledged {}, recovered {}, attempted {}",
+                round,
+                acknowledged,
+                last_entry_id,
+                attempted
+            );
+
+            // The recovered entries are fully replicated and readable.
+            for entry_id in 0..=last_entry_id {
+                for index in config.write_set(entry_id) {
+                    let entry = nodes[index].store().read_entry(1, entry_id).await.unwrap();
+                    assert_eq!(entry.value(), &Bytes::from(vec![entry_id as u8]));
+                }
+            }
+            for node in &nodes {
+                assert_eq!(
+                    node.store().state(1).await.unwrap(),
+                    LedgerState::Closed { last_entry_id }
+                );
+            }
+            let reader = ReplicatedReader::new(1, config, ensemble.clone()).unwrap();
+            assert_eq!(reader.read_lac().await.unwrap(), last_entry_id);
+
+            // The stale writer can't append and the recovery is deterministic.
+            budget.store(usize::MAX, Ordering::SeqCst);
+            assert!(writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from_static(b"stale"),
+                    vec![]
+                )
+                .await
+                .is_err());
+            let metadata = metadata_store.read(1).await.unwrap().value;
+            assert_eq!(metadata.status, LedgerStatus::Closed);
+            assert_eq!(metadata.last_entry_id, last_entry_id);
+            assert_eq!(
+                recover_ledger(1, &metadata_store, &node_set).await.unwrap(),
+                last_entry_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_with_failed_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 3).await;
+        let ensemble: Vec<Arc<dyn Node>> = nodes.iter().map(|n| n.clone() as _).collect();
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, ensemble.clone()).unwrap();
+        for i in 0..3 {
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+
+        // Entry 3 only reached the first node.
+        let entry = crate::entry::BuilderV1::new()
+            .log_id(1)
+            .entry_id(3)
+            .last_confirm_id(2)
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![3]))
+            .build();
+        nodes[0].store().add_entry(&entry).await.unwrap();
+        let metadata_store = metadata_store(config, &ensemble).await;
+
+        // Two nodes are down, the ledger can't be fenced and is left in recovery.
+        let mut degraded = ensemble.clone();
+        degraded[1] = Arc::new(DownNode("node-1".to_string()));
+        degraded[2] = Arc::new(DownNode("node-2".to_string()));
+        assert!(matches!(
+            recover_ledger(1, &metadata_store, &NodeSet::new(degraded)).await,
+            Err(Error::NotEnoughFenced {
+                fenced: 1,
+                required: 2,
+                ..
+            })
+        ));
+        assert_eq!(
+            metadata_store.read(1).await.unwrap().value.status,
+            LedgerStatus::InRecovery
+        );
+
+        // One node is down, entry 3 is found on the first node and re-replicated.
+        // The node missing from the cluster is handled as down.
+        let node_set = NodeSet::new(ensemble[..2].iter().cloned());
+        assert_eq!(
+            recover_ledger(1, &metadata_store, &node_set).await.unwrap(),
+            3
+        );
+        assert_eq!(
+            nodes[1].store().read_entry(1, 3).await.unwrap().value(),
+            &Bytes::from(vec![3])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recover_incomplete() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 3).await;
+        let ensemble: Vec<Arc<dyn Node>> = nodes.iter().map(|n| n.clone() as _).collect();
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, ensemble.clone()).unwrap();
+        writer
+            .append(Bytes::from_static(b"key"), Bytes::from_static(b"v"), vec![])
+            .await
+            .unwrap();
+
+        // Entry 1 is missing from the second node, the first one is down and the third one
+        // fails after being fenced, the entry may have been acknowledged by them.
+        let mut degraded = ensemble.clone();
+        degraded[0] = Arc::new(DownNode("node-0".to_string()));
+        degraded[2] = Arc::new(FaultyNode {
+            node: nodes[2].clone(),
+            budget: Arc::new(AtomicUsize::new(0)),
+            readable: false,
+        });
+        let metadata_store = metadata_store(config, &ensemble).await;
+        assert!(matches!(
+            recover_ledger(1, &metadata_store, &NodeSet::new(degraded)).await,
+            Err(Error::RecoveryIncomplete {
+                log_id: 1,
+                entry_id: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_recover_after_ensemble_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 5).await;
+        let all: Vec<Arc<dyn Node>> = nodes.iter().map(|n| n.clone() as _).collect();
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, all[..3].to_vec()).unwrap();
+        for i in 0..5 {
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+
+        // The last two nodes of the ensemble are replaced from entry 5, then the writer crashes.
+        // The LAC known by the new ensemble is 2, entries 3 and 4 are only
+        // on the nodes of the previous ensemble.
+        let metadata_store = metadata_store(config, &all[..3]).await;
+        let mut metadata = metadata_store.read(1).await.unwrap();
+        metadata.value.ensembles.insert(
+            5,
+            vec![
+                "node-0".to_string(),
+                "node-3".to_string(),
+                "node-4".to_string(),
+            ],
+        );
+        metadata_store
+            .compare_and_set(1, metadata.version, metadata.value)
+            .await
+            .unwrap();
+
+        let node_set = NodeSet::new(all.clone());
+        assert_eq!(
+            recover_ledger(1, &metadata_store, &node_set).await.unwrap(),
+            4
+        );
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.status, LedgerStatus::Closed);
+        assert_eq!(metadata.last_entry_id, 4);
+        for index in [0, 3, 4] {
+            assert_eq!(
+                nodes[index].store().state(1).await.unwrap(),
+                LedgerState::Closed { last_entry_id: 4 }
+            );
+        }
+        let reader =
+            ReplicatedReader::from_metadata(1, &metadata, &node_set, &ReaderOptions::default())
+                .await
+                .unwrap();
+        for entry_id in 0..5 {
+            assert_eq!(
+                reader.read_entry(entry_id).await.unwrap().value(),
+                &Bytes::from(vec![entry_id as u8])
+            );
+        }
+    }
+}
@@ -0,0 +1,1044 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use crate::encryption::{self, KeyProvider};
+use crate::entry::{AnyEntry, BuilderV1, Cipher, Header};
+use crate::ledger;
+use crate::metadata::{self, DigestType, LedgerMetadata, LedgerStatus, MetadataStore, Versioned};
+use crate::segment::INVALID_ENTRY_ID;
+
+use super::{Digest, Error, Node, NodeSet, QuorumConfig, Result};
+
+/// The `WriterOptions` is the configuration of a `ReplicatedWriter`.
+#[derive(Clone)]
+pub struct WriterOptions {
+    /// The time a node has to store an entry, a slower node is handled as failed.
+    pub add_timeout: Option<Duration>,
+    /// The max number of ensemble changes while appending a single entry.
+    pub max_ensemble_changes: usize,
+    /// The digest of the entries of a new ledger, recorded in its metadata.
+    pub digest_type: DigestType,
+    /// The password keying the HMAC digest, the readers need the same one.
+    pub password: Vec<u8>,
+    /// The provider wrapping the data key of a new ledger, its entries are stored in plaintext if `None`.
+    pub key_provider: Option<Arc<dyn KeyProvider>>,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            add_timeout: Some(Duration::from_secs(10)),
+            max_ensemble_changes: 3,
+            digest_type: DigestType::None,
+            password: Vec::new(),
+            key_provider: None,
+        }
+    }
+}
+
+// The password and the key provider are left out.
+impl fmt::Debug for WriterOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriterOptions")
+            .field("add_timeout", &self.add_timeout)
+            .field("max_ensemble_changes", &self.max_ensemble_changes)
+            .field("digest_type", &self.digest_type)
+            .field("encrypted", &self.key_provider.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The `ReplicatedWriter` is the single writer of a ledger replicated across an ensemble.
+///
+/// Each entry is sent to its write set concurrently and acknowledged once the ack quorum
+/// has stored it, the remaining writes of the write set carry on in the background.
+///
+/// The writer of a ledger created with `create` replaces the nodes which fail or time out:
+/// a new ensemble starting at the first entry a failed node may not have stored is recorded
+/// in the metadata, the entries since then are replayed to the replacements
+/// and the unacknowledged entry is sent again to the new write set.
+///
+/// The entries are V1 entries, they carry no append time, so a node stamping the V2 entries
+/// it stores leaves the replicas identical, see `LocalNode::with_append_time`.
+///
+/// The entries of a ledger created with a key provider are encrypted by a data key
+/// generated for the ledger, wrapped in its metadata.
+///
+/// An entry which isn't acknowledged may still be stored by some nodes, so once an append
+/// fails the writer rejects the following appends, the ledger must be recovered.
+pub struct ReplicatedWriter {
+    log_id: i64,
+    config: QuorumConfig,
+    ensemble: Vec<Arc<dyn Node>>,
+    options: WriterOptions,
+    digest: Digest,
+    cipher: Option<Cipher>,
+    last_entry_id: i64,
+    last_confirm_id: i64,
+    ensemble_change: Option<EnsembleChange>,
+    failed: bool,
+}
+
+// The state needed to change the ensemble of a ledger with metadata.
+struct EnsembleChange {
+    nodes: NodeSet,
+    metadata_store: Arc<dyn MetadataStore>,
+    metadata: Versioned<LedgerMetadata>,
+    // The ids of the nodes which failed a write, they are never picked again.
+    failed: HashSet<String>,
+    // The acknowledged entries since the first one not yet stored by all the nodes of its write set.
+    pending: VecDeque<PendingEntry>,
+    // Reports the outcomes of the writes, including the ones completing after the ack quorum.
+    outcomes_tx: mpsc::UnboundedSender<AddOutcome>,
+    outcomes_rx: mpsc::UnboundedReceiver<AddOutcome>,
+}
+
+// An acknowledged entry with the ids of the nodes of its write set which haven't stored it yet.
+struct PendingEntry {
+    entry_id: i64,
+    entry: Bytes,
+    unstored: HashSet<String>,
+}
+
+// The outcome of the write of an entry to a node.
+pub(super) struct AddOutcome {
+    entry_id: i64,
+    node_id: String,
+    stored: bool,
+}
+
+impl ReplicatedWriter {
+    /// Create the writer of a new ledger, the ensemble must match the config.
+    /// The ensemble is never changed since the ledger has no metadata.
+    pub fn new(log_id: i64, config: QuorumConfig, ensemble: Vec<Arc<dyn Node>>) -> Result<Self> {
+        if ensemble.len() != config.ensemble_size {
+            return Err(Error::InvalidQuorum {
+                ensemble_size: ensemble.len(),
+                write_quorum: config.write_quorum,
+                ack_quorum: config.ack_quorum,
+            });
+        }
+        Ok(Self {
+            log_id,
+            config,
+            ensemble,
+            options: WriterOptions::default(),
+            digest: Digest::new(DigestType::None, &[]),
+            cipher: None,
+            last_entry_id: INVALID_ENTRY_ID,
+            last_confirm_id: INVALID_ENTRY_ID,
+            ensemble_change: None,
+            failed: false,
+        })
+    }
+
+    /// Create a new ledger with its metadata, the ensemble is picked from the nodes.
+    /// Fails with `metadata::Error::LedgerExists` if the ledger already has metadata.
+    pub async fn create(
+        log_id: i64,
+        config: QuorumConfig,
+        nodes: NodeSet,
+        metadata_store: Arc<dyn MetadataStore>,
+        options: WriterOptions,
+    ) -> Result<Self> {
+        let ensemble = nodes.pick(config.ensemble_size, &HashSet::new())?;
+        let mut metadata = LedgerMetadata::new(ids_of(&ensemble), config);
+        metadata.digest_type = options.digest_type;
+        let mut cipher = None;
+        if let Some(key_provider) = &options.key_provider {
+            let data_key = encryption::generate_data_key()?;
+            metadata.encryption = Some(key_provider.wrap(&data_key).await?);
+            cipher = Some(Cipher::new(&data_key));
+        }
+        let version = metadata_store.create(log_id, metadata.clone()).await?;
+        let (outcomes_tx, outcomes_rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            log_id,
+            config,
+            ensemble,
+            digest: Digest::new(options.digest_type, &options.password),
+            cipher,
+            options,
+            last_entry_id: INVALID_ENTRY_ID,
+            last_confirm_id: INVALID_ENTRY_ID,
+            ensemble_change: Some(EnsembleChange {
+                nodes,
+                metadata_store,
+                metadata: Versioned {
+                    value: metadata,
+                    version,
+                },
+                failed: HashSet::new(),
+                pending: VecDeque::new(),
+                outcomes_tx,
+                outcomes_rx,
+            }),
+            failed: false,
+        })
+    }
+
+    /// Returns the log id of the ledger.
+    pub fn log_id(&self) -> i64 {
+        self.log_id
+    }
+
+    /// Returns the quorum config of the ledger.
+    pub fn config(&self) -> QuorumConfig {
+        self.config
+    }
+
+    /// Returns the nodes of the current ensemble.
+    pub fn ensemble(&self) -> &[Arc<dyn Node>] {
+        &self.ensemble
+    }
+
+    /// Returns the metadata of the ledger as last updated by the writer, if it has any.
+    pub fn metadata(&self) -> Option<&Versioned<LedgerMetadata>> {
+        self.ensemble_change.as_ref().map(|change| &change.metadata)
+    }
+
+    /// Returns the id of the last appended entry.
+    pub fn last_entry_id(&self) -> i64 {
+        self.last_entry_id
+    }
+
+    /// Returns the id of the last acknowledged entry.
+    pub fn last_confirm_id(&self) -> i64 {
+        self.last_confirm_id
+    }
+
+    /// Append an entry, returns its entry id once the ack quorum has stored it.
+    /// Fails with `ledger::Error::Fenced` if a node of the write set is fenced,
+    /// and with `Error::WriterFailed` once a previous append failed.
+    pub async fn append(&mut self, key: Bytes, value: Bytes, headers: Vec<Header>) -> Result<i64> {
+        if self.failed {
+            return Err(Error::WriterFailed(self.log_id));
+        }
+        let entry_id = self.last_entry_id + 1;
+        let mut builder = BuilderV1::new()
+            .log_id(self.log_id)
+            .entry_id(entry_id)
+            .last_confirm_id(self.last_confirm_id)
+            .kv(key, value);
+        for header in headers {
+            builder = builder.header(header);
+        }
+        let mut entry = builder.try_build()?;
+        if let Some(cipher) = &self.cipher {
+            entry = match cipher.encrypt(&AnyEntry::V1(entry))? {
+                AnyEntry::V1(entry) => entry,
+                AnyEntry::V2(_) => unreachable!("the cipher keeps the version of the entry"),
+            };
+        }
+        let entry = self.digest.encode(&entry)?;
+        if let Err(e) = self.replicate(entry_id, entry).await {
+            self.failed = true;
+            return Err(e);
+        }
+        self.last_entry_id = entry_id;
+        self.last_confirm_id = entry_id;
+        Ok(entry_id)
+    }
+
+    // Send the entry to its write set until the ack quorum stores it,
+    // the ensemble is changed on the way if nodes failed.
+    async fn replicate(&mut self, entry_id: i64, entry: Bytes) -> Result<()> {
+        // The nodes which failed the writes of the previous entries are replaced
+        // before this entry, the first one which isn't acknowledged.
+        self.change_ensemble(entry_id).await?;
+        let mut changes = 0;
+        loop {
+            let outcomes = self
+                .ensemble_change
+                .as_ref()
+                .map(|change| change.outcomes_tx.clone());
+            let result = replicate_entry(
+                self.config,
+                &self.ensemble,
+                entry_id,
+                entry.clone(),
+                false,
+                self.options.add_timeout,
+                outcomes,
+            )
+            .await;
+            match result {
+                Err(Error::NotEnoughAcks { .. }) if changes < self.options.max_ensemble_changes => {
+                    // The entry is sent again to the new write set, the nodes which
+                    // already stored it accept it again.
+                    if !self.change_ensemble(entry_id).await? {
+                        result?;
+                    }
+                    changes += 1;
+                }
+                result => {
+                    result?;
+                    if let Some(change) = self.ensemble_change.as_mut() {
+                        let unstored = self
+                            .config
+                            .write_set(entry_id)
+                            .into_iter()
+                            .map(|index| self.ensemble[index].id().to_string())
+                            .collect();
+                        change.pending.push_back(PendingEntry {
+                            entry_id,
+                            entry,
+                            unstored,
+                        });
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Close the ledger on the nodes of the ensemble, returns the id of its last entry.
+    /// At least the ack quorum of the nodes must close it, then the metadata is closed.
+    /// Fails with `Error::WriterFailed` once an append failed.
+    pub async fn close(self) -> Result<i64> {
+        if self.failed {
+            return Err(Error::WriterFailed(self.log_id));
+        }
+        close_on_ensemble(
+            self.log_id,
+            self.last_entry_id,
+            &self.ensemble,
+            self.config.ack_quorum,
+        )
+        .await?;
+        if let Some(mut change) = self.ensemble_change {
+            change
+                .update_metadata(self.log_id, |metadata| {
+                    metadata.status = LedgerStatus::Closed;
+                    metadata.last_entry_id = self.last_entry_id;
+                })
+                .await?;
+        }
+        Ok(self.last_entry_id)
+    }
+
+    // Replace the failed nodes of the ensemble before the entry, returns false if no node failed
+    // or there are not enough nodes to replace them. The new ensemble starts at the first entry
+    // of the current one a failed node hasn't stored, the entries since then are replayed to
+    // the replacements. The failures of the nodes which are no longer in the ensemble are ignored.
+    async fn change_ensemble(&mut self, entry_id: i64) -> Result<bool> {
+        let Some(change) = self.ensemble_change.as_mut() else {
+            return Ok(false);
+        };
+        change.apply_outcomes();
+        let failed: Vec<usize> = (0..self.ensemble.len())
+            .filter(|index| change.failed.contains(self.ensemble[*index].id()))
+            .collect();
+        if failed.is_empty() {
+            return Ok(false);
+        }
+        let mut exclude = change.failed.clone();
+        exclude.extend(ids_of(&self.ensemble));
+        let Ok(replacements) = change.nodes.pick(failed.len(), &exclude) else {
+            return Ok(false);
+        };
+
+        let (current_first_entry_id, _) = change.metadata.value.current_ensemble();
+        let first_entry_id = change
+            .pending
+            .iter()
+            .find(|pending| {
+                pending.entry_id >= current_first_entry_id
+                    && failed
+                        .iter()
+                        .any(|index| pending.unstored.contains(self.ensemble[*index].id()))
+            })
+            .map_or(entry_id, |pending| pending.entry_id);
+        let mut ensemble = self.ensemble.clone();
+        for (index, node) in failed.iter().zip(replacements) {
+            ensemble[*index] = node;
+        }
+        let ids = ids_of(&ensemble);
+        change
+            .update_metadata(self.log_id, |metadata| {
+                metadata.ensembles.insert(first_entry_id, ids.clone());
+            })
+            .await?;
+
+        // The entries before the new ensemble which the failed nodes haven't stored
+        // are left to the auditor.
+        for pending in change.pending.iter_mut() {
+            for index in &failed {
+                pending.unstored.remove(self.ensemble[*index].id());
+            }
+            if pending.entry_id < first_entry_id {
+                continue;
+            }
+            let write_set = self.config.write_set(pending.entry_id);
+            for index in failed.iter().filter(|index| write_set.contains(index)) {
+                let node = ensemble[*index].clone();
+                pending.unstored.insert(node.id().to_string());
+                let entry = pending.entry.clone();
+                let entry_id = pending.entry_id;
+                let add_timeout = self.options.add_timeout;
+                let outcomes = change.outcomes_tx.clone();
+                tokio::spawn(async move {
+                    let result = add_to_node(node.as_ref(), entry, false, add_timeout).await;
+                    report_outcome(&outcomes, entry_id, node.id(), &result);
+                });
+            }
+        }
+        change.release_stored();
+        self.ensemble = ensemble;
+        Ok(true)
+    }
+}
+
+impl EnsembleChange {
+    // Record the outcomes of the writes completed since the last call.
+    fn apply_outcomes(&mut self) {
+        while let Ok(outcome) = self.outcomes_rx.try_recv() {
+            if !outcome.stored {
+                self.failed.insert(outcome.node_id);
+                continue;
+            }
+            let first_entry_id = match self.pending.front() {
+                Some(pending) => pending.entry_id,
+                None => continue,
+            };
+            // The entries are pending in order and without gaps.
+            if let Some(pending) = usize::try_from(outcome.entry_id - first_entry_id)
+                .ok()
+                .and_then(|index| self.pending.get_mut(index))
+            {
+                pending.unstored.remove(&outcome.node_id);
+            }
+        }
+        self.release_stored();
+    }
+
+    // Drop the entries stored by all the nodes of their write set, up to the first one which isn't.
+    fn release_stored(&mut self) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|pending| pending.unstored.is_empty())
+        {
+            self.pending.pop_front();
+        }
+    }
+
+    // Update the metadata by compare-and-swap. The auditor may have replaced the nodes
+    // of the previous ensembles meanwhile, the update is applied again on top of it,
+    // any other concurrent update fails it.
+    async fn update_metadata(
+        &mut self,
+        log_id: i64,
+        update: impl Fn(&mut LedgerMetadata),
+    ) -> Result<()> {
+        loop {
+            let mut metadata = self.metadata.value.clone();
+            update(&mut metadata);
+            match self
+                .metadata_store
+                .compare_and_set(log_id, self.metadata.version, metadata.clone())
+                .await
+            {
+                Ok(version) => {
+                    self.metadata = Versioned {
+                        value: metadata,
+                        version,
+                    };
+                    return Ok(());
+                }
+                Err(e @ metadata::Error::BadVersion { .. }) => {
+                    let current = self.metadata_store.read(log_id).await?;
+                    if current.value.status != self.metadata.value.status
+                        || current.value.current_ensemble()
+                            != self.metadata.value.current_ensemble()
+                    {
+                        return Err(e.into());
+                    }
+                    self.metadata = current;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+fn ids_of(ensemble: &[Arc<dyn Node>]) -> Vec<String> {
+    ensemble.iter().map(|node| node.id().to_string()).collect()
+}
+
+/// Send the encoded entry to its write set, resolved once the ack quorum has stored it.
+/// The recovery adds are accepted by the nodes even if the ledger is fenced.
+///
+/// A node which doesn't store the entry within the timeout fails with `Error::Unavailable`,
+/// the outcome of each write is sent to `outcomes`, unless the node was fenced or closed.
+pub(super) async fn replicate_entry(
+    config: QuorumConfig,
+    ensemble: &[Arc<dyn Node>],
+    entry_id: i64,
+    entry: Bytes,
+    recovery: bool,
+    add_timeout: Option<Duration>,
+    outcomes: Option<mpsc::UnboundedSender<AddOutcome>>,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(config.write_quorum);
+    for index in config.write_set(entry_id) {
+        let node = ensemble[index].clone();
+        let entry = entry.clone();
+        let tx = tx.clone();
+        let outcomes = outcomes.clone();
+        tokio::spawn(async move {
+            let result = add_to_node(node.as_ref(), entry, recovery, add_timeout).await;
+            if let Some(outcomes) = outcomes {
+                report_outcome(&outcomes, entry_id, node.id(), &result);
+            }
+            let _ = tx.send(result).await;
+        });
+    }
+    drop(tx);
+
+    let mut acks = 0;
+    let mut fenced = None;
+    while let Some(result) = rx.recv().await {
+        match result {
+            Ok(()) => {
+                acks += 1;
+                if acks == config.ack_quorum {
+                    return Ok(());
+                }
+            }
+            Err(Error::Ledger(e @ ledger::Error::Fenced(_))) => fenced = Some(e),
+            Err(_) => {}
+        }
+    }
+    // A fenced node means another writer took over, report it rather than the missing acks.
+    match fenced {
+        Some(e) => Err(e.into()),
+        None => Err(Error::NotEnoughAcks {
+            entry_id,
+            acks,
+            ack_quorum: config.ack_quorum,
+        }),
+    }
+}
+
+// Add the entry to the node, it fails with `Error::Unavailable` unless stored within the timeout.
+async fn add_to_node(
+    node: &dyn Node,
+    entry: Bytes,
+    recovery: bool,
+    add_timeout: Option<Duration>,
+) -> Result<()> {
+    let add = async {
+        if recovery {
+            node.recovery_add_entry(entry).await
+        } else {
+            node.add_entry(entry).await
+        }
+    };
+    match add_timeout {
+        Some(add_timeout) => tokio::time::timeout(add_timeout, add)
+            .await
+            .unwrap_or_else(|_| Err(Error::Unavailable(node.id().to_string()))),
+        None => add.await,
+    }
+}
+
+// The fenced and closed nodes aren't failed, another writer took over the ledger.
+fn report_outcome(
+    outcomes: &mpsc::UnboundedSender<AddOutcome>,
+    entry_id: i64,
+    node_id: &str,
+    result: &Result<()>,
+) {
+    if matches!(
+        result,
+        Err(Error::Ledger(
+            ledger::Error::Fenced(_) | ledger::Error::Closed(_)
+        ))
+    ) {
+        return;
+    }
+    let _ = outcomes.send(AddOutcome {
+        entry_id,
+        node_id: node_id.to_string(),
+        stored: result.is_ok(),
+    });
+}
+
+/// Close the ledger on all nodes of the ensemble concurrently,
+/// fails unless at least `ack_quorum` of them close it.
+pub(super) async fn close_on_ensemble(
+    log_id: i64,
+    last_entry_id: i64,
+    ensemble: &[Arc<dyn Node>],
+    ack_quorum: usize,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::channel(ensemble.len());
+    for node in ensemble {
+        let node = node.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(node.close(log_id, last_entry_id).await).await;
+        });
+    }
+    drop(tx);
+    let mut closed = 0;
+    while let Some(result) = rx.recv().await {
+        if result.is_ok() {
+            closed += 1;
+        }
+    }
+    if closed < ack_quorum {
+        return Err(Error::NotEnoughAcks {
+            entry_id: last_entry_id,
+            acks: closed,
+            ack_quorum,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::tests::encode;
+    use crate::entry::Entry;
+    use crate::ledger::{LedgerState, LedgerStore};
+    use crate::metadata::MemoryMetadataStore;
+    use crate::replication::tests::{flaky_nodes, local_nodes, DownNode, FlakyNode};
+    use crate::replication::LocalNode;
+    use crate::segment::SegmentOptions;
+
+    fn nodes_of(nodes: &[Arc<LocalNode>]) -> Vec<Arc<dyn Node>> {
+        nodes.iter().map(|n| n.clone() as Arc<dyn Node>).collect()
+    }
+
+    #[tokio::test]
+    async fn test_replicated_append_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut nodes = Vec::new();
+        for i in 0..3 {
+            let store = LedgerStore::open(
+                dir.path().join(format!("node-{}", i)),
+                SegmentOptions::default(),
+            )
+            .await
+            .unwrap();
+            let node = LocalNode::new(format!("node-{}", i), store).with_append_time(true);
+            nodes.push(Arc::new(node));
+        }
+        let config = QuorumConfig::new(3, 3, 3).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, nodes_of(&nodes)).unwrap();
+        for i in 0..5 {
+            writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+        }
+        writer.close().await.unwrap();
+
+        // The nodes don't stamp the replicated entries, the replicas are identical.
+        for entry_id in 0..5 {
+            let mut replicas = Vec::new();
+            for node in &nodes {
+                let entry = node.store().read_entry(1, entry_id).await.unwrap();
+                assert_eq!(entry.append_time(), None);
+                replicas.push(encode(&entry));
+            }
+            assert!(replicas.iter().all(|replica| replica == &replicas[0]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 5).await;
+        let config = QuorumConfig::new(5, 3, 3).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, nodes_of(&nodes)).unwrap();
+        for i in 0..20 {
+            let entry_id = writer
+                .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+                .await
+                .unwrap();
+            assert_eq!(entry_id, i as i64);
+        }
+        assert_eq!(writer.close().await.unwrap(), 19);
+
+        // Each node stores exactly the entries striped to it.
+        for (index, node) in nodes.iter().enumerate() {
+            let store = node.store();
+            assert_eq!(
+                store.state(1).await.unwrap(),
+                LedgerState::Closed { last_entry_id: 19 }
+            );
+            for entry_id in 0..20 {
+                let stored = store.read_entry(1, entry_id).await;
+                if config.write_set(entry_id).contains(&index) {
+                    let entry = stored.unwrap();
+                    assert_eq!(entry.value(), &Bytes::from(vec![entry_id as u8]));
+                    assert_eq!(entry.last_confirm_id(), entry_id - 1);
+                } else {
+                    assert!(matches!(stored, Err(ledger::Error::EntryNotFound { .. })));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicated_append_with_failed_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 3).await;
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+
+        // One node is down, the ack quorum is still reached.
+        let mut ensemble = nodes_of(&nodes[..2]);
+        ensemble.push(Arc::new(DownNode("node-2".to_string())));
+        let mut writer = ReplicatedWriter::new(1, config, ensemble).unwrap();
+        for _ in 0..3 {
+            writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from_static(b"value"),
+                    vec![],
+                )
+                .await
+                .unwrap();
+        }
+
+        // Two nodes are down, no entry can be acknowledged.
+        let mut ensemble = nodes_of(&nodes[..1]);
+        ensemble.push(Arc::new(DownNode("node-1".to_string())));
+        ensemble.push(Arc::new(DownNode("node-2".to_string())));
+        let mut writer = ReplicatedWriter::new(2, config, ensemble).unwrap();
+        assert!(matches!(
+            writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from_static(b"value"),
+                    vec![]
+                )
+                .await,
+            Err(Error::NotEnoughAcks {
+                entry_id: 0,
+                acks: 1,
+                ack_quorum: 2
+            })
+        ));
+        assert_eq!(writer.last_entry_id(), INVALID_ENTRY_ID);
+        assert!(writer.close().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replicated_append_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 3).await;
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let ensemble = nodes.iter().map(|n| n.clone() as Arc<dyn Node>).collect();
+        let mut writer = ReplicatedWriter::new(1, config, ensemble).unwrap();
+        append(&mut writer, 0).await.unwrap();
+
+        // Entry 1 is stored by a single node, it can't be overwritten by the next append.
+        nodes[1].set_down(true);
+        nodes[2].set_down(true);
+        assert!(matches!(
+            append(&mut writer, 1).await,
+            Err(Error::NotEnoughAcks { entry_id: 1, .. })
+        ));
+        nodes[1].set_down(false);
+        nodes[2].set_down(false);
+        assert!(matches!(
+            append(&mut writer, 2).await,
+            Err(Error::WriterFailed(1))
+        ));
+        assert_eq!(writer.last_entry_id(), 0);
+        assert_eq!(
+            nodes[0]
+                .node
+                .store()
+                .read_entry(1, 1)
+                .await
+                .unwrap()
+                .value(),
+            &Bytes::from(vec![1])
+        );
+        assert!(matches!(writer.close().await, Err(Error::WriterFailed(1))));
+    }
+
+    #[tokio::test]
+    async fn test_replicated_append_fenced() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = local_nodes(dir.path(), 3).await;
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let mut writer = ReplicatedWriter::new(1, config, nodes_of(&nodes)).unwrap();
+        writer
+            .append(
+                Bytes::from_static(b"key"),
+                Bytes::from_static(b"value"),
+                vec![],
+            )
+            .await
+            .unwrap();
+
+        for node in &nodes {
+            node.fence(1).await.unwrap();
+        }
+        assert!(matches!(
+            writer
+                .append(
+                    Bytes::from_static(b"key"),
+                    Bytes::from_static(b"value"),
+                    vec![]
+                )
+                .await,
+            Err(Error::Ledger(ledger::Error::Fenced(1)))
+        ));
+    }
+
+    fn node_set(nodes: &[Arc<FlakyNode>]) -> NodeSet {
+        NodeSet::new(nodes.iter().map(|n| n.clone() as Arc<dyn Node>))
+    }
+
+    async fn append(writer: &mut ReplicatedWriter, i: u8) -> Result<i64> {
+        writer
+            .append(Bytes::from_static(b"key"), Bytes::from(vec![i]), vec![])
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 4).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 2, 2).unwrap();
+        let mut writer = ReplicatedWriter::create(
+            1,
+            config,
+            node_set(&nodes),
+            metadata_store.clone(),
+            WriterOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(ids_of(writer.ensemble()), ["node-0", "node-1", "node-2"]);
+        for i in 0..5 {
+            append(&mut writer, i).await.unwrap();
+        }
+
+        // Entry 5 is striped to nodes 2 and 0, the failure is detected by entry 6.
+        nodes[1].set_down(true);
+        for i in 5..10 {
+            assert_eq!(append(&mut writer, i).await.unwrap(), i as i64);
+        }
+        assert_eq!(ids_of(writer.ensemble()), ["node-0", "node-3", "node-2"]);
+        let metadata = metadata_store.read(1).await.unwrap();
+        assert_eq!(Some(&metadata), writer.metadata());
+        assert_eq!(metadata.version, 2);
+        assert_eq!(
+            metadata.value.ensembles.keys().copied().collect::<Vec<_>>(),
+            [0, 6]
+        );
+        assert_eq!(
+            metadata.value.ensemble_at(6),
+            ["node-0", "node-3", "node-2"]
+        );
+
+        // The replacement stores the entries striped to its index since the change.
+        let store = nodes[3].node.store();
+        for entry_id in 0..10 {
+            let stored = store.read_entry(1, entry_id).await;
+            if entry_id >= 6 && config.write_set(entry_id).contains(&1) {
+                assert_eq!(stored.unwrap().value(), &Bytes::from(vec![entry_id as u8]));
+            } else {
+                assert!(stored.is_err());
+            }
+        }
+
+        assert_eq!(writer.close().await.unwrap(), 9);
+        let metadata = metadata_store.read(1).await.unwrap().value;
+        assert_eq!(metadata.status, LedgerStatus::Closed);
+        assert_eq!(metadata.last_entry_id, 9);
+    }
+
+    // The clock is paused, it only moves forward once all tasks are idle,
+    // so the timeouts only fire on the nodes which don't respond.
+    #[tokio::test(start_paused = true)]
+    async fn test_ensemble_change_slow_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 5).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let options = WriterOptions {
+            add_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        // Without the slow node, the ack quorum can't be reached.
+        nodes[2].set_delay(Duration::from_secs(1));
+        let config = QuorumConfig::new(3, 3, 3).unwrap();
+        let mut writer = ReplicatedWriter::create(
+            1,
+            config,
+            node_set(&nodes[..4]),
+            metadata_store.clone(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        append(&mut writer, 0).await.unwrap();
+        assert_eq!(ids_of(writer.ensemble()), ["node-0", "node-1", "node-3"]);
+        assert_eq!(
+            metadata_store.read(1).await.unwrap().value.ensembles,
+            [(0, ids_of(writer.ensemble()))].into()
+        );
+
+        // The slow node times out after the entry is acknowledged, it's replaced before the next
+        // entry by a new ensemble starting at the entry it didn't store.
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let mut writer = ReplicatedWriter::create(
+            2,
+            config,
+            node_set(&nodes[..4]),
+            metadata_store.clone(),
+            options.clone(),
+        )
+        .await
+        .unwrap();
+        append(&mut writer, 0).await.unwrap();
+        assert_eq!(ids_of(writer.ensemble()), ["node-0", "node-1", "node-2"]);
+        // The add of the first ledger timed out as well.
+        nodes[2].wait_for_adds(2).await;
+        append(&mut writer, 1).await.unwrap();
+        assert_eq!(ids_of(writer.ensemble()), ["node-0", "node-1", "node-3"]);
+        assert_eq!(
+            metadata_store.read(2).await.unwrap().value.ensembles,
+            [(0, ids_of(writer.ensemble()))].into()
+        );
+
+        // The replacement fails as well, it's replaced again.
+        nodes[3].set_down(true);
+        let mut writer = ReplicatedWriter::create(
+            3,
+            QuorumConfig::new(3, 3, 3).unwrap(),
+            node_set(&nodes),
+            metadata_store.clone(),
+            options,
+        )
+        .await
+        .unwrap();
+        append(&mut writer, 0).await.unwrap();
+        assert_eq!(ids_of(writer.ensemble()), ["node-0", "node-1", "node-4"]);
+        assert_eq!(writer.metadata().unwrap().version, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ensemble_change_replays_pending_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 4).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 3, 2).unwrap();
+        let mut writer = ReplicatedWriter::create(
+            1,
+            config,
+            node_set(&nodes),
+            metadata_store.clone(),
+            WriterOptions {
+                add_timeout: Some(Duration::from_millis(100)),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        for i in 0..3 {
+            append(&mut writer, i).await.unwrap();
+        }
+        nodes[0].wait_for_adds(3).await;
+
+        // Entries 3 and 4 are acknowledged without node 0, which times out afterwards.
+        nodes[0].set_delay(Duration::from_secs(1));
+        for i in 3..5 {
+            append(&mut writer, i).await.unwrap();
+        }
+        nodes[0].wait_for_adds(5).await;
+        append(&mut writer, 5).await.unwrap();
+        assert_eq!(ids_of(writer.ensemble()), ["node-3", "node-1", "node-2"]);
+        assert_eq!(
+            metadata_store.read(1).await.unwrap().value.ensembles,
+            [
+                (0, vec!["node-0".into(), "node-1".into(), "node-2".into()]),
+                (3, ids_of(writer.ensemble())),
+            ]
+            .into()
+        );
+
+        // The replacement stores the entries since the new ensemble, replayed or not.
+        nodes[3].wait_for_adds(3).await;
+        let store = nodes[3].node.store();
+        for entry_id in 0..6 {
+            let stored = store.read_entry(1, entry_id).await;
+            if entry_id >= 3 {
+                assert_eq!(stored.unwrap().value(), &Bytes::from(vec![entry_id as u8]));
+            } else {
+                assert!(stored.is_err());
+            }
+        }
+        assert_eq!(writer.close().await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_change_without_replacement() {
+        let dir = tempfile::tempdir().unwrap();
+        let nodes = flaky_nodes(dir.path(), 3).await;
+        let metadata_store = Arc::new(MemoryMetadataStore::new());
+        let config = QuorumConfig::new(3, 3, 3).unwrap();
+        let mut writer = ReplicatedWriter::create(
+            1,
+            config,
+            node_set(&nodes),
+            metadata_store.clone(),
+            WriterOptions::default(),
+        )
+        .await
+        .unwrap();
+        nodes[2].set_down(true);
+        assert!(matches!(
+            append(&mut writer, 0).await,
+            Err(Error::NotEnoughAcks {
+                entry_id: 0,
+                acks: 2,
+                ack_quorum: 3
+            })
+        ));
+        assert_eq!(metadata_store.read(1).await.unwrap().version, 1);
+
+        // Not enough nodes for the ensemble.
+        assert!(matches!(
+            ReplicatedWriter::create(
+                2,
+                QuorumConfig::new(4, 3, 2).unwrap(),
+                node_set(&nodes),
+                metadata_store.clone(),
+                WriterOptions::default(),
+            )
+            .await,
+            Err(Error::NotEnoughNodes {
+                available: 3,
+                required: 4
+            })
+        ));
+        assert!(matches!(
+            ReplicatedWriter::create(
+                1,
+                config,
+                node_set(&nodes),
+                metadata_store,
+                WriterOptions::default(),
+            )
+            .await,
+            Err(Error::Metadata(crate::metadata::Error::LedgerExists(1)))
+        ));
+    }
+}
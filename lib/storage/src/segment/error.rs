@@ -0,0 +1,20 @@
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("entry")]
+    Entry(#[from] crate::entry::Error),
+
+    #[error("invalid segment magic")]
+    InvalidMagic,
+
+    #[error("unsupported segment version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("corrupted index")]
+    CorruptedIndex,
+
+    #[error("entry of {0} bytes is too large for a segment")]
+    EntryTooLarge(usize),
+}
@@ -0,0 +1,277 @@
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::entry::Entry;
+
+use super::{Error, Result, SegmentReader, SEGMENT_HEADER_SIZE};
+
+// The index file layout:
+//
+// Header: magic 4 + version 4 + interval 4 + count 8 = 20
+// Items: entry_id 8 + position 8, repeated
+// Checksum: crc32c 4 over the header and items
+const INDEX_MAGIC: [u8; 4] = *b"LGIX";
+const INDEX_VERSION: u32 = 1;
+const INDEX_HEADER_SIZE: usize = 20;
+const INDEX_ITEM_SIZE: usize = 16;
+const INDEX_CHECKSUM_SIZE: usize = 4;
+const INDEX_FILE_EXTENSION: &str = "idx";
+
+/// Returns the path of the index file of the segment.
+pub fn index_path(segment_path: impl AsRef<Path>) -> PathBuf {
+    segment_path.as_ref().with_extension(INDEX_FILE_EXTENSION)
+}
+
+/// The `SegmentIndex` is a sparse index of a segment,
+/// it maps the entry id of every `interval`th entry to the position of its record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentIndex {
+    interval: u32,
+    // The number of recorded entries.
+    count: u64,
+    items: Vec<(i64, u64)>,
+}
+
+impl SegmentIndex {
+    /// Create an empty index, `interval` must be positive.
+    pub fn new(interval: u32) -> Self {
+        assert!(interval > 0, "index interval must be positive");
+        Self {
+            interval,
+            count: 0,
+            items: Vec::new(),
+        }
+    }
+
+    /// Returns the interval of the index.
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Returns the indexed entry ids and positions.
+    pub fn items(&self) -> &[(i64, u64)] {
+        &self.items
+    }
+
+    /// Record an appended entry, only every `interval`th entry is indexed.
+    pub fn record(&mut self, entry_id: i64, position: u64) {
+        if self.count.is_multiple_of(self.interval as u64) {
+            self.items.push((entry_id, position));
+        }
+        self.count += 1;
+    }
+
+    /// Returns the position of the nearest indexed entry at or before the entry id,
+    /// the scan should start from there to find the entry.
+    pub fn lookup(&self, entry_id: i64) -> u64 {
+        let i = self.items.partition_point(|(id, _)| *id <= entry_id);
+        if i == 0 {
+            SEGMENT_HEADER_SIZE
+        } else {
+            self.items[i - 1].1
+        }
+    }
+
+    /// Rebuild the index by scanning the segment.
+    pub async fn rebuild(segment_path: impl AsRef<Path>, interval: u32) -> Result<Self> {
+        let mut index = Self::new(interval);
+        let mut reader = SegmentReader::open(segment_path).await?;
+        while let Some((position, entry)) = reader.next_entry().await? {
+            index.record(entry.entry_id(), position);
+        }
+        Ok(index)
+    }
+
+    /// Load the index of the segment, it's rebuilt and rewritten
+    /// if the index file is missing, corrupted or has another interval.
+    pub async fn load_or_rebuild(segment_path: impl AsRef<Path>, interval: u32) -> Result<Self> {
+        let segment_path = segment_path.as_ref();
+        let path = index_path(segment_path);
+        match Self::load(&path).await {
+            Ok(index) if index.interval == interval => return Ok(index),
+            Ok(_)
+            | Err(Error::CorruptedIndex)
+            | Err(Error::InvalidMagic)
+            | Err(Error::UnsupportedVersion(_)) => {}
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let index = Self::rebuild(segment_path, interval).await?;
+        index.write(&path).await?;
+        Ok(index)
+    }
+
+    /// Load the index from the index file.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let data = tokio::fs::read(path).await?;
+        if data.len() < INDEX_HEADER_SIZE + INDEX_CHECKSUM_SIZE
+            || !(data.len() - INDEX_HEADER_SIZE - INDEX_CHECKSUM_SIZE)
+                .is_multiple_of(INDEX_ITEM_SIZE)
+        {
+            return Err(Error::CorruptedIndex);
+        }
+        let (body, checksum) = data.split_at(data.len() - INDEX_CHECKSUM_SIZE);
+        if crc32c::crc32c(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(Error::CorruptedIndex);
+        }
+
+        let mut buf = body;
+        if buf[..4] != INDEX_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        buf.advance(4);
+        let version = buf.get_u32_le();
+        if version != INDEX_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let interval = buf.get_u32_le();
+        if interval == 0 {
+            return Err(Error::CorruptedIndex);
+        }
+        let count = buf.get_u64_le();
+        let mut items = Vec::with_capacity(buf.remaining() / INDEX_ITEM_SIZE);
+        while buf.has_remaining() {
+            items.push((buf.get_i64_le(), buf.get_u64_le()));
+        }
+        Ok(Self {
+            interval,
+            count,
+            items,
+        })
+    }
+
+    /// Write the index file, the file is replaced atomically.
+    pub async fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut buf = BytesMut::with_capacity(
+            INDEX_HEADER_SIZE + self.items.len() * INDEX_ITEM_SIZE + INDEX_CHECKSUM_SIZE,
+        );
+        buf.put_slice(&INDEX_MAGIC);
+        buf.put_u32_le(INDEX_VERSION);
+        buf.put_u32_le(self.interval);
+        buf.put_u64_le(self.count);
+        for (entry_id, position) in &self.items {
+            buf.put_i64_le(*entry_id);
+            buf.put_u64_le(*position);
+        }
+        let checksum = crc32c::crc32c(&buf);
+        buf.put_u32_le(checksum);
+
+        let tmp_path = path.with_extension("idx.tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &buf).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::BuilderV2;
+    use crate::segment::{segment_path, SegmentOptions, SegmentWriter};
+    use bytes::Bytes;
+
+    async fn write_segment(dir: &Path, entries: i64, interval: u32) -> (PathBuf, Vec<u64>) {
+        let path = segment_path(dir, 0);
+        let options = SegmentOptions {
+            index_interval: interval,
+            ..Default::default()
+        };
+        let mut writer = SegmentWriter::create(&path, &options).await.unwrap();
+        let mut positions = Vec::new();
+        for i in 0..entries {
+            let entry = BuilderV2::new()
+                .entry_id(i * 2)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .build();
+            positions.push(writer.append(&entry).await.unwrap());
+        }
+        writer.seal().await.unwrap();
+        (path, positions)
+    }
+
+    #[test]
+    fn test_index_lookup() {
+        let mut index = SegmentIndex::new(2);
+        for i in 0..5 {
+            index.record(i * 10, 100 + i as u64);
+        }
+        assert_eq!(index.items(), &[(0, 100), (20, 102), (40, 104)]);
+        assert_eq!(index.lookup(-1), SEGMENT_HEADER_SIZE);
+        assert_eq!(index.lookup(0), 100);
+        assert_eq!(index.lookup(19), 100);
+        assert_eq!(index.lookup(20), 102);
+        assert_eq!(index.lookup(1000), 104);
+    }
+
+    #[tokio::test]
+    async fn test_index_written_with_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, positions) = write_segment(dir.path(), 10, 3).await;
+
+        let index = SegmentIndex::load(index_path(&path)).await.unwrap();
+        assert_eq!(
+            index.items(),
+            &[
+                (0, positions[0]),
+                (6, positions[3]),
+                (12, positions[6]),
+                (18, positions[9])
+            ]
+        );
+        assert_eq!(index, SegmentIndex::rebuild(&path, 3).await.unwrap());
+
+        let mut reader = SegmentReader::open(&path).await.unwrap();
+        for i in 0..10 {
+            let entry = reader.find_entry(i * 2, &index).await.unwrap().unwrap();
+            assert_eq!(entry.entry_id(), i * 2);
+        }
+        assert!(reader.find_entry(7, &index).await.unwrap().is_none());
+        assert!(reader.find_entry(100, &index).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_index_rebuild() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = write_segment(dir.path(), 10, 3).await;
+        let expected = SegmentIndex::load(index_path(&path)).await.unwrap();
+
+        // Missing index file.
+        tokio::fs::remove_file(index_path(&path)).await.unwrap();
+        assert_eq!(
+            SegmentIndex::load_or_rebuild(&path, 3).await.unwrap(),
+            expected
+        );
+        assert_eq!(
+            SegmentIndex::load(index_path(&path)).await.unwrap(),
+            expected
+        );
+
+        // Corrupted index file.
+        let mut data = tokio::fs::read(index_path(&path)).await.unwrap();
+        data[INDEX_HEADER_SIZE] ^= 0x01;
+        tokio::fs::write(index_path(&path), &data).await.unwrap();
+        assert!(SegmentIndex::load(index_path(&path)).await.is_err());
+        assert_eq!(
+            SegmentIndex::load_or_rebuild(&path, 3).await.unwrap(),
+            expected
+        );
+
+        // Truncated index file.
+        tokio::fs::write(index_path(&path), &data[..7])
+            .await
+            .unwrap();
+        assert_eq!(
+            SegmentIndex::load_or_rebuild(&path, 3).await.unwrap(),
+            expected
+        );
+
+        // Another interval.
+        let rebuilt = SegmentIndex::load_or_rebuild(&path, 5).await.unwrap();
+        assert_eq!(rebuilt.interval(), 5);
+        assert_eq!(rebuilt.items().len(), 2);
+    }
+}
@@ -0,0 +1,242 @@
+mod error;
+mod index;
+mod reader;
+mod time_index;
+mod writer;
+
+use std::path::{Path, PathBuf};
+
+pub use error::Error;
+pub use index::{index_path, SegmentIndex};
+pub use reader::SegmentReader;
+pub use time_index::{time_index_path, TimeIndex};
+pub use writer::{RollingSegmentWriter, SegmentWriter};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The segment file layout:
+//
+// File header: magic 4 + version 4 = 8
+// Records: length 4 + encoded entry, repeated
+// Footer: zero length 4 + first_entry_id 8 + last_entry_id 8 + magic 4 = 24
+const SEGMENT_MAGIC: [u8; 4] = *b"LOGA";
+const SEGMENT_VERSION: u32 = 1;
+pub const SEGMENT_HEADER_SIZE: u64 = 8;
+const RECORD_LENGTH_SIZE: u64 = 4;
+const FOOTER_MAGIC: [u8; 4] = *b"AGOL";
+const FOOTER_SIZE: u64 = 24;
+const SEGMENT_FILE_EXTENSION: &str = "seg";
+
+/// The entry id recorded in the footer of a segment without entries.
+pub const INVALID_ENTRY_ID: i64 = -1;
+
+/// The `SegmentOptions` is the configuration of the segments in a directory.
+#[derive(Debug, Clone)]
+pub struct SegmentOptions {
+    /// The segment is rolled once appending an entry would make it exceed this size.
+    pub max_segment_size: u64,
+    /// Every `index_interval`th entry is recorded in the sparse index of the segment,
+    /// and every `index_interval`th entry with a time in its time index.
+    pub index_interval: u32,
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_size: 64 * 1024 * 1024,
+            index_interval: 64,
+        }
+    }
+}
+
+/// The `SegmentFooter` is written at the end of a sealed segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentFooter {
+    pub first_entry_id: i64,
+    pub last_entry_id: i64,
+}
+
+impl SegmentFooter {
+    fn encode(&self) -> [u8; FOOTER_SIZE as usize] {
+        let mut buf = [0; FOOTER_SIZE as usize];
+        buf[4..12].copy_from_slice(&self.first_entry_id.to_le_bytes());
+        buf[12..20].copy_from_slice(&self.last_entry_id.to_le_bytes());
+        buf[20..24].copy_from_slice(&FOOTER_MAGIC);
+        buf
+    }
+
+    fn decode(buf: &[u8; FOOTER_SIZE as usize]) -> Option<Self> {
+        if buf[..4] != [0; 4] || buf[20..24] != FOOTER_MAGIC {
+            return None;
+        }
+        Some(Self {
+            first_entry_id: i64::from_le_bytes(buf[4..12].try_into().unwrap()),
+            last_entry_id: i64::from_le_bytes(buf[12..20].try_into().unwrap()),
+        })
+    }
+}
+
+/// Returns the path of the segment starting at the entry id in the directory.
+pub fn segment_path(dir: impl AsRef<Path>, first_entry_id: i64) -> PathBuf {
+    dir.as_ref()
+        .join(format!("{:020}.{}", first_entry_id, SEGMENT_FILE_EXTENSION))
+}
+
+/// List the segments in the directory, ordered by their first entry id.
+pub async fn list_segments(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>> {
+    let mut segments = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(dir_entry) = read_dir.next_entry().await? {
+        let path = dir_entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_FILE_EXTENSION) {
+            continue;
+        }
+        let first_entry_id = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<i64>().ok());
+        if let Some(first_entry_id) = first_entry_id {
+            segments.push((first_entry_id, path));
+        }
+    }
+    segments.sort_by_key(|(first_entry_id, _)| *first_entry_id);
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{BuilderV2, Entry};
+    use bytes::Bytes;
+
+    fn new_entry(entry_id: i64, value_size: usize) -> impl Entry {
+        BuilderV2::new()
+            .log_id(1)
+            .entry_id(entry_id)
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![0; value_size]))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_segment_write_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = segment_path(dir.path(), 0);
+
+        let mut writer = SegmentWriter::create(&path, &SegmentOptions::default())
+            .await
+            .unwrap();
+        let mut positions = Vec::new();
+        for i in 0..10 {
+            positions.push(writer.append(&new_entry(i, i as usize)).await.unwrap());
+        }
+        assert_eq!(positions[0], SEGMENT_HEADER_SIZE);
+        let footer = writer.seal().await.unwrap();
+        assert_eq!(
+            footer,
+            SegmentFooter {
+                first_entry_id: 0,
+                last_entry_id: 9
+            }
+        );
+
+        let mut reader = SegmentReader::open(&path).await.unwrap();
+        assert_eq!(reader.footer(), Some(footer));
+        for (i, expected_position) in positions.iter().enumerate() {
+            let (position, entry) = reader.next_entry().await.unwrap().unwrap();
+            assert_eq!(position, *expected_position);
+            assert_eq!(entry.entry_id(), i as i64);
+            assert_eq!(entry.value().len(), i);
+        }
+        assert!(reader.next_entry().await.unwrap().is_none());
+
+        // Seek back to a specific position.
+        reader.seek(positions[5]).await.unwrap();
+        let (_, entry) = reader.next_entry().await.unwrap().unwrap();
+        assert_eq!(entry.entry_id(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_segment_without_footer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = segment_path(dir.path(), 0);
+
+        let mut writer = SegmentWriter::create(&path, &SegmentOptions::default())
+            .await
+            .unwrap();
+        writer.append(&new_entry(0, 8)).await.unwrap();
+        writer.sync().await.unwrap();
+
+        let mut reader = SegmentReader::open(&path).await.unwrap();
+        assert_eq!(reader.footer(), None);
+        assert_eq!(reader.next_entry().await.unwrap().unwrap().1.entry_id(), 0);
+        assert!(reader.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_segment_corrupted_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = segment_path(dir.path(), 0);
+
+        let mut writer = SegmentWriter::create(&path, &SegmentOptions::default())
+            .await
+            .unwrap();
+        writer.append(&new_entry(0, 8)).await.unwrap();
+        writer.sync().await.unwrap();
+        let mut data = tokio::fs::read(&path).await.unwrap();
+        let position = SEGMENT_HEADER_SIZE as usize;
+        data[position..position + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        tokio::fs::write(&path, data).await.unwrap();
+
+        let mut reader = SegmentReader::open(&path).await.unwrap();
+        assert!(matches!(
+            reader.next_entry().await,
+            Err(Error::Entry(crate::entry::Error::DecodeBufNotEnough))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_segment_invalid_magic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = segment_path(dir.path(), 0);
+        tokio::fs::write(&path, b"NOTASEGMENT").await.unwrap();
+        assert!(matches!(
+            SegmentReader::open(&path).await,
+            Err(Error::InvalidMagic)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rolling_segment_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SegmentOptions {
+            max_segment_size: 256,
+            ..Default::default()
+        };
+        let mut writer = RollingSegmentWriter::open(dir.path(), options)
+            .await
+            .unwrap();
+        for i in 0..20 {
+            writer.append(&new_entry(i, 32)).await.unwrap();
+        }
+        writer.close().await.unwrap();
+
+        let segments = list_segments(dir.path()).await.unwrap();
+        assert!(segments.len() > 1);
+        let mut next_entry_id = 0;
+        for path in segments {
+            assert!(tokio::fs::metadata(&path).await.unwrap().len() <= 256 + FOOTER_SIZE);
+            let mut reader = SegmentReader::open(&path).await.unwrap();
+            let footer = reader.footer().unwrap();
+            assert_eq!(path, segment_path(dir.path(), footer.first_entry_id));
+            assert_eq!(footer.first_entry_id, next_entry_id);
+            while let Some((_, entry)) = reader.next_entry().await.unwrap() {
+                assert_eq!(entry.entry_id(), next_entry_id);
+                next_entry_id += 1;
+            }
+            assert_eq!(footer.last_entry_id, next_entry_id - 1);
+            assert!(tokio::fs::metadata(index_path(&path)).await.is_ok());
+            assert!(tokio::fs::metadata(time_index_path(&path)).await.is_ok());
+        }
+        assert_eq!(next_entry_id, 20);
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use bytes::BytesMut;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, BufReader};
+
+use crate::entry::{self, AnyEntry, Entry};
+
+use super::{Error, Result, SegmentFooter, SegmentIndex, TimeIndex};
+use super::{FOOTER_SIZE, RECORD_LENGTH_SIZE, SEGMENT_HEADER_SIZE, SEGMENT_MAGIC, SEGMENT_VERSION};
+
+/// The `SegmentReader` iterates the entries of a segment file by position.
+pub struct SegmentReader {
+    path: PathBuf,
+    file: BufReader<File>,
+    position: u64,
+    // The end of the records, the footer is excluded.
+    end: u64,
+    footer: Option<SegmentFooter>,
+}
+
+impl SegmentReader {
+    /// Open a segment file and validate its file header.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::open(&path).await?;
+        let size = file.metadata().await?.len();
+        if size < SEGMENT_HEADER_SIZE {
+            return Err(Error::InvalidMagic);
+        }
+
+        let mut header = [0; SEGMENT_HEADER_SIZE as usize];
+        file.read_exact(&mut header).await?;
+        if header[..4] != SEGMENT_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != SEGMENT_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let mut footer = None;
+        let mut end = size;
+        if size >= SEGMENT_HEADER_SIZE + FOOTER_SIZE {
+            let mut buf = [0; FOOTER_SIZE as usize];
+            file.seek(SeekFrom::Start(size - FOOTER_SIZE)).await?;
+            file.read_exact(&mut buf).await?;
+            footer = SegmentFooter::decode(&buf);
+            if footer.is_some() {
+                end = size - FOOTER_SIZE;
+            }
+        }
+        file.seek(SeekFrom::Start(SEGMENT_HEADER_SIZE)).await?;
+
+        Ok(Self {
+            path,
+            file: BufReader::new(file),
+            position: SEGMENT_HEADER_SIZE,
+            end,
+            footer,
+        })
+    }
+
+    /// Returns the path of the segment file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the footer if the segment is sealed.
+    pub fn footer(&self) -> Option<SegmentFooter> {
+        self.footer
+    }
+
+    /// Returns the position of the next entry.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the end position of the entries.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Seek to the position of an entry record.
+    pub async fn seek(&mut self, position: u64) -> Result<()> {
+        self.file.seek(SeekFrom::Start(position)).await?;
+        self.position = position;
+        Ok(())
+    }
+
+    /// Read the next entry, returns its position and the entry,
+    /// or `None` if all entries are read.
+    pub async fn next_entry(&mut self) -> Result<Option<(u64, AnyEntry)>> {
+        if self.position + RECORD_LENGTH_SIZE > self.end {
+            return Ok(None);
+        }
+        let length = self.file.read_u32_le().await? as usize;
+        if length == 0 {
+            // The footer is reached.
+            return Ok(None);
+        }
+        // A corrupted length must not allocate past the end of the entries.
+        if length as u64 > self.end - self.position - RECORD_LENGTH_SIZE {
+            return Err(entry::Error::DecodeBufNotEnough.into());
+        }
+        let mut buf = BytesMut::zeroed(length);
+        self.file.read_exact(&mut buf).await?;
+        let entry = entry::decode(buf.freeze())?;
+
+        let position = self.position;
+        self.position += RECORD_LENGTH_SIZE + length as u64;
+        Ok(Some((position, entry)))
+    }
+
+    /// Find the entry by its entry id, the scan starts from the nearest position in the index.
+    pub async fn find_entry(
+        &mut self,
+        entry_id: i64,
+        index: &SegmentIndex,
+    ) -> Result<Option<AnyEntry>> {
+        self.seek(index.lookup(entry_id)).await?;
+        while let Some((_, entry)) = self.next_entry().await? {
+            if entry.entry_id() == entry_id {
+                return Ok(Some(entry));
+            }
+            if entry.entry_id() > entry_id {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the first entry whose time is at or after the timestamp, see `TimeIndex` for the time of an entry.
+    /// The scan starts from the entry given by the time index, located by the index.
+    pub async fn find_entry_by_time(
+        &mut self,
+        timestamp: i64,
+        time_index: &TimeIndex,
+        index: &SegmentIndex,
+    ) -> Result<Option<AnyEntry>> {
+        let position = time_index
+            .lookup(timestamp)
+            .map_or(SEGMENT_HEADER_SIZE, |entry_id| index.lookup(entry_id));
+        self.seek(position).await?;
+        while let Some((_, entry)) = self.next_entry().await? {
+            if TimeIndex::entry_time(&entry).is_some_and(|time| time >= timestamp) {
+                return Ok(Some(entry));
+            }
+        }
+        Ok(None)
+    }
+}
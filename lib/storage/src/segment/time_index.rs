@@ -0,0 +1,311 @@
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::entry::{Entry, NO_TIMESTAMP};
+
+use super::{Error, Result, SegmentReader};
+
+// The time index file layout:
+//
+// Header: magic 4 + version 4 + interval 4 + count 8 + max_timestamp 8 = 28
+// Items: timestamp 8 + entry_id 8, repeated
+// Checksum: crc32c 4 over the header and items
+const TIME_INDEX_MAGIC: [u8; 4] = *b"LGTI";
+const TIME_INDEX_VERSION: u32 = 1;
+const TIME_INDEX_HEADER_SIZE: usize = 28;
+const TIME_INDEX_ITEM_SIZE: usize = 16;
+const TIME_INDEX_CHECKSUM_SIZE: usize = 4;
+const TIME_INDEX_FILE_EXTENSION: &str = "tix";
+
+/// Returns the path of the time index file of the segment.
+pub fn time_index_path(segment_path: impl AsRef<Path>) -> PathBuf {
+    segment_path
+        .as_ref()
+        .with_extension(TIME_INDEX_FILE_EXTENSION)
+}
+
+/// The `TimeIndex` is a sparse index of a segment by time,
+/// it maps timestamps to the entry ids from which the scan for them should start.
+///
+/// The time of an entry is its append time if it was recorded, its producer timestamp otherwise,
+/// the entries without either are not indexed. The times may be out of order, so every
+/// `interval`th timed entry records the max time so far with its entry id: all the entries
+/// up to that entry are older than any later time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeIndex {
+    interval: u32,
+    // The number of recorded entries with a time.
+    count: u64,
+    // The max time of the recorded entries, `NO_TIMESTAMP` if none.
+    max_timestamp: i64,
+    items: Vec<(i64, i64)>,
+}
+
+impl TimeIndex {
+    /// Create an empty index, `interval` must be positive.
+    pub fn new(interval: u32) -> Self {
+        assert!(interval > 0, "index interval must be positive");
+        Self {
+            interval,
+            count: 0,
+            max_timestamp: NO_TIMESTAMP,
+            items: Vec::new(),
+        }
+    }
+
+    /// Returns the time of the entry as indexed, `None` if it has no time.
+    pub fn entry_time<E: Entry>(entry: &E) -> Option<i64> {
+        entry.append_time().or(entry.timestamp())
+    }
+
+    /// Returns the interval of the index.
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Returns the max time of the recorded entries, `None` if none has a time.
+    pub fn max_timestamp(&self) -> Option<i64> {
+        Some(self.max_timestamp).filter(|max_timestamp| *max_timestamp != NO_TIMESTAMP)
+    }
+
+    /// Returns the indexed max times and entry ids.
+    pub fn items(&self) -> &[(i64, i64)] {
+        &self.items
+    }
+
+    /// Record an appended entry, only every `interval`th entry with a time is indexed.
+    pub fn record<E: Entry>(&mut self, entry: &E) {
+        let Some(time) = Self::entry_time(entry) else {
+            return;
+        };
+        self.max_timestamp = self.max_timestamp.max(time);
+        // The items are kept strictly increasing by time for the binary search.
+        if self.count.is_multiple_of(self.interval as u64)
+            && self
+                .items
+                .last()
+                .is_none_or(|(timestamp, _)| *timestamp < self.max_timestamp)
+        {
+            self.items.push((self.max_timestamp, entry.entry_id()));
+        }
+        self.count += 1;
+    }
+
+    /// Returns the entry id from which the scan for the first entry at or after the time should start,
+    /// `None` if it should start from the beginning of the segment.
+    pub fn lookup(&self, timestamp: i64) -> Option<i64> {
+        let i = self.items.partition_point(|(time, _)| *time < timestamp);
+        i.checked_sub(1).map(|i| self.items[i].1)
+    }
+
+    /// Rebuild the index by scanning the segment.
+    pub async fn rebuild(segment_path: impl AsRef<Path>, interval: u32) -> Result<Self> {
+        let mut index = Self::new(interval);
+        let mut reader = SegmentReader::open(segment_path).await?;
+        while let Some((_, entry)) = reader.next_entry().await? {
+            index.record(&entry);
+        }
+        Ok(index)
+    }
+
+    /// Load the time index of the segment, it's rebuilt and rewritten
+    /// if the index file is missing, corrupted or has another interval.
+    pub async fn load_or_rebuild(segment_path: impl AsRef<Path>, interval: u32) -> Result<Self> {
+        let segment_path = segment_path.as_ref();
+        let path = time_index_path(segment_path);
+        match Self::load(&path).await {
+            Ok(index) if index.interval == interval => return Ok(index),
+            Ok(_)
+            | Err(Error::CorruptedIndex)
+            | Err(Error::InvalidMagic)
+            | Err(Error::UnsupportedVersion(_)) => {}
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let index = Self::rebuild(segment_path, interval).await?;
+        index.write(&path).await?;
+        Ok(index)
+    }
+
+    /// Load the index from the time index file.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let data = tokio::fs::read(path).await?;
+        if data.len() < TIME_INDEX_HEADER_SIZE + TIME_INDEX_CHECKSUM_SIZE
+            || !(data.len() - TIME_INDEX_HEADER_SIZE - TIME_INDEX_CHECKSUM_SIZE)
+                .is_multiple_of(TIME_INDEX_ITEM_SIZE)
+        {
+            return Err(Error::CorruptedIndex);
+        }
+        let (body, checksum) = data.split_at(data.len() - TIME_INDEX_CHECKSUM_SIZE);
+        if crc32c::crc32c(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(Error::CorruptedIndex);
+        }
+
+        let mut buf = body;
+        if buf[..4] != TIME_INDEX_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        buf.advance(4);
+        let version = buf.get_u32_le();
+        if version != TIME_INDEX_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let interval = buf.get_u32_le();
+        if interval == 0 {
+            return Err(Error::CorruptedIndex);
+        }
+        let count = buf.get_u64_le();
+        let max_timestamp = buf.get_i64_le();
+        let mut items = Vec::with_capacity(buf.remaining() / TIME_INDEX_ITEM_SIZE);
+        while buf.has_remaining() {
+            items.push((buf.get_i64_le(), buf.get_i64_le()));
+        }
+        Ok(Self {
+            interval,
+            count,
+            max_timestamp,
+            items,
+        })
+    }
+
+    /// Write the time index file, the file is replaced atomically.
+    pub async fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let mut buf = BytesMut::with_capacity(
+            TIME_INDEX_HEADER_SIZE
+                + self.items.len() * TIME_INDEX_ITEM_SIZE
+                + TIME_INDEX_CHECKSUM_SIZE,
+        );
+        buf.put_slice(&TIME_INDEX_MAGIC);
+        buf.put_u32_le(TIME_INDEX_VERSION);
+        buf.put_u32_le(self.interval);
+        buf.put_u64_le(self.count);
+        buf.put_i64_le(self.max_timestamp);
+        for (timestamp, entry_id) in &self.items {
+            buf.put_i64_le(*timestamp);
+            buf.put_i64_le(*entry_id);
+        }
+        let checksum = crc32c::crc32c(&buf);
+        buf.put_u32_le(checksum);
+
+        let tmp_path = path.with_extension("tix.tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, &buf).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::{BuilderV1, BuilderV2, EntryV2};
+    use crate::segment::{index_path, segment_path, SegmentIndex, SegmentOptions, SegmentWriter};
+    use bytes::Bytes;
+
+    fn new_entry(entry_id: i64, timestamp: i64) -> EntryV2 {
+        BuilderV2::new()
+            .entry_id(entry_id)
+            .timestamp(timestamp)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build()
+    }
+
+    #[test]
+    fn test_time_index_lookup() {
+        let mut index = TimeIndex::new(2);
+        // The times are out of order, entry 3 is older than entry 2.
+        for (entry_id, timestamp) in [(0, 100), (1, 110), (2, 130), (3, 120), (4, 140), (5, 150)] {
+            index.record(&new_entry(entry_id, timestamp));
+        }
+        // Untimed entries are skipped.
+        index.record(
+            &BuilderV1::new()
+                .entry_id(6)
+                .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+                .build(),
+        );
+        assert_eq!(index.items(), &[(100, 0), (130, 2), (140, 4)]);
+        assert_eq!(index.max_timestamp(), Some(150));
+        assert_eq!(TimeIndex::new(2).max_timestamp(), None);
+
+        assert_eq!(index.lookup(50), None);
+        assert_eq!(index.lookup(100), None);
+        assert_eq!(index.lookup(101), Some(0));
+        assert_eq!(index.lookup(125), Some(0));
+        assert_eq!(index.lookup(131), Some(2));
+        assert_eq!(index.lookup(1000), Some(4));
+
+        // The append time takes precedence over the producer timestamp.
+        let mut entry = new_entry(0, 100);
+        entry.set_append_time(90);
+        assert_eq!(TimeIndex::entry_time(&entry), Some(90));
+    }
+
+    #[tokio::test]
+    async fn test_find_entry_by_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = segment_path(dir.path(), 0);
+        let options = SegmentOptions {
+            index_interval: 3,
+            ..Default::default()
+        };
+        let mut writer = SegmentWriter::create(&path, &options).await.unwrap();
+        // Entry i is produced at 1000 + 10 * i, except entry 7 which is late.
+        let time = |i: i64| if i == 7 { 1005 } else { 1000 + 10 * i };
+        for i in 0..20 {
+            writer.append(&new_entry(i, time(i))).await.unwrap();
+        }
+        let expected = writer.time_index().clone();
+        writer.seal().await.unwrap();
+
+        let index = SegmentIndex::load(index_path(&path)).await.unwrap();
+        let time_index = TimeIndex::load(time_index_path(&path)).await.unwrap();
+        assert_eq!(time_index, expected);
+        assert_eq!(time_index, TimeIndex::rebuild(&path, 3).await.unwrap());
+        assert_eq!(time_index.max_timestamp(), Some(1190));
+
+        let mut reader = crate::segment::SegmentReader::open(&path).await.unwrap();
+        for (timestamp, entry_id) in [
+            (0, Some(0)),
+            (1000, Some(0)),
+            (1001, Some(1)),
+            (1005, Some(1)),
+            (1060, Some(6)),
+            (1061, Some(8)),
+            (1075, Some(8)),
+            (1190, Some(19)),
+            (1191, None),
+        ] {
+            let entry = reader
+                .find_entry_by_time(timestamp, &time_index, &index)
+                .await
+                .unwrap();
+            assert_eq!(entry.map(|e| e.entry_id()), entry_id, "at {}", timestamp);
+        }
+
+        // Missing or corrupted time index files are rebuilt.
+        tokio::fs::remove_file(time_index_path(&path))
+            .await
+            .unwrap();
+        assert_eq!(
+            TimeIndex::load_or_rebuild(&path, 3).await.unwrap(),
+            expected
+        );
+        let mut data = tokio::fs::read(time_index_path(&path)).await.unwrap();
+        data[TIME_INDEX_HEADER_SIZE] ^= 0x01;
+        tokio::fs::write(time_index_path(&path), &data)
+            .await
+            .unwrap();
+        assert!(matches!(
+            TimeIndex::load(time_index_path(&path)).await,
+            Err(Error::CorruptedIndex)
+        ));
+        assert_eq!(
+            TimeIndex::load_or_rebuild(&path, 3).await.unwrap(),
+            expected
+        );
+    }
+}
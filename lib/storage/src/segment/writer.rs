@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use bytes::{BufMut, BytesMut};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::entry::Entry;
+
+use super::{index_path, segment_path, time_index_path, Error, Result, SegmentFooter};
+use super::{SegmentIndex, SegmentOptions, TimeIndex};
+use super::{
+    INVALID_ENTRY_ID, RECORD_LENGTH_SIZE, SEGMENT_HEADER_SIZE, SEGMENT_MAGIC, SEGMENT_VERSION,
+};
+
+/// The `SegmentWriter` appends encoded entries to a segment file.
+///
+/// Each entry is framed by its length, the segment is sealed by a footer recording
+/// the first and last entry id, no more entries can be appended after that.
+/// The sparse index and the time index of the segment are written alongside when it's sealed.
+pub struct SegmentWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    first_entry_id: i64,
+    last_entry_id: i64,
+    index: SegmentIndex,
+    time_index: TimeIndex,
+}
+
+impl SegmentWriter {
+    /// Create a new segment file and write the file header, the file must not exist.
+    pub async fn create(path: impl AsRef<Path>, options: &SegmentOptions) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        let mut file = BufWriter::new(file);
+        file.write_all(&SEGMENT_MAGIC).await?;
+        file.write_all(&SEGMENT_VERSION.to_le_bytes()).await?;
+        Ok(Self {
+            path,
+            file,
+            size: SEGMENT_HEADER_SIZE,
+            first_entry_id: INVALID_ENTRY_ID,
+            last_entry_id: INVALID_ENTRY_ID,
+            index: SegmentIndex::new(options.index_interval),
+            time_index: TimeIndex::new(options.index_interval),
+        })
+    }
+
+    /// Returns the path of the segment file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the size of the segment file, including the buffered data.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns whether no entry is appended yet.
+    pub fn is_empty(&self) -> bool {
+        self.first_entry_id == INVALID_ENTRY_ID
+    }
+
+    /// Returns the size of the record of the entry in a segment.
+    pub fn record_size<E: Entry>(entry: &E) -> u64 {
+        RECORD_LENGTH_SIZE + entry.binary_size() as u64
+    }
+
+    /// Append an entry to the segment, returns the position of its record.
+    /// The entry is buffered, call `sync` to make it durable.
+    pub async fn append<E: Entry>(&mut self, entry: &E) -> Result<u64> {
+        let binary_size = entry.binary_size();
+        if binary_size == 0 || binary_size > u32::MAX as usize {
+            return Err(Error::EntryTooLarge(binary_size));
+        }
+
+        let mut buf = BytesMut::with_capacity(RECORD_LENGTH_SIZE as usize + binary_size);
+        buf.put_u32_le(binary_size as u32);
+        entry.encode(&mut buf)?;
+        self.file.write_all(&buf).await?;
+
+        let position = self.size;
+        self.size += buf.len() as u64;
+        if self.first_entry_id == INVALID_ENTRY_ID {
+            self.first_entry_id = entry.entry_id();
+        }
+        self.last_entry_id = entry.entry_id();
+        self.index.record(entry.entry_id(), position);
+        self.time_index.record(entry);
+        Ok(position)
+    }
+
+    /// Flush the buffered entries and sync them to the disk.
+    pub async fn sync(&mut self) -> Result<()> {
+        self.file.flush().await?;
+        self.file.get_ref().sync_data().await?;
+        Ok(())
+    }
+
+    /// Returns the sparse index of the appended entries.
+    pub fn index(&self) -> &SegmentIndex {
+        &self.index
+    }
+
+    /// Returns the time index of the appended entries.
+    pub fn time_index(&self) -> &TimeIndex {
+        &self.time_index
+    }
+
+    /// Write the footer and sync the segment, then write the indexes, returns the footer.
+    pub async fn seal(mut self) -> Result<SegmentFooter> {
+        let footer = SegmentFooter {
+            first_entry_id: self.first_entry_id,
+            last_entry_id: self.last_entry_id,
+        };
+        self.file.write_all(&footer.encode()).await?;
+        self.sync().await?;
+        self.index.write(index_path(&self.path)).await?;
+        self.time_index.write(time_index_path(&self.path)).await?;
+        Ok(footer)
+    }
+}
+
+/// The `RollingSegmentWriter` appends entries to the segments in a directory,
+/// a new segment is started once the current one reaches the max segment size.
+/// Each segment is named by its first entry id.
+pub struct RollingSegmentWriter {
+    dir: PathBuf,
+    options: SegmentOptions,
+    current: Option<SegmentWriter>,
+}
+
+impl RollingSegmentWriter {
+    /// Open the directory for appending, the directory is created if missing.
+    pub async fn open(dir: impl AsRef<Path>, options: SegmentOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            dir,
+            options,
+            current: None,
+        })
+    }
+
+    /// Returns the segment being appended.
+    pub fn current(&self) -> Option<&SegmentWriter> {
+        self.current.as_ref()
+    }
+
+    /// Append an entry, returns the path of the segment and the position of the entry in it.
+    pub async fn append<E: Entry>(&mut self, entry: &E) -> Result<(PathBuf, u64)> {
+        let record_size = SegmentWriter::record_size(entry);
+        if let Some(current) = &self.current {
+            if !current.is_empty() && current.size() + record_size > self.options.max_segment_size {
+                self.roll().await?;
+            }
+        }
+        if self.current.is_none() {
+            let path = segment_path(&self.dir, entry.entry_id());
+            self.current = Some(SegmentWriter::create(path, &self.options).await?);
+        }
+        let current = self.current.as_mut().unwrap();
+        let position = current.append(entry).await?;
+        Ok((current.path().to_path_buf(), position))
+    }
+
+    /// Sync the segment being appended.
+    pub async fn sync(&mut self) -> Result<()> {
+        if let Some(current) = &mut self.current {
+            current.sync().await?;
+        }
+        Ok(())
+    }
+
+    /// Seal the segment being appended, the next entry will start a new segment.
+    pub async fn roll(&mut self) -> Result<Option<SegmentFooter>> {
+        match self.current.take() {
+            Some(current) => Ok(Some(current.seal().await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Seal the segment being appended.
+    pub async fn close(mut self) -> Result<()> {
+        self.roll().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,22 @@
+use crate::entry;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("io")]
+    Io(#[from] std::io::Error),
+
+    #[error("entry")]
+    Entry(#[from] entry::Error),
+
+    #[error("frame of {size} bytes exceeds the max frame size {max}")]
+    FrameTooLarge { size: usize, max: usize },
+
+    #[error("malformed frame: {0}")]
+    Malformed(&'static str),
+
+    #[error("invalid op {0}")]
+    InvalidOp(u8),
+
+    #[error("invalid status {0}")]
+    InvalidStatus(u8),
+}
@@ -0,0 +1,621 @@
+mod error;
+mod protocol;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::entry::{self, DecodeOptions, EntryRef};
+use crate::ledger;
+use crate::replication::{self, Node};
+use crate::util::error_chain;
+
+pub use error::Error;
+pub use protocol::{
+    peek_correlation_id, read_frame, Op, Request, Response, Status, FRAME_LENGTH_SIZE,
+};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The backoff after a failed accept, doubled up to the max while the accepts keep failing.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The `ServerOptions` is the configuration of a server.
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// The max size of a request frame, a connection sending a larger one is closed.
+    pub max_frame_size: usize,
+    /// The max number of requests in flight per connection,
+    /// no more requests are read from the connection until one of them completes.
+    pub max_in_flight: usize,
+    /// The limits of the entries added by the clients, checked before they reach the node,
+    /// so a compressed entry can't decompress to more than `max_value_len` per value.
+    pub decode_options: DecodeOptions,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 * 1024 * 1024,
+            max_in_flight: 1024,
+            decode_options: DecodeOptions {
+                max_entry_size: 16 * 1024 * 1024,
+                max_value_len: 16 * 1024 * 1024,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// The `Server` serves the requests of the clients of a storage node over TCP.
+///
+/// The requests of a connection are pipelined, each one is handled as soon as it's read
+/// and its response is sent once it completes, tagged with the correlation id of the request.
+/// The adds of the same ledger are the exception, they're handled one after the other
+/// in the order they're read, so a node stores the entries of a writer in order.
+/// A connection sending a frame which can't be decoded is closed, an added entry exceeding
+/// the decode options is rejected with `Status::BadRequest`.
+pub struct Server {
+    listener: TcpListener,
+    node: Arc<dyn Node>,
+    options: ServerOptions,
+}
+
+impl Server {
+    /// Bind the server to the address, the requests are served by the node.
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        node: Arc<dyn Node>,
+        options: ServerOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            node,
+            options,
+        })
+    }
+
+    /// Returns the address the server is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Serve the connections until the shutdown future resolves,
+    /// the open connections are dropped then.
+    ///
+    /// A failed accept doesn't stop the server, e.g. when it runs out of file descriptors:
+    /// the error is logged and the server backs off before accepting again.
+    pub async fn serve(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        tokio::pin!(shutdown);
+        let mut connections = JoinSet::new();
+        let mut backoff = ACCEPT_BACKOFF;
+        loop {
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                accepted = self.listener.accept() => {
+                    let stream = match accepted.and_then(|(stream, _)| {
+                        stream.set_nodelay(true)?;
+                        Ok(stream)
+                    }) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::warn!("failed to accept a connection: {}", e);
+                            tokio::select! {
+                                _ = &mut shutdown => return Ok(()),
+                                _ = tokio::time::sleep(backoff) => {}
+                            }
+                            backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                            continue;
+                        }
+                    };
+                    backoff = ACCEPT_BACKOFF;
+                    let node = self.node.clone();
+                    let options = self.options.clone();
+                    connections.spawn(serve_connection(stream, node, options));
+                }
+                // Reap the finished connections, a failed connection doesn't stop the server.
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            }
+        }
+    }
+}
+
+async fn serve_connection(
+    stream: TcpStream,
+    node: Arc<dyn Node>,
+    options: ServerOptions,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (tx, rx) = mpsc::channel(options.max_in_flight);
+    let writing = tokio::spawn(write_responses(writer, rx));
+    let in_flight = Arc::new(Semaphore::new(options.max_in_flight));
+    // The completion of the last add read for each ledger, the next add of the ledger waits for it.
+    let mut last_adds: HashMap<i64, oneshot::Receiver<()>> = HashMap::new();
+
+    let reading = async {
+        while let Some(body) = read_frame(&mut reader, options.max_frame_size).await? {
+            let (correlation_id, request) = Request::decode(body)?;
+            let permit = in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+            let (previous_add, add_done) = match &request {
+                Request::AddEntry { entry, .. } => match EntryRef::new(entry) {
+                    Ok(view) => {
+                        if last_adds.len() > options.max_in_flight {
+                            // Forget the ledgers whose last add completed.
+                            last_adds.retain(|_, done| {
+                                matches!(done.try_recv(), Err(oneshot::error::TryRecvError::Empty))
+                            });
+                        }
+                        let (done_tx, done_rx) = oneshot::channel();
+                        (last_adds.insert(view.log_id(), done_rx), Some(done_tx))
+                    }
+                    // The add is rejected as soon as it's handled.
+                    Err(_) => (None, None),
+                },
+                _ => (None, None),
+            };
+            let node = node.clone();
+            let tx = tx.clone();
+            let decode_options = options.decode_options;
+            tokio::spawn(async move {
+                if let Some(previous_add) = previous_add {
+                    // Resolved once the previous add completed, or failed.
+                    let _ = previous_add.await;
+                }
+                let response = handle_request(node.as_ref(), request, &decode_options).await;
+                drop(add_done);
+                let _ = tx.send(encode_response(correlation_id, response)).await;
+                drop(permit);
+            });
+        }
+        Ok(())
+    }
+    .await;
+
+    // The responses of the requests in flight are still sent.
+    drop(tx);
+    let written = writing.await.expect("response writer never panics");
+    reading.and(written)
+}
+
+async fn write_responses(writer: OwnedWriteHalf, mut rx: mpsc::Receiver<BytesMut>) -> Result<()> {
+    let mut writer = BufWriter::new(writer);
+    while let Some(frame) = rx.recv().await {
+        writer.write_all(&frame).await?;
+        // Flush once no response is ready, so the pipelined responses share the writes.
+        if rx.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn handle_request(
+    node: &dyn Node,
+    request: Request,
+    decode_options: &DecodeOptions,
+) -> Response {
+    let op = request.op();
+    let result = match request {
+        Request::AddEntry { recovery, entry } => {
+            match entry::decode_with_options(entry.clone(), decode_options) {
+                Err(e) => Err(e.into()),
+                Ok(_) if recovery => node.recovery_add_entry(entry).await,
+                Ok(_) => node.add_entry(entry).await,
+            }
+            .map(|()| Response::AddEntry)
+        }
+        Request::ReadEntry { log_id, entry_id } => node
+            .read_entry(log_id, entry_id)
+            .await
+            .map(Response::ReadEntry),
+        Request::ReadLac { log_id } => node.read_lac(log_id).await.map(Response::ReadLac),
+        Request::Fence { log_id } => node.fence(log_id).await.map(Response::Fence),
+        Request::Close {
+            log_id,
+            last_entry_id,
+        } => node
+            .close(log_id, last_entry_id)
+            .await
+            .map(|()| Response::Close),
+        Request::EntryIds {
+            log_id,
+            first_entry_id,
+            last_entry_id,
+        } => node
+            .entry_ids(log_id, first_entry_id, last_entry_id)
+            .await
+            .map(Response::EntryIds),
+    };
+    result.unwrap_or_else(|e| Response::Error {
+        op,
+        status: status_of(&e),
+        message: error_chain(&e),
+    })
+}
+
+fn encode_response(correlation_id: u64, response: Response) -> BytesMut {
+    let mut buf = BytesMut::new();
+    if let Err(e) = response.encode(correlation_id, &mut buf) {
+        buf.clear();
+        Response::Error {
+            op: response.op(),
+            status: Status::Internal,
+            message: error_chain(&e),
+        }
+        .encode(correlation_id, &mut buf)
+        .expect("error response is always encodable");
+    }
+    buf
+}
+
+fn status_of(error: &replication::Error) -> Status {
+    match error {
+        replication::Error::Ledger(ledger::Error::LedgerNotFound(_)) => Status::LedgerNotFound,
+        replication::Error::Ledger(ledger::Error::EntryNotFound { .. }) => Status::EntryNotFound,
+        replication::Error::Ledger(ledger::Error::Fenced(_)) => Status::Fenced,
+        replication::Error::Ledger(ledger::Error::Closed(_)) => Status::Closed,
+        // A corrupted stored entry fails with `ledger::Error::Entry`, an internal error.
+        replication::Error::Entry(_) => Status::BadRequest,
+        replication::Error::Unavailable(_) => Status::Unavailable,
+        _ => Status::Internal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use tokio::io::AsyncWriteExt;
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::entry::{AnyEntry, BuilderV1, Compression, Entry};
+    use crate::ledger::LedgerStore;
+    use crate::replication::tests::local_nodes;
+    use crate::replication::LocalNode;
+    use crate::segment::SegmentOptions;
+
+    fn encoded_entry(log_id: i64, entry_id: i64, value: &'static [u8]) -> Bytes {
+        let entry = BuilderV1::new()
+            .log_id(log_id)
+            .entry_id(entry_id)
+            .last_confirm_id(entry_id - 1)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(value))
+            .build();
+        let mut buf = BytesMut::new();
+        entry.encode(&mut buf).unwrap();
+        buf.freeze()
+    }
+
+    // Send the requests in a single write and collect the responses by correlation id.
+    async fn pipeline(stream: &mut TcpStream, requests: Vec<Request>) -> HashMap<u64, Response> {
+        let mut buf = BytesMut::new();
+        for (correlation_id, request) in requests.iter().enumerate() {
+            request.encode(correlation_id as u64, &mut buf);
+        }
+        stream.write_all(&buf).await.unwrap();
+        let mut responses = HashMap::new();
+        while responses.len() < requests.len() {
+            let body = read_frame(stream, 1024 * 1024).await.unwrap().unwrap();
+            let (correlation_id, response) =
+                Response::decode(body, &DecodeOptions::default()).unwrap();
+            assert_eq!(response.op(), requests[correlation_id as usize].op());
+            responses.insert(correlation_id, response);
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = local_nodes(dir.path(), 1).await.remove(0);
+        let server = Server::bind("127.0.0.1:0", node, ServerOptions::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve(async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let requests = (0..10)
+            .map(|i| Request::AddEntry {
+                recovery: false,
+                entry: encoded_entry(1, i, b"value"),
+            })
+            .collect();
+        let responses = pipeline(&mut stream, requests).await;
+        assert!(responses
+            .values()
+            .all(|response| matches!(response, Response::AddEntry)));
+
+        let mut responses = pipeline(
+            &mut stream,
+            vec![
+                Request::ReadEntry {
+                    log_id: 1,
+                    entry_id: 3,
+                },
+                Request::ReadEntry {
+                    log_id: 1,
+                    entry_id: 10,
+                },
+                Request::ReadLac { log_id: 1 },
+                Request::ReadLac { log_id: 2 },
+                Request::AddEntry {
+                    recovery: false,
+                    entry: Bytes::from_static(b"garbage"),
+                },
+            ],
+        )
+        .await;
+        let Some(Response::ReadEntry(entry)) = responses.remove(&0) else {
+            panic!("entry 3 is not read");
+        };
+        assert_eq!(entry.entry_id(), 3);
+        assert_eq!(entry.value(), &Bytes::from_static(b"value"));
+        assert_eq!(responses[&1].status(), Status::EntryNotFound);
+        assert!(matches!(responses[&2], Response::ReadLac(8)));
+        assert_eq!(responses[&3].status(), Status::LedgerNotFound);
+        assert_eq!(responses[&4].status(), Status::BadRequest);
+
+        // The fenced ledger only accepts the recovery adds.
+        let responses = pipeline(&mut stream, vec![Request::Fence { log_id: 1 }]).await;
+        assert!(matches!(responses[&0], Response::Fence(8)));
+        let responses = pipeline(
+            &mut stream,
+            vec![
+                Request::AddEntry {
+                    recovery: false,
+                    entry: encoded_entry(1, 10, b"stale"),
+                },
+                Request::AddEntry {
+                    recovery: true,
+                    entry: encoded_entry(1, 10, b"recovered"),
+                },
+            ],
+        )
+        .await;
+        assert!(matches!(
+            &responses[&0],
+            Response::Error { status: Status::Fenced, message, .. } if message.contains("fenced")
+        ));
+        assert!(matches!(responses[&1], Response::AddEntry));
+        let responses = pipeline(
+            &mut stream,
+            vec![Request::Close {
+                log_id: 1,
+                last_entry_id: 10,
+            }],
+        )
+        .await;
+        assert!(matches!(responses[&0], Response::Close));
+        let responses = pipeline(&mut stream, vec![Request::ReadLac { log_id: 1 }]).await;
+        assert!(matches!(responses[&0], Response::ReadLac(10)));
+
+        shutdown.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+    }
+
+    // The `RecordingNode` records the ids of the added entries in the order they reach the node,
+    // the adds of the first entries of a ledger are the slowest so the others would overtake them.
+    struct RecordingNode {
+        node: Arc<LocalNode>,
+        added: std::sync::Mutex<Vec<(i64, i64)>>,
+    }
+
+    #[async_trait]
+    impl Node for RecordingNode {
+        fn id(&self) -> &str {
+            self.node.id()
+        }
+
+        async fn add_entry(&self, entry: Bytes) -> replication::Result<()> {
+            let (log_id, entry_id) = {
+                let view = EntryRef::new(&entry)?;
+                (view.log_id(), view.entry_id())
+            };
+            for _ in entry_id..10 {
+                tokio::task::yield_now().await;
+            }
+            self.added.lock().unwrap().push((log_id, entry_id));
+            self.node.add_entry(entry).await
+        }
+
+        async fn recovery_add_entry(&self, entry: Bytes) -> replication::Result<()> {
+            self.node.recovery_add_entry(entry).await
+        }
+
+        async fn read_entry(&self, log_id: i64, entry_id: i64) -> replication::Result<AnyEntry> {
+            self.node.read_entry(log_id, entry_id).await
+        }
+
+        async fn read_lac(&self, log_id: i64) -> replication::Result<i64> {
+            self.node.read_lac(log_id).await
+        }
+
+        async fn fence(&self, log_id: i64) -> replication::Result<i64> {
+            self.node.fence(log_id).await
+        }
+
+        async fn close(&self, log_id: i64, last_entry_id: i64) -> replication::Result<()> {
+            self.node.close(log_id, last_entry_id).await
+        }
+
+        async fn entry_ids(
+            &self,
+            log_id: i64,
+            first_entry_id: i64,
+            last_entry_id: i64,
+        ) -> replication::Result<Vec<i64>> {
+            self.node
+                .entry_ids(log_id, first_entry_id, last_entry_id)
+                .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_ordered_adds() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = Arc::new(RecordingNode {
+            node: local_nodes(dir.path(), 1).await.remove(0),
+            added: std::sync::Mutex::new(Vec::new()),
+        });
+        let server = Server::bind("127.0.0.1:0", node.clone(), ServerOptions::default())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(std::future::pending()));
+
+        // The adds of two ledgers are pipelined, interleaved.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let requests = (0..10)
+            .flat_map(|i| [encoded_entry(1, i, b"value"), encoded_entry(2, i, b"value")])
+            .map(|entry| Request::AddEntry {
+                recovery: false,
+                entry,
+            })
+            .collect();
+        let responses = pipeline(&mut stream, requests).await;
+        assert!(responses
+            .values()
+            .all(|response| matches!(response, Response::AddEntry)));
+        let added = node.added.lock().unwrap().clone();
+        for log_id in [1, 2] {
+            let entry_ids: Vec<i64> = added
+                .iter()
+                .filter(|(id, _)| *id == log_id)
+                .map(|(_, entry_id)| *entry_id)
+                .collect();
+            assert_eq!(entry_ids, (0..10).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve_decode_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = local_nodes(dir.path(), 1).await.remove(0);
+        let options = ServerOptions {
+            decode_options: DecodeOptions {
+                max_value_len: 1024,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let server = Server::bind("127.0.0.1:0", node.clone(), options)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(std::future::pending()));
+
+        // The values decompressing past the limit are rejected, not only the large frames.
+        #[allow(unused_mut)]
+        let mut compressions = vec![Compression::None];
+        #[cfg(feature = "zstd")]
+        compressions.push(Compression::Zstd);
+        let mut requests = vec![Request::AddEntry {
+            recovery: false,
+            entry: encoded_entry(1, 0, b"value"),
+        }];
+        for (i, compression) in compressions.into_iter().enumerate() {
+            let entry = BuilderV1::new()
+                .log_id(1)
+                .entry_id(i as i64 + 1)
+                .kv(
+                    Bytes::from_static(b"key"),
+                    Bytes::from(vec![0; 1024 * 1024]),
+                )
+                .compression(compression)
+                .build();
+            let mut buf = BytesMut::new();
+            entry.encode(&mut buf).unwrap();
+            for recovery in [false, true] {
+                requests.push(Request::AddEntry {
+                    recovery,
+                    entry: buf.clone().freeze(),
+                });
+            }
+        }
+        let count = requests.len();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let responses = pipeline(&mut stream, requests).await;
+        assert!(matches!(responses[&0], Response::AddEntry));
+        for i in 1..count as u64 {
+            assert!(matches!(
+                &responses[&i],
+                Response::Error { status: Status::BadRequest, message, .. } if message.contains("value length")
+            ));
+        }
+        assert_eq!(node.store().read_lac(1).await.unwrap(), -1);
+
+        // The local node enforces its own limits too.
+        let store = LedgerStore::open(dir.path().join("limited"), SegmentOptions::default())
+            .await
+            .unwrap();
+        let limited = LocalNode::new("limited", store).with_decode_options(DecodeOptions {
+            max_value_len: 1024,
+            ..Default::default()
+        });
+        let mut buf = BytesMut::new();
+        BuilderV1::new()
+            .log_id(1)
+            .kv(Bytes::from_static(b"key"), Bytes::from(vec![0; 2048]))
+            .build()
+            .encode(&mut buf)
+            .unwrap();
+        assert!(matches!(
+            limited.add_entry(buf.freeze()).await,
+            Err(replication::Error::Entry(entry::Error::ValueTooLong { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_serve_invalid_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let node = local_nodes(dir.path(), 1).await.remove(0);
+        let options = ServerOptions {
+            max_frame_size: 1024,
+            ..Default::default()
+        };
+        let server = Server::bind("127.0.0.1:0", node, options).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(std::future::pending()));
+
+        // A frame larger than the max, the connection is closed.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&4096u32.to_le_bytes()).await.unwrap();
+        assert!(read_frame(&mut stream, 1024).await.unwrap().is_none());
+
+        // An unknown op, the connection is closed.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        Request::ReadLac { log_id: 1 }.encode(1, &mut buf);
+        buf[FRAME_LENGTH_SIZE] = 42;
+        stream.write_all(&buf).await.unwrap();
+        assert!(read_frame(&mut stream, 1024).await.unwrap().is_none());
+
+        // The server still serves the other connections.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let responses = pipeline(&mut stream, vec![Request::Fence { log_id: 1 }]).await;
+        assert!(matches!(responses[&0], Response::Fence(-1)));
+    }
+}
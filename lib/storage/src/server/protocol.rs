@@ -0,0 +1,542 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::entry::{self, AnyEntry, DecodeOptions, Entry};
+
+use super::{Error, Result};
+
+// The frame layout, all integers are little endian:
+//
+// Frame: length 4 + body
+// Request body: op 1 + correlation id 8 + payload
+// Response body: op 1 + correlation id 8 + status 1 + payload
+//
+// Request payloads:
+//   AddEntry: flags 1 + encoded entry
+//   ReadEntry: log id 8 + entry id 8
+//   ReadLac, Fence: log id 8
+//   Close: log id 8 + last entry id 8
+//   EntryIds: log id 8 + first entry id 8 + last entry id 8
+// Response payloads if the status is `Ok`, the error message in UTF-8 otherwise:
+//   ReadEntry: encoded entry
+//   ReadLac, Fence: LAC 8
+//   AddEntry, Close: empty
+//   EntryIds: entry id 8 * count
+pub const FRAME_LENGTH_SIZE: usize = 4;
+const REQUEST_HEADER_SIZE: usize = 9;
+const RESPONSE_HEADER_SIZE: usize = 10;
+const ADD_FLAG_RECOVERY: u8 = 0x01;
+
+/// The `Op` is the operation of a request, echoed by its response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Op {
+    AddEntry = 1,
+    ReadEntry = 2,
+    ReadLac = 3,
+    Fence = 4,
+    Close = 5,
+    EntryIds = 6,
+}
+
+impl TryFrom<u8> for Op {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Op::AddEntry),
+            2 => Ok(Op::ReadEntry),
+            3 => Ok(Op::ReadLac),
+            4 => Ok(Op::Fence),
+            5 => Ok(Op::Close),
+            6 => Ok(Op::EntryIds),
+            _ => Err(Error::InvalidOp(value)),
+        }
+    }
+}
+
+/// The `Status` tells whether a request succeeded, or why it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    /// The request is invalid, e.g. the entry can't be decoded.
+    BadRequest = 1,
+    LedgerNotFound = 2,
+    EntryNotFound = 3,
+    Fenced = 4,
+    Closed = 5,
+    /// The node can't serve the request for now, it may succeed later.
+    Unavailable = 6,
+    Internal = 7,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Status::Ok),
+            1 => Ok(Status::BadRequest),
+            2 => Ok(Status::LedgerNotFound),
+            3 => Ok(Status::EntryNotFound),
+            4 => Ok(Status::Fenced),
+            5 => Ok(Status::Closed),
+            6 => Ok(Status::Unavailable),
+            7 => Ok(Status::Internal),
+            _ => Err(Error::InvalidStatus(value)),
+        }
+    }
+}
+
+/// The `Request` is a request to a storage node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Add an encoded entry, a recovery add is accepted even if the ledger is fenced.
+    AddEntry {
+        recovery: bool,
+        entry: Bytes,
+    },
+    ReadEntry {
+        log_id: i64,
+        entry_id: i64,
+    },
+    ReadLac {
+        log_id: i64,
+    },
+    Fence {
+        log_id: i64,
+    },
+    Close {
+        log_id: i64,
+        last_entry_id: i64,
+    },
+    /// List the ids of the stored entries from `first_entry_id` to `last_entry_id` inclusive.
+    EntryIds {
+        log_id: i64,
+        first_entry_id: i64,
+        last_entry_id: i64,
+    },
+}
+
+impl Request {
+    /// Returns the op of the request.
+    pub fn op(&self) -> Op {
+        match self {
+            Request::AddEntry { .. } => Op::AddEntry,
+            Request::ReadEntry { .. } => Op::ReadEntry,
+            Request::ReadLac { .. } => Op::ReadLac,
+            Request::Fence { .. } => Op::Fence,
+            Request::Close { .. } => Op::Close,
+            Request::EntryIds { .. } => Op::EntryIds,
+        }
+    }
+
+    /// Encode the request as a frame, tagged with the correlation id.
+    pub fn encode(&self, correlation_id: u64, buf: &mut BytesMut) {
+        let payload_size = match self {
+            Request::AddEntry { entry, .. } => 1 + entry.len(),
+            Request::ReadEntry { .. } | Request::Close { .. } => 16,
+            Request::ReadLac { .. } | Request::Fence { .. } => 8,
+            Request::EntryIds { .. } => 24,
+        };
+        let body_size = REQUEST_HEADER_SIZE + payload_size;
+        buf.reserve(FRAME_LENGTH_SIZE + body_size);
+        buf.put_u32_le(body_size as u32);
+        buf.put_u8(self.op() as u8);
+        buf.put_u64_le(correlation_id);
+        match self {
+            Request::AddEntry { recovery, entry } => {
+                buf.put_u8(if *recovery { ADD_FLAG_RECOVERY } else { 0 });
+                buf.put_slice(entry);
+            }
+            Request::ReadEntry { log_id, entry_id } => {
+                buf.put_i64_le(*log_id);
+                buf.put_i64_le(*entry_id);
+            }
+            Request::ReadLac { log_id } | Request::Fence { log_id } => buf.put_i64_le(*log_id),
+            Request::Close {
+                log_id,
+                last_entry_id,
+            } => {
+                buf.put_i64_le(*log_id);
+                buf.put_i64_le(*last_entry_id);
+            }
+            Request::EntryIds {
+                log_id,
+                first_entry_id,
+                last_entry_id,
+            } => {
+                buf.put_i64_le(*log_id);
+                buf.put_i64_le(*first_entry_id);
+                buf.put_i64_le(*last_entry_id);
+            }
+        }
+    }
+
+    /// Decode the body of a request frame, returns the correlation id and the request.
+    pub fn decode(mut body: Bytes) -> Result<(u64, Request)> {
+        if body.remaining() < REQUEST_HEADER_SIZE {
+            return Err(Error::Malformed("request header is truncated"));
+        }
+        let op = Op::try_from(body.get_u8())?;
+        let correlation_id = body.get_u64_le();
+        let request = match op {
+            Op::AddEntry => {
+                if !body.has_remaining() {
+                    return Err(Error::Malformed("add entry flags are missing"));
+                }
+                let flags = body.get_u8();
+                Request::AddEntry {
+                    recovery: flags & ADD_FLAG_RECOVERY != 0,
+                    entry: body,
+                }
+            }
+            Op::ReadEntry => {
+                let [log_id, entry_id] = get_ids(&mut body)?;
+                Request::ReadEntry { log_id, entry_id }
+            }
+            Op::ReadLac => {
+                let [log_id] = get_ids(&mut body)?;
+                Request::ReadLac { log_id }
+            }
+            Op::Fence => {
+                let [log_id] = get_ids(&mut body)?;
+                Request::Fence { log_id }
+            }
+            Op::Close => {
+                let [log_id, last_entry_id] = get_ids(&mut body)?;
+                Request::Close {
+                    log_id,
+                    last_entry_id,
+                }
+            }
+            Op::EntryIds => {
+                let [log_id, first_entry_id, last_entry_id] = get_ids(&mut body)?;
+                Request::EntryIds {
+                    log_id,
+                    first_entry_id,
+                    last_entry_id,
+                }
+            }
+        };
+        Ok((correlation_id, request))
+    }
+}
+
+/// The `Response` is the response of a storage node to a request.
+pub enum Response {
+    AddEntry,
+    ReadEntry(AnyEntry),
+    ReadLac(i64),
+    /// The LAC known by the node when the ledger is fenced.
+    Fence(i64),
+    Close,
+    EntryIds(Vec<i64>),
+    /// The request failed, the message is only meant for humans.
+    Error {
+        op: Op,
+        status: Status,
+        message: String,
+    },
+}
+
+impl Response {
+    /// Returns the op of the request of the response.
+    pub fn op(&self) -> Op {
+        match self {
+            Response::AddEntry => Op::AddEntry,
+            Response::ReadEntry(_) => Op::ReadEntry,
+            Response::ReadLac(_) => Op::ReadLac,
+            Response::Fence(_) => Op::Fence,
+            Response::Close => Op::Close,
+            Response::EntryIds(_) => Op::EntryIds,
+            Response::Error { op, .. } => *op,
+        }
+    }
+
+    /// Returns the status of the response.
+    pub fn status(&self) -> Status {
+        match self {
+            Response::Error { status, .. } => *status,
+            _ => Status::Ok,
+        }
+    }
+
+    /// Encode the response as a frame, tagged with the correlation id of the request.
+    pub fn encode(&self, correlation_id: u64, buf: &mut BytesMut) -> Result<()> {
+        let payload_size = match self {
+            Response::AddEntry | Response::Close => 0,
+            Response::ReadEntry(entry) => entry.binary_size(),
+            Response::ReadLac(_) | Response::Fence(_) => 8,
+            Response::EntryIds(entry_ids) => entry_ids.len() * 8,
+            Response::Error { message, .. } => message.len(),
+        };
+        let body_size = RESPONSE_HEADER_SIZE + payload_size;
+        buf.reserve(FRAME_LENGTH_SIZE + body_size);
+        buf.put_u32_le(body_size as u32);
+        buf.put_u8(self.op() as u8);
+        buf.put_u64_le(correlation_id);
+        buf.put_u8(self.status() as u8);
+        match self {
+            Response::AddEntry | Response::Close => {}
+            Response::ReadEntry(entry) => entry.encode(buf)?,
+            Response::ReadLac(lac) | Response::Fence(lac) => buf.put_i64_le(*lac),
+            Response::EntryIds(entry_ids) => {
+                for entry_id in entry_ids {
+                    buf.put_i64_le(*entry_id);
+                }
+            }
+            Response::Error { message, .. } => buf.put_slice(message.as_bytes()),
+        }
+        Ok(())
+    }
+
+    /// Decode the body of a response frame, returns the correlation id and the response.
+    /// The entry of a `ReadEntry` response is decoded with the options.
+    pub fn decode(mut body: Bytes, options: &DecodeOptions) -> Result<(u64, Response)> {
+        if body.remaining() < RESPONSE_HEADER_SIZE {
+            return Err(Error::Malformed("response header is truncated"));
+        }
+        let op = Op::try_from(body.get_u8())?;
+        let correlation_id = body.get_u64_le();
+        let status = Status::try_from(body.get_u8())?;
+        if status != Status::Ok {
+            let response = Response::Error {
+                op,
+                status,
+                message: String::from_utf8_lossy(&body).into_owned(),
+            };
+            return Ok((correlation_id, response));
+        }
+        let response = match op {
+            Op::AddEntry => Response::AddEntry,
+            Op::ReadEntry => Response::ReadEntry(entry::decode_with_options(body, options)?),
+            Op::ReadLac => {
+                let [lac] = get_ids(&mut body)?;
+                Response::ReadLac(lac)
+            }
+            Op::Fence => {
+                let [lac] = get_ids(&mut body)?;
+                Response::Fence(lac)
+            }
+            Op::Close => Response::Close,
+            Op::EntryIds => {
+                if !body.remaining().is_multiple_of(8) {
+                    return Err(Error::Malformed("payload size doesn't match the op"));
+                }
+                let mut entry_ids = Vec::with_capacity(body.remaining() / 8);
+                while body.has_remaining() {
+                    entry_ids.push(body.get_i64_le());
+                }
+                Response::EntryIds(entry_ids)
+            }
+        };
+        Ok((correlation_id, response))
+    }
+}
+
+/// Returns the correlation id of a request or response frame body,
+/// so a response whose payload can't be decoded still fails the right request.
+pub fn peek_correlation_id(body: &[u8]) -> Option<u64> {
+    let id = body.get(1..REQUEST_HEADER_SIZE)?;
+    Some(u64::from_le_bytes(id.try_into().unwrap()))
+}
+
+/// Read the body of the next frame, returns `None` if the stream ends between two frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> Result<Option<Bytes>> {
+    let mut length = [0; FRAME_LENGTH_SIZE];
+    let read = reader.read(&mut length).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[read..]).await?;
+    let size = u32::from_le_bytes(length) as usize;
+    if size > max_frame_size {
+        return Err(Error::FrameTooLarge {
+            size,
+            max: max_frame_size,
+        });
+    }
+    let mut body = BytesMut::zeroed(size);
+    reader.read_exact(&mut body).await?;
+    Ok(Some(body.freeze()))
+}
+
+// Read the fixed size ids of a payload, nothing may follow them.
+fn get_ids<const N: usize>(body: &mut Bytes) -> Result<[i64; N]> {
+    if body.remaining() != N * 8 {
+        return Err(Error::Malformed("payload size doesn't match the op"));
+    }
+    Ok(std::array::from_fn(|_| body.get_i64_le()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::BuilderV1;
+
+    fn frame_body(buf: BytesMut) -> Bytes {
+        let mut buf = buf.freeze();
+        let size = buf.get_u32_le() as usize;
+        assert_eq!(size, buf.len());
+        buf
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        let requests = [
+            Request::AddEntry {
+                recovery: false,
+                entry: Bytes::from_static(b"entry"),
+            },
+            Request::AddEntry {
+                recovery: true,
+                entry: Bytes::new(),
+            },
+            Request::ReadEntry {
+                log_id: 1,
+                entry_id: 2,
+            },
+            Request::ReadLac { log_id: 3 },
+            Request::Fence { log_id: -1 },
+            Request::Close {
+                log_id: 4,
+                last_entry_id: -1,
+            },
+            Request::EntryIds {
+                log_id: 5,
+                first_entry_id: 0,
+                last_entry_id: 9,
+            },
+        ];
+        for (correlation_id, request) in requests.into_iter().enumerate() {
+            let mut buf = BytesMut::new();
+            request.encode(correlation_id as u64, &mut buf);
+            let decoded = Request::decode(frame_body(buf)).unwrap();
+            assert_eq!(decoded, (correlation_id as u64, request));
+        }
+    }
+
+    #[test]
+    fn test_response_roundtrip() {
+        let entry = BuilderV1::new()
+            .log_id(1)
+            .entry_id(2)
+            .kv(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+            .build();
+        let mut buf = BytesMut::new();
+        Response::ReadEntry(AnyEntry::V1(entry))
+            .encode(7, &mut buf)
+            .unwrap();
+        let (correlation_id, response) =
+            Response::decode(frame_body(buf), &DecodeOptions::default()).unwrap();
+        assert_eq!(correlation_id, 7);
+        let Response::ReadEntry(entry) = response else {
+            panic!("unexpected response to {:?}", response.op());
+        };
+        assert_eq!(entry.entry_id(), 2);
+        assert_eq!(entry.value(), &Bytes::from_static(b"value"));
+
+        let mut buf = BytesMut::new();
+        Response::Fence(5).encode(8, &mut buf).unwrap();
+        let decoded = Response::decode(frame_body(buf), &DecodeOptions::default()).unwrap();
+        assert!(matches!(decoded, (8, Response::Fence(5))));
+
+        let mut buf = BytesMut::new();
+        Response::EntryIds(vec![0, 3, 6])
+            .encode(10, &mut buf)
+            .unwrap();
+        let decoded = Response::decode(frame_body(buf), &DecodeOptions::default()).unwrap();
+        assert!(matches!(decoded, (10, Response::EntryIds(ids)) if ids == [0, 3, 6]));
+
+        let mut buf = BytesMut::new();
+        Response::Error {
+            op: Op::AddEntry,
+            status: Status::Fenced,
+            message: "ledger 1 is fenced".to_string(),
+        }
+        .encode(9, &mut buf)
+        .unwrap();
+        let decoded = Response::decode(frame_body(buf), &DecodeOptions::default()).unwrap();
+        assert!(matches!(
+            decoded,
+            (9, Response::Error { op: Op::AddEntry, status: Status::Fenced, message })
+                if message == "ledger 1 is fenced"
+        ));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        assert!(matches!(
+            Request::decode(Bytes::from_static(&[1, 0, 0])),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            Request::decode(Bytes::from_static(&[9, 0, 0, 0, 0, 0, 0, 0, 0])),
+            Err(Error::InvalidOp(9))
+        ));
+        assert!(matches!(
+            Request::decode(Bytes::from_static(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 1])),
+            Err(Error::Malformed(_))
+        ));
+        assert!(matches!(
+            Response::decode(
+                Bytes::from_static(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 8]),
+                &DecodeOptions::default()
+            ),
+            Err(Error::InvalidStatus(8))
+        ));
+        assert!(matches!(
+            Response::decode(
+                Bytes::from_static(&[2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]),
+                &DecodeOptions::default()
+            ),
+            Err(Error::Entry(_))
+        ));
+        assert!(matches!(
+            Response::decode(
+                Bytes::from_static(&[6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]),
+                &DecodeOptions::default()
+            ),
+            Err(Error::Malformed(_))
+        ));
+        assert_eq!(
+            peek_correlation_id(&[2, 7, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]),
+            Some(7)
+        );
+        assert_eq!(peek_correlation_id(&[2, 7, 0]), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let mut buf = BytesMut::new();
+        Request::ReadLac { log_id: 1 }.encode(1, &mut buf);
+        Request::Fence { log_id: 2 }.encode(2, &mut buf);
+        let frames = buf.freeze();
+
+        let mut reader = &frames[..];
+        let body = read_frame(&mut reader, 1024).await.unwrap().unwrap();
+        assert_eq!(
+            Request::decode(body).unwrap(),
+            (1, Request::ReadLac { log_id: 1 })
+        );
+        assert!(read_frame(&mut reader, 1024).await.unwrap().is_some());
+        assert!(read_frame(&mut reader, 1024).await.unwrap().is_none());
+
+        // A frame larger than the max, or cut in the middle.
+        let mut reader = &frames[..];
+        assert!(matches!(
+            read_frame(&mut reader, 8).await,
+            Err(Error::FrameTooLarge { size: 17, max: 8 })
+        ));
+        let mut reader = &frames[..10];
+        assert!(matches!(
+            read_frame(&mut reader, 1024).await,
+            Err(Error::Io(_))
+        ));
+    }
+}
@@ -3,3 +3,20 @@ pub fn copy_slice(src: &[u8], dst: &mut [u8]) -> usize {
     dst[..n].copy_from_slice(&src[..n]);
     n
 }
+
+/// Sync the directory so the files created, renamed or removed in it survive a crash.
+pub async fn sync_dir(dir: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    tokio::fs::File::open(dir).await?.sync_all().await
+}
+
+/// Returns the message of the error followed by the messages of its sources.
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        message.push_str(": ");
+        message.push_str(&error.to_string());
+        source = error.source();
+    }
+    message
+}